@@ -0,0 +1,41 @@
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, Criterion};
+use web_server::{create_msg_from_code, make_response, parse_request, Config, DocumentRoot};
+
+fn bench_make_response(c: &mut Criterion) {
+    let config = Config::default();
+    let root = DocumentRoot::Directories(vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("webroot")]);
+
+    // 小さな静的ファイルの配信
+    let (request, _) = parse_request(b"GET /index.html HTTP/1.0\r\nHost: localhost\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    c.bench_function("make_response static file", |b| {
+        b.iter(|| make_response(&request, &root, &config).unwrap())
+    });
+
+    // 存在しないファイル
+    let (request, _) = parse_request(b"GET /not_found.html HTTP/1.0\r\n\r\n")
+        .unwrap()
+        .unwrap();
+    c.bench_function("make_response not found", |b| {
+        b.iter(|| make_response(&request, &root, &config).unwrap())
+    });
+}
+
+fn bench_error_response(c: &mut Criterion) {
+    let config = Config::default();
+    for status_code in [400, 404, 501] {
+        c.bench_function(&format!("create_msg_from_code {}", status_code), |b| {
+            b.iter(|| create_msg_from_code(status_code, None).unwrap().to_bytes(&config, None))
+        });
+    }
+}
+
+fn bench_parse_request(c: &mut Criterion) {
+    let request = b"GET /index.html HTTP/1.1\r\nHost: localhost\r\nUser-Agent: bench\r\nAccept: */*\r\n\r\n";
+    c.bench_function("parse_request", |b| b.iter(|| parse_request(request).unwrap()));
+}
+
+criterion_group!(benches, bench_make_response, bench_error_response, bench_parse_request);
+criterion_main!(benches);
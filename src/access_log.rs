@@ -0,0 +1,152 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::warn;
+use crate::config::AccessLogConfig;
+use crate::error::{Error, Result};
+
+/**
+* アクセスログに記録する1件のリクエスト
+*/
+pub struct RequestLog {
+    // リクエストを受信した時刻
+    pub time: SystemTime,
+    pub remote_addr: Option<SocketAddr>,
+    pub method: String,
+    pub target: String,
+    // HTTP/1.xのマイナーバージョン
+    pub version: u8,
+    pub status: u16,
+    // 送信したボディのバイト数(HEADでは0)
+    pub body_bytes: usize,
+    // レスポンスの作成にかかった時間
+    pub duration: Duration,
+}
+
+/**
+* アクセスログの出力先。ライブラリとして使う場合はWebServer::set_access_logで独自の出力先に差し替えられる
+*/
+pub trait AccessLogSink {
+    /**
+    * レスポンスを送信待ちにするたびに呼ばれる。出力の失敗はリクエストの処理に影響させない
+    */
+    fn log_request(&mut self, record: &RequestLog);
+}
+
+/**
+* 何も記録しない
+*/
+pub struct NoopLog;
+
+impl AccessLogSink for NoopLog {
+    fn log_request(&mut self, _record: &RequestLog) {}
+}
+
+/**
+* 標準エラー出力に1リクエスト1行のテキストで記録する
+*/
+pub struct StderrLog;
+
+impl AccessLogSink for StderrLog {
+    fn log_request(&mut self, record: &RequestLog) {
+        let remote = record.remote_addr.map_or_else(|| "-".to_string(), |addr| addr.ip().to_string());
+        eprintln!(
+            "{} {:.3} \"{} {} HTTP/1.{}\" {} {} {}ms",
+            remote,
+            unix_seconds(record.time),
+            record.method,
+            record.target,
+            record.version,
+            record.status,
+            record.body_bytes,
+            record.duration.as_millis()
+        );
+    }
+}
+
+/**
+* ファイルの末尾に1リクエスト1行のJSONで追記する
+*/
+pub struct JsonFileLog {
+    file: File,
+}
+
+impl JsonFileLog {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonFileLog { file })
+    }
+}
+
+impl AccessLogSink for JsonFileLog {
+    fn log_request(&mut self, record: &RequestLog) {
+        let remote = record.remote_addr.map_or_else(|| "null".to_string(), |addr| json_string(&addr.ip().to_string()));
+        let line = format!(
+            "{{\"time\":{:.3},\"remote_addr\":{},\"method\":{},\"target\":{},\"version\":\"HTTP/1.{}\",\
+             \"status\":{},\"body_bytes\":{},\"duration_ms\":{}}}\n",
+            unix_seconds(record.time),
+            remote,
+            json_string(&record.method),
+            json_string(&record.target),
+            record.version,
+            record.status,
+            record.body_bytes,
+            record.duration.as_millis()
+        );
+        if let Err(e) = self.file.write_all(line.as_bytes()) {
+            warn!("Failed to write the access log: {}", e);
+        }
+    }
+}
+
+/**
+* 設定に対応する出力先を作る
+*/
+pub(crate) fn open_access_log(config: &AccessLogConfig) -> Result<Box<dyn AccessLogSink>, Error> {
+    Ok(match config {
+        AccessLogConfig::None => Box::new(NoopLog),
+        AccessLogConfig::Stderr => Box::new(StderrLog),
+        AccessLogConfig::Json(path) => Box::new(
+            JsonFileLog::open(path)
+                .map_err(|e| Error::Config(format!("Failed to open access log {}: {}", path, e)))?,
+        ),
+    })
+}
+
+/**
+* UNIX時間の秒数(小数点以下はミリ秒まで)
+*/
+fn unix_seconds(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH).map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+/**
+* JSONの文字列リテラルにする。"と\と制御文字をエスケープする
+*/
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_strings_escape_quotes_and_control_characters() {
+        assert_eq!(json_string("/a b"), "\"/a b\"");
+        assert_eq!(json_string("say \"hi\"\\"), "\"say \\\"hi\\\"\\\\\"");
+        assert_eq!(json_string("line\nbreak\u{7f}"), "\"line\\u000abreak\\u007f\"");
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::IpAddr;
+use serde::Deserialize;
+
+/**
+* "192.168.0.0/16"や"fd00::/8"の形式のアドレス範囲。プレフィックス長を省略した場合は単一のアドレスとする
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /**
+    * アドレスが範囲に含まれるか。IPv4射影アドレス(::ffff:a.b.c.d)はIPv4として比較する
+    */
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid CIDR: {:?}", value);
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value.as_str(), None),
+        };
+        let network: IpAddr = address.parse().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+        Ok(Cidr { network: network.to_canonical(), prefix_len })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Cidr {
+        Cidr::try_from(value.to_string()).unwrap()
+    }
+
+    #[test]
+    fn ipv4_range_contains_only_its_addresses() {
+        let range = cidr("192.168.0.0/16");
+        assert!(range.contains("192.168.10.1".parse().unwrap()));
+        assert!(range.contains("::ffff:192.168.10.1".parse().unwrap()));
+        assert!(!range.contains("192.169.0.1".parse().unwrap()));
+        assert!(!range.contains("fd00::1".parse().unwrap()));
+        assert!(cidr("0.0.0.0/0").contains("203.0.113.5".parse().unwrap()));
+        assert!(!cidr("10.0.0.1").contains("10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn ipv6_range_contains_only_its_addresses() {
+        let range = cidr("fd00::/8");
+        assert!(range.contains("fd12::1".parse().unwrap()));
+        assert!(!range.contains("fe80::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn invalid_ranges_are_rejected() {
+        for value in ["10.0.0.0/33", "fd00::/129", "10.0.0/8", "10.0.0.0/x"] {
+            assert!(Cidr::try_from(value.to_string()).is_err(), "{}", value);
+        }
+    }
+}
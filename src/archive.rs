@@ -0,0 +1,124 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use zip::ZipArchive;
+use crate::error::{Error, Result};
+use crate::path::to_relative_path;
+
+/**
+* ファイルを配信するzipまたはtarアーカイブ
+*/
+pub struct Archive {
+    kind: ArchiveKind,
+}
+
+enum ArchiveKind {
+    // エントリ名とアーカイブ内のインデックス
+    Zip {
+        archive: RefCell<ZipArchive<File>>,
+        entries: HashMap<String, usize>,
+    },
+    // エントリ名とファイル内のデータの位置、サイズ
+    Tar {
+        file: File,
+        entries: HashMap<String, (u64, u64)>,
+    },
+}
+
+impl Archive {
+    /**
+    * アーカイブを開いてエントリの一覧を作成する。形式は拡張子で判別する。
+    * ドキュメントルートと同様に、".."やドットファイルを含むエントリは配信しない
+    */
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let kind = match Path::new(path).extension().and_then(|e| e.to_str()) {
+            Some("zip") => {
+                let mut archive = ZipArchive::new(File::open(path)?)?;
+                let mut entries = HashMap::new();
+                for index in 0..archive.len() {
+                    let entry = archive.by_index(index)?;
+                    if entry.is_dir() {
+                        continue;
+                    }
+                    if let Some(name) = safe_entry_name(&entry.name()?) {
+                        entries.insert(name, index);
+                    }
+                }
+                ArchiveKind::Zip {
+                    archive: RefCell::new(archive),
+                    entries,
+                }
+            }
+            Some("tar") => {
+                let mut archive = tar::Archive::new(File::open(path)?);
+                let mut entries = HashMap::new();
+                for entry in archive.entries()? {
+                    let entry = entry?;
+                    if !entry.header().entry_type().is_file() {
+                        continue;
+                    }
+                    if let Some(name) = safe_entry_name(&entry.path()?.to_string_lossy()) {
+                        entries.insert(name, (entry.raw_file_position(), entry.size()));
+                    }
+                }
+                ArchiveKind::Tar {
+                    file: File::open(path)?,
+                    entries,
+                }
+            }
+            _ => return Err(Error::Config(format!("Unsupported archive format: {}", path))),
+        };
+        Ok(Archive { kind })
+    }
+
+    /**
+    * ドキュメントルートからの相対パスに対応するエントリを読み込む
+    */
+    pub fn read(&self, relative: &str) -> io::Result<Option<Vec<u8>>> {
+        match &self.kind {
+            ArchiveKind::Zip { archive, entries } => {
+                let Some(&index) = entries.get(relative) else {
+                    return Ok(None);
+                };
+                let mut archive = archive.borrow_mut();
+                let mut entry = archive.by_index(index).map_err(io::Error::other)?;
+                let mut buf = Vec::new();
+                entry.read_to_end(&mut buf)?;
+                Ok(Some(buf))
+            }
+            ArchiveKind::Tar { file, entries } => {
+                let Some(&(offset, size)) = entries.get(relative) else {
+                    return Ok(None);
+                };
+                let mut buf = vec![0u8; size as usize];
+                file.read_exact_at(&mut buf, offset)?;
+                Ok(Some(buf))
+            }
+        }
+    }
+}
+
+/**
+* エントリ名をリクエストのパスと同じ規則で正規化する
+*/
+fn safe_entry_name(name: &str) -> Option<String> {
+    to_relative_path(name.trim_start_matches("./"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entry_names_follow_the_request_path_rules() {
+        assert_eq!(safe_entry_name("index.html").as_deref(), Some("index.html"));
+        assert_eq!(safe_entry_name("./css/site.css").as_deref(), Some("css/site.css"));
+        assert_eq!(safe_entry_name(".well-known/security.txt").as_deref(), Some(".well-known/security.txt"));
+        for name in ["../outside.txt", "a/../../b", ".env", "docs/.git/config"] {
+            assert_eq!(safe_entry_name(name), None, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use log::{error, warn};
+use crate::clock::Clock;
+use crate::config::{is_valid_header_name, is_valid_header_value, CgiConfig};
+use crate::error::{Error, Result};
+use crate::path::to_relative_path;
+use crate::request::Request;
+use crate::response::{create_msg_from_code, Handled, Response};
+
+// スクリプトの終了を確認する間隔
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+// スクリプトの出力からレスポンスに引き継がないヘッダ。Content-Lengthはサーバが付与する
+const DROPPED_HEADERS: [&str; 4] = ["Content-Length", "Connection", "Keep-Alive", "Transfer-Encoding"];
+
+/**
+* CGIのpath_prefix配下のパスであれば、path_prefixを除いた"/スクリプト名/PATH_INFO"を返す
+*/
+pub(crate) fn cgi_script_path<'a>(path: &'a str, path_prefix: &str) -> Option<&'a str> {
+    let rest = path.strip_prefix(path_prefix.trim_end_matches('/'))?;
+    rest.starts_with('/').then_some(rest)
+}
+
+/**
+* 実行の準備ができたCGIスクリプト。runで実行する。
+* 実行中はスクリプトの終了を待つので、サーバはイベントループとは別のスレッドで実行する
+*/
+pub(crate) struct CgiJob {
+    command: Command,
+    script: PathBuf,
+    body: Vec<u8>,
+    timeout: Duration,
+    max_output_bytes: usize,
+}
+
+/**
+* CGIスクリプトの実行を準備する。スクリプトが見つからない場合などはその場でレスポンスを返す
+*/
+pub(crate) fn prepare_cgi(
+    request: &Request,
+    cgi: &CgiConfig,
+    script_path: &str,
+) -> Result<Handled, Error> {
+    // 先頭のセグメントがスクリプト名、残りがPATH_INFO
+    let (script_name, path_info) = match script_path[1..].find('/') {
+        Some(index) => script_path.split_at(index + 1),
+        None => (script_path, ""),
+    };
+    let Some(name) = to_relative_path(script_name) else {
+        return create_msg_from_code(403, None).map(Handled::Ready);
+    };
+    let script = Path::new(&cgi.dir).join(&name);
+    if name.is_empty() || !script.is_file() {
+        return create_msg_from_code(404, None).map(Handled::Ready);
+    }
+
+    let mut command = Command::new(fs::canonicalize(&script)?);
+    command
+        .env_clear()
+        .env("GATEWAY_INTERFACE", "CGI/1.1")
+        .env("SERVER_SOFTWARE", "mio webserver")
+        .env("SERVER_PROTOCOL", format!("HTTP/1.{}", request.version))
+        .env("REQUEST_METHOD", &request.method)
+        .env("SCRIPT_NAME", format!("{}{}", cgi.path_prefix.trim_end_matches('/'), script_name))
+        .env("PATH_INFO", path_info)
+        .env("QUERY_STRING", request.query().unwrap_or(""))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    // #!/usr/bin/env などでインタプリタを探せるようにPATHは引き継ぐ
+    if let Some(path) = std::env::var_os("PATH") {
+        command.env("PATH", path);
+    }
+    if let Some(remote_addr) = request.remote_addr {
+        command.env("REMOTE_ADDR", remote_addr.ip().to_string());
+    }
+    if let Some(host) = request.host() {
+        let server_name = host.rsplit_once(':').map_or(host, |(name, _)| name);
+        command.env("SERVER_NAME", server_name);
+    }
+    if !request.body.is_empty() {
+        command.env("CONTENT_LENGTH", request.body.len().to_string());
+    }
+    for (name, value) in cgi_header_variables(&request.headers) {
+        command.env(name, value);
+    }
+    Ok(Handled::Cgi(CgiJob {
+        command,
+        script,
+        body: request.body.clone(),
+        timeout: Duration::from_secs(cgi.timeout),
+        max_output_bytes: cgi.max_output_bytes,
+    }))
+}
+
+/**
+* リクエストヘッダを渡す環境変数。Content-TypeはCONTENT_TYPE、それ以外はHTTP_(名前)とする。
+* ProxyヘッダをHTTP_PROXYとして渡すと、プロキシの設定と解釈したスクリプトの通信を
+* クライアントが指定した先に送らせることができる(httpoxy、CVE-2016-5385)ので渡さない
+*/
+fn cgi_header_variables(headers: &[(String, String)]) -> Vec<(String, String)> {
+    let mut variables = Vec::new();
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Content-Type") {
+            variables.push(("CONTENT_TYPE".to_string(), value.clone()));
+            continue;
+        }
+        let variable = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        if name.eq_ignore_ascii_case("Content-Length") || variable == "HTTP_PROXY" {
+            continue;
+        }
+        variables.push((variable, value.clone()));
+    }
+    variables
+}
+
+impl CgiJob {
+    /**
+    * スクリプトを実行してその出力をレスポンスにする。
+    * タイムアウトした場合はスクリプトを終了させて504を返し、出力が不正な場合やmax_output_bytesを超えた場合は502を返す
+    */
+    pub(crate) fn run(mut self, clock: &dyn Clock) -> Result<Response, Error> {
+        let mut child = self.command.spawn()?;
+        // パイプのバッファが埋まって止まらないように、ボディの書き込みと出力の読み込みは別スレッドで行う
+        let mut stdin = child.stdin.take();
+        let body = std::mem::take(&mut self.body);
+        thread::spawn(move || {
+            if let Some(stdin) = stdin.as_mut() {
+                // スクリプトがボディを読まずに終了した場合の書き込みエラーは無視する
+                let _ = stdin.write_all(&body);
+            }
+        });
+        let mut stdout = child.stdout.take();
+        // 上限を1バイトでも超えたら読むのをやめる
+        let limit = self.max_output_bytes as u64 + 1;
+        let reader = thread::spawn(move || {
+            let mut output = Vec::new();
+            if let Some(stdout) = stdout.as_mut() {
+                stdout.take(limit).read_to_end(&mut output)?;
+            }
+            Ok::<_, std::io::Error>(output)
+        });
+
+        let deadline = clock.now() + self.timeout;
+        loop {
+            let exited = child.try_wait()?.is_some();
+            // 出力が上限を超えたらスクリプトの終了を待たない
+            if exited || reader.is_finished() {
+                break;
+            }
+            if clock.now() >= deadline {
+                warn!("CGI script {} timed out", self.script.display());
+                child.kill()?;
+                child.wait()?;
+                // 出力を読むスレッドはパイプが閉じると終了するので待たない
+                return create_msg_from_code(504, None);
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+        let output = reader.join().map_err(|_| Error::Internal("CGI reader thread panicked".to_string()))??;
+        if output.len() > self.max_output_bytes {
+            error!("CGI script {} wrote more than {} bytes", self.script.display(), self.max_output_bytes);
+            // 書き込み中のスクリプトは終了させる。既に終了していればエラーは無視する
+            let _ = child.kill();
+            child.wait()?;
+            return create_msg_from_code(502, None);
+        }
+        // 出力を閉じた後もしばらく動き続けるスクリプトは、タイムアウトまで終了を待つ
+        while child.try_wait()?.is_none() {
+            if clock.now() >= deadline {
+                warn!("CGI script {} timed out", self.script.display());
+                child.kill()?;
+                child.wait()?;
+                return create_msg_from_code(504, None);
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+
+        match parse_cgi_output(&output) {
+            Some(response) => Ok(response),
+            None => {
+                error!("Invalid output from CGI script {}", self.script.display());
+                create_msg_from_code(502, None)
+            }
+        }
+    }
+}
+
+/**
+* スクリプトの出力(ヘッダ、空行、ボディ)をレスポンスに変換する。
+* Statusがなければ200、Locationだけがあれば302とする
+*/
+fn parse_cgi_output(output: &[u8]) -> Option<Response> {
+    // 改行はCRLFとLFのどちらも受け付ける
+    let (header_len, separator_len) = match output.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(index) => (index, 4),
+        None => (output.windows(2).position(|w| w == b"\n\n")?, 2),
+    };
+    let header = std::str::from_utf8(&output[..header_len]).ok()?;
+    let body = output[header_len + separator_len..].to_vec();
+
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in header.lines() {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        if !is_valid_header_name(name) || !is_valid_header_value(value) {
+            return None;
+        }
+        if name.eq_ignore_ascii_case("Status") {
+            status = Some(value.split_whitespace().next()?.parse::<u16>().ok()?);
+        } else if !DROPPED_HEADERS.iter().any(|dropped| dropped.eq_ignore_ascii_case(name)) {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    let has_header = |target: &str| headers.iter().any(|(name, _)| name.eq_ignore_ascii_case(target));
+    let status = match status {
+        Some(status) => status,
+        None if has_header("Location") => 302,
+        None if has_header("Content-Type") => 200,
+        // CGI/1.1ではContent-Type、Location、Statusのいずれかが必須
+        None => return None,
+    };
+
+    let mut response = create_msg_from_code(status, Some(body)).ok()?;
+    for (name, value) in &headers {
+        response.add_header(name, value);
+    }
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn request_headers_become_http_variables() {
+        let variables = cgi_header_variables(&headers(&[
+            ("X-Forwarded-For", "10.0.0.1"),
+            ("Content-Type", "text/plain"),
+            ("Content-Length", "5"),
+        ]));
+        assert_eq!(
+            variables,
+            headers(&[("HTTP_X_FORWARDED_FOR", "10.0.0.1"), ("CONTENT_TYPE", "text/plain")])
+        );
+    }
+
+    #[test]
+    fn proxy_header_is_not_passed() {
+        for name in ["Proxy", "proxy", "PROXY"] {
+            assert!(cgi_header_variables(&headers(&[(name, "http://attacker.example")])).is_empty());
+        }
+    }
+
+    #[test]
+    fn cgi_output_is_parsed() {
+        let response = parse_cgi_output(b"Status: 404 Not Found\r\nX-Script: 1\r\n\r\nmissing").unwrap();
+        assert_eq!(response.status_code, 404);
+        assert_eq!(response.body, b"missing");
+        let response = parse_cgi_output(b"Location: /next\n\n").unwrap();
+        assert_eq!(response.status_code, 302);
+        assert!(parse_cgi_output(b"X-Script: 1\n\n").is_none());
+        assert!(parse_cgi_output(b"Content-Type: text/plain").is_none());
+    }
+}
@@ -0,0 +1,70 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/**
+* サーバが参照する現在時刻。タイムアウト、キャッシュの期限、レート制限、アクセスログの時刻に使う。
+* WebServer::with_clockでMockClockを渡すと、時刻を進めてタイムアウトなどを確かめられる
+*/
+pub trait Clock: Send + Sync {
+    // 経過時間の計算に使う単調増加の時刻
+    fn now(&self) -> Instant;
+    // ログなどに記録する日時
+    fn system_now(&self) -> SystemTime;
+}
+
+/**
+* OSの時計をそのまま返す
+*/
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/**
+* advanceを呼んだ時だけ進む時計。作成した時点の時刻から始まる
+*/
+pub struct MockClock {
+    start: Instant,
+    start_system: SystemTime,
+    offset: Mutex<Duration>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock { start: Instant::now(), start_system: SystemTime::now(), offset: Mutex::new(Duration::ZERO) }
+    }
+
+    /**
+    * 時刻をdurationだけ進める
+    */
+    pub fn advance(&self, duration: Duration) {
+        *self.offset.lock().unwrap_or_else(|e| e.into_inner()) += duration;
+    }
+
+    fn offset(&self) -> Duration {
+        *self.offset.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + self.offset()
+    }
+
+    fn system_now(&self) -> SystemTime {
+        self.start_system + self.offset()
+    }
+}
@@ -0,0 +1,1056 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use serde::Deserialize;
+use crate::acl::Cidr;
+use crate::error::{Error, Result};
+use crate::response::create_msg_from_code;
+
+// read_buffer_sizeの下限
+const MIN_READ_BUFFER_SIZE: usize = 64;
+// read_retriesの上限
+const MAX_READ_RETRIES: u32 = 5;
+// 再試行で待つ時間の合計の上限(ミリ秒)。待つ間はリクエストのレスポンスが返らないため大きくしない
+const MAX_READ_RETRY_BACKOFF_MS: u64 = 10_000;
+// デフォルトで圧縮するContent-Type
+const DEFAULT_COMPRESSIBLE_TYPES: [&str; 10] = [
+    "text/html",
+    "text/css",
+    "text/javascript",
+    "text/plain",
+    "text/csv",
+    "application/json",
+    "application/manifest+json",
+    "application/xml",
+    "application/wasm",
+    "image/svg+xml",
+];
+
+// TLSのリスニングソケットに平文のHTTPリクエストが届いた場合に返すテキスト
+const DEFAULT_TLS_PLAINTEXT_MESSAGE: &str = "This port only accepts HTTPS. Use an https:// URL to connect.\n";
+
+/**
+* 設定ファイル(TOML)の内容
+*/
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // ドキュメントルート。複数指定した場合は先頭から順にファイルを探す。
+    // 1つだけならwebroot = "public"とも書ける
+    #[serde(alias = "webroot", deserialize_with = "string_or_list")]
+    pub webroots: Vec<String>,
+    // 全レスポンスに付与する追加ヘッダ
+    pub headers: Vec<HeaderRule>,
+    // 起動時にドキュメントルートのシンボリックリンクを解決し、以降はそのパスから配信する
+    // (SIGHUPで再解決する)
+    pub snapshot_root: bool,
+    // テンプレート置換の設定。未設定の場合は置換しない
+    pub templates: Option<TemplateConfig>,
+    // キープアライブ中の接続がアイドル状態のまま維持される秒数。0の場合はキープアライブしない
+    pub keep_alive_timeout: u64,
+    // リクエストの受信を完了するまでの秒数。途中まで受信していれば408を返して閉じる。0の場合は無制限
+    pub read_timeout: u64,
+    // リクエストの受信を始めてからレスポンスを送信し終えるまでの秒数。0の場合は無制限
+    pub request_timeout: u64,
+    // 送信待ちのレスポンスがあるのに送信が少しも進まない状態が続いたら接続を閉じるまでの秒数。0の場合は無制限
+    pub write_timeout: u64,
+    // レスポンスの作成にこれ以上かかったリクエストをwarnで記録する(ミリ秒)
+    pub slow_request_ms: Option<u64>,
+    // アクセスログの出力先
+    pub access_log: AccessLogConfig,
+    // リクエストターゲット(パスとクエリ文字列)の最大長。超えた場合は414を返す
+    pub max_target_length: usize,
+    // 1つの接続でパイプライン化されたリクエストを続けて処理する数の上限。
+    // 達したら読み込みを止め、送信し終えてから残りを処理する
+    pub max_pipelined_requests: usize,
+    // 同時に接続できるクライアントの最大数。超えた接続はすぐに閉じる
+    pub max_connections: usize,
+    // 起動時にファイルディスクリプタのソフトリミットをハードリミットまで引き上げる
+    pub raise_fd_limit: bool,
+    // 新しい接続を受け付けるレートの上限。未設定の場合は制限しない
+    pub accept_rate: Option<AcceptRate>,
+    // 受け付けた接続にTCPキープアライブ(SO_KEEPALIVE)を設定する。Noneの場合は設定しない
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    // 1回のイベントループで受け付ける接続の最大数。残りは既存の接続のイベントを処理してから受け付ける
+    pub max_accepts_per_poll: usize,
+    // 接続待ちキューの長さ(listen(2)のbacklog)
+    pub listen_backlog: i32,
+    // ソケットアクティベーション(LISTEN_FDS)でリスニングソケットが渡されていれば、起動時に指定したアドレスの代わりに使う
+    pub socket_activation: bool,
+    // /debug/echoでパース済みのリクエストを返す
+    pub debug_echo: bool,
+    // /debug/connectionsで接続中の全ての接続の状態、接続元、送受信したバイト数、経過時間をJSONで返す
+    pub debug_connections: bool,
+    // /statusで接続数、1秒あたりのリクエスト数、ステータスコードごとの件数をHTMLで返す
+    pub status_dashboard: bool,
+    // /versionでバージョン、gitのコミット、ビルド日時をJSONで返す
+    pub version_endpoint: bool,
+    // このパスへのGETで、クエリのfilesに","区切りで指定したファイルを順に連結して1つのレスポンスで返す
+    pub concat_path: Option<String>,
+    // 503と429のレスポンスに付与するRetry-After。指定しない場合は状況ごとの秒数を使う
+    pub retry_after: Option<RetryAfter>,
+    // HEADのヘルスチェックにヘッダをパースせずに200を返すパス
+    pub health_check_path: Option<String>,
+    // 起動時にメモリに読み込んでおき、ディスクから読まずに返すファイル(URLのパス)
+    pub preload: Vec<String>,
+    // このHTMLファイルが存在する間はヘルスチェック以外の全てのリクエストに503とその内容を返す
+    // (起動時とSIGHUPで確認する)
+    pub maintenance_page: Option<String>,
+    // ステータスコードごとに、ボディのないエラーレスポンスに付けるファイル
+    pub status_pages: Vec<StatusPage>,
+    // 1回のreadで読み込む最大バイト数
+    pub read_buffer_size: usize,
+    // TRACEでリクエストを返す。無効の場合は405を返す(Cross-Site Tracing対策)
+    pub trace_echo: bool,
+    // 不正なリクエストへの400や414などに、原因を説明する短いテキストのボディを付ける(開発用)
+    pub verbose_errors: bool,
+    // レスポンスのステータスラインとヘッダの最大バイト数。超えた場合は代わりに500を返す
+    pub max_response_header_size: usize,
+    // Serverヘッダを付与する。無効にすると全てのレスポンスからServerヘッダを取り除く
+    pub server_header: bool,
+    // 配信するファイルの拡張子またはパスごとのCache-Control
+    pub cache_control: Vec<CacheControlRule>,
+    // パスごとに103 Early Hintsで先に送るLinkヘッダ
+    pub early_hints: Vec<EarlyHintRule>,
+    // パスごとに2xxのレスポンスへ付与するpreloadのLinkヘッダ。HTTP/2で終端するプロキシがサーバープッシュに使う
+    pub preload_links: Vec<PreloadLinkRule>,
+    // ドキュメントルートのファイルに付与するETagの強さ。offの場合は付与しない
+    pub etag: EtagStrength,
+    // ドキュメントルートのファイルに更新日時のLast-Modifiedを付与し、If-Modified-Sinceで304を返す
+    pub last_modified: bool,
+    // HEADのルートがないパスへのHEADをGETのルートで処理し、ETagなどの検証用のヘッダをGETと揃える
+    pub head_as_get: bool,
+    // ドキュメントルートのファイルへのGETでRange(単一のバイト範囲)に206で応答し、Accept-Ranges: bytesを付与する
+    pub byte_ranges: bool,
+    // Accept-Encodingにgzipを含むクライアントにテキストファイルを圧縮して返す
+    pub compression: bool,
+    // これより小さいファイルは圧縮しない(バイト)
+    pub compression_min_size: usize,
+    // 圧縮するContent-Type(パラメータを除いたメディアタイプ)
+    pub compressible_types: Vec<String>,
+    // ドキュメントルートの代わりにファイルを配信するzipまたはtarアーカイブ
+    pub archive: Option<String>,
+    // ドキュメントルートのファイルの読み込みが一時的なエラー(ESTALEやEIOなど)で失敗した場合に再試行する回数
+    pub read_retries: u32,
+    // 最初の再試行までのミリ秒数。再試行のたびに倍にする
+    pub read_retry_delay_ms: u64,
+    // 拡張子のないファイルの内容からテキストかバイナリかを判別してContent-Typeを付与する
+    pub content_sniffing: bool,
+    // テキストファイルのBOMやHTMLの<meta charset>からContent-Typeのcharsetを決める
+    pub charset_detection: bool,
+    // ドキュメントルート内のシンボリックリンクをたどる。無効の場合はリンクを経由するパスに403を返す。
+    // 有効な場合でもドキュメントルートの外を指すリンクは403を返す
+    pub follow_symlinks: bool,
+    // パスの大文字小文字がディレクトリの実際の名前と一致しないファイルには404を返す(大文字小文字を区別しないファイルシステム向け)
+    pub exact_case: bool,
+    // Content-Disposition: attachmentを付けてダウンロードさせる拡張子
+    pub download_extensions: Vec<String>,
+    // Content-Disposition: attachmentを付けてダウンロードさせるContent-Type(パラメータを除く)。それ以外はブラウザで表示させる
+    pub download_types: Vec<String>,
+    // クエリ文字列にdownloadがあればContent-Disposition: attachmentを付ける
+    pub download_query: bool,
+    // 値で配信するファイルを切り替えるクエリパラメータ。?lang=frならindex.htmlの代わりにindex.fr.htmlがあれば返す
+    pub query_variants: Vec<String>,
+    // PUTで受け取ったファイルを保存し、DELETEで削除するディレクトリ。未設定の場合はPUTとDELETEに501を返す
+    pub upload_root: Option<String>,
+    // 同時に受信できるアップロード(ボディを持つPUTとPOST)の数
+    pub max_concurrent_uploads: usize,
+    // 受信中のアップロードのボディの合計の上限(バイト)
+    pub max_upload_bytes: usize,
+    // 全ての接続の送信待ちのレスポンスの合計の上限(バイト)。超える大きなレスポンスには代わりに503を返す
+    pub max_response_buffer_bytes: usize,
+    // ファイルの有無に関わらず403を返す拡張子(phpやbakなど)
+    pub blocked_extensions: Vec<String>,
+    // User-Agentにいずれかを含むリクエストを拒否する文字列(大文字小文字を区別しない)
+    pub blocked_user_agents: Vec<String>,
+    // blocked_user_agentsに一致したリクエストに返すステータスコード
+    pub blocked_user_agent_status: u16,
+    // WebSocketのハンドシェイクを受け付け、受信したメッセージをそのまま返すパス
+    pub websocket_echo_path: Option<String>,
+    // Server-Sent Eventsの購読を受け付けるパス。EventSenderで送ったイベントを配信する
+    pub sse_path: Option<String>,
+    // WebSocketとServer-Sent Eventsの接続の同時接続数の上限。超える接続の開始には503を返す
+    pub max_long_lived_connections: usize,
+    // add_cached_routeで登録したルートのレスポンスをキャッシュする件数の上限。超えた場合は最後に使ったのが古いものから捨てる
+    pub route_cache_entries: usize,
+    // HEADに使う、配信したファイルのメタデータをキャッシュする件数の上限。0にするとキャッシュしない
+    pub metadata_cache_entries: usize,
+    // パスごとに接続を許可するアドレス範囲
+    pub access_control: Vec<AccessControlRule>,
+    // 接続を受け付けるアドレス範囲。空の場合は全て受け付ける
+    pub allow_clients: Vec<Cidr>,
+    // 受け付けてすぐに閉じるアドレス範囲。allow_clientsより優先する
+    pub deny_clients: Vec<Cidr>,
+    // 全てのリクエストをHTTPSにリダイレクトする追加のリスニングソケット
+    pub redirect_listener: Option<RedirectListener>,
+    // 起動時に指定したアドレスに加えて受け付けるリスニングソケット。tlsを指定するとTLSで受け付ける
+    pub listeners: Vec<ListenerConfig>,
+    // TLSのリスニングソケットに平文のHTTPリクエストが届いた場合に400とともに返すテキスト
+    pub tls_plaintext_message: String,
+    // CGIの設定。未設定の場合はCGIを実行しない
+    pub cgi: Option<CgiConfig>,
+    // GET /に固定で返す内容。設定した場合はindex.htmlよりも優先する
+    pub root_response: Option<RootResponse>,
+    // ドキュメントルートにindex.htmlがなければ、GET /に組み込みの"It works"ページを返す(設置直後の確認用)
+    pub placeholder_page: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            webroots: vec!["webroot".to_string()],
+            headers: Vec::new(),
+            snapshot_root: false,
+            templates: None,
+            keep_alive_timeout: 5,
+            read_timeout: 30,
+            request_timeout: 0,
+            write_timeout: 30,
+            slow_request_ms: None,
+            access_log: AccessLogConfig::None,
+            max_target_length: 8192,
+            max_pipelined_requests: 16,
+            max_connections: 1024,
+            raise_fd_limit: false,
+            accept_rate: None,
+            tcp_keepalive: None,
+            max_accepts_per_poll: 64,
+            listen_backlog: 1024,
+            socket_activation: false,
+            debug_echo: false,
+            debug_connections: false,
+            status_dashboard: false,
+            version_endpoint: false,
+            concat_path: None,
+            retry_after: None,
+            health_check_path: None,
+            preload: Vec::new(),
+            maintenance_page: None,
+            status_pages: Vec::new(),
+            read_buffer_size: 1024,
+            trace_echo: false,
+            verbose_errors: false,
+            max_response_header_size: 64 * 1024,
+            server_header: true,
+            cache_control: Vec::new(),
+            early_hints: Vec::new(),
+            preload_links: Vec::new(),
+            etag: EtagStrength::Weak,
+            last_modified: false,
+            head_as_get: true,
+            byte_ranges: true,
+            compression: false,
+            compression_min_size: 1024,
+            compressible_types: DEFAULT_COMPRESSIBLE_TYPES.iter().map(|t| t.to_string()).collect(),
+            archive: None,
+            read_retries: 0,
+            read_retry_delay_ms: 10,
+            content_sniffing: true,
+            charset_detection: true,
+            follow_symlinks: true,
+            exact_case: false,
+            download_extensions: Vec::new(),
+            download_types: Vec::new(),
+            download_query: false,
+            query_variants: Vec::new(),
+            upload_root: None,
+            max_concurrent_uploads: 4,
+            max_upload_bytes: 4 * 1024 * 1024,
+            max_response_buffer_bytes: 256 * 1024 * 1024,
+            blocked_extensions: Vec::new(),
+            blocked_user_agents: Vec::new(),
+            blocked_user_agent_status: 403,
+            websocket_echo_path: None,
+            sse_path: None,
+            max_long_lived_connections: 256,
+            route_cache_entries: 256,
+            metadata_cache_entries: 256,
+            access_control: Vec::new(),
+            allow_clients: Vec::new(),
+            deny_clients: Vec::new(),
+            redirect_listener: None,
+            listeners: Vec::new(),
+            tls_plaintext_message: DEFAULT_TLS_PLAINTEXT_MESSAGE.to_string(),
+            cgi: None,
+            root_response: None,
+            placeholder_page: false,
+        }
+    }
+}
+
+/**
+* statusのレスポンスのボディにするファイル。content_typeを省略した場合は拡張子から決める
+*/
+#[derive(Debug, Deserialize)]
+pub struct StatusPage {
+    pub status: u16,
+    pub file: String,
+    pub content_type: Option<String>,
+}
+
+/**
+* Cache-Controlの設定。extensionかpath_prefixのどちらか一方を指定する
+*/
+#[derive(Debug, Deserialize)]
+pub struct CacheControlRule {
+    pub extension: Option<String>,
+    pub path_prefix: Option<String>,
+    pub value: String,
+}
+
+impl CacheControlRule {
+    fn matches(&self, path: &str) -> bool {
+        match (&self.extension, &self.path_prefix) {
+            (Some(extension), _) => Path::new(path).extension() == Some(extension.as_ref()),
+            (None, Some(prefix)) => path.starts_with(prefix.as_str()),
+            (None, None) => false,
+        }
+    }
+}
+
+/**
+* pathへのGETとHEADに、レスポンスの前に103 Early HintsでLinkヘッダ("</style.css>; rel=preload; as=style"など)を送る
+*/
+#[derive(Debug, Deserialize)]
+pub struct EarlyHintRule {
+    pub path: String,
+    pub links: Vec<String>,
+}
+
+/**
+* pathへのGETとHEADの2xxのレスポンスにLinkヘッダ("</style.css>; rel=preload; as=style"など)を付与する
+*/
+#[derive(Debug, Deserialize)]
+pub struct PreloadLinkRule {
+    pub path: String,
+    pub links: Vec<String>,
+}
+
+/**
+* 1秒あたりper_second件、瞬間的にはburst件まで接続を受け付ける。
+* 超えた接続は受け付けずに接続待ちキューに残す
+*/
+#[derive(Debug, Deserialize)]
+pub struct AcceptRate {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+/**
+* 通信がidle秒途絶えたら、interval秒ごとに最大count回キープアライブのプローブを送り、応答がなければ接続を切る。
+* intervalとcountを省略した場合はOSの既定値を使う
+*/
+#[derive(Debug, Deserialize)]
+pub struct TcpKeepalive {
+    pub idle: u64,
+    pub interval: Option<u64>,
+    pub count: Option<u32>,
+}
+
+/**
+* アクセスログの出力先。"none"、"stderr"、またはJSONで追記するファイル({ json = "access.log" })
+*/
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogConfig {
+    None,
+    Stderr,
+    Json(String),
+}
+
+/**
+* ファイルのサイズと更新日時から作るETagの強さ。
+* weakはW/"..."、strongは"..."を付与する。strongは同じ更新日時のうちに内容が変わらない場合にのみ使う
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EtagStrength {
+    Off,
+    Weak,
+    Strong,
+}
+
+/**
+* Retry-Afterの値。秒数(120)またはHTTP-date("Wed, 21 Oct 2026 07:28:00 GMT")で指定する
+*/
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum RetryAfter {
+    Seconds(u64),
+    Date(String),
+}
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryAfter::Seconds(seconds) => write!(f, "{}", seconds),
+            RetryAfter::Date(date) => write!(f, "{}", date),
+        }
+    }
+}
+
+/**
+* 文字列1つまたは文字列の配列で指定する値
+*/
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    One(String),
+    List(Vec<String>),
+}
+
+/**
+* "public"と["public"]のどちらも配列として読み込む
+*/
+fn string_or_list<'de, D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::One(value) => vec![value],
+        StringOrList::List(values) => values,
+    })
+}
+
+/**
+* path_prefix配下へのリクエストをallowのいずれかに含まれる送信元のみに許可する
+*/
+#[derive(Debug, Deserialize)]
+pub struct AccessControlRule {
+    pub path_prefix: String,
+    pub allow: Vec<Cidr>,
+}
+
+/**
+* 指定した拡張子のファイル中の{{変数名}}を設定値で置換して配信する
+*/
+#[derive(Debug, Deserialize)]
+pub struct TemplateConfig {
+    #[serde(default = "default_template_extension")]
+    pub extension: String,
+    #[serde(default)]
+    pub vars: HashMap<String, String>,
+}
+
+fn default_template_extension() -> String {
+    "shtml".to_string()
+}
+
+/**
+* addrで受け付けたリクエストをhttps://Host/パスに301でリダイレクトする
+*/
+#[derive(Debug, Deserialize)]
+pub struct RedirectListener {
+    pub addr: String,
+    // リダイレクト先のポート。省略した場合は443
+    pub https_port: Option<u16>,
+}
+
+/**
+* 追加のリスニングソケット
+*/
+#[derive(Debug, Deserialize)]
+pub struct ListenerConfig {
+    pub addr: String,
+    // 指定した場合はTLSで受け付ける
+    pub tls: Option<TlsConfig>,
+}
+
+/**
+* TLSのサーバ証明書(チェーン)と秘密鍵のPEMファイル。
+* SNIのホスト名がsniのいずれにも一致しない場合や、クライアントがSNIを送らない場合はこの証明書を使う
+*/
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub sni: Vec<SniConfig>,
+}
+
+/**
+* SNIのホスト名ごとの証明書と、そのホスト名で接続したリクエストのドキュメントルート
+*/
+#[derive(Debug, Deserialize)]
+pub struct SniConfig {
+    pub name: String,
+    pub cert: String,
+    pub key: String,
+    // 省略した場合はwebrootsから配信する
+    pub webroot: Option<String>,
+}
+
+/**
+* path_prefix配下へのリクエストでdir内のスクリプトをCGI/1.1として実行する
+*/
+#[derive(Debug, Deserialize)]
+pub struct CgiConfig {
+    #[serde(default = "default_cgi_path_prefix")]
+    pub path_prefix: String,
+    // スクリプトを置くディレクトリ(設定ファイルのあるディレクトリからの相対パス)
+    #[serde(default = "default_cgi_dir")]
+    pub dir: String,
+    // スクリプトの実行を打ち切るまでの秒数
+    #[serde(default = "default_cgi_timeout")]
+    pub timeout: u64,
+    // スクリプトの出力(ヘッダとボディ)の最大バイト数。超えた場合はスクリプトを終了させて502を返す
+    #[serde(default = "default_cgi_max_output_bytes")]
+    pub max_output_bytes: usize,
+}
+
+fn default_cgi_path_prefix() -> String {
+    "/cgi-bin".to_string()
+}
+
+fn default_cgi_dir() -> String {
+    "cgi-bin".to_string()
+}
+
+fn default_cgi_timeout() -> u64 {
+    10
+}
+
+fn default_cgi_max_output_bytes() -> usize {
+    16 * 1024 * 1024
+}
+
+/**
+* GET /に返す固定のレスポンス
+*/
+#[derive(Debug, Deserialize)]
+pub struct RootResponse {
+    pub body: String,
+    #[serde(default = "default_root_content_type")]
+    pub content_type: String,
+}
+
+fn default_root_content_type() -> String {
+    "text/plain; charset=utf-8".to_string()
+}
+
+/**
+* 追加レスポンスヘッダの設定。path_prefixを指定した場合はそのパス配下のみに付与する
+*/
+#[derive(Debug, Deserialize)]
+pub struct HeaderRule {
+    pub name: String,
+    pub value: String,
+    pub path_prefix: Option<String>,
+}
+
+impl Config {
+    /**
+    * 設定ファイルを読み込んで検証する。相対パスは設定ファイルのあるディレクトリを基準にする
+    */
+    pub fn load(path: &str) -> Result<Self, Error> {
+        let mut config: Config = toml::from_str(&fs::read_to_string(path)?)?;
+        if let Some(base) = Path::new(path).parent() {
+            config.resolve_paths(base);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /**
+    * 設定内のファイルとディレクトリの相対パスをbaseからのパスに置き換える。絶対パスはそのまま
+    */
+    fn resolve_paths(&mut self, base: &Path) {
+        let resolve = |path: &mut String| *path = base.join(&*path).to_string_lossy().into_owned();
+        self.webroots.iter_mut().for_each(resolve);
+        self.archive.iter_mut().for_each(resolve);
+        self.upload_root.iter_mut().for_each(resolve);
+        self.maintenance_page.iter_mut().for_each(resolve);
+        self.status_pages.iter_mut().for_each(|page| resolve(&mut page.file));
+        if let AccessLogConfig::Json(path) = &mut self.access_log {
+            resolve(path);
+        }
+        if let Some(cgi) = &mut self.cgi {
+            resolve(&mut cgi.dir);
+        }
+        for tls in self.listeners.iter_mut().filter_map(|listener| listener.tls.as_mut()) {
+            resolve(&mut tls.cert);
+            resolve(&mut tls.key);
+            for sni in &mut tls.sni {
+                resolve(&mut sni.cert);
+                resolve(&mut sni.key);
+                sni.webroot.iter_mut().for_each(resolve);
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.webroots.is_empty() {
+            return Err(Error::Config("webroots must not be empty".to_string()));
+        }
+        if self.max_connections == 0 {
+            return Err(Error::Config("max_connections must be positive".to_string()));
+        }
+        if let Some(rate) = &self.accept_rate {
+            if rate.per_second.is_nan() || rate.per_second <= 0.0 || rate.burst == 0 {
+                return Err(Error::Config("accept_rate per_second and burst must be positive".to_string()));
+            }
+        }
+        if let Some(keepalive) = &self.tcp_keepalive {
+            if keepalive.idle == 0 || keepalive.interval == Some(0) || keepalive.count == Some(0) {
+                return Err(Error::Config("tcp_keepalive idle, interval and count must be positive".to_string()));
+            }
+        }
+        if self.max_accepts_per_poll == 0 {
+            return Err(Error::Config("max_accepts_per_poll must be positive".to_string()));
+        }
+        if self.listen_backlog <= 0 {
+            return Err(Error::Config("listen_backlog must be positive".to_string()));
+        }
+        if self.max_target_length == 0 {
+            return Err(Error::Config("max_target_length must be positive".to_string()));
+        }
+        if self.max_pipelined_requests == 0 {
+            return Err(Error::Config("max_pipelined_requests must be positive".to_string()));
+        }
+        if self.max_response_header_size == 0 {
+            return Err(Error::Config("max_response_header_size must be positive".to_string()));
+        }
+        if self.max_response_buffer_bytes == 0 {
+            return Err(Error::Config("max_response_buffer_bytes must be positive".to_string()));
+        }
+        if self.read_buffer_size < MIN_READ_BUFFER_SIZE {
+            return Err(Error::Config(format!("read_buffer_size must be at least {}", MIN_READ_BUFFER_SIZE)));
+        }
+        if self.read_retries > MAX_READ_RETRIES {
+            return Err(Error::Config(format!("read_retries must be at most {}", MAX_READ_RETRIES)));
+        }
+        // 待つ時間は再試行のたびに倍にするので、read_retry_delay_ms * (2^read_retries - 1)になる
+        let backoff_ms = (0..self.read_retries)
+            .fold(0u64, |total, retry| total.saturating_add(self.read_retry_delay_ms.saturating_mul(1 << retry)));
+        if backoff_ms > MAX_READ_RETRY_BACKOFF_MS {
+            return Err(Error::Config(format!(
+                "read_retry_delay_ms doubled over read_retries must total at most {}ms",
+                MAX_READ_RETRY_BACKOFF_MS
+            )));
+        }
+        for rule in &self.cache_control {
+            if rule.extension.is_some() == rule.path_prefix.is_some() {
+                return Err(Error::Config("cache_control requires exactly one of extension or path_prefix".to_string()));
+            }
+            if !is_valid_header_value(&rule.value) {
+                return Err(Error::Config(format!("Invalid cache_control value: {:?}", rule.value)));
+            }
+        }
+        if let Some(param) = self.query_variants.iter().find(|param| param.is_empty() || param.contains(['&', '=', '%', '+'])) {
+            return Err(Error::Config(format!("Invalid query_variants parameter: {:?}", param)));
+        }
+        if self.blocked_user_agents.iter().any(|pattern| pattern.is_empty()) {
+            return Err(Error::Config("blocked_user_agents must not contain an empty pattern".to_string()));
+        }
+        if !matches!(self.blocked_user_agent_status, 400 | 403 | 404) {
+            return Err(Error::Config(format!(
+                "blocked_user_agent_status must be 400, 403 or 404: {}",
+                self.blocked_user_agent_status
+            )));
+        }
+        for (index, page) in self.status_pages.iter().enumerate() {
+            if !(400..600).contains(&page.status) {
+                return Err(Error::Config(format!("status_pages status must be 4xx or 5xx: {}", page.status)));
+            }
+            // レスポンスを作成できないステータスコードのページは使われることがない
+            if create_msg_from_code(page.status, None).is_err() {
+                return Err(Error::Config(format!("status_pages status is not supported: {}", page.status)));
+            }
+            if self.status_pages[..index].iter().any(|other| other.status == page.status) {
+                return Err(Error::Config(format!("Duplicate status_pages status: {}", page.status)));
+            }
+            if let Some(content_type) = page.content_type.as_deref().filter(|t| !is_valid_header_value(t)) {
+                return Err(Error::Config(format!("Invalid status_pages content_type: {:?}", content_type)));
+            }
+        }
+        for rule in &self.early_hints {
+            if !rule.path.starts_with('/') {
+                return Err(Error::Config(format!("early_hints path must start with '/': {:?}", rule.path)));
+            }
+            if let Some(link) = rule.links.iter().find(|link| link.is_empty() || !is_valid_header_value(link)) {
+                return Err(Error::Config(format!("Invalid early_hints link: {:?}", link)));
+            }
+        }
+        for (index, rule) in self.preload_links.iter().enumerate() {
+            if !rule.path.starts_with('/') {
+                return Err(Error::Config(format!("preload_links path must start with '/': {:?}", rule.path)));
+            }
+            if self.preload_links[..index].iter().any(|other| other.path == rule.path) {
+                return Err(Error::Config(format!("Duplicate preload_links path: {:?}", rule.path)));
+            }
+            // Linkの値は"<URI>; パラメータ"の形式
+            if let Some(link) = rule.links.iter().find(|link| !link.starts_with('<') || !link.contains('>') || !is_valid_header_value(link)) {
+                return Err(Error::Config(format!("Invalid preload_links link: {:?}", link)));
+            }
+        }
+        if let Some(root_response) = &self.root_response {
+            if !is_valid_header_value(&root_response.content_type) {
+                return Err(Error::Config(format!("Invalid root_response content_type: {:?}", root_response.content_type)));
+            }
+        }
+        if let Some(RetryAfter::Date(date)) = &self.retry_after {
+            // IMF-fixdate("Sun, 06 Nov 1994 08:49:37 GMT")の形式のみ受け付ける
+            if date.len() != 29 || !date.ends_with(" GMT") || !is_valid_header_value(date) {
+                return Err(Error::Config(format!("retry_after must be seconds or an HTTP-date: {:?}", date)));
+            }
+        }
+        if let Some(path) = &self.concat_path {
+            if !path.starts_with('/') || path.contains('?') {
+                return Err(Error::Config(format!("Invalid concat_path: {:?}", path)));
+            }
+        }
+        if let Some(path) = &self.health_check_path {
+            if !path.starts_with('/') || path.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+                return Err(Error::Config(format!("Invalid health_check_path: {:?}", path)));
+            }
+        }
+        if let Some(cgi) = &self.cgi {
+            if !cgi.path_prefix.starts_with('/') {
+                return Err(Error::Config("cgi path_prefix must start with '/'".to_string()));
+            }
+            if cgi.timeout == 0 {
+                return Err(Error::Config("cgi timeout must be positive".to_string()));
+            }
+            if cgi.max_output_bytes == 0 {
+                return Err(Error::Config("cgi max_output_bytes must be positive".to_string()));
+            }
+        }
+        for rule in &self.headers {
+            if !is_valid_header_name(&rule.name) {
+                return Err(Error::Config(format!("Invalid header name: {:?}", rule.name)));
+            }
+            if !is_valid_header_value(&rule.value) {
+                return Err(Error::Config(format!("Invalid value for header {}: {:?}", rule.name, rule.value)));
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    * 圧縮するContent-Typeか。charsetなどのパラメータと大文字小文字は区別しない
+    */
+    pub fn is_compressible_type(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or(content_type).trim();
+        self.compressible_types.iter().any(|t| t.trim().eq_ignore_ascii_case(media_type))
+    }
+
+    /**
+    * 配信を禁止している拡張子のファイルか。拡張子の大文字小文字と先頭の"."は区別しない
+    */
+    pub fn is_blocked_extension(&self, path: &str) -> bool {
+        has_extension_in(path, &self.blocked_extensions)
+    }
+
+    /**
+    * ダウンロードさせる拡張子のファイルか。拡張子の大文字小文字と先頭の"."は区別しない
+    */
+    pub fn is_download_extension(&self, path: &str) -> bool {
+        has_extension_in(path, &self.download_extensions)
+    }
+
+    /**
+    * ダウンロードさせるContent-Typeか。パラメータと大文字小文字は区別しない
+    */
+    pub fn is_download_type(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or(content_type).trim();
+        self.download_types.iter().any(|t| t.trim().eq_ignore_ascii_case(media_type))
+    }
+
+    /**
+    * 接続元からの接続を受け付けるか。deny_clientsに含まれれば拒否し、
+    * allow_clientsを指定している場合はそのいずれかに含まれる場合のみ受け付ける
+    */
+    pub fn is_client_allowed(&self, remote: IpAddr) -> bool {
+        if self.deny_clients.iter().any(|cidr| cidr.contains(remote)) {
+            return false;
+        }
+        self.allow_clients.is_empty() || self.allow_clients.iter().any(|cidr| cidr.contains(remote))
+    }
+
+    /**
+    * 送信元にパスへのアクセスを許可するか。最初に一致したpath_prefixの設定を使い、
+    * 一致する設定がなければ許可する。送信元が不明な場合は制限されたパスを拒否する
+    */
+    pub fn is_access_allowed(&self, path: &str, remote: Option<IpAddr>) -> bool {
+        match self.access_control.iter().find(|rule| path.starts_with(rule.path_prefix.as_str())) {
+            Some(rule) => remote.is_some_and(|remote| rule.allow.iter().any(|cidr| cidr.contains(remote))),
+            None => true,
+        }
+    }
+
+    /**
+    * パスに103 Early Hintsで送るLinkヘッダの値
+    */
+    pub fn early_hints_for(&self, path: &str) -> &[String] {
+        self.early_hints.iter().find(|rule| rule.path == path).map_or(&[], |rule| rule.links.as_slice())
+    }
+
+    /**
+    * パスのレスポンスに付与するpreloadのLinkヘッダの値
+    */
+    pub fn preload_links_for(&self, path: &str) -> &[String] {
+        self.preload_links.iter().find(|rule| rule.path == path).map_or(&[], |rule| rule.links.as_slice())
+    }
+
+    /**
+    * パスに対応するCache-Controlの値。最初に一致した設定を使う
+    */
+    pub fn cache_control_for(&self, path: &str) -> Option<&str> {
+        self.cache_control
+            .iter()
+            .find(|rule| rule.matches(path))
+            .map(|rule| rule.value.as_str())
+    }
+}
+
+/**
+* パスの拡張子がextensionsのいずれかと一致するか
+*/
+fn has_extension_in(path: &str, extensions: &[String]) -> bool {
+    let Some(extension) = Path::new(path).extension().and_then(|extension| extension.to_str()) else {
+        return false;
+    };
+    extensions
+        .iter()
+        .any(|candidate| candidate.trim_start_matches('.').eq_ignore_ascii_case(extension))
+}
+
+/**
+* ヘッダ名がRFC 9110のtokenであるか
+*/
+pub(crate) fn is_valid_header_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/**
+* ヘッダ値に改行などの制御文字が含まれていないか(ヘッダインジェクション対策)
+*/
+pub(crate) fn is_valid_header_value(value: &str) -> bool {
+    value.bytes().all(|b| b == b'\t' || (b >= 0x20 && b != 0x7f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_status_page(status: u16) -> Config {
+        let page = StatusPage { status, file: "errors/page.html".to_string(), content_type: None };
+        Config { status_pages: vec![page], ..Config::default() }
+    }
+
+    #[test]
+    fn counts_and_sizes_must_be_positive() {
+        let configs = [
+            Config { max_accepts_per_poll: 0, ..Config::default() },
+            Config { max_connections: 0, ..Config::default() },
+            Config { listen_backlog: 0, ..Config::default() },
+            Config { max_pipelined_requests: 0, ..Config::default() },
+        ];
+        for config in configs {
+            assert!(matches!(config.validate(), Err(Error::Config(_))));
+        }
+        assert!(Config { max_accepts_per_poll: 1, ..Config::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn blocked_user_agent_settings_are_checked() {
+        let config = Config { blocked_user_agents: vec![String::new()], ..Config::default() };
+        assert!(matches!(config.validate(), Err(Error::Config(_))));
+        for status in [200, 401, 500] {
+            let config = Config { blocked_user_agent_status: status, ..Config::default() };
+            assert!(matches!(config.validate(), Err(Error::Config(_))), "{}", status);
+        }
+        assert!(Config { blocked_user_agent_status: 404, ..Config::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn preload_links_must_be_link_values() {
+        let rule = |path: &str, link: &str| PreloadLinkRule { path: path.to_string(), links: vec![link.to_string()] };
+        let link = "</style.css>; rel=preload; as=style";
+        for rules in [
+            vec![rule("style", link)],
+            vec![rule("/", "/style.css")],
+            vec![rule("/", "</style.css>; rel=preload\r\nX-Injected: 1")],
+            vec![rule("/", link), rule("/", link)],
+        ] {
+            let config = Config { preload_links: rules, ..Config::default() };
+            assert!(matches!(config.validate(), Err(Error::Config(_))));
+        }
+        assert!(Config { preload_links: vec![rule("/", link)], ..Config::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn tcp_keepalive_values_must_be_positive() {
+        let keepalive = |idle, interval, count| Config {
+            tcp_keepalive: Some(TcpKeepalive { idle, interval, count }),
+            ..Config::default()
+        };
+        for config in [keepalive(0, None, None), keepalive(30, Some(0), None), keepalive(30, None, Some(0))] {
+            assert!(matches!(config.validate(), Err(Error::Config(_))));
+        }
+        assert!(keepalive(30, None, None).validate().is_ok());
+        let config: Config = toml::from_str("[tcp_keepalive]\nidle = 60\ncount = 3\n").unwrap();
+        let keepalive = config.tcp_keepalive.unwrap();
+        assert_eq!((keepalive.idle, keepalive.interval, keepalive.count), (60, None, Some(3)));
+    }
+
+    #[test]
+    fn a_single_webroot_can_be_given_as_a_string() {
+        let config: Config = toml::from_str("webroot = \"./public\"\n").unwrap();
+        assert_eq!(config.webroots, ["./public"]);
+        let config: Config = toml::from_str("webroots = \"./public\"\n").unwrap();
+        assert_eq!(config.webroots, ["./public"]);
+        let config: Config = toml::from_str("webroot = [\"a\", \"b\"]\n").unwrap();
+        assert_eq!(config.webroots, ["a", "b"]);
+        assert!(toml::from_str::<Config>("webroot = \"a\"\nwebroots = [\"b\"]\n").is_err());
+        assert!(toml::from_str::<Config>("webroot = 1\n").is_err());
+    }
+
+    #[test]
+    fn relative_paths_are_resolved_against_the_config_directory() {
+        let toml = r#"
+            webroots = ["public", "/srv/shared"]
+            archive = "site.zip"
+            upload_root = "uploads"
+            maintenance_page = "maintenance.html"
+            access_log = { json = "logs/access.log" }
+
+            [[status_pages]]
+            status = 404
+            file = "errors/404.html"
+
+            [cgi]
+            dir = "cgi-bin"
+
+            [[listeners]]
+            addr = "127.0.0.1:8443"
+            tls = { cert = "tls/cert.pem", key = "/etc/tls/key.pem", sni = [
+                { name = "a.test", cert = "tls/a.pem", key = "tls/a.key", webroot = "a" },
+            ] }
+        "#;
+        let mut config: Config = toml::from_str(toml).unwrap();
+        config.resolve_paths(Path::new("/etc/web-server"));
+        assert_eq!(config.webroots, ["/etc/web-server/public", "/srv/shared"]);
+        assert_eq!(config.archive.as_deref(), Some("/etc/web-server/site.zip"));
+        assert_eq!(config.upload_root.as_deref(), Some("/etc/web-server/uploads"));
+        assert_eq!(config.maintenance_page.as_deref(), Some("/etc/web-server/maintenance.html"));
+        assert_eq!(config.access_log, AccessLogConfig::Json("/etc/web-server/logs/access.log".to_string()));
+        assert_eq!(config.status_pages[0].file, "/etc/web-server/errors/404.html");
+        assert_eq!(config.cgi.unwrap().dir, "/etc/web-server/cgi-bin");
+        let tls = config.listeners[0].tls.as_ref().unwrap();
+        assert_eq!((tls.cert.as_str(), tls.key.as_str()), ("/etc/web-server/tls/cert.pem", "/etc/tls/key.pem"));
+        let sni = &tls.sni[0];
+        assert_eq!((sni.cert.as_str(), sni.key.as_str()), ("/etc/web-server/tls/a.pem", "/etc/web-server/tls/a.key"));
+        assert_eq!(sni.webroot.as_deref(), Some("/etc/web-server/a"));
+    }
+
+    #[test]
+    fn read_retries_are_bounded() {
+        assert!(Config { read_retries: MAX_READ_RETRIES, ..Config::default() }.validate().is_ok());
+        let config = Config { read_retries: MAX_READ_RETRIES + 1, ..Config::default() };
+        assert!(matches!(config.validate(), Err(Error::Config(_))));
+        // 5回なら最初の待ち時間の31倍待つ
+        let config = |read_retry_delay_ms| Config { read_retries: 5, read_retry_delay_ms, ..Config::default() };
+        assert!(config(MAX_READ_RETRY_BACKOFF_MS / 31).validate().is_ok());
+        assert!(matches!(config(MAX_READ_RETRY_BACKOFF_MS / 31 + 1).validate(), Err(Error::Config(_))));
+        assert!(matches!(config(u64::MAX).validate(), Err(Error::Config(_))));
+        // 再試行しなければ待ち時間は使わない
+        assert!(Config { read_retries: 0, read_retry_delay_ms: u64::MAX, ..Config::default() }.validate().is_ok());
+    }
+
+    #[test]
+    fn client_lists_check_ipv4_and_ipv6() {
+        let cidrs = |ranges: &[&str]| -> Vec<Cidr> {
+            ranges.iter().map(|range| Cidr::try_from(range.to_string()).unwrap()).collect()
+        };
+        let config = Config {
+            allow_clients: cidrs(&["192.0.2.0/24", "2001:db8::/32"]),
+            deny_clients: cidrs(&["192.0.2.128/25", "2001:db8:bad::/48"]),
+            ..Config::default()
+        };
+        for (addr, expected) in [
+            ("192.0.2.1", true),
+            // deny_clientsはallow_clientsより優先する
+            ("192.0.2.200", false),
+            ("198.51.100.1", false),
+            ("2001:db8::1", true),
+            ("2001:db8:bad::1", false),
+            ("::ffff:192.0.2.1", true),
+        ] {
+            assert_eq!(config.is_client_allowed(addr.parse().unwrap()), expected, "{}", addr);
+        }
+        // allow_clientsが空の場合は拒否していない全てのアドレスを受け付ける
+        let config = Config { deny_clients: cidrs(&["192.0.2.0/24"]), ..Config::default() };
+        assert!(config.is_client_allowed("198.51.100.1".parse().unwrap()));
+        assert!(!config.is_client_allowed("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn status_pages_accept_codes_the_server_can_send() {
+        for status in [401, 404, 429, 503] {
+            assert!(with_status_page(status).validate().is_ok(), "{}", status);
+        }
+    }
+
+    #[test]
+    fn status_pages_reject_unsupported_codes() {
+        for status in [200, 302, 499, 599] {
+            assert!(with_status_page(status).validate().is_err(), "{}", status);
+        }
+    }
+
+    #[test]
+    fn listen_backlog_must_be_positive() {
+        assert!(Config { listen_backlog: 1, ..Config::default() }.validate().is_ok());
+        for backlog in [0, -1] {
+            assert!(Config { listen_backlog: backlog, ..Config::default() }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn read_buffer_size_has_a_minimum() {
+        assert!(Config { read_buffer_size: MIN_READ_BUFFER_SIZE, ..Config::default() }.validate().is_ok());
+        assert!(Config { read_buffer_size: MIN_READ_BUFFER_SIZE - 1, ..Config::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn first_matching_cache_control_rule_wins() {
+        let rule = |extension: Option<&str>, path_prefix: Option<&str>, value: &str| CacheControlRule {
+            extension: extension.map(str::to_string),
+            path_prefix: path_prefix.map(str::to_string),
+            value: value.to_string(),
+        };
+        let config = Config {
+            cache_control: vec![
+                rule(None, Some("/assets/"), "max-age=600"),
+                rule(Some("js"), None, "no-cache"),
+            ],
+            ..Config::default()
+        };
+        assert_eq!(config.cache_control_for("/assets/app.js"), Some("max-age=600"));
+        assert_eq!(config.cache_control_for("/app.js"), Some("no-cache"));
+        assert_eq!(config.cache_control_for("/app.json"), None);
+        assert_eq!(config.cache_control_for("/js"), None);
+    }
+
+    #[test]
+    fn cache_control_rule_needs_exactly_one_matcher() {
+        let both = CacheControlRule {
+            extension: Some("js".to_string()),
+            path_prefix: Some("/assets/".to_string()),
+            value: "no-cache".to_string(),
+        };
+        assert!(Config { cache_control: vec![both], ..Config::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn compressible_type_ignores_parameters_and_case() {
+        let config = Config::default();
+        assert!(config.is_compressible_type("text/html; charset=utf-8"));
+        assert!(config.is_compressible_type("Application/JSON"));
+        assert!(!config.is_compressible_type("image/png"));
+        let config = Config { compressible_types: vec!["image/png".to_string()], ..Config::default() };
+        assert!(config.is_compressible_type("image/png"));
+        assert!(!config.is_compressible_type("text/html"));
+    }
+}
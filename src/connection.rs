@@ -0,0 +1,607 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use rustls::ServerConnection;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::router::BodyStream;
+use crate::server::PendingJob;
+use crate::tls::{flush_tls, read_tls};
+
+// リクエストやフレームの処理後にrequest_bufferの容量がこれを超えていれば縮める
+const REQUEST_BUFFER_HIGH_WATER: usize = 64 * 1024;
+// 縮めた後のrequest_bufferの容量
+const REQUEST_BUFFER_BASELINE: usize = 8 * 1024;
+// リクエストの受信中に一度に読み込んでrequest_bufferに溜める最大のバイト数。超えたら処理してから続きを読む
+const MAX_READ_AHEAD: usize = 256 * 1024;
+
+/**
+* 接続の状態。遷移はConnection::set_stateで一元的に行う
+*
+* ReadingRequest -> Processing -> WritingResponse -> KeepAliveIdle -> ReadingRequest -> ...
+*                                                 -> Processing(パイプライン化された次のリクエスト)
+*                                                 -> WebSocket(101を送信した後)
+*                                                 -> EventStream(イベントストリームのヘッダを送信した後)
+* どの状態からもClosingに遷移できる
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectionState {
+    // リクエストを受信中
+    ReadingRequest,
+    // 受信したリクエストのレスポンスを作成中
+    Processing,
+    // レスポンスを送信中
+    WritingResponse,
+    // キープアライブで次のリクエストを待っている。値は待ち始めた時刻
+    KeepAliveIdle(Instant),
+    // ハンドシェイクが完了し、WebSocketのフレームを送受信している
+    WebSocket,
+    // Server-Sent Eventsのイベントを送信している
+    EventStream,
+    // 接続を閉じる
+    Closing,
+}
+
+impl ConnectionState {
+    /**
+    * 状態の名前。KeepAliveIdleの待ち始めた時刻は含めない
+    */
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConnectionState::ReadingRequest => "ReadingRequest",
+            ConnectionState::Processing => "Processing",
+            ConnectionState::WritingResponse => "WritingResponse",
+            ConnectionState::KeepAliveIdle(_) => "KeepAliveIdle",
+            ConnectionState::WebSocket => "WebSocket",
+            ConnectionState::EventStream => "EventStream",
+            ConnectionState::Closing => "Closing",
+        }
+    }
+
+    /**
+    * nextへの遷移が許可されているか
+    */
+    pub fn can_transition_to(&self, next: &ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (self, next),
+            (ReadingRequest, Processing)
+                | (Processing, WritingResponse)
+                | (WritingResponse, Processing)
+                | (WritingResponse, KeepAliveIdle(_))
+                | (WritingResponse, WebSocket | EventStream)
+                | (KeepAliveIdle(_), ReadingRequest)
+                | (ReadingRequest | Processing | WritingResponse | KeepAliveIdle(_) | WebSocket | EventStream, Closing)
+        )
+    }
+}
+
+/**
+* 接続済みクライアントの状態
+*/
+pub(crate) struct Connection {
+    pub(crate) stream: mio::net::TcpStream,
+    // TLSで受け付けた接続のTLSの状態。平文の接続ではNone
+    pub(crate) tls: Option<ServerConnection>,
+    // 接続元のアドレス
+    pub(crate) remote_addr: SocketAddr,
+    // リダイレクト用のリスニングソケットで受け付けた接続か
+    pub(crate) redirect_to_https: bool,
+    // 受け付けたリスニングソケットのWebServer::listenersでのインデックス
+    pub(crate) listener: usize,
+    pub(crate) state: ConnectionState,
+    // 受信途中のリクエスト
+    pub(crate) request_buffer: Vec<u8>,
+    // 送信待ちのレスポンス。パイプライン化されたリクエストの順に並ぶ
+    responses: VecDeque<Vec<u8>>,
+    // 先頭のレスポンスのうち送信済みのバイト数
+    pub(crate) written: usize,
+    // この接続の送信待ちのレスポンスのバイト数の合計
+    buffered: usize,
+    // 全ての接続の送信待ちのレスポンスのバイト数の合計。queue_responseで加算し、送信し終えたら減算する
+    buffered_total: Arc<AtomicUsize>,
+    // タイムアウトの起点の時刻を取得する時計
+    clock: Arc<dyn Clock>,
+    // レスポンス送信後も接続を維持するか
+    pub(crate) keep_alive: bool,
+    // レスポンス送信後にHTTP以外のやり取りに切り替える場合の遷移先(WebSocket、EventStream)
+    pub(crate) after_response: Option<ConnectionState>,
+    // ボディを受信中のアップロードのContent-Length
+    pub(crate) upload_bytes: Option<usize>,
+    // ボディをストリーミングで受け取るルートに渡している途中のリクエスト
+    pub(crate) body_stream: Option<BodyStream>,
+    // 別スレッドでCGIスクリプトの実行やファイルの読み直しを待っているリクエスト。終了するまで後続のリクエストは処理しない
+    pub(crate) pending_job: Option<PendingJob>,
+    // MAX_READ_AHEADに達して、ソケットに未読のデータを残したまま読み込みを止めたか
+    pub(crate) read_paused: bool,
+    // リクエストの最初のバイトを受信した時刻(パイプライン化された次のリクエストは前のレスポンスを送り終えた時刻)。
+    // read_timeoutとrequest_timeoutはここから数える
+    pub(crate) request_started: Instant,
+    // 最後に送信が進んだ時刻。送信待ちがない状態でレスポンスを追加した時も更新する
+    last_write_progress: Instant,
+    // TLSの接続で最初に受信したデータを確認したか
+    first_bytes_checked: bool,
+    // 接続を受け付けた時刻
+    pub(crate) accepted_at: Instant,
+    // 受信したバイト数と送信したバイト数(TLSの接続では復号後と暗号化前の平文)
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+}
+
+/**
+* 接続のタイムアウトの設定
+*/
+pub(crate) struct Timeouts {
+    pub(crate) keep_alive: Duration,
+    pub(crate) read: Duration,
+    pub(crate) request: Duration,
+    pub(crate) write: Duration,
+}
+
+impl Timeouts {
+    pub(crate) fn from_config(config: &Config) -> Self {
+        Timeouts {
+            keep_alive: Duration::from_secs(config.keep_alive_timeout),
+            read: Duration::from_secs(config.read_timeout),
+            request: Duration::from_secs(config.request_timeout),
+            write: Duration::from_secs(config.write_timeout),
+        }
+    }
+}
+
+impl Connection {
+    pub(crate) fn new(
+        stream: mio::net::TcpStream,
+        remote_addr: SocketAddr,
+        buffered_total: Arc<AtomicUsize>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        Connection {
+            stream,
+            tls: None,
+            remote_addr,
+            redirect_to_https: false,
+            listener: 0,
+            state: ConnectionState::ReadingRequest,
+            request_buffer: Vec::new(),
+            responses: VecDeque::new(),
+            written: 0,
+            buffered: 0,
+            buffered_total,
+            clock,
+            keep_alive: false,
+            after_response: None,
+            upload_bytes: None,
+            body_stream: None,
+            pending_job: None,
+            read_paused: false,
+            request_started: now,
+            last_write_progress: now,
+            first_bytes_checked: false,
+            accepted_at: now,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    /**
+    * 接続の時計の現在時刻
+    */
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    pub(crate) fn set_state(&mut self, next: ConnectionState) {
+        debug_assert!(
+            self.state.can_transition_to(&next),
+            "invalid connection state transition {:?} -> {:?}",
+            self.state,
+            next
+        );
+        self.state = next;
+    }
+
+    /**
+    * 処理したリクエストやフレームのlenバイトをrequest_bufferから取り除く。後続のデータは残す。
+    * 大きなリクエストで容量が増えた場合は、キープアライブ中にメモリを持ち続けないように縮める
+    */
+    pub(crate) fn consume_buffered(&mut self, len: usize) {
+        self.request_buffer.drain(..len);
+        if self.request_buffer.capacity() > REQUEST_BUFFER_HIGH_WATER {
+            self.request_buffer.shrink_to(REQUEST_BUFFER_BASELINE);
+        }
+    }
+
+    /**
+    * レスポンスやフレームを送信待ちの末尾に追加する
+    */
+    pub(crate) fn queue_response(&mut self, response: Vec<u8>) {
+        if self.responses.is_empty() {
+            self.last_write_progress = self.clock.now();
+        }
+        self.buffered += response.len();
+        self.buffered_total.fetch_add(response.len(), Ordering::Relaxed);
+        self.responses.push_back(response);
+    }
+
+    /**
+    * 送信待ちのレスポンスのバイト数の合計(送信途中のものを含む)
+    */
+    pub(crate) fn buffered_bytes(&self) -> usize {
+        self.buffered
+    }
+
+    /**
+    * WouldBlockになるまで読み込んでrequest_bufferに溜める。相手が接続を閉じた場合はtrueを返す。
+    * 平文の接続でリクエストを受信中の場合は、MAX_READ_AHEADに達した時点で止めてread_pausedを立てる
+    */
+    pub(crate) fn read_available(&mut self, buffer: &mut [u8]) -> io::Result<bool> {
+        if let Some(tls) = &mut self.tls {
+            let buffered = self.request_buffer.len();
+            let result = read_tls(tls, &mut self.stream, &mut self.request_buffer, buffer);
+            self.bytes_read += (self.request_buffer.len() - buffered) as u64;
+            if buffered == 0 && !self.request_buffer.is_empty() {
+                self.request_started = self.clock.now();
+            }
+            return result;
+        }
+        loop {
+            match self.stream.read(buffer) {
+                Ok(0) => return Ok(true),
+                Ok(nbytes) => {
+                    self.bytes_read += nbytes as u64;
+                    // 空のバッファに受信したデータは新しいリクエストの最初のバイト
+                    if self.request_buffer.is_empty() {
+                        self.request_started = self.clock.now();
+                    }
+                    self.request_buffer.extend_from_slice(&buffer[..nbytes]);
+                    if self.state == ConnectionState::ReadingRequest && self.request_buffer.len() >= MAX_READ_AHEAD {
+                        self.read_paused = true;
+                        return Ok(false);
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /**
+    * TLSの接続に最初に届いたデータが平文のHTTPリクエストであればtrueを返す。
+    * TLSのレコードは0x16(Handshake)で始まるので、先頭がメソッドの英大文字であればTLSではない。
+    * データを消費しないように覗くだけで、確認するのは最初のデータが届いた時の1回だけ
+    */
+    pub(crate) fn receives_plaintext_on_tls(&mut self) -> io::Result<bool> {
+        if self.tls.is_none() || self.first_bytes_checked {
+            return Ok(false);
+        }
+        let mut first = [0u8; 1];
+        match self.stream.peek(&mut first) {
+            // 切断は続くreadで扱う
+            Ok(0) => Ok(false),
+            Ok(_) => {
+                self.first_bytes_checked = true;
+                Ok(first[0].is_ascii_uppercase())
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /**
+    * 送信待ちのレスポンスを受信したリクエストの順に書き込む。
+    * 書き込みがブロックした場合はfalseを返し、続きは次に書き込み可能になった時に送る
+    */
+    pub(crate) fn write_responses(&mut self) -> io::Result<bool> {
+        if let Some(tls) = &mut self.tls {
+            loop {
+                // 平文をTLSの送信バッファに移し、暗号化したものを送信する
+                while let Some(response) = self.responses.front() {
+                    let nbytes = tls.writer().write(&response[self.written..])?;
+                    if nbytes == 0 {
+                        break;
+                    }
+                    // TLSの送信バッファには上限があるので、平文を移せたら送信が進んでいる
+                    self.last_write_progress = self.clock.now();
+                    self.bytes_written += nbytes as u64;
+                    self.written += nbytes;
+                    if self.written == response.len() {
+                        self.buffered -= response.len();
+                        self.buffered_total.fetch_sub(response.len(), Ordering::Relaxed);
+                        self.responses.pop_front();
+                        self.written = 0;
+                    }
+                }
+                if !flush_tls(tls, &mut self.stream)? {
+                    return Ok(false);
+                }
+                if self.responses.is_empty() {
+                    return Ok(true);
+                }
+                if tls.is_handshaking() {
+                    // ハンドシェイクが完了するまで平文は送信バッファに溜まり続けない
+                    return Ok(false);
+                }
+            }
+        }
+        while let Some(response) = self.responses.front() {
+            match self.stream.write(&response[self.written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(nbytes) => {
+                    self.last_write_progress = self.clock.now();
+                    self.bytes_written += nbytes as u64;
+                    self.written += nbytes;
+                    if self.written == response.len() {
+                        self.buffered -= response.len();
+                        self.buffered_total.fetch_sub(response.len(), Ordering::Relaxed);
+                        self.responses.pop_front();
+                        self.written = 0;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /**
+    * WebSocketやイベントストリームの接続か。開始するレスポンスを送信中のものも含める
+    */
+    pub(crate) fn is_long_lived(&self) -> bool {
+        matches!(self.state, ConnectionState::WebSocket | ConnectionState::EventStream)
+            || self.after_response.is_some()
+    }
+
+    /**
+    * TLSのハンドシェイクの応答などの送信しきれていないデータがあるか
+    */
+    pub(crate) fn wants_write(&self) -> bool {
+        self.tls.as_ref().is_some_and(|tls| tls.wants_write())
+    }
+
+    /**
+    * 現在の状態のタイムアウト時刻。タイムアウトしない状態ではNoneを返す。
+    * request_timeoutはリクエストの受信からレスポンスを送信し終えるまでの全体の期限。
+    * 送信待ちがあればどの状態でもwrite_deadlineを含める
+    */
+    pub(crate) fn deadline(&self, timeouts: &Timeouts) -> Option<Instant> {
+        let request_deadline = (!timeouts.request.is_zero()).then(|| self.request_started + timeouts.request);
+        let deadline = match self.state {
+            ConnectionState::KeepAliveIdle(since) => Some(since + timeouts.keep_alive),
+            ConnectionState::ReadingRequest if !timeouts.read.is_zero() => {
+                let read_deadline = self.request_started + timeouts.read;
+                Some(request_deadline.map_or(read_deadline, |deadline| deadline.min(read_deadline)))
+            }
+            ConnectionState::ReadingRequest | ConnectionState::WritingResponse => request_deadline,
+            _ => None,
+        };
+        match (deadline, self.write_deadline(timeouts.write)) {
+            (Some(deadline), Some(write_deadline)) => Some(deadline.min(write_deadline)),
+            (deadline, write_deadline) => deadline.or(write_deadline),
+        }
+    }
+
+    /**
+    * 送信が進まないまま接続を閉じる時刻。送信待ちがない場合とwrite_timeoutが0の場合はNoneを返す
+    */
+    pub(crate) fn write_deadline(&self, write_timeout: Duration) -> Option<Instant> {
+        (!self.responses.is_empty() && !write_timeout.is_zero()).then(|| self.last_write_progress + write_timeout)
+    }
+
+    /**
+    * 先頭のレスポンスをまだ1バイトも送信していなければ、送信待ちのレスポンスを全て破棄してtrueを返す
+    */
+    pub(crate) fn discard_unsent_responses(&mut self) -> bool {
+        if self.written > 0 {
+            return false;
+        }
+        self.buffered_total.fetch_sub(self.buffered, Ordering::Relaxed);
+        self.buffered = 0;
+        self.responses.clear();
+        true
+    }
+}
+
+impl Drop for Connection {
+    /**
+    * 送信待ちのレスポンスを合計から除く。TLSの接続はclose_notifyを送ってから閉じる。送信できなくても待たない
+    */
+    fn drop(&mut self) {
+        self.buffered_total.fetch_sub(self.buffered, Ordering::Relaxed);
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            let _ = flush_tls(tls, &mut self.stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use crate::clock::MockClock;
+    use ConnectionState::*;
+
+    /**
+    * ループバックで接続したソケットのConnectionと、その相手側のソケット
+    */
+    fn connection(clock: &Arc<MockClock>) -> (Connection, std::net::TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, remote_addr) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let clock: Arc<dyn Clock> = clock.clone();
+        let connection =
+            Connection::new(mio::net::TcpStream::from_std(stream), remote_addr, Arc::new(AtomicUsize::new(0)), clock);
+        (connection, peer)
+    }
+
+    fn timeouts(keep_alive: u64, read: u64, request: u64, write: u64) -> Timeouts {
+        Timeouts {
+            keep_alive: Duration::from_secs(keep_alive),
+            read: Duration::from_secs(read),
+            request: Duration::from_secs(request),
+            write: Duration::from_secs(write),
+        }
+    }
+
+    #[test]
+    fn request_cycle_transitions_are_allowed() {
+        let idle = KeepAliveIdle(Instant::now());
+        for (from, to) in [
+            (ReadingRequest, Processing),
+            (Processing, WritingResponse),
+            (WritingResponse, Processing),
+            (WritingResponse, idle),
+            (WritingResponse, WebSocket),
+            (WritingResponse, EventStream),
+            (idle, ReadingRequest),
+        ] {
+            assert!(from.can_transition_to(&to), "{:?} -> {:?}", from, to);
+        }
+    }
+
+    #[test]
+    fn every_open_state_can_close() {
+        for state in [ReadingRequest, Processing, WritingResponse, KeepAliveIdle(Instant::now()), WebSocket, EventStream] {
+            assert!(state.can_transition_to(&Closing), "{:?}", state);
+        }
+        assert!(!Closing.can_transition_to(&Closing));
+    }
+
+    #[test]
+    fn skipping_states_is_not_allowed() {
+        for (from, to) in [
+            (ReadingRequest, WritingResponse),
+            (ReadingRequest, WebSocket),
+            (Processing, KeepAliveIdle(Instant::now())),
+            (KeepAliveIdle(Instant::now()), Processing),
+            (WebSocket, ReadingRequest),
+            (EventStream, WritingResponse),
+            (Closing, ReadingRequest),
+        ] {
+            assert!(!from.can_transition_to(&to), "{:?} -> {:?}", from, to);
+        }
+    }
+
+    #[test]
+    fn state_names_omit_the_idle_time() {
+        assert_eq!(KeepAliveIdle(Instant::now()).name(), "KeepAliveIdle");
+        assert_eq!(WritingResponse.name(), "WritingResponse");
+    }
+
+    #[test]
+    fn keep_alive_idle_deadline_counts_from_the_idle_start() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, _peer) = connection(&clock);
+        let accepted = clock.now();
+        connection.set_state(Processing);
+        connection.set_state(WritingResponse);
+        clock.advance(Duration::from_secs(3));
+        let idle_since = clock.now();
+        connection.set_state(KeepAliveIdle(idle_since));
+        assert_eq!(connection.deadline(&timeouts(5, 30, 0, 0)), Some(idle_since + Duration::from_secs(5)));
+        // 次のリクエストを受信し始めたらread_timeoutに切り替わる。最初のバイトを受信するまでは前の起点のまま
+        connection.set_state(ReadingRequest);
+        assert_eq!(connection.deadline(&timeouts(5, 30, 0, 0)), Some(accepted + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn deadline_depends_on_the_state() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, _peer) = connection(&clock);
+        let start = clock.now();
+        // read_timeoutとrequest_timeoutの早い方
+        assert_eq!(connection.deadline(&timeouts(5, 30, 10, 0)), Some(start + Duration::from_secs(10)));
+        assert_eq!(connection.deadline(&timeouts(5, 30, 0, 0)), Some(start + Duration::from_secs(30)));
+        assert_eq!(connection.deadline(&timeouts(5, 0, 0, 0)), None);
+        connection.set_state(Processing);
+        assert_eq!(connection.deadline(&timeouts(5, 30, 10, 0)), None);
+        connection.set_state(WritingResponse);
+        assert_eq!(connection.deadline(&timeouts(5, 30, 10, 0)), Some(start + Duration::from_secs(10)));
+        clock.advance(Duration::from_secs(3));
+        connection.set_state(KeepAliveIdle(clock.now()));
+        assert_eq!(connection.deadline(&timeouts(5, 30, 10, 0)), Some(start + Duration::from_secs(8)));
+    }
+
+    #[test]
+    fn buffer_capacity_shrinks_after_a_large_request() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, _peer) = connection(&clock);
+        let large = vec![b'x'; REQUEST_BUFFER_HIGH_WATER * 4];
+        connection.request_buffer.extend_from_slice(&large);
+        connection.request_buffer.extend_from_slice(b"GET / HTTP/1.1\r\n");
+        connection.consume_buffered(large.len());
+        // 後続のデータは残したまま容量を縮める
+        assert_eq!(connection.request_buffer, b"GET / HTTP/1.1\r\n");
+        assert!(connection.request_buffer.capacity() <= REQUEST_BUFFER_HIGH_WATER);
+        for _ in 0..10 {
+            connection.request_buffer.extend_from_slice(b"GET / HTTP/1.1\r\n\r\n");
+            let len = connection.request_buffer.len();
+            connection.consume_buffered(len);
+            assert!(connection.request_buffer.capacity() <= REQUEST_BUFFER_HIGH_WATER);
+        }
+    }
+
+    #[test]
+    fn written_responses_leave_the_buffered_total() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, mut peer) = connection(&clock);
+        let total = Arc::clone(&connection.buffered_total);
+        connection.queue_response(b"first".to_vec());
+        connection.queue_response(b"second".to_vec());
+        assert_eq!(connection.buffered_bytes(), 11);
+        assert_eq!(total.load(Ordering::Relaxed), 11);
+        assert!(connection.write_responses().unwrap());
+        assert_eq!(connection.buffered_bytes(), 0);
+        assert_eq!(total.load(Ordering::Relaxed), 0);
+        let mut received = [0u8; 11];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"firstsecond");
+
+        // 送信し終えずに閉じた接続の分も合計から除く
+        connection.queue_response(b"pending".to_vec());
+        assert_eq!(total.load(Ordering::Relaxed), 7);
+        drop(connection);
+        assert_eq!(total.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn unsent_responses_can_be_discarded() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, _peer) = connection(&clock);
+        let total = Arc::clone(&connection.buffered_total);
+        connection.queue_response(b"pending".to_vec());
+        connection.queue_response(b"next".to_vec());
+        assert!(connection.discard_unsent_responses());
+        assert_eq!(connection.buffered_bytes(), 0);
+        assert_eq!(total.load(Ordering::Relaxed), 0);
+
+        // 送信を始めたレスポンスは途中で打ち切ると区切りが壊れるので破棄しない
+        connection.queue_response(b"started".to_vec());
+        connection.written = 1;
+        assert!(!connection.discard_unsent_responses());
+        assert_eq!(connection.buffered_bytes(), 7);
+    }
+
+    #[test]
+    fn queued_response_sets_a_write_deadline() {
+        let clock = Arc::new(MockClock::new());
+        let (mut connection, _peer) = connection(&clock);
+        assert_eq!(connection.write_deadline(Duration::from_secs(20)), None);
+        connection.queue_response(b"HTTP/1.0 200 OK\r\n\r\n".to_vec());
+        let deadline = clock.now() + Duration::from_secs(20);
+        assert_eq!(connection.write_deadline(Duration::from_secs(20)), Some(deadline));
+        // 0の場合は無制限
+        assert_eq!(connection.write_deadline(Duration::ZERO), None);
+        connection.set_state(Processing);
+        assert_eq!(connection.deadline(&timeouts(5, 30, 0, 20)), Some(deadline));
+    }
+}
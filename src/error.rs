@@ -0,0 +1,60 @@
+use std::io;
+use std::net::AddrParseError;
+use std::path::PathBuf;
+use thiserror::Error;
+use crate::request::ParseError;
+
+/**
+* ライブラリの関数が返すエラー
+*/
+#[derive(Debug, Error)]
+pub enum Error {
+    // リクエストのパースエラー
+    #[error("{0}")]
+    Parse(#[from] ParseError),
+    // ソケットやファイルの入出力のエラー
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    // ドキュメントルートのファイルの読み込みが一時的なエラー(ESTALEやEIOなど)で失敗した。
+    // read_retriesを設定していれば、呼び出し側が別スレッドで読み直す
+    #[error("{source}")]
+    TransientRead { relative: String, path: PathBuf, source: io::Error },
+    // 設定ファイルや設定に指定したファイル(アーカイブ、証明書など)の誤り
+    #[error("{0}")]
+    Config(String),
+    // TLSやWebSocket、CGIなどのプロトコルのエラー
+    #[error("{0}")]
+    Protocol(String),
+    // 接続IDの不整合など、サーバ内部の状態のエラー
+    #[error("{0}")]
+    Internal(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Self {
+        Error::Config(e.to_string())
+    }
+}
+
+impl From<AddrParseError> for Error {
+    fn from(e: AddrParseError) -> Self {
+        Error::Config(format!("invalid address: {}", e))
+    }
+}
+
+impl From<zip::result::ZipError> for Error {
+    fn from(e: zip::result::ZipError) -> Self {
+        match e {
+            zip::result::ZipError::Io(e) => Error::Io(e),
+            e => Error::Config(format!("invalid zip archive: {}", e)),
+        }
+    }
+}
+
+impl From<rustls::Error> for Error {
+    fn from(e: rustls::Error) -> Self {
+        Error::Protocol(e.to_string())
+    }
+}
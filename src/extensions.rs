@@ -0,0 +1,54 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+/**
+* リクエストごとの型をキーにした値の入れ物。
+* リクエストフックで認証したユーザなどを入れ、ルートの処理やレスポンスフックで取り出す。型ごとに1つの値を持つ
+*/
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl Extensions {
+    /**
+    * 値を入れる。同じ型の値が入っていれば置き換えて古い値を返す
+    */
+    pub fn insert<T: 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.values
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|value| *value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_are_kept_one_per_type() {
+        let mut extensions = Extensions::default();
+        assert_eq!(extensions.insert(1u32), None);
+        assert_eq!(extensions.insert("user".to_string()), None);
+        assert_eq!(extensions.insert(2u32), Some(1));
+        assert_eq!(extensions.get::<u32>(), Some(&2));
+        *extensions.get_mut::<String>().unwrap() += "-admin";
+        assert_eq!(extensions.remove::<String>(), Some("user-admin".to_string()));
+        assert_eq!(extensions.get::<String>(), None);
+        assert_eq!(extensions.get::<u64>(), None);
+    }
+}
@@ -0,0 +1,39 @@
+mod access_log;
+mod acl;
+mod archive;
+mod cgi;
+mod clock;
+mod config;
+mod connection;
+mod error;
+mod extensions;
+mod http_date;
+mod metadata_cache;
+mod mime;
+mod path;
+mod rate_limit;
+mod request;
+mod response;
+mod response_cache;
+mod router;
+mod server;
+mod stats;
+mod upload;
+mod sse;
+mod tls;
+mod websocket;
+
+pub use access_log::{AccessLogSink, JsonFileLog, NoopLog, RequestLog, StderrLog};
+pub use acl::Cidr;
+pub use archive::Archive;
+pub use clock::{Clock, MockClock, SystemClock};
+pub use connection::ConnectionState;
+pub use error::{Error, Result};
+pub use extensions::Extensions;
+pub use metadata_cache::MetadataCache;
+pub use config::{AcceptRate, AccessControlRule, AccessLogConfig, CacheControlRule, CgiConfig, Config, EarlyHintRule, EtagStrength, HeaderRule, ListenerConfig, PreloadLinkRule, RedirectListener, RetryAfter, RootResponse, SniConfig, StatusPage, TcpKeepalive, TemplateConfig, TlsConfig};
+pub use request::{parse_request, parse_request_head, ParseError, Request, RequestHead, Section};
+pub use response::{create_content_response, create_msg_from_code, make_response, render_template, DocumentRoot, PreloadedFiles, Response};
+pub use router::{BodyHandler, RouteHandler, StreamingRouteHandler};
+pub use server::{RequestHook, ResponseHook, WebServer};
+pub use sse::EventSender;
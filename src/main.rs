@@ -1,226 +1,140 @@
-use std::collections::HashMap;
-use std::{env, process};
-use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use anyhow::anyhow;
-use log::{debug, error};
-use mio::{Events, Token, Poll, Interest};
-use mio::event::Event;
-use regex::Regex;
+use std::{env, panic, process};
+use std::io::{self, Write};
+use std::backtrace::Backtrace;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Command;
+use log::{error, info, warn};
+use web_server::{Config, WebServer};
 
-const SERVER: Token = Token(0);
-//ドキュメントルートのパス
-const WEBROOT: &str = "/webroot";
-
-struct WebServer {
-    listening_socket: mio::net::TcpListener,
-    connections: HashMap<usize, mio::net::TcpStream>, //サーバに接続されているクライアントを管理するハッシュテーブル
-    next_connection_id: usize,
-}
-
-impl WebServer {
-    /**
-    * サーバの初期化
-    */
-    fn new(addr: &str) -> anyhow::Result<Self, anyhow::Error> {
-        let address = addr.parse()?;
-        let listening_socket = mio::net::TcpListener::bind(address)?;
-        Ok(WebServer {
-            listening_socket,
-            connections: HashMap::new(),
-            next_connection_id: 1,
-        })
+fn main() {
+    env::set_var("RUST_LOG", "debug");
+    env_logger::init();
+    // パニックの内容とバックトレースをログに出力する
+    panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, Backtrace::force_capture());
+    }));
+    // "--"で始まる引数はオプションとして扱う
+    let (options, args): (Vec<String>, Vec<String>) =
+        env::args().skip(1).partition(|arg| arg.starts_with("--"));
+    if args.is_empty() || args.len() > 2 {
+        error!("wrong number of arguments");
+        process::exit(1);
     }
-    /**
-     *
-     */
-    fn run(&mut self) -> Result<(), anyhow::Error> {
-        let mut poll = Poll::new()?;
-        // サーバーソケットの状態を監視対象に登録する
-        poll.registry().register(
-            &mut self.listening_socket,
-            SERVER,
-            Interest::READABLE
-        )?;
-
-        //イベントキュー
-        let mut events = Events::with_capacity(1024);
-        // HTTPのレスポンス用バッファ
-        let mut response = Vec::new();
-
-        loop {
-            //現在のスレッドをブロックしてイベントを待つ。
-            if let Err(e) = poll.poll(&mut events, None) {
-                error!("{}", e);
-                continue;
-            }
-            for event in &events {
-                match event.token() {
-                    SERVER => {
-                        //　リスニングソケットの読み込み準備完了イベントが発生
-                        let (stream, remote) = match self.listening_socket.accept() {
-                            Ok(t) => t,
-                            Err(e) => {
-                                error!("{}", e);
-                                continue;
-                            }
-                        };
-                        debug!("Connection from {}", &remote);
-                        //接続済みソケットを監視対象に登録
-                        self.register_connection(&poll, stream).unwrap_or_else(|e| error!("{}", e));
-
-                    }
-
-                    Token(conn_id) => {
-                        //　接続済みソケットでイベントが発生
-                        self.http_handler(conn_id, event, &poll, &mut response)
-                            .unwrap_or_else(|e| error!("{}", e));
-                    }
-                }
+    let mut open_browser = false;
+    let mut print_addr = false;
+    for option in &options {
+        match option.as_str() {
+            // 起動後にブラウザで開く
+            "--open" => open_browser = true,
+            // バインドしたアドレスを標準出力に出力する
+            "--print-addr" => print_addr = true,
+            _ => {
+                error!("unknown option: {}", option);
+                process::exit(1);
             }
-
         }
-
-
     }
-
-    /**
-    *　接続済みソケットを監視対象に登録する
-    */
-    fn register_connection(
-        &mut self,
-        poll: &Poll,
-        mut stream: mio::net::TcpStream,
-    ) -> anyhow::Result<(), anyhow::Error> {
-        let token = Token(self.next_connection_id);
-        poll.registry().register(&mut stream, token, Interest::READABLE)?;
-
-        if self.connections.insert(self.next_connection_id, stream).is_some() {
-            // HashMapは既存のキーで値が更新されると更新前の値を返す
-            error!("Connection ID is already exist.");
-        }
-        self.next_connection_id += 1;
-        Ok(())
+    // 第2引数で設定ファイルを指定できる
+    let config = match args.get(1) {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        }),
+        None => Config::default(),
+    };
+    let mut server = WebServer::new(&args[0], config).unwrap_or_else(|e| {
+        error!("{}",e);
+        panic!();
+    });
+    // 受け取ったリスニングソケットを起動するプログラムが自分宛てと誤解しないように取り除く。
+    // 他のスレッドを起動する前に行う
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(name);
+    }
+    // 設定ファイルを指定した場合は、SIGHUPで読み直す
+    if let Some(path) = args.get(1) {
+        server.set_config_path(path);
     }
 
-    /**
-    * 接続済みソケットで発生したイベントのハンドラ
-    */
-    fn http_handler(
-        &mut self,
-        conn_id: usize,
-        event: &Event,
-        poll: &Poll,
-        response: &mut Vec<u8>,
-    ) -> anyhow::Result<(), anyhow::Error> {
-        let stream = self
-            .connections
-            .get_mut(&conn_id)
-            .ok_or_else(|| anyhow!("Invalid connection ID {}", conn_id))?;
-
-        if event.is_readable() {
-            //ソケットから読み込み可能
-            debug!("readable conn_id: {}", conn_id);
-            let mut buffer = [0u8; 1024];
-            let nbytes = stream.read(&mut buffer)?;
-
-            if nbytes != 0 {
-                *response = make_response(&buffer[..nbytes])?;
-                //書き込み操作の可否を監視対象に入れる
-                poll.registry().reregister(stream, Token(conn_id), Interest::WRITABLE)?;
-            } else {
-                // 通信終了
-                self.connections.remove(&conn_id);
+    if open_browser || print_addr {
+        match server.local_addr() {
+            Ok(addr) => {
+                if print_addr {
+                    // 他のプログラムから読み取れるように"アドレス:ポート"だけを出力する
+                    println!("{}", addr);
+                    io::stdout().flush().unwrap_or_else(|e| warn!("{}", e));
+                }
+                if open_browser {
+                    launch_browser(&browser_url(addr));
+                }
             }
-            Ok(())
-        } else if event.is_writable() {
-            //ソケットに書き込み可能
-            debug!("writable conn_id: {}", conn_id);
-            stream.write_all(response)?;
-            self.connections.remove(&conn_id);
-            Ok(())
-        } else {
-            Err(anyhow!("Undefined event"))
+            Err(e) => warn!("Failed to get the bound address: {}", e),
         }
-
     }
 
+    server.run().unwrap_or_else(|e| {
+        error!("{}", e);
+        panic!();
+    })
 }
 
-fn make_response(buffer: &[u8]) -> anyhow::Result<Vec<u8>, anyhow::Error> {
-    //リクエストラインをパースする
-    let http_pattern = Regex::new(r"(.*) (.*) HTTP/1.([0-1])\r\n.*")?;
-    let Some(captures) = http_pattern.captures(std::str::from_utf8(buffer)?) else {
-        //不正なリクエスト
-        return create_msg_from_code(400, None);
+/**
+* バインドしたアドレスのURL。0.0.0.0などの場合はループバックアドレスを使う
+*/
+fn browser_url(addr: SocketAddr) -> String {
+    let ip = match addr.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        ip => ip,
     };
+    format!("http://{}/", SocketAddr::new(ip, addr.port()))
+}
 
-    let method = captures[1].to_string();
-    let path = format!(
-        "{}{}{}",
-        env::current_dir()?.display(),
-        WEBROOT,
-        &captures[2]
-    );
-    let _version = captures[3].to_string();
-
-    if method == "GET" {
-        let Ok(file) = File::open(path) else {
-            return create_msg_from_code(404, None);
-        };
-        let mut reader = BufReader::new(file);
-        let mut buf = Vec::new();
-        reader.read_to_end(&mut buf)?;
-        create_msg_from_code(200, Some(buf))
-    } else {
-        //サポートしていないHTTPメソッド
-        create_msg_from_code(501, None)
+/**
+* OS(std::env::consts::OSの値)ごとのブラウザ起動コマンドのプログラムと引数
+*/
+fn browser_command(os: &str, url: &str) -> (&'static str, Vec<String>) {
+    match os {
+        "macos" => ("open", vec![url.to_string()]),
+        // startの最初の引数はウィンドウのタイトルなので空にする
+        "windows" => ("cmd", vec!["/C".to_string(), "start".to_string(), String::new(), url.to_string()]),
+        _ => ("xdg-open", vec![url.to_string()]),
     }
-
 }
 
-fn create_msg_from_code(
-    status_code: u16,
-    msg: Option<Vec<u8>>
-) -> anyhow::Result<Vec<u8>, anyhow::Error> {
-    match status_code {
-        200 => {
-            let mut header = "HTTP/1.0 200 OK\r\nserver: mio webserver\r\n\r\n"
-                .to_string()
-                .into_bytes();
-            if let Some(mut msg) = msg {
-                header.append(&mut msg);
-            }
-            Ok(header)
-        },
-        400 => Ok("HTTP/1.0 400 Bad Request\r\nServer: mio webserver\r\n\r\n"
-            .to_string()
-            .into_bytes()),
-        404 => Ok("HTTP/1.0 404 Not Found\r\nServer: mio webserver\r\n\r\n"
-            .to_string()
-            .into_bytes()),
-        501 => Ok("HTTP/1.0 501 Not Implemented\r\nServer: mio webserver\r\n\r\n"
-            .to_string()
-            .into_bytes()),
-        _ => Err(anyhow!("Undefined status code."))
+/**
+* ブラウザを起動する。失敗しても起動は続ける
+*/
+fn launch_browser(url: &str) {
+    info!("Opening {}", url);
+    let (program, args) = browser_command(env::consts::OS, url);
+    if let Err(e) = Command::new(program).args(&args).spawn() {
+        warn!("Failed to open a browser: {}", e);
     }
 }
 
-fn main() {
-    env::set_var("RUST_LOG", "debug");
-    env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        error!("wrong number of arguments");
-        process::exit(1);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "http://127.0.0.1:8080/";
+
+    #[test]
+    fn browser_command_for_each_os() {
+        assert_eq!(browser_command("macos", URL), ("open", vec![URL.to_string()]));
+        assert_eq!(
+            browser_command("windows", URL),
+            ("cmd", vec!["/C".to_string(), "start".to_string(), String::new(), URL.to_string()])
+        );
+        for os in ["linux", "freebsd", "openbsd"] {
+            assert_eq!(browser_command(os, URL), ("xdg-open", vec![URL.to_string()]));
+        }
     }
-    let mut server = WebServer::new(&args[1]).unwrap_or_else(|e| {
-        error!("{}",e);
-        panic!();
-    });
 
-    server.run().unwrap_or_else(|e| {
-        error!("{}", e);
-        panic!();
-    })
+    #[test]
+    fn browser_url_uses_loopback_for_unspecified_addresses() {
+        assert_eq!(browser_url("0.0.0.0:8080".parse().unwrap()), "http://127.0.0.1:8080/");
+        assert_eq!(browser_url("[::]:8080".parse().unwrap()), "http://[::1]:8080/");
+        assert_eq!(browser_url("192.168.1.5:80".parse().unwrap()), "http://192.168.1.5:80/");
+    }
 }
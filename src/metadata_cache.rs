@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use crate::response::Response;
+
+/**
+* キャッシュしたファイルのメタデータと、そのファイルに返したレスポンスのヘッダ
+*/
+struct CachedMetadata {
+    // 配信したファイルと、キャッシュした時点のサイズと更新日時。どちらかが変わっていれば使わない
+    path: PathBuf,
+    len: u64,
+    modified: SystemTime,
+    // ボディを除いたレスポンス。body_lenにボディの長さを持つ
+    response: Response,
+    // 最後に使った順番。上限を超えた場合は最も小さいものから捨てる
+    last_used: u64,
+}
+
+/**
+* HEADにファイルを開かずに応答するための、配信したファイルのメタデータ(サイズ、更新日時、ETagなどのヘッダ)のキャッシュ。
+* ファイルとレスポンスを左右するリクエストの値をキーとし、最大capacity件を最後に使った順(LRU)で残す
+*/
+pub struct MetadataCache {
+    entries: HashMap<String, CachedMetadata>,
+    capacity: usize,
+    // 使うたびに増やすカウンタ
+    clock: u64,
+}
+
+impl MetadataCache {
+    pub fn new(capacity: usize) -> Self {
+        MetadataCache { entries: HashMap::new(), capacity, clock: 0 }
+    }
+
+    /**
+    * ファイルのサイズと更新日時がキャッシュした時から変わっていなければ、ボディを除いたレスポンスを返す。
+    * 変わっていれば(ファイルが消えた場合を含む)捨ててNoneを返す
+    */
+    pub(crate) fn get(&mut self, key: &str) -> Option<Response> {
+        let entry = self.entries.get(key)?;
+        let unchanged = fs::metadata(&entry.path).is_ok_and(|metadata| {
+            metadata.len() == entry.len && metadata.modified().is_ok_and(|modified| modified == entry.modified)
+        });
+        if !unchanged {
+            self.entries.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        Some(entry.response.clone())
+    }
+
+    /**
+    * pathのファイルに返したレスポンスを、ボディを除いてキャッシュする。lenとmodifiedは読み込んだ時のファイルのもの
+    */
+    pub(crate) fn insert(&mut self, key: String, path: PathBuf, len: u64, modified: SystemTime, response: &Response) {
+        if self.capacity == 0 || response.status_code != 200 {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict_least_recently_used();
+        }
+        self.clock += 1;
+        let mut response = response.clone();
+        response.body_len = Some(response.body.len());
+        response.body = Vec::new();
+        let entry = CachedMetadata { path, len, modified, response, last_used: self.clock };
+        self.entries.insert(key, entry);
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use crate::response::create_msg_from_code;
+
+    fn file(name: &str, content: &str) -> (PathBuf, u64, SystemTime) {
+        let path = env::temp_dir().join(format!("metadata-cache-{}-{}", name, std::process::id()));
+        fs::write(&path, content).unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        (path, metadata.len(), metadata.modified().unwrap())
+    }
+
+    #[test]
+    fn hit_keeps_the_length_without_the_body() {
+        let (path, len, modified) = file("hit", "content");
+        let mut cache = MetadataCache::new(4);
+        let response = create_msg_from_code(200, Some(b"content".to_vec())).unwrap();
+        cache.insert("a".to_string(), path.clone(), len, modified, &response);
+        let cached = cache.get("a").unwrap();
+        assert!(cached.body.is_empty());
+        assert_eq!(cached.body_len, Some(7));
+
+        // サイズが変わったら捨てる
+        fs::write(&path, "longer content").unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("a").is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn only_full_responses_are_cached_and_the_oldest_is_evicted() {
+        let (path, len, modified) = file("evict", "x");
+        let mut cache = MetadataCache::new(2);
+        let ok = create_msg_from_code(200, Some(b"x".to_vec())).unwrap();
+        cache.insert("missing".to_string(), path.clone(), len, modified, &create_msg_from_code(404, None).unwrap());
+        assert!(cache.get("missing").is_none());
+        cache.insert("a".to_string(), path.clone(), len, modified, &ok);
+        cache.insert("b".to_string(), path.clone(), len, modified, &ok);
+        cache.get("a");
+        cache.insert("c".to_string(), path.clone(), len, modified, &ok);
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+        fs::remove_file(path).unwrap();
+    }
+}
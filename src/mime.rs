@@ -0,0 +1,190 @@
+use std::path::Path;
+
+// 拡張子とContent-Typeの対応表
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("xml", "application/xml"),
+    ("json", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+];
+
+// 拡張子のないファイルの判別に使う先頭のバイト数
+const SNIFF_LEN: usize = 512;
+// <meta charset>を探すHTMLの先頭のバイト数(HTML Standardのprescanと同じ)
+const META_CHARSET_SCAN_LEN: usize = 1024;
+// BOMと対応する文字コード。UTF-32は扱わない
+const BOMS: [(&[u8], &str); 3] = [(b"\xEF\xBB\xBF", "utf-8"), (b"\xFE\xFF", "utf-16be"), (b"\xFF\xFE", "utf-16le")];
+
+/**
+* 拡張子からContent-Typeを推定する
+*/
+pub fn content_type_for(path: &str) -> Option<&'static str> {
+    let extension = Path::new(path).extension()?.to_str()?;
+    MIME_TYPES
+        .iter()
+        .find(|(ext, _)| ext.eq_ignore_ascii_case(extension))
+        .map(|(_, mime)| *mime)
+}
+
+/**
+* 拡張子のないファイルの先頭を調べてテキストかバイナリかを判別する。
+* MIMEタイプの誤認を利用した攻撃を避けるため、text/htmlなどとは判定せず
+* text/plainかapplication/octet-streamのどちらかを返す
+*/
+pub fn sniff_content_type(body: &[u8]) -> &'static str {
+    let head = &body[..body.len().min(SNIFF_LEN)];
+    let is_text = match std::str::from_utf8(head) {
+        Ok(_) => true,
+        // 判別範囲の末尾でマルチバイト文字が切れている場合はテキストとみなす
+        Err(e) => e.error_len().is_none(),
+    } && !head
+        .iter()
+        .any(|&b| b.is_ascii_control() && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c));
+    if is_text {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/**
+* テキストファイルの文字コードを先頭のバイトだけで判別する。BOMがあればそれに従い、
+* HTMLでは先頭1024バイト以内の<meta charset="...">または<meta ... content="...; charset=...">の宣言を使う。
+* 判別できなければNone(拡張子から決めたcharsetのまま)
+*/
+pub fn detect_charset(body: &[u8], html: bool) -> Option<String> {
+    if let Some((_, charset)) = BOMS.iter().find(|(bom, _)| body.starts_with(bom)) {
+        return Some(charset.to_string());
+    }
+    if !html {
+        return None;
+    }
+    let head = body[..body.len().min(META_CHARSET_SCAN_LEN)].to_ascii_lowercase();
+    let mut rest = &head[..];
+    while let Some(start) = find(rest, b"<meta") {
+        let tag = &rest[start..];
+        let tag = &tag[..find(tag, b">").unwrap_or(tag.len())];
+        if let Some(charset) = find(tag, b"charset").and_then(|pos| charset_value(&tag[pos + b"charset".len()..])) {
+            return Some(charset);
+        }
+        rest = &rest[start + b"<meta".len()..];
+    }
+    None
+}
+
+/**
+* "charset"に続く"=値"の値。引用符は取り除き、文字コード名に使える文字だけを受け付ける
+*/
+fn charset_value(rest: &[u8]) -> Option<String> {
+    let rest = rest.trim_ascii_start().strip_prefix(b"=")?.trim_ascii_start();
+    let rest = rest.strip_prefix(b"\"").or_else(|| rest.strip_prefix(b"'")).unwrap_or(rest);
+    let len = rest
+        .iter()
+        .position(|&b| !(b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':')))
+        .unwrap_or(rest.len());
+    (len > 0).then(|| String::from_utf8_lossy(&rest[..len]).into_owned())
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modern_web_formats_have_types() {
+        for (path, expected) in [
+            ("/img/photo.webp", "image/webp"),
+            ("/img/photo.avif", "image/avif"),
+            ("/fonts/a.woff2", "font/woff2"),
+            ("/fonts/a.woff", "font/woff"),
+            ("/app.wasm", "application/wasm"),
+            ("/app.mjs", "text/javascript; charset=utf-8"),
+            ("/site.webmanifest", "application/manifest+json"),
+            ("/movie.webm", "video/webm"),
+            ("/IMAGE.WEBP", "image/webp"),
+        ] {
+            assert_eq!(content_type_for(path), Some(expected), "{}", path);
+        }
+        assert_eq!(content_type_for("/README"), None);
+        assert_eq!(content_type_for("/archive.unknown"), None);
+    }
+
+    #[test]
+    fn utf8_text_is_sniffed_as_plain_text() {
+        assert_eq!(sniff_content_type(b"# README\n\nHello\tworld\r\n"), "text/plain; charset=utf-8");
+        assert_eq!(sniff_content_type("日本語のテキスト".as_bytes()), "text/plain; charset=utf-8");
+        assert_eq!(sniff_content_type(b""), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn binary_is_sniffed_as_octet_stream() {
+        assert_eq!(sniff_content_type(b"\x7fELF\x02\x01\x01\x00"), "application/octet-stream");
+        assert_eq!(sniff_content_type(b"\xff\xfe\xfd"), "application/octet-stream");
+        assert_eq!(sniff_content_type(b"text with a NUL\x00"), "application/octet-stream");
+    }
+
+    #[test]
+    fn html_is_never_sniffed_as_html() {
+        assert_eq!(sniff_content_type(b"<!DOCTYPE html><script>alert(1)</script>"), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn multibyte_character_cut_at_the_sniff_limit_is_text() {
+        let mut body = vec![b'a'; SNIFF_LEN - 1];
+        body.extend_from_slice("あ".as_bytes());
+        assert_eq!(sniff_content_type(&body), "text/plain; charset=utf-8");
+    }
+
+    #[test]
+    fn charset_is_detected_from_a_bom() {
+        assert_eq!(detect_charset(b"\xEF\xBB\xBFhello", false).as_deref(), Some("utf-8"));
+        assert_eq!(detect_charset(b"\xFF\xFEh\0", false).as_deref(), Some("utf-16le"));
+        assert_eq!(detect_charset(b"\xFE\xFF\0h", true).as_deref(), Some("utf-16be"));
+        assert_eq!(detect_charset(b"hello", false), None);
+    }
+
+    #[test]
+    fn charset_is_detected_from_an_html_meta_declaration() {
+        for html in [
+            &b"<html><head><META CHARSET=\"ISO-8859-1\"></head>"[..],
+            b"<meta name=viewport><meta charset='iso-8859-1'>",
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=iso-8859-1\">",
+        ] {
+            assert_eq!(detect_charset(html, true).as_deref(), Some("iso-8859-1"), "{}", String::from_utf8_lossy(html));
+        }
+        // HTML以外や先頭1024バイトより後の宣言は使わない
+        assert_eq!(detect_charset(b"<meta charset=\"iso-8859-1\">", false), None);
+        let mut late = vec![b' '; META_CHARSET_SCAN_LEN];
+        late.extend_from_slice(b"<meta charset=\"iso-8859-1\">");
+        assert_eq!(detect_charset(&late, true), None);
+        assert_eq!(detect_charset(b"<meta charset=\"\">", true), None);
+    }
+}
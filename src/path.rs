@@ -0,0 +1,88 @@
+// Windowsで予約されているデバイス名。拡張子を付けても(CON.txtなど)デバイスを指す
+const WINDOWS_RESERVED_NAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/**
+* リクエストターゲットのパスをドキュメントルートからの相対パスに変換する。
+* ".."による親ディレクトリへの移動やドットファイル(.gitなど)を含むパスはNoneを返す。
+* Windowsでは別のファイルやデバイスを指しうるセグメント(is_unsafe_windows_segment)を含むパスもNoneを返す
+*/
+pub fn to_relative_path(target: &str) -> Option<String> {
+    let relative = target.trim_start_matches('/');
+    for segment in relative.split('/') {
+        // ACMEなどで使われる.well-knownは例外とする
+        if segment.starts_with('.') && segment != ".well-known" {
+            return None;
+        }
+        if cfg!(windows) && is_unsafe_windows_segment(segment) {
+            return None;
+        }
+    }
+    Some(relative.to_string())
+}
+
+/**
+* Windowsでパスのセグメントとして安全でないか。
+* "\"による区切り(a\..\b)、":"を含む代替データストリーム("file.txt::$DATA")やドライブ名、
+* Windowsが取り除く末尾の"."と空白("index.html."がindex.htmlを指す)、予約されたデバイス名(CON、NUL、COM1など)を拒否する
+*/
+pub fn is_unsafe_windows_segment(segment: &str) -> bool {
+    if segment.contains(['\\', ':']) || segment.ends_with(['.', ' ']) {
+        return true;
+    }
+    let stem = segment.split('.').next().unwrap_or(segment).trim_end_matches(' ');
+    WINDOWS_RESERVED_NAMES.iter().any(|name| name.eq_ignore_ascii_case(stem))
+}
+
+/**
+* パス中の連続したスラッシュを1つにまとめる("//foo///bar"は"/foo/bar"になる)
+*/
+pub fn collapse_slashes(path: &str) -> String {
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c == '/' && collapsed.ends_with('/') {
+            continue;
+        }
+        collapsed.push(c);
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unsafe_windows_segments_are_detected() {
+        for segment in [
+            "a\\..\\b",
+            "file.txt::$DATA",
+            "C:",
+            "index.html.",
+            "index.html ",
+            "CON",
+            "nul",
+            "Com1.txt",
+            "LPT9.tar.gz",
+            "AUX .txt",
+        ] {
+            assert!(is_unsafe_windows_segment(segment), "{:?}", segment);
+        }
+        for segment in ["index.html", "console.log", "com10", "nullable.txt", "my file.txt"] {
+            assert!(!is_unsafe_windows_segment(segment), "{:?}", segment);
+        }
+    }
+
+    #[test]
+    fn windows_segments_are_rejected_only_on_windows() {
+        for target in ["/CON", "/docs/index.html.", "/a\\..\\secret.txt", "/file.txt::$DATA"] {
+            assert_eq!(to_relative_path(target).is_none(), cfg!(windows), "{}", target);
+        }
+        // どの環境でも拒否するもの
+        assert_eq!(to_relative_path("/../secret.txt"), None);
+        assert_eq!(to_relative_path("/.git/config"), None);
+        assert_eq!(to_relative_path("/docs/index.html").as_deref(), Some("docs/index.html"));
+    }
+}
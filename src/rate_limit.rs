@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/**
+* トークンバケットによるレート制限。
+* 1秒あたりrate個のトークンが最大burst個まで溜まり、1回の操作で1個消費する
+*/
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub(crate) fn new(rate: f64, burst: u32, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last_refill: now,
+        }
+    }
+
+    /**
+    * nowの時点で溜まっているトークンの数
+    */
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        (self.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    /**
+    * トークンを1個消費する。残っていなければfalseを返す
+    */
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> bool {
+        self.tokens = self.tokens_at(now);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /**
+    * 次にトークンが1個溜まるまでの時間
+    */
+    pub(crate) fn time_until_available(&self, now: Instant) -> Duration {
+        let tokens = self.tokens_at(now);
+        if tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - tokens) / self.rate)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_is_available_at_once_then_refills_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 3, start);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(start));
+        }
+        assert!(!bucket.try_acquire(start));
+        assert_eq!(bucket.time_until_available(start), Duration::from_millis(500));
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)));
+        assert!(!bucket.try_acquire(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn tokens_do_not_exceed_the_burst() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2, start);
+        let later = start + Duration::from_secs(60);
+        assert!(bucket.try_acquire(later));
+        assert!(bucket.try_acquire(later));
+        assert!(!bucket.try_acquire(later));
+    }
+}
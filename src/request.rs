@@ -0,0 +1,727 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::net::SocketAddr;
+use crate::config::{is_valid_header_name, is_valid_header_value};
+use crate::extensions::Extensions;
+use crate::path::collapse_slashes;
+
+// リクエストラインの最大長
+const MAX_REQUEST_LINE_LEN: usize = 8192;
+// ヘッダ部(リクエストラインを除く)の最大長
+const MAX_HEADER_LEN: usize = 8192;
+// ボディの最大長
+const MAX_BODY_LEN: usize = 1024 * 1024;
+// HTTP/2のコネクションプリフェイスの先頭行(RFC 9113 3.4)
+const HTTP2_PREFACE_LINE: &str = "PRI * HTTP/2.0";
+// ヘルスチェックの高速な処理では扱わず、通常のパースに任せるヘッダ
+const FAST_PATH_EXCLUDED_HEADERS: [&str; 4] = ["Content-Length", "Transfer-Encoding", "Expect", "Upgrade"];
+
+/**
+* パース済みのHTTPリクエスト
+*/
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    // HTTP/1.xのマイナーバージョン
+    pub version: u8,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // 送信元のアドレス。parse_requestは設定しないので、受信した接続の情報から設定する
+    pub remote_addr: Option<SocketAddr>,
+    // リクエストフックからルートの処理やレスポンスフックに渡す値
+    pub extensions: Extensions,
+}
+
+impl Request {
+    /**
+    * ヘッダの値を取得する。ヘッダ名は大文字小文字を区別しない
+    */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /**
+    * Connectionヘッダに指定したオプション(keep-alive、closeなど)が含まれているか
+    */
+    pub fn has_connection_option(&self, option: &str) -> bool {
+        self.headers
+            .iter()
+            .filter(|(n, _)| n.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, v)| v.split(','))
+            .any(|v| v.trim().eq_ignore_ascii_case(option))
+    }
+
+    /**
+    * クライアントが接続の維持を望んでいるか。
+    * HTTP/1.0はConnection: keep-aliveがある場合のみ、HTTP/1.1はConnection: closeがない限り維持する
+    */
+    pub fn wants_keep_alive(&self) -> bool {
+        if self.version >= 1 {
+            !self.has_connection_option("close")
+        } else {
+            self.has_connection_option("keep-alive")
+        }
+    }
+
+    /**
+    * リクエストターゲットのパス部分。
+    * absolute-form("http://host/path")の場合はホスト部分を取り除き、クエリ文字列も除いたパスを返す
+    */
+    pub fn path(&self) -> &str {
+        let path = match split_absolute_form(&self.target) {
+            Some((_, path)) => path,
+            None => &self.target,
+        };
+        path.split_once('?').map_or(path, |(path, _)| path)
+    }
+
+    /**
+    * リクエストターゲットの"?"以降のクエリ文字列。"?"がなければNoneを返す
+    */
+    pub fn query(&self) -> Option<&str> {
+        self.target.split_once('?').map(|(_, query)| query)
+    }
+
+    /**
+    * クエリ文字列を"&"で区切った名前と値の組。%XXと"+"(空白)をデコードし、"="のない項目は値を空文字列とする
+    */
+    pub fn query_params(&self) -> Vec<(String, String)> {
+        let Some(query) = self.query() else {
+            return Vec::new();
+        };
+        query
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                (decode_query_component(name), decode_query_component(value))
+            })
+            .collect()
+    }
+
+    /**
+    * クエリパラメータの値。同じ名前が複数ある場合は最初の値を返す
+    */
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        self.query_params().into_iter().find(|(n, _)| n == name).map(|(_, value)| value)
+    }
+
+    /**
+    * リクエスト先のホスト。
+    * absolute-formの場合はHostヘッダよりもターゲットに含まれるホストを優先する(RFC 9112 3.2.2)
+    */
+    pub fn host(&self) -> Option<&str> {
+        match split_absolute_form(&self.target) {
+            Some((host, _)) => Some(host),
+            None => self.header("Host"),
+        }
+    }
+}
+
+/**
+* absolute-formのリクエストターゲットをホストとパスに分割する
+*/
+fn split_absolute_form(target: &str) -> Option<(&str, &str)> {
+    let (scheme, rest) = target.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    match rest.find('/') {
+        Some(index) => Some((&rest[..index], &rest[index..])),
+        // "http://host"のようにパスが省略された場合はルートとする
+        None => Some((rest, "/")),
+    }
+}
+
+/**
+* application/x-www-form-urlencodedのクエリの名前や値をデコードする。
+* 不正な%XXはそのまま残し、UTF-8でないバイト列は置換文字にする
+*/
+fn decode_query_component(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/**
+* ターゲットのパス部分の連続したスラッシュをまとめる。
+* absolute-formのスキームとホスト、クエリ文字列はそのまま残す
+*/
+fn normalize_target(target: &str) -> String {
+    let (target, query) = match target.split_once('?') {
+        Some((target, query)) => (target, Some(query)),
+        None => (target, None),
+    };
+    let mut normalized = match split_absolute_form(target) {
+        Some((_, path)) if target.ends_with(path) => {
+            let origin = &target[..target.len() - path.len()];
+            format!("{}{}", origin, collapse_slashes(path))
+        }
+        // "http://host"のようにパスが省略されている
+        Some(_) => target.to_string(),
+        None => collapse_slashes(target),
+    };
+    if let Some(query) = query {
+        normalized.push('?');
+        normalized.push_str(query);
+    }
+    normalized
+}
+
+/**
+* リクエストのパースエラー
+*/
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    BadRequestLine,
+    UnsupportedVersion,
+    // HTTP/2のコネクションプリフェイス("PRI * HTTP/2.0")
+    Http2Preface,
+    // バージョンのないHTTP/0.9形式のリクエストライン("GET /path")。ヘッダを待たずに拒否する
+    Http09Request,
+    BadHeader,
+    // Content-LengthとTransfer-Encodingの併用など、ボディの長さが曖昧なリクエスト
+    AmbiguousFraming,
+    // chunkedなどのTransfer-Encodingはデコードできないので、ボディの終わりが分からない
+    UnsupportedTransferEncoding,
+    TooLong(Section),
+}
+
+/**
+* 長さ制限を超えたリクエストの部位
+*/
+#[derive(Debug, PartialEq)]
+pub enum Section {
+    RequestLine,
+    // リクエストライン中のリクエストターゲット
+    Target,
+    Headers,
+    Body,
+}
+
+impl ParseError {
+    /**
+    * エラーに対応するステータスコード
+    */
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ParseError::BadRequestLine => 400,
+            ParseError::UnsupportedVersion => 505,
+            ParseError::Http2Preface => 505,
+            ParseError::Http09Request => 400,
+            ParseError::BadHeader => 400,
+            ParseError::AmbiguousFraming => 400,
+            ParseError::UnsupportedTransferEncoding => 501,
+            ParseError::TooLong(Section::RequestLine | Section::Target) => 414,
+            ParseError::TooLong(Section::Headers) => 431,
+            ParseError::TooLong(Section::Body) => 413,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::BadRequestLine => write!(f, "malformed request line"),
+            ParseError::UnsupportedVersion => write!(f, "unsupported HTTP version"),
+            ParseError::Http2Preface => write!(f, "HTTP/2 connection preface"),
+            ParseError::Http09Request => write!(f, "HTTP/0.9 request line without a version"),
+            ParseError::BadHeader => write!(f, "malformed header field"),
+            ParseError::AmbiguousFraming => write!(f, "ambiguous message framing"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "unsupported transfer coding"),
+            ParseError::TooLong(Section::RequestLine) => write!(f, "request line too long"),
+            ParseError::TooLong(Section::Target) => write!(f, "request target too long"),
+            ParseError::TooLong(Section::Headers) => write!(f, "header fields too long"),
+            ParseError::TooLong(Section::Body) => write!(f, "body too long"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/**
+* パース済みのリクエストラインとヘッダ。ボディはまだ受信していない場合がある
+*/
+pub struct RequestHead {
+    // ボディが空のリクエスト
+    pub request: Request,
+    // リクエストラインとヘッダの長さ
+    pub len: usize,
+    pub content_length: usize,
+}
+
+/**
+* バッファからリクエストをパースする。
+* リクエストが揃っていなければNoneを、揃っていればリクエストとその長さを返す
+*/
+pub fn parse_request(buffer: &[u8]) -> Result<Option<(Request, usize)>, ParseError> {
+    let Some(head) = parse_request_head(buffer)? else {
+        return Ok(None);
+    };
+    //Content-Lengthの分だけボディを読む
+    let end = head.len + head.content_length;
+    if buffer.len() < end {
+        return Ok(None);
+    }
+    let mut request = head.request;
+    request.body = buffer[head.len..end].to_vec();
+    Ok(Some((request, end)))
+}
+
+/**
+* バッファからリクエストラインとヘッダをパースする。ヘッダが揃っていなければNoneを返す
+*/
+pub fn parse_request_head(buffer: &[u8]) -> Result<Option<RequestHead>, ParseError> {
+    let head = parse_head_unbounded(buffer)?;
+    if head.as_ref().is_some_and(|head| head.content_length > MAX_BODY_LEN) {
+        return Err(ParseError::TooLong(Section::Body));
+    }
+    Ok(head)
+}
+
+/**
+* parse_request_headと同じだが、ボディを溜めずに受け渡すストリーミング用にボディの長さを制限しない
+*/
+pub(crate) fn parse_head_unbounded(buffer: &[u8]) -> Result<Option<RequestHead>, ParseError> {
+    //リクエストラインをパースする
+    let Some(line_end) = find_crlf(buffer) else {
+        if buffer.len() > MAX_REQUEST_LINE_LEN {
+            return Err(ParseError::TooLong(Section::RequestLine));
+        }
+        return Ok(None);
+    };
+    if line_end > MAX_REQUEST_LINE_LEN {
+        return Err(ParseError::TooLong(Section::RequestLine));
+    }
+    let line = std::str::from_utf8(&buffer[..line_end]).map_err(|_| ParseError::BadRequestLine)?;
+    let (method, target, version) = parse_request_line(line)?;
+
+    //ヘッダをパースする
+    let mut headers = Vec::new();
+    let mut pos = line_end + 2;
+    loop {
+        let rest = &buffer[pos..];
+        let Some(end) = find_crlf(rest) else {
+            if pos + rest.len() - (line_end + 2) > MAX_HEADER_LEN {
+                return Err(ParseError::TooLong(Section::Headers));
+            }
+            return Ok(None);
+        };
+        if pos + end - (line_end + 2) > MAX_HEADER_LEN {
+            return Err(ParseError::TooLong(Section::Headers));
+        }
+        if end == 0 {
+            // 空行でヘッダ終了
+            pos += 2;
+            break;
+        }
+        headers.push(parse_header_field(&decode_field(&rest[..end]))?);
+        pos += end + 2;
+    }
+    let content_length = check_framing(&headers)?;
+    let request = Request {
+        method: method.to_string(),
+        target: normalize_target(target),
+        version,
+        headers,
+        body: Vec::new(),
+        remote_addr: None,
+        extensions: Extensions::default(),
+    };
+    Ok(Some(RequestHead { request, len: pos, content_length }))
+}
+
+/**
+* バッファの先頭が"HEAD (path) HTTP/1.x"のリクエストであれば、ヘッダをパースせずに
+* リクエストの長さとクライアントが接続の維持を望んでいるかを返す。ヘッダは名前とConnectionの値だけを確認し、
+* ヘッダ部が揃っていない場合やボディの扱いを変えるヘッダを含む場合はNoneを返して通常のパースに任せる
+*/
+pub(crate) fn match_health_check(buffer: &[u8], path: &str) -> Option<(usize, bool)> {
+    let rest = buffer.strip_prefix(b"HEAD ")?.strip_prefix(path.as_bytes())?;
+    let (version, headers) = match rest.strip_prefix(b" HTTP/1.1\r\n") {
+        Some(headers) => (1, headers),
+        None => (0, rest.strip_prefix(b" HTTP/1.0\r\n")?),
+    };
+    let (mut close, mut keep_alive) = (false, false);
+    let mut pos = 0;
+    loop {
+        let end = find_crlf(&headers[pos..])?;
+        if end == 0 {
+            // Request::wants_keep_aliveと同じ判定
+            let wants_keep_alive = if version >= 1 { !close } else { keep_alive };
+            return Some((buffer.len() - headers.len() + pos + 2, wants_keep_alive));
+        }
+        let line = std::str::from_utf8(&headers[pos..pos + end]).ok()?;
+        let (name, value) = line.split_once(':')?;
+        if !is_valid_header_name(name)
+            || FAST_PATH_EXCLUDED_HEADERS.iter().any(|excluded| excluded.eq_ignore_ascii_case(name))
+        {
+            return None;
+        }
+        if name.eq_ignore_ascii_case("Connection") {
+            for option in value.split(',').map(str::trim) {
+                close |= option.eq_ignore_ascii_case("close");
+                keep_alive |= option.eq_ignore_ascii_case("keep-alive");
+            }
+        }
+        pos += end + 2;
+        if pos > MAX_HEADER_LEN {
+            return None;
+        }
+    }
+}
+
+fn find_crlf(buffer: &[u8]) -> Option<usize> {
+    buffer.windows(2).position(|w| w == b"\r\n")
+}
+
+/**
+* リクエストライン(メソッド、リクエストターゲット、HTTPバージョン)をパースする
+*/
+fn parse_request_line(line: &str) -> Result<(&str, &str, u8), ParseError> {
+    if line == HTTP2_PREFACE_LINE {
+        return Err(ParseError::Http2Preface);
+    }
+    let mut parts = line.split(' ');
+    // HTTP/0.9のレスポンス(ヘッダのないボディのみ)には対応しないため、HTTP/1.0として扱わずに400を返す。
+    // 続くヘッダや空行は送られてこないので、受信を待たずにリクエストラインだけで判断する
+    if let [method, target] = line.split(' ').collect::<Vec<_>>()[..] {
+        if is_valid_header_name(method) && target.starts_with('/') {
+            return Err(ParseError::Http09Request);
+        }
+    }
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::BadRequestLine);
+    };
+    if !is_valid_header_name(method) || target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::BadRequestLine);
+    }
+    let version = match version {
+        "HTTP/1.0" => 0,
+        "HTTP/1.1" => 1,
+        // 形式は正しいが対応していないバージョン
+        v if is_http_version(v) => return Err(ParseError::UnsupportedVersion),
+        _ => return Err(ParseError::BadRequestLine),
+    };
+    Ok((method, target, version))
+}
+
+fn is_http_version(version: &str) -> bool {
+    let Some(v) = version.strip_prefix("HTTP/") else {
+        return false;
+    };
+    let v = v.as_bytes();
+    v.len() == 3 && v[0].is_ascii_digit() && v[1] == b'.' && v[2].is_ascii_digit()
+}
+
+/**
+* リクエストスマグリングの原因となるボディ長の指定を拒否する。
+* Content-LengthとTransfer-Encodingの併用と、値の異なる複数のContent-Lengthがエラーになる。
+* Transfer-Encodingはデコードできないため、Content-Lengthがなくても拒否する。
+* ボディを読まずに次のリクエストとして扱うと、ボディに埋め込んだリクエストが処理されてしまう。
+* ボディの長さを返す
+*/
+fn check_framing(headers: &[(String, String)]) -> Result<usize, ParseError> {
+    let mut content_length = None;
+    let mut transfer_encoding = false;
+    for (name, value) in headers {
+        if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // "chunked"や"gzip, chunked"のようなtransfer-codingのリスト。パラメータは";"以降に付く
+            let valid = value
+                .split(',')
+                .all(|coding| is_valid_header_name(coding.split(';').next().unwrap_or_default().trim()));
+            if !valid {
+                return Err(ParseError::BadHeader);
+            }
+            transfer_encoding = true;
+        } else if name.eq_ignore_ascii_case("Content-Length") {
+            // "Content-Length: 5, 5"のようなリスト形式も考慮する
+            for v in value.split(',') {
+                let v = v.trim();
+                if v.is_empty() || !v.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(ParseError::AmbiguousFraming);
+                }
+                match content_length {
+                    Some(prev) if prev != v => return Err(ParseError::AmbiguousFraming),
+                    _ => content_length = Some(v),
+                }
+            }
+        }
+    }
+    if transfer_encoding && content_length.is_some() {
+        return Err(ParseError::AmbiguousFraming);
+    }
+    if transfer_encoding {
+        return Err(ParseError::UnsupportedTransferEncoding);
+    }
+    match content_length {
+        Some(v) => v.parse().map_err(|_| ParseError::TooLong(Section::Body)),
+        None => Ok(0),
+    }
+}
+
+/**
+* ヘッダのフィールドを文字列にする。UTF-8でなければ、値に使えるobs-text(RFC 9110 5.5)を含むものとして
+* ISO-8859-1で1バイトを1文字にする。ヘッダ名はtokenなのでどちらでも変わらない
+*/
+fn decode_field(field: &[u8]) -> Cow<'_, str> {
+    match std::str::from_utf8(field) {
+        Ok(field) => Cow::Borrowed(field),
+        Err(_) => Cow::Owned(field.iter().map(|&b| b as char).collect()),
+    }
+}
+
+/**
+* "名前: 値"の形式のヘッダをパースする
+*/
+fn parse_header_field(field: &str) -> Result<(String, String), ParseError> {
+    let (name, value) = field.split_once(':').ok_or(ParseError::BadHeader)?;
+    // ヘッダ名とコロンの間の空白はRFC 9112で禁止されている
+    if !is_valid_header_name(name) {
+        return Err(ParseError::BadHeader);
+    }
+    let value = value.trim_matches(|c| c == ' ' || c == '\t');
+    if !is_valid_header_value(value) {
+        return Err(ParseError::BadHeader);
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<(Request, usize)>, ParseError> {
+        parse_request(raw.as_bytes())
+    }
+
+    #[test]
+    fn parses_complete_request() {
+        let raw = "GET /a//b?x=1 HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (request, len) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.target, "/a/b?x=1");
+        assert_eq!(request.version, 1);
+        assert_eq!(request.header("host"), Some("example.com"));
+        assert_eq!(len, raw.len());
+    }
+
+    #[test]
+    fn waits_for_incomplete_request() {
+        assert!(parse("GET / HTTP/1.1\r\nHost: a\r\n").unwrap().is_none());
+        assert!(parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel").unwrap().is_none());
+    }
+
+    #[test]
+    fn bad_request_line_is_400() {
+        for line in ["GET  / HTTP/1.1", "GET / HTTP/1.1 extra", "G(T / HTTP/1.1", "GET / HTTX/1.1", "\u{1}"] {
+            let err = parse(&format!("{}\r\n\r\n", line)).err().unwrap();
+            assert_eq!(err, ParseError::BadRequestLine, "{:?}", line);
+            assert_eq!(err.status_code(), 400);
+        }
+        // UTF-8でないリクエストライン
+        assert_eq!(parse_request(b"GET /\xff HTTP/1.1\r\n\r\n").err(), Some(ParseError::BadRequestLine));
+    }
+
+    #[test]
+    fn unsupported_version_is_505() {
+        let err = parse("GET / HTTP/2.0\r\n\r\n").err().unwrap();
+        assert_eq!(err, ParseError::UnsupportedVersion);
+        assert_eq!(err.status_code(), 505);
+        let err = parse("PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n").err().unwrap();
+        assert_eq!(err, ParseError::Http2Preface);
+        assert_eq!(err.status_code(), 505);
+    }
+
+    #[test]
+    fn http09_request_line_is_400() {
+        let err = parse("GET /index.html\r\n").err().unwrap();
+        assert_eq!(err, ParseError::Http09Request);
+        assert_eq!(err.status_code(), 400);
+    }
+
+    #[test]
+    fn bad_header_is_400() {
+        for field in ["NoColon", "Bad Name: x", "Name : x", "X: a\u{1}b"] {
+            let err = parse(&format!("GET / HTTP/1.1\r\n{}\r\n\r\n", field)).err().unwrap();
+            assert_eq!(err, ParseError::BadHeader, "{:?}", field);
+            assert_eq!(err.status_code(), 400);
+        }
+    }
+
+    #[test]
+    fn obs_text_header_value_is_accepted() {
+        let (request, _) = parse_request(b"GET / HTTP/1.1\r\nX-Name: caf\xe9\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.header("X-Name"), Some("caf\u{e9}"));
+    }
+
+    #[test]
+    fn query_params_are_decoded() {
+        let raw = "GET /search?q=caf%C3%A9+au+lait&flag&&q=second&bad=%zz HTTP/1.1\r\n\r\n";
+        let (request, _) = parse(raw).unwrap().unwrap();
+        assert_eq!(
+            request.query_params(),
+            [("q", "café au lait"), ("flag", ""), ("q", "second"), ("bad", "%zz")]
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+        );
+        assert_eq!(request.query_param("q").as_deref(), Some("café au lait"));
+        assert_eq!(request.query_param("missing"), None);
+    }
+
+    #[test]
+    fn long_request_line_is_414() {
+        let target = "a".repeat(MAX_REQUEST_LINE_LEN);
+        let err = parse(&format!("GET /{} HTTP/1.1\r\n\r\n", target)).err().unwrap();
+        assert_eq!(err, ParseError::TooLong(Section::RequestLine));
+        assert_eq!(err.status_code(), 414);
+        // 改行が届く前でも上限を超えた時点でエラーにする
+        assert_eq!(parse(&format!("GET /{}", target)).err(), Some(ParseError::TooLong(Section::RequestLine)));
+        assert_eq!(ParseError::TooLong(Section::Target).status_code(), 414);
+    }
+
+    #[test]
+    fn long_headers_are_431() {
+        let value = "a".repeat(MAX_HEADER_LEN);
+        let err = parse(&format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", value)).err().unwrap();
+        assert_eq!(err, ParseError::TooLong(Section::Headers));
+        assert_eq!(err.status_code(), 431);
+    }
+
+    #[test]
+    fn long_body_is_413() {
+        let err = parse(&format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LEN + 1)).err().unwrap();
+        assert_eq!(err, ParseError::TooLong(Section::Body));
+        assert_eq!(err.status_code(), 413);
+        // ストリーミング用のパースはボディの長さを制限しない
+        let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_LEN + 1);
+        assert!(parse_head_unbounded(raw.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_content_length_with_transfer_encoding() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        let err = parse(raw).err().unwrap();
+        assert_eq!(err, ParseError::AmbiguousFraming);
+        assert_eq!(err.status_code(), 400);
+    }
+
+    #[test]
+    fn rejects_conflicting_content_length_headers() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!";
+        assert_eq!(parse(raw).err(), Some(ParseError::AmbiguousFraming));
+    }
+
+    #[test]
+    fn rejects_conflicting_content_length_list() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\nhello!";
+        let err = parse(raw).err().unwrap();
+        assert_eq!(err, ParseError::AmbiguousFraming);
+        assert_eq!(err.status_code(), 400);
+    }
+
+    #[test]
+    fn accepts_repeated_identical_content_length() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\nContent-Length: 5\r\n\r\nhello";
+        let (request, len) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.body, b"hello");
+        assert_eq!(len, raw.len());
+    }
+
+    #[test]
+    fn rejects_bare_transfer_encoding() {
+        // ボディをデコードせずに読み進めると、チャンクの中身が次のリクエストとして扱われてしまう
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\nGET /admin HTTP/1.1\r\n\r\n";
+        let err = parse(raw).err().unwrap();
+        assert_eq!(err, ParseError::UnsupportedTransferEncoding);
+        assert_eq!(err.status_code(), 501);
+    }
+
+    #[test]
+    fn rejects_transfer_encoding_on_streaming_parse() {
+        let raw = b"POST /upload HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n";
+        assert_eq!(parse_head_unbounded(raw).err(), Some(ParseError::UnsupportedTransferEncoding));
+    }
+
+    #[test]
+    fn rejects_malformed_transfer_encoding() {
+        for value in ["", "chunked,,", "chun ked", "\"chunked\""] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {}\r\n\r\n", value);
+            let err = parse(&raw).err().unwrap();
+            assert_eq!(err, ParseError::BadHeader, "{:?}", value);
+            assert_eq!(err.status_code(), 400);
+        }
+    }
+
+    #[test]
+    fn absolute_form_target_uses_its_path_and_host() {
+        let raw = "GET http://example.com:8080//docs/a.html?lang=ja HTTP/1.1\r\nHost: other.example\r\n\r\n";
+        let (request, _) = parse(raw).unwrap().unwrap();
+        assert_eq!(request.path(), "/docs/a.html");
+        assert_eq!(request.query(), Some("lang=ja"));
+        // ターゲットのホストをHostヘッダより優先する
+        assert_eq!(request.host(), Some("example.com:8080"));
+        let (request, _) = parse("GET HTTPS://example.com HTTP/1.1\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.path(), "/");
+        assert_eq!(request.host(), Some("example.com"));
+        // http以外のスキームはabsolute-formとして扱わない
+        let (request, _) = parse("GET ftp://example.com/a HTTP/1.1\r\nHost: h\r\n\r\n").unwrap().unwrap();
+        assert_eq!(request.host(), Some("h"));
+    }
+
+    #[test]
+    fn keep_alive_default_depends_on_the_version() {
+        for (version, connection, expected) in [
+            ("1.0", "", false),
+            ("1.0", "Connection: keep-alive\r\n", true),
+            ("1.1", "", true),
+            ("1.1", "Connection: close\r\n", false),
+            ("1.1", "Connection: Keep-Alive, Close\r\n", false),
+        ] {
+            let (request, _) = parse(&format!("GET / HTTP/{}\r\n{}\r\n", version, connection)).unwrap().unwrap();
+            assert_eq!(request.wants_keep_alive(), expected, "{} {:?}", version, connection);
+        }
+    }
+
+    #[test]
+    fn health_check_fast_path_matches_only_the_configured_head_request() {
+        let raw = b"HEAD /healthz HTTP/1.1\r\nHost: lb\r\n\r\nGET / HTTP/1.1\r\n";
+        let len = raw.len() - "GET / HTTP/1.1\r\n".len();
+        assert_eq!(match_health_check(raw, "/healthz"), Some((len, true)));
+        for raw in [&b"HEAD /healthz HTTP/1.0\r\n\r\n"[..], b"HEAD /healthz HTTP/1.1\r\nConnection: close\r\n\r\n"] {
+            assert_eq!(match_health_check(raw, "/healthz"), Some((raw.len(), false)));
+        }
+        for raw in [
+            &b"GET /healthz HTTP/1.1\r\n\r\n"[..],
+            b"HEAD /healthz2 HTTP/1.1\r\n\r\n",
+            b"HEAD /healthz?x HTTP/1.1\r\n\r\n",
+            b"HEAD /healthz HTTP/1.1\r\nHost: lb\r\n",
+            b"HEAD /healthz HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello",
+            b"HEAD /healthz HTTP/1.1\r\nBad Header: x\r\n\r\n",
+        ] {
+            assert_eq!(match_health_check(raw, "/healthz"), None, "{:?}", String::from_utf8_lossy(raw));
+        }
+    }
+}
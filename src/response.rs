@@ -0,0 +1,1397 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::warn;
+use crate::archive::Archive;
+use crate::cgi::{cgi_script_path, prepare_cgi, CgiJob};
+use crate::clock::SystemClock;
+use crate::config::{Config, EtagStrength};
+use crate::error::{Error, Result};
+use crate::http_date::{format_http_date, parse_http_date};
+use crate::metadata_cache::MetadataCache;
+use crate::mime::{content_type_for, detect_charset, sniff_content_type};
+use crate::path::to_relative_path;
+use crate::request::Request;
+use crate::upload::{delete_file, patch_file, put_file};
+
+// リクエストの内容を返すデバッグ用エンドポイント
+const DEBUG_ECHO_PATH: &str = "/debug/echo";
+// ビルド情報を返すエンドポイント
+const VERSION_PATH: &str = "/version";
+// 設定に関わらずサポートしているメソッド
+const BASE_METHODS: [&str; 3] = ["GET", "HEAD", "OPTIONS"];
+// upload_root設定時にサポートするメソッド
+const UPLOAD_ALLOWED_METHODS: [&str; 3] = ["PUT", "DELETE", "PATCH"];
+// ディレクトリへのリクエストで返すファイル
+const INDEX_FILE: &str = "index.html";
+// concat_pathで連結できるファイルの最大数
+const MAX_CONCAT_FILES: usize = 32;
+// 圧縮後のサイズが元のサイズのこの割合(%)を超える場合は圧縮しない
+const MAX_COMPRESSED_RATIO: usize = 90;
+// 設定のRetry-Afterを付与するステータスコード
+const RETRY_AFTER_STATUS_CODES: [u16; 2] = [429, 503];
+// placeholder_page有効時に、index.htmlがなければGET /に返すページ
+const PLACEHOLDER_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><meta charset=\"utf-8\"><title>It works</title></head>
+<body>
+<h1>It works</h1>
+<p>The server is running, but there is no content yet. Add an index.html to the document root.</p>
+</body>
+</html>
+";
+
+/**
+* HTTPレスポンス
+*/
+#[derive(Clone)]
+pub struct Response {
+    pub status_code: u16,
+    pub reason: &'static str,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    // ボディを後から送り続ける。Content-Lengthを付与せず、接続を閉じてボディの終わりを示す
+    pub stream: bool,
+    // HEADへの応答。Content-Lengthはボディの長さのまま付与し、ボディは送信しない
+    pub omit_body: bool,
+    // Content-Lengthとするボディの長さ。Noneの場合はbodyの長さ。
+    // キャッシュしたメタデータからHEADに応答する場合に、ボディを持たずに長さだけを示す
+    pub body_len: Option<usize>,
+}
+
+impl Response {
+    pub fn new(status_code: u16, reason: &'static str) -> Self {
+        Response {
+            status_code,
+            reason,
+            headers: vec![("Server".to_string(), "mio webserver".to_string())],
+            body: Vec::new(),
+            stream: false,
+            omit_body: false,
+            body_len: None,
+        }
+    }
+
+    pub fn add_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /**
+    * ヘッダの値。名前の大文字小文字は区別しない
+    */
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /**
+    * レスポンスの内容を左右したリクエストヘッダをVaryに追加する
+    */
+    pub fn add_vary(&mut self, name: &str) {
+        match self.headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case("Vary")) {
+            Some((_, value)) => {
+                if !value.split(',').any(|v| v.trim().eq_ignore_ascii_case(name)) {
+                    value.push_str(", ");
+                    value.push_str(name);
+                }
+            }
+            None => self.add_header("Vary", name),
+        }
+    }
+
+    /**
+    * OPTIONSや405で返すAllowにメソッドを追加する。Allowがなければ何もしない
+    */
+    pub(crate) fn add_allowed_method(&mut self, method: &str) {
+        if let Some((_, value)) = self.headers.iter_mut().find(|(n, _)| n.eq_ignore_ascii_case("Allow")) {
+            if !value.split(',').any(|v| v.trim() == method) {
+                value.push_str(", ");
+                value.push_str(method);
+            }
+        }
+    }
+
+    /**
+    * レスポンスをバイト列に変換する。設定された追加ヘッダもここで付与する
+    */
+    pub fn to_bytes(&self, config: &Config, path: Option<&str>) -> Vec<u8> {
+        let mut bytes = self.head(config, path).into_bytes();
+        if !self.omit_body {
+            bytes.extend_from_slice(&self.body);
+        }
+        bytes
+    }
+
+    /**
+    * ステータスラインとヘッダ(末尾の空行を含む)。to_bytesで送信するものと同じ
+    */
+    pub(crate) fn head(&self, config: &Config, path: Option<&str>) -> String {
+        // 1xx(101や103)はHTTP/1.1で定義されているため、HTTP/1.1で応答する
+        let version = if (100..200).contains(&self.status_code) { "HTTP/1.1" } else { "HTTP/1.0" };
+        let mut header = format!("{} {} {}\r\n", version, self.status_code, self.reason);
+        for (name, value) in &self.headers {
+            // サーバのソフトウェアを明かさない設定では、CGIなどが付与したものも含めて取り除く
+            if !config.server_header && name.eq_ignore_ascii_case("Server") {
+                continue;
+            }
+            // ボディの長さはここで付与するので、フックやハンドラが付与した値で食い違わないように取り除く
+            if name.eq_ignore_ascii_case("Content-Length") {
+                continue;
+            }
+            header.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        // キープアライブ時にレスポンスの終わりがわかるように長さを付与する。
+        // ボディを持たないことが明らかな1xx、204、304には付与しない(RFC 9110 8.6)
+        if !(100..200).contains(&self.status_code) && !matches!(self.status_code, 204 | 304) && !self.stream {
+            header.push_str(&format!("Content-Length: {}\r\n", self.body_len.unwrap_or(self.body.len())));
+        }
+        // 混雑やメンテナンスを示すレスポンスには、Retry-Afterがなければ設定の値を付与する
+        if RETRY_AFTER_STATUS_CODES.contains(&self.status_code) && self.header("Retry-After").is_none() {
+            if let Some(retry_after) = &config.retry_after {
+                header.push_str(&format!("Retry-After: {}\r\n", retry_after));
+            }
+        }
+        for rule in &config.headers {
+            let matched = match (&rule.path_prefix, path) {
+                (None, _) => true,
+                (Some(prefix), Some(path)) => path.starts_with(prefix.as_str()),
+                (Some(_), None) => false,
+            };
+            if matched {
+                header.push_str(&format!("{}: {}\r\n", rule.name, rule.value));
+            }
+        }
+        header.push_str("\r\n");
+        header
+    }
+}
+
+/**
+* 配信するファイルの取得元
+*/
+#[derive(Clone)]
+pub enum DocumentRoot {
+    // 優先順に並べたディレクトリ
+    Directories(Vec<PathBuf>),
+    Archive(Rc<Archive>),
+    // 起動時に読み込んだファイル(preload)を先に探し、なければ元のドキュメントルートから読み込む
+    Preloaded(Rc<PreloadedFiles>, Box<DocumentRoot>),
+    // 元のドキュメントルートから配信したファイルのメタデータをキャッシュし、変更されていなければHEADに使う
+    MetadataCached(Rc<RefCell<MetadataCache>>, Box<DocumentRoot>),
+}
+
+/**
+* preloadで読み込んだファイルの内容と更新日時。ドキュメントルートからの相対パスをキーとする
+*/
+pub type PreloadedFiles = HashMap<String, (Vec<u8>, Option<SystemTime>)>;
+
+impl DocumentRoot {
+    /**
+    * ドキュメントルートが読み込み可能か。
+    * 権限の変更やボリュームのアンマウントで全てのディレクトリが読めなくなった場合にfalseを返す
+    */
+    pub fn is_available(&self) -> bool {
+        match self {
+            DocumentRoot::Directories(dirs) => dirs.iter().any(|dir| fs::read_dir(dir).is_ok()),
+            DocumentRoot::Archive(_) => true,
+            DocumentRoot::Preloaded(_, root) | DocumentRoot::MetadataCached(_, root) => root.is_available(),
+        }
+    }
+
+    /**
+    * filesを先に探し、なければこのドキュメントルートから読み込む
+    */
+    pub(crate) fn with_files(&self, files: PreloadedFiles) -> DocumentRoot {
+        DocumentRoot::Preloaded(Rc::new(files), Box::new(self.clone()))
+    }
+
+    /**
+    * preloadに指定したパスのファイルを読み込む。見つからない、または配信できないファイルがあればエラーとする
+    */
+    pub(crate) fn preload(&self, paths: &[String], config: &Config) -> Result<PreloadedFiles, Error> {
+        let mut files = HashMap::new();
+        for path in paths {
+            let relative = to_relative_path(path)
+                .filter(|relative| !relative.is_empty() && !relative.ends_with('/'))
+                .filter(|relative| !config.is_blocked_extension(relative))
+                .ok_or_else(|| Error::Config(format!("Invalid preload path: {:?}", path)))?;
+            // 起動時と設定の再読み込み時だけなので、一時的なエラーはこのスレッドで待って読み直す
+            let lookup = match self.read(&relative, config) {
+                Err(Error::TransientRead { relative, path, .. }) if config.read_retries > 0 => {
+                    let (buf, modified) = ReadJob::new(relative, path, config).run()?;
+                    Lookup::Found(buf, modified)
+                }
+                lookup => lookup?,
+            };
+            match lookup {
+                Lookup::Found(buf, modified) => {
+                    files.insert(relative, (buf, modified));
+                }
+                Lookup::NotFound | Lookup::Forbidden => {
+                    return Err(Error::Config(format!("Preload file is not servable: {}", path)))
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /**
+    * ドキュメントルートからの相対パスのファイルを読み込む。
+    * 複数のディレクトリがある場合は最初に見つかったファイルを返す
+    */
+    fn read(&self, relative: &str, config: &Config) -> Result<Lookup, Error> {
+        match self {
+            DocumentRoot::Directories(dirs) => {
+                for dir in dirs {
+                    let path = dir.join(relative);
+                    // ディレクトリは開けても読み込めないので存在しないものとして扱う
+                    if !path.is_file() {
+                        continue;
+                    }
+                    // 大文字小文字を区別しないファイルシステムでも、名前が完全に一致しなければ存在しないものとする
+                    if config.exact_case && !has_exact_case(dir, relative)? {
+                        continue;
+                    }
+                    if !is_inside_root(dir, relative, config.follow_symlinks)? {
+                        return Ok(Lookup::Forbidden);
+                    }
+                    match read_file(&path) {
+                        Ok((buf, modified)) => return Ok(Lookup::Found(buf, modified)),
+                        // 確認してから開くまでの間に消えた、または読めないファイルは次のディレクトリを探す
+                        Err(e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied) => {
+                            continue
+                        }
+                        // 待って読み直すかは呼び出し側が決める(handle_requestを参照)
+                        Err(e) if is_transient_io_error(&e) => {
+                            return Err(Error::TransientRead { relative: relative.to_string(), path, source: e })
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Ok(Lookup::NotFound)
+            }
+            DocumentRoot::Archive(archive) => match archive.read(relative)? {
+                Some(buf) => Ok(Lookup::Found(buf, None)),
+                None => Ok(Lookup::NotFound),
+            },
+            DocumentRoot::Preloaded(files, root) => match files.get(relative) {
+                Some((buf, modified)) => Ok(Lookup::Found(buf.clone(), *modified)),
+                None => root.read(relative, config),
+            },
+            DocumentRoot::MetadataCached(_, root) => root.read(relative, config),
+        }
+    }
+
+    /**
+    * readで読み込むディレクトリ内のファイルのパス。アーカイブやpreloadのファイルはNone
+    */
+    fn file_path(&self, relative: &str, config: &Config) -> Option<PathBuf> {
+        match self {
+            DocumentRoot::Directories(dirs) => dirs.iter().find_map(|dir| {
+                let path = dir.join(relative);
+                let exact = !config.exact_case || has_exact_case(dir, relative).unwrap_or(false);
+                (path.is_file() && exact).then_some(path)
+            }),
+            DocumentRoot::Archive(_) => None,
+            DocumentRoot::Preloaded(files, root) => {
+                if files.contains_key(relative) {
+                    return None;
+                }
+                root.file_path(relative, config)
+            }
+            DocumentRoot::MetadataCached(_, root) => root.file_path(relative, config),
+        }
+    }
+
+    /**
+    * keyでキャッシュしたレスポンスがあり、ファイルが変更されていなければボディを除いて返す
+    */
+    fn cached_head(&self, key: &str, relative: &str) -> Option<Response> {
+        match self {
+            DocumentRoot::MetadataCached(cache, _) => cache.borrow_mut().get(key),
+            DocumentRoot::Preloaded(files, root) if !files.contains_key(relative) => root.cached_head(key, relative),
+            _ => None,
+        }
+    }
+
+    /**
+    * relativeのファイルに返したレスポンスをkeyでキャッシュする。メタデータをキャッシュしないルートでは何もしない
+    */
+    fn cache_head(
+        &self,
+        key: String,
+        relative: &str,
+        len: usize,
+        modified: SystemTime,
+        response: &Response,
+        config: &Config,
+    ) {
+        match self {
+            DocumentRoot::MetadataCached(cache, root) => {
+                if let Some(path) = root.file_path(relative, config) {
+                    cache.borrow_mut().insert(key, path, len as u64, modified, response);
+                }
+            }
+            DocumentRoot::Preloaded(files, root) if !files.contains_key(relative) => {
+                root.cache_head(key, relative, len, modified, response, config)
+            }
+            _ => {}
+        }
+    }
+}
+
+/**
+* 一時的なエラーで読めなかったドキュメントルートのファイルを読み直す処理。
+* 待つ間も他の接続を処理できるように、サーバはCGIと同じく別スレッドで実行する
+*/
+pub(crate) struct ReadJob {
+    // ドキュメントルートからの相対パス。読み直した内容はこのパスのpreloadとして扱う
+    relative: String,
+    path: PathBuf,
+    retries: u32,
+    delay_ms: u64,
+}
+
+impl ReadJob {
+    fn new(relative: String, path: PathBuf, config: &Config) -> Self {
+        ReadJob { relative, path, retries: config.read_retries, delay_ms: config.read_retry_delay_ms }
+    }
+
+    pub(crate) fn relative(&self) -> &str {
+        &self.relative
+    }
+
+    /**
+    * ファイルの内容と更新日時を、read_retriesの回数までread_retry_delay_msから倍にしながら待って読み直す
+    */
+    pub(crate) fn run(&self) -> io::Result<(Vec<u8>, Option<SystemTime>)> {
+        retry_transient(&self.path, self.retries, self.delay_ms, || read_file(&self.path))
+    }
+}
+
+/**
+* readが一時的なエラーで失敗した場合に、retriesの回数まで待ちながら呼び直す
+*/
+fn retry_transient<T>(
+    path: &Path,
+    retries: u32,
+    delay_ms: u64,
+    mut read: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = Duration::from_millis(delay_ms);
+    let mut retried = 0;
+    loop {
+        match read() {
+            Err(e) if retried < retries && is_transient_io_error(&e) => {
+                warn!("Transient error reading {}: {}; retrying in {}ms", path.display(), e, delay.as_millis());
+                thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                retried += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<(Vec<u8>, Option<SystemTime>)> {
+    let file = File::open(path)?;
+    let modified = file.metadata()?.modified().ok();
+    let mut reader = BufReader::new(file);
+    let mut buf = Vec::new();
+    reader.read_to_end(&mut buf)?;
+    Ok((buf, modified))
+}
+
+/**
+* 開き直せば成功しうるエラーか。NFSのファイルハンドルの失効やタイムアウトなどで、NotFoundなどは含まない
+*/
+fn is_transient_io_error(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+        || matches!(e.raw_os_error(), Some(libc::ESTALE | libc::EIO | libc::ETIMEDOUT))
+}
+
+/**
+* ドキュメントルートからファイルを探した結果
+*/
+enum Lookup {
+    // ファイルの内容と更新日時(アーカイブ内のファイルはNone)
+    Found(Vec<u8>, Option<SystemTime>),
+    NotFound,
+    // シンボリックリンクの制限により配信できない
+    Forbidden,
+}
+
+/**
+* 相対パスの各要素が、ディレクトリの実際のエントリ名と大文字小文字まで一致するか
+*/
+fn has_exact_case(dir: &Path, relative: &str) -> io::Result<bool> {
+    let mut path = dir.to_path_buf();
+    for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+        let mut entries = fs::read_dir(&path)?;
+        if !entries.any(|entry| entry.is_ok_and(|entry| entry.file_name() == segment)) {
+            return Ok(false);
+        }
+        path.push(segment);
+    }
+    Ok(true)
+}
+
+/**
+* ドキュメントルート配下のファイルがシンボリックリンクを経由してルートの外に出ていないか。
+* follow_symlinksが無効の場合はルート配下のシンボリックリンクを経由すること自体を禁止する
+*/
+fn is_inside_root(dir: &Path, relative: &str, follow_symlinks: bool) -> io::Result<bool> {
+    if !follow_symlinks {
+        let mut path = dir.to_path_buf();
+        for segment in relative.split('/').filter(|segment| !segment.is_empty()) {
+            path.push(segment);
+            if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+                return Ok(false);
+            }
+        }
+    }
+    let root = fs::canonicalize(dir)?;
+    Ok(fs::canonicalize(dir.join(relative))?.starts_with(root))
+}
+
+/**
+* リクエストに対するレスポンスを作成する。CGIスクリプトとファイルの読み直しはこのスレッドで終了まで待って実行する
+*/
+pub fn make_response(
+    request: &Request,
+    root: &DocumentRoot,
+    config: &Config,
+) -> Result<Response, Error> {
+    let mut reread = PreloadedFiles::new();
+    loop {
+        let handled = if reread.is_empty() {
+            handle_request(request, root, config)?
+        } else {
+            handle_request(request, &root.with_files(reread.clone()), config)?
+        };
+        match handled {
+            Handled::Ready(response) => return Ok(response),
+            Handled::Cgi(job) => return job.run(&SystemClock),
+            Handled::Read(job) => {
+                let file = job.run()?;
+                reread.insert(job.relative, file);
+            }
+        }
+    }
+}
+
+/**
+* make_responseの処理結果。CGIスクリプトはすぐには実行せず、呼び出し側が実行するスレッドを選ぶ
+*/
+pub(crate) enum Handled {
+    Ready(Response),
+    Cgi(CgiJob),
+    // 一時的なエラーで読めなかったファイル。読み直した内容をwith_filesで加えたドキュメントルートで処理し直す
+    Read(ReadJob),
+}
+
+/**
+* make_responseと同じだが、CGIスクリプトは実行せずに準備したものを返す
+*/
+pub(crate) fn handle_request(
+    request: &Request,
+    root: &DocumentRoot,
+    config: &Config,
+) -> Result<Handled, Error> {
+    if let Some(response) = special_response(request, config)? {
+        return Ok(Handled::Ready(response));
+    }
+    let response = if config.concat_path.as_deref() == Some(request.path())
+        && matches!(request.method.as_str(), "GET" | "HEAD")
+    {
+        concat_files(request, root, config)
+    } else {
+        if let Some(cgi) = &config.cgi {
+            if let Some(script_path) = cgi_script_path(request.path(), &cgi.path_prefix) {
+                return prepare_cgi(request, cgi, script_path);
+            }
+        }
+        method_response(request, root, config)
+    };
+    match response {
+        Err(Error::TransientRead { relative, path, .. }) if config.read_retries > 0 => {
+            Ok(Handled::Read(ReadJob::new(relative, path, config)))
+        }
+        response => response.map(Handled::Ready),
+    }
+}
+
+/**
+* メソッドやパスに関わらず先に判定するレスポンス(417、アクセス制御の403、デバッグ用のエンドポイントなど)。
+* ファイルシステムを参照しないので、ドキュメントルートが読めない間も使える。該当しなければNone
+*/
+pub(crate) fn special_response(request: &Request, config: &Config) -> Result<Option<Response>, Error> {
+    let target = request.path();
+
+    // 100-continue以外の期待には応えられない(RFC 9110 10.1.1)
+    if request.header("Expect").is_some_and(|expect| !expect.trim().eq_ignore_ascii_case("100-continue")) {
+        return create_msg_from_code(417, None).map(Some);
+    }
+
+    if !config.is_access_allowed(target, request.remote_addr.map(|addr| addr.ip())) {
+        return create_msg_from_code(403, None).map(Some);
+    }
+
+    if config.debug_echo && target == DEBUG_ECHO_PATH {
+        return echo_request(request).map(Some);
+    }
+
+    if config.version_endpoint && target == VERSION_PATH {
+        return version_response().map(Some);
+    }
+
+    Ok(None)
+}
+
+/**
+* メソッドごとのレスポンス
+*/
+fn method_response(request: &Request, root: &DocumentRoot, config: &Config) -> Result<Response, Error> {
+    let target = request.path();
+    let response = if request.method == "OPTIONS" {
+        let mut response = create_msg_from_code(204, None)?;
+        response.add_header("Allow", &allowed_methods(config));
+        response
+    } else if request.method == "TRACE" {
+        if config.trace_echo {
+            trace_response(request)?
+        } else {
+            let mut response = create_msg_from_code(405, None)?;
+            response.add_header("Allow", &allowed_methods(config));
+            response
+        }
+    } else if request.method == "GET" || request.method == "HEAD" {
+        match &config.root_response {
+            Some(root_response) if target == "/" => {
+                create_content_response(200, &root_response.content_type, root_response.body.clone())?
+            }
+            _ => serve_file(request, root, config)?,
+        }
+    } else if let (Some(upload_root), "PUT") = (&config.upload_root, request.method.as_str()) {
+        put_file(request, upload_root, config)?
+    } else if let (Some(upload_root), "DELETE") = (&config.upload_root, request.method.as_str()) {
+        delete_file(request, upload_root, config)?
+    } else if let (Some(upload_root), "PATCH") = (&config.upload_root, request.method.as_str()) {
+        patch_file(request, upload_root, config)?
+    } else {
+        //サポートしていないHTTPメソッド
+        create_msg_from_code(501, None)?
+    };
+    Ok(response)
+
+}
+
+/**
+* 同じホストとパスのhttps://のURLに301でリダイレクトする。Hostがなければ400を返す
+*/
+pub(crate) fn https_redirect(request: &Request, https_port: Option<u16>) -> Result<Response, Error> {
+    let Some(host) = request.host() else {
+        return create_msg_from_code(400, None);
+    };
+    // Hostのポートは平文のポートなので取り除く。IPv6アドレスの":"は残す
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.contains(']') => name,
+        _ => host,
+    };
+    let authority = match https_port {
+        Some(port) if port != 443 => format!("{}:{}", host, port),
+        _ => host.to_string(),
+    };
+    let path_and_query = match request.query() {
+        Some(query) => format!("{}?{}", request.path(), query),
+        None => request.path().to_string(),
+    };
+    let mut response = create_msg_from_code(301, None)?;
+    response.add_header("Location", &format!("https://{}{}", authority, path_and_query));
+    Ok(response)
+}
+
+/**
+* 設定で有効になっているメソッドをAllowの形式で返す
+*/
+fn allowed_methods(config: &Config) -> String {
+    let mut methods = BASE_METHODS.to_vec();
+    if config.trace_echo {
+        methods.push("TRACE");
+    }
+    if config.upload_root.is_some() {
+        methods.extend(UPLOAD_ALLOWED_METHODS);
+    }
+    methods.join(", ")
+}
+
+/**
+* クエリのfilesに","区切りで指定したファイルを順に連結したレスポンス。
+* 各パスはserve_fileと同じく".."やドットファイル、blocked_extensionsを拒否する
+*/
+fn concat_files(request: &Request, root: &DocumentRoot, config: &Config) -> Result<Response, Error> {
+    let Some(files) = request.query_param("files") else {
+        return create_msg_from_code(400, None);
+    };
+    let paths: Vec<&str> = files.split(',').map(str::trim).filter(|path| !path.is_empty()).collect();
+    if paths.is_empty() || paths.len() > MAX_CONCAT_FILES {
+        return create_msg_from_code(400, None);
+    }
+    let mut body = Vec::new();
+    let mut content_type = None;
+    for path in paths {
+        let Some(relative) = to_relative_path(path) else {
+            return create_msg_from_code(403, None);
+        };
+        if relative.is_empty() || relative.ends_with('/') || config.is_blocked_extension(&relative) {
+            return create_msg_from_code(403, None);
+        }
+        let buf = match root.read(&relative, config)? {
+            Lookup::Found(buf, _) => buf,
+            Lookup::NotFound => return create_msg_from_code(404, None),
+            Lookup::Forbidden => return create_msg_from_code(403, None),
+        };
+        let file_type = content_type_for(&relative).unwrap_or("application/octet-stream");
+        // 種類の異なるファイルを連結した場合はapplication/octet-streamにする
+        content_type = match content_type {
+            Some(previous) if previous != file_type => Some("application/octet-stream"),
+            _ => Some(file_type),
+        };
+        body.extend_from_slice(&buf);
+    }
+    create_content_response(200, content_type.unwrap_or("application/octet-stream"), body)
+}
+
+/**
+* ドキュメントルートのファイルを返す
+*/
+fn serve_file(
+    request: &Request,
+    root: &DocumentRoot,
+    config: &Config,
+) -> Result<Response, Error> {
+    let target = request.path();
+    let Some(mut relative) = to_relative_path(target) else {
+        return create_msg_from_code(403, None);
+    };
+    if relative.is_empty() || relative.ends_with('/') {
+        relative.push_str(INDEX_FILE);
+    }
+    // ソースやバックアップの流出を防ぐため、ファイルの有無を調べる前に拒否する
+    if config.is_blocked_extension(&relative) {
+        return create_msg_from_code(403, None);
+    }
+    // 変更されていないファイルへのHEADには、キャッシュしたメタデータからファイルを開かずに応答する
+    let cache_key = metadata_cache_key(request, config);
+    if request.method == "HEAD" {
+        if let Some(response) = cache_key.as_deref().and_then(|key| root.cached_head(key, &relative)) {
+            return Ok(response);
+        }
+    }
+    // クエリパラメータで選ばれたファイルがあれば、元のファイルの代わりに返す
+    let mut variant = None;
+    for value in config.query_variants.iter().filter_map(|param| request.query_param(param)) {
+        let Some(variant_path) = query_variant_path(&relative, &value, config) else {
+            continue;
+        };
+        if let found @ Lookup::Found(..) = root.read(&variant_path, config)? {
+            relative = variant_path;
+            variant = Some(found);
+            break;
+        }
+    }
+    let lookup = match variant {
+        Some(found) => found,
+        None => root.read(&relative, config)?,
+    };
+    let (buf, modified) = match lookup {
+        Lookup::Found(buf, modified) => (buf, modified),
+        Lookup::NotFound if config.placeholder_page && target == "/" => {
+            return create_content_response(200, "text/html; charset=utf-8", PLACEHOLDER_PAGE);
+        }
+        Lookup::NotFound => return create_msg_from_code(404, None),
+        Lookup::Forbidden => return create_msg_from_code(403, None),
+    };
+    let file_len = buf.len();
+
+    // テンプレートの出力は変数の値によって変わるので、ファイルの更新日時から作るETagとLast-Modifiedは付けない
+    let mut etag = modified.and_then(|modified| file_etag(modified, buf.len(), config.etag));
+    let mut last_modified = modified.filter(|_| config.last_modified);
+    let mut response = match &config.templates {
+        Some(templates) if Path::new(&relative).extension() == Some(templates.extension.as_ref()) => {
+            etag = None;
+            last_modified = None;
+            let body = render_template(&buf, &templates.vars);
+            create_content_response(200, "text/html", body)?
+        }
+        _ => {
+            let content_type = match content_type_for(&relative) {
+                Some(content_type) => Some(content_type),
+                None if config.content_sniffing && Path::new(&relative).extension().is_none() => {
+                    Some(sniff_content_type(&buf))
+                }
+                None => None,
+            };
+            let charset = content_type
+                .filter(|content_type| config.charset_detection && content_type.starts_with("text/"))
+                .and_then(|content_type| detect_charset(&buf, content_type.starts_with("text/html")));
+            let mut response = create_msg_from_code(200, Some(buf))?;
+            match (content_type, charset) {
+                (Some(content_type), Some(charset)) => {
+                    let essence = content_type.split(';').next().unwrap_or(content_type);
+                    response.add_header("Content-Type", &format!("{}; charset={}", essence, charset));
+                }
+                (Some(content_type), None) => response.add_header("Content-Type", content_type),
+                (None, _) => {}
+            }
+            response
+        }
+    };
+    let download_requested = config.download_query
+        && request.query().is_some_and(|query| {
+            query.split('&').any(|param| param.split('=').next() == Some("download"))
+        });
+    let download_type = response.header("Content-Type").is_some_and(|t| config.is_download_type(t));
+    if download_requested || download_type || config.is_download_extension(&relative) {
+        response.add_header("Content-Disposition", &content_disposition(&relative));
+    }
+    if let Some(cache_control) = config.cache_control_for(target) {
+        response.add_header("Cache-Control", cache_control);
+    }
+    // Rangeは元のファイルのバイト位置を指すので、範囲を指定したリクエストには圧縮しない
+    let range_requested = config.byte_ranges && request.method == "GET" && request.header("Range").is_some();
+    let compressible = response.header("Content-Type").is_some_and(|t| config.is_compressible_type(t));
+    if config.compression && compressible && !range_requested && response.body.len() >= config.compression_min_size {
+        negotiate_encoding(request, &mut response)?;
+    }
+    if let Some(modified) = last_modified {
+        response.add_header("Last-Modified", &format_http_date(modified));
+    }
+    if let Some(mut etag) = etag {
+        // 圧縮したものは元のファイルとバイト列が異なるので別のETagにする
+        if response.header("Content-Encoding").is_some() {
+            etag.insert_str(etag.len() - 1, "-gzip");
+        }
+        response.add_header("ETag", &etag);
+        if request.header("If-None-Match").is_some_and(|value| etag_list_matches(value, &etag)) {
+            return not_modified(&response);
+        }
+    }
+    // If-None-Matchがある場合はIf-Modified-Sinceを無視する(RFC 9110 13.2.2)
+    let date_matches = last_modified.is_some_and(|modified| is_not_modified_since(request, modified));
+    if date_matches && request.header("If-None-Match").is_none() {
+        return not_modified(&response);
+    }
+    if config.byte_ranges && response.header("Content-Encoding").is_none() {
+        response.add_header("Accept-Ranges", "bytes");
+        if range_requested {
+            return partial_content(request, response);
+        }
+    }
+    if let (Some(key), Some(modified)) = (cache_key, modified) {
+        root.cache_head(key, &relative, file_len, modified, &response, config);
+    }
+    Ok(response)
+}
+
+/**
+* HEADに使うメタデータのキャッシュのキー。レスポンスのヘッダを左右するパス、クエリ、Accept-Encodingから作る。
+* 条件付きリクエストや範囲の指定、query_variantsのパラメータがあるリクエストはキャッシュしない(None)
+*/
+fn metadata_cache_key(request: &Request, config: &Config) -> Option<String> {
+    let conditional = ["If-None-Match", "If-Modified-Since", "Range", "If-Range"]
+        .iter()
+        .any(|name| request.header(name).is_some());
+    if conditional || config.query_variants.iter().any(|param| request.query_param(param).is_some()) {
+        return None;
+    }
+    Some(format!(
+        "{}?{}\n{}",
+        request.path(),
+        request.query().unwrap_or(""),
+        request.header("Accept-Encoding").unwrap_or("")
+    ))
+}
+
+/**
+* Rangeで指定した範囲の206のレスポンス。範囲が1つでない場合や形式が不正な場合、
+* If-Rangeが一致しない場合はRangeを無視して全体を返す。範囲がファイルの外であれば416を返す(RFC 9110 14.2)
+*/
+fn partial_content(request: &Request, mut response: Response) -> Result<Response, Error> {
+    if !if_range_matches(request, &response) {
+        return Ok(response);
+    }
+    let len = response.body.len();
+    let Some(range) = request.header("Range").and_then(|value| parse_byte_range(value, len)) else {
+        return Ok(response);
+    };
+    let Some((start, end)) = range else {
+        let mut response = create_msg_from_code(416, None)?;
+        response.add_header("Content-Range", &format!("bytes */{}", len));
+        return Ok(response);
+    };
+    response.status_code = 206;
+    response.reason = "Partial Content";
+    response.body = response.body[start..=end].to_vec();
+    response.add_header("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+    Ok(response)
+}
+
+/**
+* If-Rangeがない、またはその値(強いETagかHTTP-date)がレスポンスのETagかLast-Modifiedと一致するか。
+* 弱いETagは範囲の結合に使えないので一致しない(RFC 9110 13.1.5)
+*/
+fn if_range_matches(request: &Request, response: &Response) -> bool {
+    let Some(value) = request.header("If-Range").map(str::trim) else {
+        return true;
+    };
+    if value.starts_with('"') {
+        return response.header("ETag").is_some_and(|etag| etag == value);
+    }
+    match (parse_http_date(value), response.header("Last-Modified").and_then(parse_http_date)) {
+        (Some(date), Some(modified)) => date == modified,
+        _ => false,
+    }
+}
+
+/**
+* "bytes=開始-終了"、"bytes=開始-"、"bytes=-末尾のバイト数"の形式のRangeから、lenバイトのうち返す範囲(両端を含む)を求める。
+* 形式が不正な場合や複数の範囲の場合はNone、範囲がファイルの外の場合はSome(None)
+*/
+fn parse_byte_range(value: &str, len: usize) -> Option<Option<(usize, usize)>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (first, last) = spec.trim().split_once('-')?;
+    let parse = |number: &str| -> Option<usize> {
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        // 桁数が多すぎる値はファイルの長さを超えるものとして扱う
+        Some(number.parse().unwrap_or(usize::MAX))
+    };
+    let range = match (first.trim(), last.trim()) {
+        ("", suffix) => {
+            let suffix = parse(suffix)?;
+            (suffix > 0 && len > 0).then(|| (len - suffix.min(len), len - 1))
+        }
+        (first, "") => {
+            let start = parse(first)?;
+            (start < len).then(|| (start, len - 1))
+        }
+        (first, last) => {
+            let (start, end) = (parse(first)?, parse(last)?);
+            if start > end {
+                return None;
+            }
+            (start < len).then(|| (start, end.min(len - 1)))
+        }
+    };
+    Some(range)
+}
+
+/**
+* クエリパラメータの値で選ぶファイルのパス。拡張子の前に値を挿入する(docs/index.htmlとfrならdocs/index.fr.html)。
+* 値は英数字と"-"、"_"のみとし、"/"や".."で別のディレクトリを指せないようにする。
+* 挿入した値が拡張子になる場合(READMEとbakならREADME.bak)もあるので、blocked_extensionsに当たるパスはNoneを返す
+*/
+fn query_variant_path(relative: &str, value: &str, config: &Config) -> Option<String> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return None;
+    }
+    let (dir, name) = match relative.rsplit_once('/') {
+        Some((dir, name)) => (format!("{}/", dir), name),
+        None => (String::new(), relative),
+    };
+    let name = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => format!("{}.{}.{}", stem, value, extension),
+        _ => format!("{}.{}", name, value),
+    };
+    to_relative_path(&format!("{}{}", dir, name)).filter(|path| !config.is_blocked_extension(path))
+}
+
+/**
+* ファイルのサイズと更新日時から作るETag。etagの設定がoffの場合はNone
+*/
+fn file_etag(modified: SystemTime, len: usize, strength: EtagStrength) -> Option<String> {
+    let modified = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let tag = format!("\"{:x}-{:x}-{:x}\"", len, modified.as_secs(), modified.subsec_nanos());
+    match strength {
+        EtagStrength::Off => None,
+        EtagStrength::Weak => Some(format!("W/{}", tag)),
+        EtagStrength::Strong => Some(tag),
+    }
+}
+
+/**
+* If-None-Matchの値("*"またはETagのリスト)がETagに一致するか。弱い比較なのでW/の有無は区別しない(RFC 9110 13.1.2)
+*/
+fn etag_list_matches(value: &str, etag: &str) -> bool {
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    value.trim() == "*" || value.split(',').any(|candidate| opaque(candidate) == opaque(etag))
+}
+
+/**
+* GETとHEADのIf-Modified-Sinceの日時以降にファイルが更新されていないか。ヘッダがない、または日時が不正な場合はfalse
+*/
+fn is_not_modified_since(request: &Request, modified: SystemTime) -> bool {
+    if !matches!(request.method.as_str(), "GET" | "HEAD") {
+        return false;
+    }
+    let Some(since) = request.header("If-Modified-Since").and_then(parse_http_date) else {
+        return false;
+    };
+    // HTTP-dateは秒単位なので、更新日時の秒未満は切り捨てて比べる
+    let modified = modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let since = since.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    modified <= since
+}
+
+/**
+* 304のレスポンス。キャッシュの更新に使うヘッダは200の場合と同じものを付与する(RFC 9110 15.4.5)
+*/
+fn not_modified(response: &Response) -> Result<Response, Error> {
+    let mut not_modified = create_msg_from_code(304, None)?;
+    for name in ["ETag", "Last-Modified", "Cache-Control", "Vary", "Content-Location"] {
+        if let Some(value) = response.header(name) {
+            not_modified.add_header(name, value);
+        }
+    }
+    Ok(not_modified)
+}
+
+/**
+* ダウンロードさせるContent-Disposition。ファイル名はパスの最後の要素から作り、
+* ヘッダインジェクションを防ぐためquoted-stringで使えない文字は"_"に置き換える
+*/
+fn content_disposition(path: &str) -> String {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let filename: String = name
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+        .collect();
+    format!("attachment; filename=\"{}\"", filename)
+}
+
+/**
+* Accept-Encodingの値からgzipで圧縮するかを決める(RFC 9110 12.5.3)。
+* gzipの品質値は"gzip"、なければ"*"の値とし、0の場合や明示したidentityの品質値の方が高い場合は圧縮しない。
+* identityを明示していなければ、gzipを受け付ける限りgzipを優先する
+*/
+fn prefers_gzip(accept_encoding: &str) -> bool {
+    let mut gzip = None;
+    let mut wildcard = None;
+    let mut identity = None;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        // 品質値が不正な項目は無視する
+        let Some(quality) = parse_quality(params) else {
+            continue;
+        };
+        if coding.eq_ignore_ascii_case("gzip") || coding.eq_ignore_ascii_case("x-gzip") {
+            gzip = Some(quality);
+        } else if coding == "*" {
+            wildcard = Some(quality);
+        } else if coding.eq_ignore_ascii_case("identity") {
+            identity = Some(quality);
+        }
+    }
+    let gzip = gzip.or(wildcard).unwrap_or(0.0);
+    gzip > 0.0 && identity.is_none_or(|identity| gzip >= identity)
+}
+
+/**
+* ";q=0.5"のようなパラメータから品質値を取り出す。qがなければ1とし、0から1の範囲外であればNoneを返す
+*/
+fn parse_quality<'a>(params: impl Iterator<Item = &'a str>) -> Option<f32> {
+    let mut quality = 1.0;
+    for param in params {
+        if let Some((name, value)) = param.split_once('=') {
+            if name.trim().eq_ignore_ascii_case("q") {
+                quality = value.trim().parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))?;
+            }
+        }
+    }
+    Some(quality)
+}
+
+/**
+* Accept-Encodingでgzipが選ばれればボディを圧縮する。
+* 圧縮の有無はAccept-Encodingによって変わるため、どちらの場合もVaryを付与する
+*/
+fn negotiate_encoding(request: &Request, response: &mut Response) -> Result<(), Error> {
+    response.add_vary("Accept-Encoding");
+    if request.header("Accept-Encoding").is_some_and(prefers_gzip) {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&response.body)?;
+        let compressed = encoder.finish()?;
+        // 圧縮しても小さくならない場合は展開の手間を省くため元のまま返す
+        if compressed.len() * 100 > response.body.len() * MAX_COMPRESSED_RATIO {
+            return Ok(());
+        }
+        response.body = compressed;
+        response.add_header("Content-Encoding", "gzip");
+    }
+    Ok(())
+}
+
+/**
+* サーバが解釈したリクエストの内容をテキストで返す
+*/
+fn echo_request(request: &Request) -> Result<Response, Error> {
+    let mut body = format!(
+        "method: {}\ntarget: {}\nversion: HTTP/1.{}\n\n",
+        request.method, request.target, request.version
+    );
+    for (name, value) in &request.headers {
+        body.push_str(&format!("{}: {}\n", name, value));
+    }
+    create_content_response(200, "text/plain; charset=utf-8", body)
+}
+
+/**
+* クレートのバージョン、gitのコミット、ビルド日時をJSONで返す。値はビルド時に埋め込まれている
+*/
+fn version_response() -> Result<Response, Error> {
+    let body = format!(
+        "{{\"version\":\"{}\",\"commit\":\"{}\",\"build_timestamp\":\"{}\"}}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("BUILD_GIT_COMMIT"),
+        env!("BUILD_TIMESTAMP")
+    );
+    create_content_response(200, "application/json", body)
+}
+
+/**
+* 受け取ったリクエストをmessage/httpとして返す
+*/
+fn trace_response(request: &Request) -> Result<Response, Error> {
+    let mut body = format!(
+        "{} {} HTTP/1.{}\r\n",
+        request.method, request.target, request.version
+    );
+    for (name, value) in &request.headers {
+        body.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    body.push_str("\r\n");
+    create_content_response(200, "message/http", body)
+}
+
+/**
+* {{変数名}}を置換する。ループや条件分岐はサポートしない。
+* 未定義の変数はそのまま残す
+*/
+pub fn render_template(template: &[u8], vars: &HashMap<String, String>) -> Vec<u8> {
+    let mut output = Vec::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.windows(2).position(|w| w == b"{{") {
+        let Some(len) = rest[start + 2..].windows(2).position(|w| w == b"}}") else {
+            break;
+        };
+        let end = start + 2 + len;
+        output.extend_from_slice(&rest[..start]);
+        let value = std::str::from_utf8(&rest[start + 2..end])
+            .ok()
+            .and_then(|name| vars.get(name.trim()));
+        match value {
+            Some(value) => output.extend_from_slice(value.as_bytes()),
+            None => output.extend_from_slice(&rest[start..end + 2]),
+        }
+        rest = &rest[end + 2..];
+    }
+    output.extend_from_slice(rest);
+    output
+}
+
+pub fn create_msg_from_code(
+    status_code: u16,
+    msg: Option<Vec<u8>>
+) -> Result<Response, Error> {
+    let reason = match status_code {
+        101 => "Switching Protocols",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        421 => "Misdirected Request",
+        422 => "Unprocessable Content",
+        426 => "Upgrade Required",
+        428 => "Precondition Required",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        451 => "Unavailable For Legal Reasons",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => return Err(Error::Protocol(format!("Undefined status code {}", status_code)))
+    };
+    let mut response = Response::new(status_code, reason);
+    if let Some(msg) = msg {
+        response.body = msg;
+    }
+    Ok(response)
+}
+
+/**
+* メモリ上で作成したボディとContent-Typeのレスポンス。ルートの処理でJSONやステータスページを返す場合に使う。
+* Content-Lengthと既定のヘッダは送信時にファイルと同様に付与する
+*/
+pub fn create_content_response(
+    status_code: u16,
+    content_type: &str,
+    body: impl Into<Vec<u8>>,
+) -> Result<Response, Error> {
+    let mut response = create_msg_from_code(status_code, Some(body.into()))?;
+    response.add_header("Content-Type", content_type);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HeaderRule, RetryAfter};
+
+    #[test]
+    fn retry_after_status_codes_have_reason_phrases() {
+        for status in RETRY_AFTER_STATUS_CODES {
+            assert!(create_msg_from_code(status, None).is_ok(), "{}", status);
+        }
+        assert_eq!(create_msg_from_code(429, None).unwrap().reason, "Too Many Requests");
+        assert_eq!(create_msg_from_code(401, None).unwrap().reason, "Unauthorized");
+        assert_eq!(create_msg_from_code(409, None).unwrap().reason, "Conflict");
+        assert_eq!(create_msg_from_code(422, None).unwrap().reason, "Unprocessable Content");
+        assert!(create_msg_from_code(299, None).is_err());
+    }
+
+    #[test]
+    fn retry_after_is_added_only_to_429_and_503() {
+        let config = Config { retry_after: Some(RetryAfter::Seconds(120)), ..Config::default() };
+        for (status, expected) in [(429, true), (503, true), (500, false), (200, false)] {
+            let head = create_msg_from_code(status, None).unwrap().head(&config, None);
+            assert_eq!(head.contains("Retry-After: 120\r\n"), expected, "{}", head);
+        }
+    }
+
+    #[test]
+    fn retry_after_set_by_the_handler_is_kept() {
+        let config = Config { retry_after: Some(RetryAfter::Seconds(120)), ..Config::default() };
+        let mut response = create_msg_from_code(503, None).unwrap();
+        response.add_header("Retry-After", "5");
+        let head = response.head(&config, None);
+        assert!(head.contains("Retry-After: 5\r\n"), "{}", head);
+        assert!(!head.contains("Retry-After: 120"), "{}", head);
+    }
+
+    fn header_rule(name: &str, value: &str, path_prefix: Option<&str>) -> HeaderRule {
+        HeaderRule { name: name.to_string(), value: value.to_string(), path_prefix: path_prefix.map(str::to_string) }
+    }
+
+    #[test]
+    fn configured_headers_are_added_by_path() {
+        let config = Config {
+            headers: vec![
+                header_rule("X-Content-Type-Options", "nosniff", None),
+                header_rule("X-Frame-Options", "DENY", Some("/admin")),
+            ],
+            ..Config::default()
+        };
+        let response = create_msg_from_code(200, None).unwrap();
+        let head = response.head(&config, Some("/admin/users"));
+        assert!(head.contains("X-Content-Type-Options: nosniff\r\n"), "{}", head);
+        assert!(head.contains("X-Frame-Options: DENY\r\n"), "{}", head);
+        let head = response.head(&config, Some("/index.html"));
+        assert!(head.contains("X-Content-Type-Options: nosniff\r\n"), "{}", head);
+        assert!(!head.contains("X-Frame-Options"), "{}", head);
+        // パスのないレスポンス(不正なリクエストへの400など)にはpath_prefixの設定を付けない
+        let head = response.head(&config, None);
+        assert!(!head.contains("X-Frame-Options"), "{}", head);
+    }
+
+    #[test]
+    fn invalid_header_rules_are_rejected() {
+        for rule in [header_rule("X Bad", "1", None), header_rule("X-Bad", "a\r\nInjected: 1", None)] {
+            assert!(Config { headers: vec![rule], ..Config::default() }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn template_variables_are_substituted() {
+        let vars = HashMap::from([("name".to_string(), "world".to_string()), ("n".to_string(), "1".to_string())]);
+        assert_eq!(render_template(b"Hello, {{name}}! {{ n }}", &vars), b"Hello, world! 1");
+        // 未定義の変数と閉じていない括弧はそのまま残す
+        assert_eq!(render_template(b"{{missing}} {{name", &vars), b"{{missing}} {{name");
+    }
+
+    #[test]
+    fn vary_lists_each_request_header_once() {
+        let mut response = create_msg_from_code(200, None).unwrap();
+        response.add_vary("Accept-Encoding");
+        response.add_vary("Origin");
+        response.add_vary("accept-encoding");
+        assert_eq!(response.header("Vary"), Some("Accept-Encoding, Origin"));
+    }
+
+    #[test]
+    fn https_redirect_drops_the_plaintext_port() {
+        let location = |host: &str, https_port: Option<u16>| {
+            let raw = format!("GET /p?q=1 HTTP/1.1\r\nHost: {}\r\n\r\n", host);
+            let (request, _) = crate::request::parse_request(raw.as_bytes()).unwrap().unwrap();
+            let response = https_redirect(&request, https_port).unwrap();
+            response.header("Location").unwrap().to_string()
+        };
+        assert_eq!(location("example.com:8080", None), "https://example.com/p?q=1");
+        assert_eq!(location("example.com", Some(443)), "https://example.com/p?q=1");
+        assert_eq!(location("[::1]:8080", Some(8443)), "https://[::1]:8443/p?q=1");
+        assert_eq!(location("[::1]", None), "https://[::1]/p?q=1");
+    }
+
+    #[test]
+    fn content_disposition_filename_cannot_break_the_header() {
+        assert_eq!(content_disposition("files/report 2026.pdf"), "attachment; filename=\"report 2026.pdf\"");
+        assert_eq!(content_disposition("a\"b\\c.txt"), "attachment; filename=\"a_b_c.txt\"");
+        assert_eq!(content_disposition("x\r\nSet-Cookie: a.txt"), "attachment; filename=\"x__Set-Cookie: a.txt\"");
+        assert_eq!(content_disposition("日本語.txt"), "attachment; filename=\"___.txt\"");
+    }
+
+    #[test]
+    fn gzip_negotiation_honors_quality_values() {
+        for (accept_encoding, expected) in [
+            ("gzip", true),
+            ("GZIP, deflate", true),
+            ("x-gzip", true),
+            ("gzip;q=0", false),
+            ("gzip;q=0.0, identity", false),
+            ("*", true),
+            ("*;q=0", false),
+            ("*, gzip;q=0", false),
+            ("identity", false),
+            ("identity;q=1, gzip;q=0.5", false),
+            ("identity;q=0.5, gzip", true),
+            ("br, deflate", false),
+            ("gzip;q=2, identity", false),
+            ("", false),
+        ] {
+            assert_eq!(prefers_gzip(accept_encoding), expected, "{:?}", accept_encoding);
+        }
+    }
+
+    #[test]
+    fn server_header_can_be_omitted() {
+        let mut response = create_msg_from_code(404, None).unwrap();
+        // CGIなどが付与したServerヘッダも取り除く
+        response.add_header("server", "cgi");
+        let head = response.head(&Config::default(), None);
+        assert!(head.contains("Server: mio webserver\r\n"), "{}", head);
+
+        let config = Config { server_header: false, ..Config::default() };
+        let head = response.head(&config, None);
+        assert!(!head.to_ascii_lowercase().contains("server:"), "{}", head);
+        assert!(head.starts_with("HTTP/1.0 404 Not Found\r\n"), "{}", head);
+        assert!(head.ends_with("\r\n\r\n") && !head.ends_with("\r\n\r\n\r\n"), "{:?}", head);
+    }
+
+    #[test]
+    fn exact_case_compares_every_path_segment() {
+        let dir = std::env::temp_dir().join(format!("web-server-exact-case-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("Docs")).unwrap();
+        fs::write(dir.join("Docs/Readme.txt"), "a").unwrap();
+        assert!(has_exact_case(&dir, "/Docs/Readme.txt").unwrap());
+        assert!(!has_exact_case(&dir, "/docs/Readme.txt").unwrap());
+        assert!(!has_exact_case(&dir, "/Docs/README.txt").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn byte_ranges_are_clamped_to_the_length() {
+        assert_eq!(parse_byte_range("bytes=0-", 10), Some(Some((0, 9))));
+        assert_eq!(parse_byte_range(" bytes= -4 ", 10), Some(Some((6, 9))));
+        assert_eq!(parse_byte_range("bytes=3-99999999999999999999999", 10), Some(Some((3, 9))));
+        assert_eq!(parse_byte_range("bytes=99999999999999999999999-", 10), Some(None));
+        // 空のファイルはどの範囲も満たせない
+        assert_eq!(parse_byte_range("bytes=0-", 0), Some(None));
+        assert_eq!(parse_byte_range("bytes=-1", 0), Some(None));
+        for value in ["bytes=1", "bytes=-", "bytes=+1-2", "bytes=0-1,2-3"] {
+            assert_eq!(parse_byte_range(value, 10), None, "{}", value);
+        }
+    }
+
+    #[test]
+    fn transient_read_errors_are_retried_up_to_the_limit() {
+        let path = Path::new("/mnt/nfs/index.html");
+        // 最初のfailures回はNFSのファイルハンドルの失効で失敗する読み込み
+        let flaky = |failures: u32, calls: &mut u32| -> io::Result<&'static str> {
+            *calls += 1;
+            if *calls <= failures {
+                Err(io::Error::from_raw_os_error(libc::ESTALE))
+            } else {
+                Ok("content")
+            }
+        };
+        let (retries, delay_ms) = (2, 1);
+        let mut calls = 0;
+        assert_eq!(retry_transient(path, retries, delay_ms, || flaky(2, &mut calls)).unwrap(), "content");
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let e = retry_transient(path, retries, delay_ms, || flaky(3, &mut calls)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ESTALE));
+        assert_eq!(calls, 3);
+
+        // 既定では再試行しない
+        let mut calls = 0;
+        assert!(retry_transient(path, 0, 10, || flaky(1, &mut calls)).is_err());
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn missing_files_are_not_retried() {
+        let mut calls = 0;
+        let result = retry_transient(Path::new("/missing"), 5, 1, || -> io::Result<()> {
+            calls += 1;
+            Err(io::ErrorKind::NotFound.into())
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(calls, 1);
+        assert!(!is_transient_io_error(&io::Error::from_raw_os_error(libc::EACCES)));
+        assert!(is_transient_io_error(&io::Error::from_raw_os_error(libc::EIO)));
+    }
+}
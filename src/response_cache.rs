@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::response::Response;
+
+/**
+* キャッシュしたレスポンス
+*/
+struct CachedResponse {
+    response: Response,
+    // キャッシュした時刻。返す時にAgeを計算する
+    stored_at: Instant,
+    expires_at: Instant,
+    // 最後に使った順番。上限を超えた場合は最も小さいものから捨てる
+    last_used: u64,
+}
+
+/**
+* add_cached_routeで登録したルートのレスポンスのキャッシュ。メソッドとリクエストターゲットをキーとし、
+* 最大capacity件を最後に使った順(LRU)で残す
+*/
+pub(crate) struct ResponseCache {
+    entries: HashMap<String, CachedResponse>,
+    capacity: usize,
+    // 使うたびに増やすカウンタ
+    clock: u64,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ResponseCache { entries: HashMap::new(), capacity, clock: 0 }
+    }
+
+    /**
+    * 上限を変更する。減らした場合は超えた分を捨てる
+    */
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > self.capacity {
+            self.evict_least_recently_used();
+        }
+    }
+
+    /**
+    * 期限内のレスポンスがあれば、キャッシュしてからの秒数をAgeヘッダに付けて返す。期限切れのものは捨てる
+    */
+    pub(crate) fn get(&mut self, key: &str, now: Instant) -> Option<Response> {
+        let expired = self.entries.get(key)?.expires_at <= now;
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        self.clock += 1;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = self.clock;
+        let mut response = entry.response.clone();
+        let age = now.saturating_duration_since(entry.stored_at).as_secs();
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Age"));
+        response.add_header("Age", &age.to_string());
+        Some(response)
+    }
+
+    /**
+    * 2xxのレスポンスをttlの間キャッシュする。Cache-Control: no-storeを付けたレスポンスはキャッシュしない
+    */
+    pub(crate) fn insert(&mut self, key: String, response: &Response, ttl: Duration, now: Instant) {
+        if self.capacity == 0 || !(200..300).contains(&response.status_code) || is_no_store(response) {
+            return;
+        }
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict_least_recently_used();
+        }
+        self.clock += 1;
+        let entry =
+            CachedResponse { response: response.clone(), stored_at: now, expires_at: now + ttl, last_used: self.clock };
+        self.entries.insert(key, entry);
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| key.clone());
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+/**
+* Cache-Controlにno-storeが含まれるか
+*/
+fn is_no_store(response: &Response) -> bool {
+    response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Cache-Control"))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-store"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::create_msg_from_code;
+
+    fn ok() -> Response {
+        create_msg_from_code(200, Some(b"cached".to_vec())).unwrap()
+    }
+
+    #[test]
+    fn hit_within_ttl_carries_age() {
+        let mut cache = ResponseCache::new(4);
+        let now = Instant::now();
+        cache.insert("GET /".to_string(), &ok(), Duration::from_secs(30), now);
+        let response = cache.get("GET /", now + Duration::from_secs(12)).unwrap();
+        assert_eq!(response.body, b"cached");
+        assert_eq!(response.header("Age"), Some("12"));
+        assert!(cache.get("GET /", now + Duration::from_secs(30)).is_none());
+    }
+
+    #[test]
+    fn only_storable_responses_are_cached() {
+        let mut cache = ResponseCache::new(4);
+        let now = Instant::now();
+        cache.insert("GET /missing".to_string(), &create_msg_from_code(404, None).unwrap(), Duration::from_secs(30), now);
+        let mut no_store = ok();
+        no_store.add_header("Cache-Control", "private, no-store");
+        cache.insert("GET /private".to_string(), &no_store, Duration::from_secs(30), now);
+        assert!(cache.get("GET /missing", now).is_none());
+        assert!(cache.get("GET /private", now).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let mut cache = ResponseCache::new(2);
+        let now = Instant::now();
+        let ttl = Duration::from_secs(30);
+        cache.insert("a".to_string(), &ok(), ttl, now);
+        cache.insert("b".to_string(), &ok(), ttl, now);
+        cache.get("a", now);
+        cache.insert("c".to_string(), &ok(), ttl, now);
+        assert!(cache.get("a", now).is_some());
+        assert!(cache.get("b", now).is_none());
+        assert!(cache.get("c", now).is_some());
+    }
+}
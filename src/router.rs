@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use crate::config::is_valid_header_name;
+use crate::error::{Error, Result};
+use crate::request::Request;
+use crate::response::Response;
+
+/**
+* ルートに登録する処理。パスパラメータ(":id"の部分に一致したセグメント)を名前から引ける
+*/
+pub type RouteHandler = Box<dyn Fn(&Request, &HashMap<String, String>) -> Result<Response, Error>>;
+
+/**
+* ボディを受信した順に少しずつ受け取る処理。ボディ全体をメモリに溜めずに扱える
+*/
+pub trait BodyHandler {
+    /**
+    * 受信したボディの一部。イベントループで受信するたびに呼ばれる
+    */
+    fn write(&mut self, chunk: &[u8]) -> Result<(), Error>;
+
+    /**
+    * ボディを全て受け取った後にレスポンスを作成する。requestのbodyは空
+    */
+    fn finish(self: Box<Self>, request: &Request) -> Result<Response, Error>;
+}
+
+/**
+* ボディをストリーミングで受け取るルートに登録する処理。ヘッダが揃った時点で呼ばれ、ボディを渡す先を返す
+*/
+pub type StreamingRouteHandler =
+    Box<dyn Fn(&Request, &HashMap<String, String>) -> Result<Box<dyn BodyHandler>, Error>>;
+
+/**
+* パターンのセグメント
+*/
+enum Segment {
+    // そのまま一致する必要がある
+    Literal(String),
+    // 任意の空でないセグメントに一致し、名前をつけて取り出す
+    Param(String),
+}
+
+struct Route<H> {
+    method: String,
+    segments: Vec<Segment>,
+    handler: H,
+}
+
+/**
+* メソッドと"/users/:id"の形式のパターンでリクエストを振り分ける。
+* 同じパスに一致する場合は、先頭から見て最初に異なるセグメントが固定のものを優先する
+*/
+#[derive(Default)]
+pub(crate) struct Router {
+    routes: Vec<Route<RouteHandler>>,
+    streaming_routes: Vec<Route<StreamingRouteHandler>>,
+}
+
+impl Router {
+    pub(crate) fn add(&mut self, method: &str, pattern: &str, handler: RouteHandler) {
+        add_route(&mut self.routes, method, pattern, handler);
+    }
+
+    pub(crate) fn add_streaming(&mut self, method: &str, pattern: &str, handler: StreamingRouteHandler) {
+        add_route(&mut self.streaming_routes, method, pattern, handler);
+    }
+
+    pub(crate) fn has_streaming_routes(&self) -> bool {
+        !self.streaming_routes.is_empty()
+    }
+
+    /**
+    * リクエストのパスに一致するルートのメソッド。head_as_getの場合、GETのルートがあればHEADも含める
+    */
+    pub(crate) fn methods_for(&self, request: &Request, head_as_get: bool) -> Vec<&str> {
+        let path: Vec<&str> = split_path(request.path()).collect();
+        let routes = self.routes.iter().map(|route| (&route.method, &route.segments));
+        let streaming_routes = self.streaming_routes.iter().map(|route| (&route.method, &route.segments));
+        let mut methods: Vec<&str> = routes
+            .chain(streaming_routes)
+            .filter(|(_, segments)| match_segments(segments, &path).is_some())
+            .map(|(method, _)| method.as_str())
+            .collect();
+        if head_as_get && methods.contains(&"GET") && !methods.contains(&"HEAD") {
+            methods.push("HEAD");
+        }
+        methods
+    }
+
+    /**
+    * 一致するルートがあれば、その処理で作成したレスポンスを返す。
+    * head_as_getの場合、HEADのルートがないHEADのリクエストはGETのルートで処理する(ボディは送信時に省く)
+    */
+    pub(crate) fn dispatch(&self, request: &Request, head_as_get: bool) -> Option<Result<Response, Error>> {
+        find_route(&self.routes, request, &request.method)
+            .or_else(|| {
+                (head_as_get && request.method == "HEAD").then(|| find_route(&self.routes, request, "GET")).flatten()
+            })
+            .map(|(route, params)| (route.handler)(request, &params))
+    }
+
+    /**
+    * ボディをストリーミングで受け取るルートに一致すれば、ボディを渡す先を返す。requestはヘッダまでのリクエスト
+    */
+    pub(crate) fn open_stream(&self, request: &Request) -> Option<Result<Box<dyn BodyHandler>, Error>> {
+        find_route(&self.streaming_routes, request, &request.method).map(|(route, params)| (route.handler)(request, &params))
+    }
+}
+
+/**
+* ボディの受信中のストリーミングのリクエスト
+*/
+pub(crate) struct BodyStream {
+    request: Request,
+    // まだ受信していないボディのバイト数
+    remaining: usize,
+    // 書き込みに失敗した場合はエラーを保持し、残りのボディは読み捨てる
+    handler: Result<Box<dyn BodyHandler>, Error>,
+}
+
+impl BodyStream {
+    pub(crate) fn new(request: Request, content_length: usize, handler: Result<Box<dyn BodyHandler>, Error>) -> Self {
+        BodyStream { request, remaining: content_length, handler }
+    }
+
+    /**
+    * バッファの先頭からボディの続きを渡し、消費したバイト数を返す
+    */
+    pub(crate) fn feed(&mut self, buffer: &[u8]) -> usize {
+        let len = self.remaining.min(buffer.len());
+        self.remaining -= len;
+        if let Ok(handler) = &mut self.handler {
+            // 処理中のパニックでサーバが止まらないようにエラーとして扱う
+            let result = panic::catch_unwind(AssertUnwindSafe(|| handler.write(&buffer[..len])))
+                .unwrap_or_else(|_| Err(Error::Internal("Panic while streaming the request body".to_string())));
+            if let Err(e) = result {
+                self.handler = Err(e);
+            }
+        }
+        len
+    }
+
+    pub(crate) fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub(crate) fn into_parts(self) -> (Request, Result<Box<dyn BodyHandler>, Error>) {
+        (self.request, self.handler)
+    }
+}
+
+/**
+* ルートを追加し、優先度の高い順に並べ直す。
+* methodはPROPFINDなどの拡張メソッドも含め、RFC 9110のtokenでなければパニックする(登録しても一致するリクエストが届かないため)
+*/
+fn add_route<H>(routes: &mut Vec<Route<H>>, method: &str, pattern: &str, handler: H) {
+    assert!(is_valid_header_name(method), "invalid method token for route {}: {:?}", pattern, method);
+    let segments = split_path(pattern)
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(name) => Segment::Param(name.to_string()),
+            None => Segment::Literal(segment.to_string()),
+        })
+        .collect();
+    routes.push(Route { method: method.to_string(), segments, handler });
+    // 安定ソートなので、優先度が同じルートは登録順のまま
+    routes.sort_by_cached_key(|route| {
+        route.segments.iter().map(|segment| matches!(segment, Segment::Param(_))).collect::<Vec<_>>()
+    });
+}
+
+/**
+* リクエストのパスとmethodに一致する最初のルートとパスパラメータ
+*/
+fn find_route<'a, H>(
+    routes: &'a [Route<H>],
+    request: &Request,
+    method: &str,
+) -> Option<(&'a Route<H>, HashMap<String, String>)> {
+    let path: Vec<&str> = split_path(request.path()).collect();
+    routes
+        .iter()
+        .filter(|route| route.method == method)
+        .find_map(|route| Some((route, match_segments(&route.segments, &path)?)))
+}
+
+/**
+* パスを"/"で区切る。末尾のスラッシュは無視する
+*/
+fn split_path(path: &str) -> impl Iterator<Item = &str> {
+    path.trim_matches('/').split('/').filter(|segment| !segment.is_empty())
+}
+
+/**
+* パスがパターンに一致すればパスパラメータを返す
+*/
+fn match_segments(segments: &[Segment], path: &[&str]) -> Option<HashMap<String, String>> {
+    if segments.len() != path.len() {
+        return None;
+    }
+    let mut params = HashMap::new();
+    for (segment, value) in segments.iter().zip(path) {
+        match segment {
+            Segment::Literal(literal) if literal == value => {}
+            Segment::Literal(_) => return None,
+            Segment::Param(name) => {
+                params.insert(name.clone(), value.to_string());
+            }
+        }
+    }
+    Some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segments(pattern: &str) -> Vec<Segment> {
+        let mut routes = Vec::new();
+        add_route(&mut routes, "GET", pattern, ());
+        routes.pop().unwrap().segments
+    }
+
+    #[test]
+    fn params_are_captured_by_name() {
+        let path: Vec<&str> = split_path("/users/42/posts/7/").collect();
+        let params = match_segments(&segments("/users/:id/posts/:post"), &path).unwrap();
+        assert_eq!(params.get("id").map(String::as_str), Some("42"));
+        assert_eq!(params.get("post").map(String::as_str), Some("7"));
+        assert!(match_segments(&segments("/users/:id"), &path).is_none());
+        assert!(match_segments(&segments("/users/:id/comments/:post"), &path).is_none());
+    }
+
+    #[test]
+    fn literal_segments_sort_before_params() {
+        let mut routes = Vec::new();
+        for (pattern, id) in [("/users/:id", 1), ("/:any/me", 2), ("/users/me", 3)] {
+            add_route(&mut routes, "GET", pattern, id);
+        }
+        let order: Vec<i32> = routes.iter().map(|route| route.handler).collect();
+        assert_eq!(order, [3, 1, 2]);
+    }
+}
@@ -0,0 +1,2326 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::env;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{self, PathBuf};
+use std::process;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::{debug, error, info, warn};
+use mio::{Events, Token, Poll, Interest, Waker};
+use mio::event::Event;
+use rustls::{ServerConfig, ServerConnection};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook_mio::v0_8::Signals;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use crate::access_log::{open_access_log, AccessLogSink, RequestLog};
+use crate::archive::Archive;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::connection::{Connection, ConnectionState, Timeouts};
+use crate::error::{Error, Result};
+use crate::mime::content_type_for;
+use crate::rate_limit::TokenBucket;
+use crate::metadata_cache::MetadataCache;
+use crate::response_cache::ResponseCache;
+use crate::stats::RequestStats;
+use crate::request::{
+    match_health_check, parse_head_unbounded, parse_request, parse_request_head, ParseError, Request, Section,
+};
+use crate::response::{create_content_response, create_msg_from_code, handle_request, https_redirect, special_response, DocumentRoot, Handled, PreloadedFiles, Response};
+use crate::router::{BodyHandler, BodyStream, Router};
+use crate::upload::UPLOAD_METHODS;
+use crate::sse::{event_stream_response, format_event, EventSender};
+use crate::tls::{alpn_protocol, flush_tls, load_server_config};
+use crate::websocket::{
+    encode_frame, handshake_response, is_upgrade_request, parse_frame, OPCODE_BINARY, OPCODE_CLOSE,
+    OPCODE_PING, OPCODE_PONG, OPCODE_TEXT,
+};
+
+// シグナル受信用のトークン。接続IDと衝突しないように最大値を使う
+const SIGNAL: Token = Token(usize::MAX);
+// EventSenderからの通知用のトークン
+const WAKER: Token = Token(usize::MAX - 1);
+// リスニングソケットのトークン。listeners[i]のトークンはToken(LISTENER_BASE - i)
+const LISTENER_BASE: usize = usize::MAX - 2;
+// 接続以外に使うファイルディスクリプタ(リスニングソケット、Poll、配信するファイルなど)の見込み数
+const RESERVED_FDS: libc::rlim_t = 32;
+// ドキュメントルートが読めない場合に返すRetry-Afterの秒数(retry_afterを設定していない場合)
+const ROOT_UNAVAILABLE_RETRY_AFTER: u64 = 30;
+// ドキュメントルートが読めている間、読み込み可能かを確かめ直す間隔。読めない間はリクエストごとに確かめる
+const ROOT_AVAILABILITY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// メンテナンス中に返すRetry-Afterの秒数(retry_afterを設定していない場合)
+const MAINTENANCE_RETRY_AFTER: u64 = 300;
+// 同時アップロード数の上限で拒否した場合に返すRetry-Afterの秒数(retry_afterを設定していない場合)
+const UPLOAD_RETRY_AFTER: u64 = 5;
+// 送信待ちのレスポンスの合計の上限で拒否した場合に返すRetry-Afterの秒数(retry_afterを設定していない場合)
+const RESPONSE_BUFFER_RETRY_AFTER: u64 = 5;
+// これより小さいレスポンスはmax_response_buffer_bytesに関わらず送信待ちにする
+const LARGE_RESPONSE_BYTES: usize = 64 * 1024;
+// 1つの接続の送信待ちがこれを超えたら、パイプライン化された後続のリクエストは送信し終えるまで処理しない
+const PIPELINE_HIGH_WATER: usize = 1024 * 1024;
+// request_timeoutを過ぎて503を返す場合のRetry-Afterの秒数(retry_afterを設定していない場合)
+const REQUEST_TIMEOUT_RETRY_AFTER: u64 = 5;
+// debug_connections有効時に接続の一覧を返すパス
+const DEBUG_CONNECTIONS_PATH: &str = "/debug/connections";
+// status_dashboard有効時にリクエストの統計のHTMLを返すパス
+const STATUS_DASHBOARD_PATH: &str = "/status";
+// max_long_lived_connectionsに達してWebSocketやイベントストリームを断る場合のRetry-Afterの秒数
+const LONG_LIVED_RETRY_AFTER: u64 = 5;
+// ソケットアクティベーションで渡される最初のファイルディスクリプタ(sd_listen_fds(3)のSD_LISTEN_FDS_START)
+const LISTEN_FDS_START: i32 = 3;
+// poll.pollがこの回数続けて失敗したらPollを作り直す
+const POLL_FAILURES_BEFORE_RESTART: u32 = 5;
+// Pollを作り直す回数の上限。超えた場合はrunからエラーを返して終了する
+const MAX_POLL_RESTARTS: u32 = 3;
+// 最後に作り直してからこの時間正常に動作したら、作り直した回数を数え直す
+const POLL_RESTART_RESET: Duration = Duration::from_secs(60);
+// poll.pollが失敗した後、再び呼び出すまでの待ち時間の上限(失敗が続くほど長く待つ)
+const MAX_POLL_FAILURE_BACKOFF: Duration = Duration::from_millis(500);
+
+/**
+* poll.pollが失敗した後の動作
+*/
+#[derive(Debug, PartialEq)]
+enum PollRecovery {
+    // 待ってから同じPollで呼び直す
+    Retry(Duration),
+    // Pollを作り直して全てのソケットを登録し直す
+    Restart,
+    // 作り直しても失敗し続けるため、runからエラーを返す
+    Stop,
+}
+
+/**
+* poll.pollの失敗を数え、失敗が続く場合にPollを作り直すかどうかを決める
+*/
+#[derive(Default)]
+struct PollWatchdog {
+    // 続けて失敗したpoll.pollの回数
+    failures: u32,
+    // Pollを作り直した回数と、最後に作り直した時刻
+    restarts: u32,
+    last_restart: Option<Instant>,
+}
+
+impl PollWatchdog {
+    fn on_failure(&mut self, now: Instant) -> PollRecovery {
+        self.failures += 1;
+        if self.failures < POLL_FAILURES_BEFORE_RESTART {
+            return PollRecovery::Retry(MAX_POLL_FAILURE_BACKOFF.min(Duration::from_millis(10) * 2u32.pow(self.failures)));
+        }
+        if self.restarts >= MAX_POLL_RESTARTS {
+            return PollRecovery::Stop;
+        }
+        self.failures = 0;
+        self.restarts += 1;
+        self.last_restart = Some(now);
+        PollRecovery::Restart
+    }
+
+    /**
+    * poll.pollが成功した。最後に作り直してからPOLL_RESTART_RESETが経っていれば作り直した回数を数え直し、trueを返す
+    */
+    fn on_success(&mut self, now: Instant) -> bool {
+        self.failures = 0;
+        if self.last_restart.is_some_and(|restarted| now.saturating_duration_since(restarted) >= POLL_RESTART_RESET) {
+            self.restarts = 0;
+            self.last_restart = None;
+            return true;
+        }
+        false
+    }
+}
+
+/**
+* リスニングソケットと、そこで受け付けた接続の扱い
+*/
+struct Listener {
+    // 終了を始めた後は閉じてNoneにする。SNIの設定などは処理中の接続のために残す
+    socket: Option<mio::net::TcpListener>,
+    // 全てのリクエストをHTTPSにリダイレクトする(redirect_listener)
+    redirect_to_https: bool,
+    // TLSで受け付ける場合のTLSの設定
+    tls: Option<Arc<ServerConfig>>,
+    // SNIのホスト名(小文字)ごとのドキュメントルート(絶対パス)
+    sni_roots: HashMap<String, PathBuf>,
+}
+
+pub struct WebServer {
+    // 先頭は起動時に指定したアドレス、続いてredirect_listener、listenersの順に並ぶ
+    listeners: Vec<Listener>,
+    connections: HashMap<usize, Connection>, //サーバに接続されているクライアントを管理するハッシュテーブル
+    next_connection_id: usize,
+    config: Config,
+    // 起動時に絶対パスにしたドキュメントルート。snapshot_root有効時はシンボリックリンクも解決済み
+    webroots: Vec<PathBuf>,
+    // ソケットからの読み込みに使うバッファ
+    read_buffer: Vec<u8>,
+    // archive設定時に配信元とするアーカイブ
+    archive: Option<Rc<Archive>>,
+    // preloadで読み込んだファイル
+    preloaded: Rc<PreloadedFiles>,
+    // 小文字にしたblocked_user_agents。リクエストごとに変換しないように設定の読み込み時に作る
+    blocked_user_agents: Vec<String>,
+    // ドキュメントルートが読み込み可能か。状態が変わった時だけログを出すために保持する
+    root_available: bool,
+    // 最後にドキュメントルートが読み込み可能かを確かめた時刻
+    root_checked_at: Option<Instant>,
+    // runで使うPoll。EventSenderのWakerを登録するためにnewで作成する
+    poll: Option<Poll>,
+    // EventSenderから送られたイベント
+    event_receiver: Receiver<String>,
+    event_sender: EventSender,
+    // CGIの実行やファイルの読み直しをしたスレッドから送られた結果
+    job_receiver: Receiver<JobResult>,
+    job_sender: Sender<JobResult>,
+    // 次に別スレッドで実行する処理に割り当てる番号
+    next_job: u64,
+    // accept_rate設定時の受け付けのレート制限
+    accept_limiter: Option<TokenBucket>,
+    // レート制限により受け付けを中断しているか
+    accept_paused: bool,
+    // max_accepts_per_pollに達して、接続待ちを残したまま受け付けを止めたリスニングソケットのインデックス
+    pending_accepts: Vec<usize>,
+    // レスポンスを作成する前に登録順に呼び出す
+    request_hooks: Vec<RequestHook>,
+    // レスポンスをバイト列にする直前に登録順に呼び出す
+    response_hooks: Vec<ResponseHook>,
+    // add_routeで登録したルート。静的ファイルより先に照合する
+    router: Router,
+    // add_cached_routeで登録したルートのレスポンスのキャッシュ
+    route_cache: Rc<RefCell<ResponseCache>>,
+    // HEADに使う、配信したファイルのメタデータのキャッシュ
+    metadata_cache: Rc<RefCell<MetadataCache>>,
+    // ヘルスチェックに返すレスポンス
+    health_check_responses: Option<HealthCheckResponses>,
+    // ボディのないエラーレスポンスに付けるボディ
+    status_pages: StatusPages,
+    // メンテナンス中に返すページ。メンテナンス中でなければNone
+    maintenance_page: Option<Vec<u8>>,
+    // 全ての接続の送信待ちのレスポンスのバイト数の合計
+    buffered_response_bytes: Arc<AtomicUsize>,
+    // パースできたリクエストを記録するアクセスログ
+    access_log: Box<dyn AccessLogSink>,
+    // アクセスログに記録したリクエストの件数。status_dashboardで表示する
+    request_stats: RequestStats,
+    // タイムアウトやキャッシュの期限などに使う時刻
+    clock: Arc<dyn Clock>,
+    // SIGHUPで読み直す設定ファイル。Noneの場合はドキュメントルートとメンテナンスページだけを確認し直す
+    config_path: Option<String>,
+    // SIGTERMかSIGINTを受け取り、リスニングソケットを閉じて処理中の接続の完了を待っているか
+    shutting_down: bool,
+}
+
+/**
+* レスポンスを作成した後の処理に必要な、リクエストを受け付けた時の状態
+*/
+pub(crate) struct ResponseContext {
+    // リクエストを取り出した時刻。slow_request_thresholdとアクセスログの処理時間に使う
+    started: Instant,
+    // アクセスログに記録する受信日時
+    received_at: SystemTime,
+    // クライアントが送信側を閉じているか
+    closed: bool,
+    // WebSocketのハンドシェイクか
+    websocket: bool,
+    // イベントストリームの購読か
+    event_stream: bool,
+}
+
+/**
+* 別スレッドでCGIの実行やファイルの読み直しを待っているリクエスト。終了するまで接続はProcessingのままにする
+*/
+pub(crate) struct PendingJob {
+    job_id: u64,
+    request: Request,
+    context: ResponseContext,
+    // これまでに読み直したファイル。処理し直す時にドキュメントルートより先に探す
+    reread: PreloadedFiles,
+}
+
+/**
+* 実行結果を返す先の接続と、その処理の番号
+*/
+struct JobTicket {
+    conn_id: usize,
+    job_id: u64,
+}
+
+/**
+* 別スレッドから送られる結果
+*/
+struct JobResult {
+    ticket: JobTicket,
+    output: JobOutput,
+}
+
+enum JobOutput {
+    // CGIのレスポンス
+    Response(Result<Response, Error>),
+    // 読み直したファイルのドキュメントルートからの相対パスと内容
+    File(String, io::Result<(Vec<u8>, Option<SystemTime>)>),
+}
+
+/**
+* status_pagesから読み込んだステータスコードごとのContent-Typeとボディ
+*/
+type StatusPages = HashMap<u16, (String, Vec<u8>)>;
+
+/**
+* ヘルスチェックに返すレスポンスのバイト列(キープアライブする場合、閉じる場合)
+*/
+type HealthCheckResponses = (Vec<u8>, Vec<u8>);
+
+/**
+* レスポンスを作成する前のリクエストの前処理。認証したユーザなどをRequest::extensionsに入れてルートの処理に渡す
+*/
+pub type RequestHook = Box<dyn Fn(&mut Request)>;
+
+/**
+* レスポンスの後処理。ヘッダの追加やステータスの書き換え、ログの出力などに使う
+*/
+pub type ResponseHook = Box<dyn Fn(&Request, &mut Response)>;
+
+impl WebServer {
+    /**
+    * サーバの初期化
+    */
+    pub fn new(addr: &str, config: Config) -> Result<Self, Error> {
+        WebServer::with_clock(addr, config, Arc::new(SystemClock))
+    }
+
+    /**
+    * 時刻をclockから取得するサーバの初期化。MockClockを渡すと、タイムアウトやキャッシュの期限を時刻を進めて確かめられる
+    */
+    pub fn with_clock(addr: &str, config: Config, clock: Arc<dyn Clock>) -> Result<Self, Error> {
+        check_fd_limit(&config);
+        let activated = if config.socket_activation { activated_listener()? } else { None };
+        let socket = match activated {
+            Some(socket) => {
+                info!("Listening on {} (passed by socket activation; {} is ignored)", socket.local_addr()?, addr);
+                socket
+            }
+            None => {
+                let socket = bind_listener(addr.parse()?, config.listen_backlog)?;
+                // ポート0を指定した場合はOSが選んだポートになる
+                info!("Listening on {}", socket.local_addr()?);
+                socket
+            }
+        };
+        let mut listeners = vec![Listener { socket: Some(socket), redirect_to_https: false, tls: None, sni_roots: HashMap::new() }];
+        if let Some(redirect) = &config.redirect_listener {
+            let socket = bind_listener(redirect.addr.parse()?, config.listen_backlog)?;
+            info!("Redirecting to HTTPS on {}", socket.local_addr()?);
+            listeners.push(Listener { socket: Some(socket), redirect_to_https: true, tls: None, sni_roots: HashMap::new() });
+        }
+        for listener in &config.listeners {
+            let tls = match &listener.tls {
+                Some(tls) => Some(load_server_config(tls)?),
+                None => None,
+            };
+            let socket = bind_listener(listener.addr.parse()?, config.listen_backlog)?;
+            let scheme = if tls.is_some() { "https" } else { "http" };
+            info!("Listening on {} ({})", socket.local_addr()?, scheme);
+            let mut sni_roots = HashMap::new();
+            for sni in listener.tls.iter().flat_map(|tls| &tls.sni) {
+                if let Some(webroot) = &sni.webroot {
+                    sni_roots.insert(sni.name.to_ascii_lowercase(), path::absolute(webroot)?);
+                }
+            }
+            listeners.push(Listener { socket: Some(socket), redirect_to_https: false, tls, sni_roots });
+        }
+        let webroots = resolve_webroots(&config)?;
+        let archive = open_archive(&config)?;
+        let preloaded = base_root(&webroots, &archive).preload(&config.preload, &config)?;
+        let poll = Poll::new()?;
+        let waker = Arc::new(Mutex::new(Waker::new(poll.registry(), WAKER)?));
+        let (sender, event_receiver) = mpsc::channel();
+        let (job_sender, job_receiver) = mpsc::channel();
+        Ok(WebServer {
+            listeners,
+            poll: Some(poll),
+            event_receiver,
+            event_sender: EventSender { sender, waker },
+            job_receiver,
+            job_sender,
+            next_job: 0,
+            accept_limiter: config
+                .accept_rate
+                .as_ref()
+                .map(|rate| TokenBucket::new(rate.per_second, rate.burst, clock.now())),
+            accept_paused: false,
+            pending_accepts: Vec::new(),
+            request_hooks: Vec::new(),
+            response_hooks: Vec::new(),
+            router: Router::default(),
+            route_cache: Rc::new(RefCell::new(ResponseCache::new(config.route_cache_entries))),
+            metadata_cache: Rc::new(RefCell::new(MetadataCache::new(config.metadata_cache_entries))),
+            maintenance_page: load_maintenance_page(&config),
+            health_check_responses: health_check_responses(&config)?,
+            status_pages: load_status_pages(&config)?,
+            archive,
+            preloaded: Rc::new(preloaded),
+            blocked_user_agents: lowercase_patterns(&config.blocked_user_agents),
+            access_log: open_access_log(&config.access_log)?,
+            request_stats: RequestStats::new(clock.now()),
+            clock,
+            config_path: None,
+            shutting_down: false,
+            connections: HashMap::new(),
+            next_connection_id: 1,
+            read_buffer: vec![0u8; config.read_buffer_size],
+            config,
+            webroots,
+            root_available: true,
+            root_checked_at: None,
+            buffered_response_bytes: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+    /**
+    * リクエストの前処理を登録する。リクエストを処理するたびに、ルートや静的ファイルのレスポンスを作成する前に呼び出す。
+    * ストリーミングのルートでは、ボディを受信し終えてBodyHandler::finishを呼ぶ前に呼び出す
+    */
+    pub fn add_request_hook(&mut self, hook: impl Fn(&mut Request) + 'static) {
+        self.request_hooks.push(Box::new(hook));
+    }
+
+    /**
+    * レスポンスの後処理を登録する。リクエストを処理するたびに、作成したレスポンスを送信する前に呼び出す
+    */
+    pub fn add_response_hook(&mut self, hook: impl Fn(&Request, &mut Response) + 'static) {
+        self.response_hooks.push(Box::new(hook));
+    }
+
+    /**
+    * SIGHUPを受け取った時にpathの設定ファイルを読み直し、以降のリクエストに適用する。
+    * 読み込みや検証に失敗した場合は現在の設定のまま続ける。
+    * リスニングソケットに関する設定(listeners、redirect_listener、listen_backlog、socket_activation)は再起動するまで変わらない
+    */
+    pub fn set_config_path(&mut self, path: &str) {
+        self.config_path = Some(path.to_string());
+    }
+
+    /**
+    * アクセスログの出力先を差し替える。access_logの設定で開いた出力先の代わりに使う
+    */
+    pub fn set_access_log(&mut self, sink: impl AccessLogSink + 'static) {
+        self.access_log = Box::new(sink);
+    }
+
+    /**
+    * methodのリクエストのうちパスが"/users/:id"の形式のパターンに一致するものをhandlerで処理する。
+    * ":"で始まるセグメントは任意のセグメントに一致し、その値をパスパラメータとしてhandlerに渡す。
+    * 複数のルートに一致する場合はパラメータでないセグメントが前にあるものを優先する。
+    * methodにはPROPFINDなどの拡張メソッドも指定でき、tokenでなければパニックする。
+    * どのルートにも一致しない拡張メソッドのリクエストには501を返す。
+    * head_as_getが有効な場合、GETで登録したルートはHEADにも同じヘッダで応答する
+    */
+    pub fn add_route(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: impl Fn(&Request, &HashMap<String, String>) -> Result<Response, Error> + 'static,
+    ) {
+        self.router.add(method, pattern, Box::new(handler));
+    }
+
+    /**
+    * add_routeと同じように登録し、handlerが返した2xxのレスポンスをttlの間キャッシュする。
+    * メソッドとリクエストターゲット(クエリ文字列を含む)が同じリクエストには、期限内であればhandlerを呼ばずにキャッシュを返す。
+    * キャッシュから返すレスポンスにはキャッシュしてからの秒数をAgeヘッダで付ける。
+    * Cache-Control: no-storeを付けたレスポンスはキャッシュしない。件数はroute_cache_entriesで制限する
+    */
+    pub fn add_cached_route(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        ttl: Duration,
+        handler: impl Fn(&Request, &HashMap<String, String>) -> Result<Response, Error> + 'static,
+    ) {
+        let cache = Rc::clone(&self.route_cache);
+        let clock = Arc::clone(&self.clock);
+        let cached = move |request: &Request, params: &HashMap<String, String>| {
+            let key = format!("{} {}", request.method, request.target);
+            if let Some(response) = cache.borrow_mut().get(&key, clock.now()) {
+                return Ok(response);
+            }
+            let response = handler(request, params)?;
+            cache.borrow_mut().insert(key, &response, ttl, clock.now());
+            Ok(response)
+        };
+        self.router.add(method, pattern, Box::new(cached));
+    }
+
+    /**
+    * add_routeと同じパターンで、ボディを溜めずに受信した順にBodyHandlerへ渡すルートを登録する。
+    * handlerはヘッダが揃った時点で呼ばれ、ボディの長さはmax_upload_bytesなどの制限を受けない
+    */
+    pub fn add_streaming_route(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: impl Fn(&Request, &HashMap<String, String>) -> Result<Box<dyn BodyHandler>, Error> + 'static,
+    ) {
+        self.router.add_streaming(method, pattern, Box::new(handler));
+    }
+
+    /**
+    * sse_pathの購読者にイベントを送るためのハンドル
+    */
+    pub fn event_sender(&self) -> EventSender {
+        self.event_sender.clone()
+    }
+
+    /**
+    * 起動時に指定したアドレスで実際にバインドしたアドレス
+    */
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        listener_socket(&self.listeners[0])?.local_addr()
+    }
+
+    /**
+    * 全てのリスニングソケットで実際にバインドしたアドレス。順番はlocal_addr、redirect_listener、listenersの順
+    */
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|listener| listener_socket(listener)?.local_addr()).collect()
+    }
+
+    /**
+     * イベントループを実行する
+     */
+    pub fn run(&mut self) -> Result<(), Error> {
+        let mut poll = self.poll.take().ok_or_else(|| Error::Internal("Server is already running".to_string()))?;
+        // SIGHUPと終了のシグナルを監視対象に登録する
+        let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT])?;
+        self.register_sources(&poll, &mut signals)?;
+
+        //イベントキュー
+        let mut events = Events::with_capacity(1024);
+        let mut watchdog = PollWatchdog::default();
+
+        loop {
+            //現在のスレッドをブロックしてイベントを待つ。
+            //タイムアウトする接続があれば、最も早くタイムアウトする時刻までに起きる
+            if let Err(e) = poll.poll(&mut events, self.next_timeout()) {
+                // シグナル受信による割り込みはエラーではない
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("poll failed ({} in a row): {}", watchdog.failures + 1, e);
+                match watchdog.on_failure(self.clock.now()) {
+                    // 失敗し続ける場合にCPUを使い切らないように、少し待ってから呼び直す
+                    PollRecovery::Retry(backoff) => std::thread::sleep(backoff),
+                    PollRecovery::Restart => {
+                        poll = self.restart_poll(&poll, &mut signals)?;
+                        warn!(
+                            "Recreated the poll instance after repeated failures (restart {} of {})",
+                            watchdog.restarts,
+                            MAX_POLL_RESTARTS
+                        );
+                    }
+                    PollRecovery::Stop => {
+                        error!("poll kept failing after {} restarts; stopping the server", watchdog.restarts);
+                        return Err(e.into());
+                    }
+                }
+                continue;
+            }
+            if watchdog.on_success(self.clock.now()) {
+                info!("Event loop has been healthy since the last poll restart");
+            }
+            self.handle_timeouts(&poll);
+            if self.accept_paused {
+                // 中断中に届いた接続は新たなイベントが発生しないため、ここで受け付けを再開する
+                for index in 0..self.listeners.len() {
+                    self.accept_connections(&poll, index);
+                }
+            }
+            for event in &events {
+                if let Some(index) = self.listener_index(event.token()) {
+                    //　リスニングソケットの読み込み準備完了イベントが発生
+                    self.accept_connections(&poll, index);
+                    continue;
+                }
+                match event.token() {
+                    WAKER => {
+                        self.dispatch_events(&poll);
+                        self.dispatch_job_results(&poll);
+                    }
+
+                    SIGNAL => {
+                        for signal in signals.pending() {
+                            if signal == SIGTERM || signal == SIGINT {
+                                if self.shutting_down {
+                                    warn!(
+                                        "Received a second shutdown signal; stopping without waiting for {} connections",
+                                        self.connections.len()
+                                    );
+                                    return Ok(());
+                                }
+                                self.begin_shutdown(&poll);
+                            } else if signal == SIGHUP && self.config_path.is_some() {
+                                self.reload_config();
+                            } else if signal == SIGHUP {
+                                self.reload_snapshot_root();
+                                self.reload_preload();
+                                self.reload_maintenance_page();
+                            }
+                        }
+                    }
+
+                    Token(conn_id) => {
+                        //　接続済みソケットでイベントが発生
+                        self.http_handler(conn_id, event, &poll)
+                            .unwrap_or_else(|e| error!("{}", e));
+                    }
+                }
+            }
+            // 上限まで受け付けて残した接続は、既存の接続のイベントを処理してから受け付ける
+            for index in std::mem::take(&mut self.pending_accepts) {
+                self.accept_connections(&poll, index);
+            }
+            if self.shutting_down && self.connections.is_empty() {
+                info!("All connections are closed; shutting down");
+                return Ok(());
+            }
+
+        }
+
+
+    }
+
+    /**
+    * 終了を始める。新しい接続を受け付けないように、まずリスニングソケットの監視を解除して閉じる。
+    * 次のリクエストを待っているキープアライブの接続、リクエストをまだ受信していない接続、WebSocketやイベントストリームの接続はすぐに閉じ、
+    * リクエストの受信中や処理中の接続はレスポンスを送信し終えてから閉じる(以降のレスポンスはConnection: closeにする)
+    */
+    fn begin_shutdown(&mut self, poll: &Poll) {
+        for listener in &mut self.listeners {
+            if let Some(mut socket) = listener.socket.take() {
+                if let Err(e) = poll.registry().deregister(&mut socket) {
+                    warn!("Failed to deregister a listener: {}", e);
+                }
+            }
+        }
+        self.pending_accepts.clear();
+        self.accept_paused = false;
+        self.shutting_down = true;
+        for connection in self.connections.values_mut() {
+            let idle = match connection.state {
+                ConnectionState::KeepAliveIdle(_) | ConnectionState::WebSocket | ConnectionState::EventStream => true,
+                // リクエストを1バイトも受信していない接続は、送られてくるのを待たずに閉じる
+                ConnectionState::ReadingRequest => {
+                    connection.request_buffer.is_empty() && connection.body_stream.is_none()
+                }
+                _ => false,
+            };
+            if idle {
+                connection.set_state(ConnectionState::Closing);
+            }
+        }
+        self.connections.retain(|_, connection| connection.state != ConnectionState::Closing);
+        info!("Stopped accepting connections; waiting for {} connections to finish", self.connections.len());
+    }
+
+    /**
+    * リスニングソケットとシグナルをPollの監視対象に登録する
+    */
+    fn register_sources(&mut self, poll: &Poll, signals: &mut Signals) -> Result<(), Error> {
+        // サーバーソケットの状態を監視対象に登録する
+        for (index, listener) in self.listeners.iter_mut().enumerate() {
+            if let Some(socket) = &mut listener.socket {
+                poll.registry().register(socket, Token(LISTENER_BASE - index), Interest::READABLE)?;
+            }
+        }
+        poll.registry().register(signals, SIGNAL, Interest::READABLE)?;
+        Ok(())
+    }
+
+    /**
+    * poll.pollが失敗し続ける場合に、Pollを作り直して全てのソケットとWakerを登録し直す。
+    * 接続ごとの監視対象のイベントは保持していないので、読み書きの両方で登録し、関係のないイベントは状態に応じて無視させる。
+    * 作り直す間に届いた接続やイベントの通知は失われうるため、登録後に受け付けと配信を一度行う
+    */
+    fn restart_poll(&mut self, old: &Poll, signals: &mut Signals) -> Result<Poll, Error> {
+        // mioはソケットを登録したPollを記録していて、解除しないと新しいPollに登録できない。
+        // 壊れたPollでは解除に失敗しうるが、記録は消えるので無視する
+        for listener in &mut self.listeners {
+            if let Some(socket) = &mut listener.socket {
+                let _ = old.registry().deregister(socket);
+            }
+        }
+        let _ = old.registry().deregister(signals);
+        for connection in self.connections.values_mut() {
+            let _ = old.registry().deregister(&mut connection.stream);
+        }
+        let poll = Poll::new()?;
+        self.register_sources(&poll, signals)?;
+        for (&conn_id, connection) in self.connections.iter_mut() {
+            poll.registry().register(
+                &mut connection.stream,
+                Token(conn_id),
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+        let waker = Waker::new(poll.registry(), WAKER)?;
+        *self
+            .event_sender
+            .waker
+            .lock()
+            .map_err(|_| Error::Internal("EventSender waker is poisoned".to_string()))? = waker;
+        for index in 0..self.listeners.len() {
+            self.accept_connections(&poll, index);
+        }
+        self.dispatch_events(&poll);
+        info!("Re-registered {} listeners and {} connections", self.listeners.len(), self.connections.len());
+        Ok(poll)
+    }
+
+    /**
+    * 設定ファイルを読み直して置き換える。ドキュメントルートやアーカイブなど設定から作る状態も作り直す。
+    * 送信待ちのレスポンスはそのまま送信し、以降に処理するリクエストから新しい設定を使う
+    */
+    fn reload_config(&mut self) {
+        let Some(path) = self.config_path.clone() else {
+            return;
+        };
+        if let Err(e) = self.apply_config(&path) {
+            error!("Failed to reload {}: {}; keeping the current configuration", path, e);
+            return;
+        }
+        info!("Reloaded configuration from {}; listener settings take effect after a restart", path);
+    }
+
+    /**
+    * 設定ファイルを読み込み、設定から作る状態を全て作り直してから置き換える。途中で失敗した場合は何も変えない
+    */
+    fn apply_config(&mut self, path: &str) -> Result<(), Error> {
+        let config = Config::load(path)?;
+        let webroots = resolve_webroots(&config)?;
+        let archive = open_archive(&config)?;
+        let preloaded = base_root(&webroots, &archive).preload(&config.preload, &config)?;
+        let health_check_responses = health_check_responses(&config)?;
+        let status_pages = load_status_pages(&config)?;
+        // 出力先が変わらなければ、set_access_logで差し替えたものも含めて今の出力先を使い続ける
+        if config.access_log != self.config.access_log {
+            self.access_log = open_access_log(&config.access_log)?;
+        }
+        self.webroots = webroots;
+        self.root_checked_at = None;
+        self.archive = archive;
+        self.preloaded = Rc::new(preloaded);
+        self.blocked_user_agents = lowercase_patterns(&config.blocked_user_agents);
+        self.health_check_responses = health_check_responses;
+        self.status_pages = status_pages;
+        let now = self.clock.now();
+        self.accept_limiter = config.accept_rate.as_ref().map(|rate| TokenBucket::new(rate.per_second, rate.burst, now));
+        self.accept_paused = false;
+        self.read_buffer = vec![0u8; config.read_buffer_size];
+        self.route_cache.borrow_mut().set_capacity(config.route_cache_entries);
+        // ヘッダの設定が変わりうるので、キャッシュしたメタデータは使わない
+        self.metadata_cache = Rc::new(RefCell::new(MetadataCache::new(config.metadata_cache_entries)));
+        self.config = config;
+        self.reload_maintenance_page();
+        Ok(())
+    }
+
+    /**
+    * snapshot_root有効時にドキュメントルートを再解決する
+    */
+    fn reload_snapshot_root(&mut self) {
+        if !self.config.snapshot_root {
+            debug!("snapshot_root is disabled; document root is not re-resolved");
+            return;
+        }
+        match resolve_snapshot_root(&self.config.webroots) {
+            Ok(roots) => {
+                for root in &roots {
+                    info!("Document root resolved to {}", root.display());
+                }
+                self.webroots = roots;
+                self.root_checked_at = None;
+                self.metadata_cache = Rc::new(RefCell::new(MetadataCache::new(self.config.metadata_cache_entries)));
+            }
+            // 解決に失敗した場合は以前のルートで配信を続ける
+            Err(e) => error!("Failed to resolve document root: {}", e),
+        }
+    }
+
+    /**
+    * preloadのファイルを読み込み直す。失敗した場合は以前に読み込んだ内容を返し続ける
+    */
+    fn reload_preload(&mut self) {
+        if self.config.preload.is_empty() {
+            return;
+        }
+        match base_root(&self.webroots, &self.archive).preload(&self.config.preload, &self.config) {
+            Ok(preloaded) => {
+                info!("Reloaded {} preloaded files", preloaded.len());
+                self.preloaded = Rc::new(preloaded);
+            }
+            Err(e) => error!("Failed to reload preloaded files: {}", e),
+        }
+    }
+
+    /**
+    * maintenance_pageのファイルの有無を確認し、メンテナンスモードを切り替える
+    */
+    fn reload_maintenance_page(&mut self) {
+        let page = load_maintenance_page(&self.config);
+        match (&self.maintenance_page, &page) {
+            (None, Some(_)) => info!("Maintenance mode enabled"),
+            (Some(_), None) => info!("Maintenance mode disabled"),
+            _ => {}
+        }
+        self.maintenance_page = page;
+    }
+
+    /**
+    * リクエストごとのドキュメントルート
+    */
+    fn document_root(&self, conn_id: usize) -> DocumentRoot {
+        // TLSの接続でSNIのホスト名にドキュメントルートを設定していれば、そこから配信する
+        let sni_root = self.connections.get(&conn_id).and_then(|connection| {
+            let name = connection.tls.as_ref()?.server_name()?.to_ascii_lowercase();
+            self.listeners[connection.listener].sni_roots.get(&name)
+        });
+        if let Some(root) = sni_root {
+            return DocumentRoot::Directories(vec![root.clone()]);
+        }
+        let root = DocumentRoot::MetadataCached(
+            Rc::clone(&self.metadata_cache),
+            Box::new(base_root(&self.webroots, &self.archive)),
+        );
+        if self.preloaded.is_empty() {
+            return root;
+        }
+        DocumentRoot::Preloaded(Rc::clone(&self.preloaded), Box::new(root))
+    }
+
+    /**
+    * 最も早くタイムアウトを迎える接続までの時間
+    */
+    fn next_timeout(&self) -> Option<Duration> {
+        let timeouts = Timeouts::from_config(&self.config);
+        let now = self.clock.now();
+        // 受け付けを中断している場合はトークンが溜まった時に再開する
+        let accept_resume = match (&self.accept_limiter, self.accept_paused) {
+            (Some(limiter), true) => Some(limiter.time_until_available(now)),
+            // 接続待ちを残している場合は新たなイベントが発生しないため、待たずに受け付けを続ける
+            _ if !self.pending_accepts.is_empty() => Some(Duration::ZERO),
+            _ => None,
+        };
+        self.connections
+            .values()
+            .filter_map(|connection| connection.deadline(&timeouts))
+            .map(|deadline| deadline.saturating_duration_since(now))
+            .chain(accept_resume)
+            .min()
+    }
+
+    /**
+    * タイムアウトした接続を処理する。
+    * キープアライブ中の接続と何も受信していない接続は閉じ、リクエストを受信途中の接続には408を返す。
+    * レスポンスを送信し始める前にrequest_timeoutを過ぎた接続には503を返す。
+    * write_timeoutの間送信が進まなかった接続は、相手が受信していないので何も送らずに閉じる
+    */
+    fn handle_timeouts(&mut self, poll: &Poll) {
+        let timeouts = Timeouts::from_config(&self.config);
+        let now = self.clock.now();
+        for (conn_id, connection) in self.connections.iter_mut() {
+            if connection.deadline(&timeouts).is_none_or(|deadline| deadline > now) {
+                continue;
+            }
+            if connection.write_deadline(timeouts.write).is_some_and(|deadline| deadline <= now) {
+                warn!(
+                    "No write progress on conn_id {} for {}s with {} bytes pending; closing",
+                    conn_id,
+                    self.config.write_timeout,
+                    connection.buffered_bytes()
+                );
+                connection.set_state(ConnectionState::Closing);
+                continue;
+            }
+            let state = connection.state;
+            let response = match state {
+                ConnectionState::ReadingRequest if !connection.request_buffer.is_empty() => {
+                    debug!("request timeout conn_id: {}", conn_id);
+                    create_msg_from_code(408, None)
+                }
+                ConnectionState::WritingResponse if connection.discard_unsent_responses() => {
+                    warn!("Request on conn_id {} exceeded request_timeout; responding 503", conn_id);
+                    service_unavailable(&self.config, REQUEST_TIMEOUT_RETRY_AFTER)
+                }
+                _ => {
+                    debug!("timeout conn_id: {}", conn_id);
+                    connection.set_state(ConnectionState::Closing);
+                    continue;
+                }
+            };
+            let result = response.and_then(|response| {
+                Ok(respond_and_close(*conn_id, connection, poll, response, &self.config, &self.status_pages)?)
+            });
+            if let Err(e) = result {
+                error!("{}", e);
+                connection.set_state(ConnectionState::Closing);
+            }
+        }
+        self.connections.retain(|_, connection| connection.state != ConnectionState::Closing);
+    }
+
+    /**
+    * リスニングソケットのトークンであれば、listenersのインデックスを返す
+    */
+    fn listener_index(&self, token: Token) -> Option<usize> {
+        LISTENER_BASE.checked_sub(token.0).filter(|index| *index < self.listeners.len())
+    }
+
+    /**
+    * 接続待ちのクライアントをWouldBlockになるまで受け付ける。
+    * 1回のイベントで複数の接続が届いていても次のイベントまで待たせない。
+    * ただし既存の接続のイベントを処理できるように、max_accepts_per_poll件受け付けたら残りは次のループで受け付ける。
+    * indexはlistenersのうち受け付けるリスニングソケット
+    */
+    fn accept_connections(&mut self, poll: &Poll, index: usize) {
+        let mut accepted = 0;
+        loop {
+            if accepted >= self.config.max_accepts_per_poll {
+                if !self.pending_accepts.contains(&index) {
+                    self.pending_accepts.push(index);
+                }
+                break;
+            }
+            if let Some(limiter) = &self.accept_limiter {
+                if !limiter.time_until_available(self.clock.now()).is_zero() {
+                    // 接続待ちキューに残し、トークンが溜まるまで受け付けない
+                    if !self.accept_paused {
+                        debug!("Accept rate exceeded; pausing accept");
+                    }
+                    self.accept_paused = true;
+                    break;
+                }
+                self.accept_paused = false;
+            }
+            let Some(socket) = &self.listeners[index].socket else {
+                break;
+            };
+            let (stream, remote) = match socket.accept() {
+                Ok(t) => t,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    // 1つの接続の失敗で残りの接続を取りこぼさないように受け付けを続ける
+                    error!("{}", e);
+                    if is_resource_exhausted(&e) {
+                        // ファイルディスクリプタ不足では続けても失敗するため次のイベントを待つ
+                        break;
+                    }
+                    continue;
+                }
+            };
+            accepted += 1;
+            debug!("Connection from {}", &remote);
+            if !self.config.is_client_allowed(remote.ip()) {
+                // streamをdropして閉じる
+                info!("Rejected connection from {}: client address is not allowed", remote);
+                continue;
+            }
+            if let Some(limiter) = &mut self.accept_limiter {
+                limiter.try_acquire(self.clock.now());
+            }
+            if self.connections.len() >= self.config.max_connections {
+                // streamをdropして閉じる
+                warn!("Rejected connection from {}: max_connections reached", remote);
+                continue;
+            }
+            //接続済みソケットを監視対象に登録
+            self.register_connection(poll, stream, remote, index).unwrap_or_else(|e| error!("{}", e));
+        }
+    }
+
+    /**
+    *　接続済みソケットを監視対象に登録する
+    */
+    fn register_connection(
+        &mut self,
+        poll: &Poll,
+        mut stream: mio::net::TcpStream,
+        remote_addr: SocketAddr,
+        index: usize,
+    ) -> Result<(), Error> {
+        let listener = &self.listeners[index];
+        let tls = match &listener.tls {
+            Some(tls) => Some(ServerConnection::new(tls.clone())?),
+            None => None,
+        };
+        let redirect_to_https = listener.redirect_to_https;
+        let conn_id = self.allocate_connection_id()?;
+        // 使用中のIDで登録すると既存の接続のイベントと取り違えるため、登録する前に確認する。
+        // allocate_connection_idが使用中のIDを返すことはないので、ここに来るのは内部のバグであり、新しい接続を閉じる
+        let Entry::Vacant(entry) = self.connections.entry(conn_id) else {
+            return Err(Error::Internal(format!("Connection ID {} is already in use", conn_id)));
+        };
+        if let Some(keepalive) = &self.config.tcp_keepalive {
+            // 設定できなくても通信はできるので、接続は閉じずに続ける
+            if let Err(e) = set_tcp_keepalive(&stream, keepalive) {
+                warn!("Failed to set TCP keepalive on conn_id {}: {}", conn_id, e);
+            }
+        }
+        poll.registry().register(&mut stream, Token(conn_id), Interest::READABLE)?;
+
+        let mut connection = Connection::new(stream, remote_addr, self.buffered_response_bytes.clone(), Arc::clone(&self.clock));
+        connection.redirect_to_https = redirect_to_https;
+        connection.listener = index;
+        connection.tls = tls;
+        entry.insert(connection);
+        Ok(())
+    }
+
+    /**
+    * 新しい接続のID。リスニングソケットなどの予約済みのトークンに達したら1に戻り、使用中のIDは飛ばす
+    */
+    fn allocate_connection_id(&mut self) -> Result<usize, Error> {
+        let reserved = LISTENER_BASE + 1 - self.listeners.len();
+        for _ in 0..=self.connections.len() {
+            let conn_id = self.next_connection_id;
+            self.next_connection_id = if conn_id + 1 >= reserved { 1 } else { conn_id + 1 };
+            if !self.connections.contains_key(&conn_id) {
+                return Ok(conn_id);
+            }
+        }
+        Err(Error::Internal("No free connection ID".to_string()))
+    }
+
+    /**
+    * 受信済みのリクエストをすべて処理し、レスポンスを受信した順に送信待ちにする。
+    * リクエストが1つも揃っていなければfalseを返す
+    */
+    fn process_requests(
+        &mut self,
+        conn_id: usize,
+        poll: &Poll,
+        closed: bool,
+    ) -> Result<bool, Error> {
+        let mut processed = 0;
+        while self.process_request(conn_id, closed)? {
+            processed += 1;
+            let Some(connection) = self.connections.get(&conn_id) else {
+                break;
+            };
+            // CGIの終了を待つ間は後続のリクエストを処理しない。レスポンスの順序を保つため
+            if connection.pending_job.is_some() {
+                break;
+            }
+            // 接続を閉じるレスポンスより後のリクエストには応答しない。
+            // 送信待ちが溜まっている、または上限の数だけ処理した場合は、読み込みを止めたまま送信し終えてから続きを処理する
+            if !connection.keep_alive
+                || connection.buffered_bytes() > PIPELINE_HIGH_WATER
+                || processed >= self.config.max_pipelined_requests
+            {
+                break;
+            }
+        }
+        let queued = processed > 0;
+        if queued {
+            let connection = self
+                .connections
+                .get_mut(&conn_id)
+                .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+            if connection.pending_job.is_some() && connection.buffered_bytes() == 0 {
+                // 送信するものがなければ、CGIが終了するまでProcessingのまま待つ
+                return Ok(true);
+            }
+            connection.set_state(ConnectionState::WritingResponse);
+            //書き込み操作の可否を監視対象に入れる
+            poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::WRITABLE)?;
+        }
+        Ok(queued)
+    }
+
+    /**
+    * 受信済みのデータからリクエストを1つ取り出してレスポンスを作成し、送信待ちの末尾に加える。
+    * リクエストが揃っていなければfalseを返す
+    */
+    fn process_request(
+        &mut self,
+        conn_id: usize,
+        closed: bool,
+    ) -> Result<bool, Error> {
+        let root = self.document_root(conn_id);
+        let long_lived = self.connections.values().filter(|connection| connection.is_long_lived()).count();
+        // 処理中の接続を借用した後は他の接続を参照できないので、一覧を返す場合に備えて先に集めておく
+        let connection_list = self.config.debug_connections.then(|| connections_json(&self.connections));
+        let active_connections = self.connections.len();
+        let active_uploads = self.active_uploads(conn_id);
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+
+        if let Some((health_check_path, (keep_alive_response, close_response))) =
+            self.config.health_check_path.as_deref().zip(self.health_check_responses.as_ref())
+        {
+            if let Some((len, wants_keep_alive)) = match_health_check(&connection.request_buffer, health_check_path) {
+                if connection.state != ConnectionState::Processing {
+                    connection.set_state(ConnectionState::Processing);
+                }
+                connection.consume_buffered(len);
+                connection.upload_bytes = None;
+                connection.after_response = None;
+                connection.keep_alive =
+                    self.config.keep_alive_timeout > 0 && !closed && !self.shutting_down && wants_keep_alive;
+                let response = if connection.keep_alive { keep_alive_response } else { close_response };
+                connection.queue_response(response.clone());
+                return Ok(true);
+            }
+        }
+
+        // ストリーミングのルートに一致するリクエストは、ヘッダが揃った時点でボディの受け渡しを始める
+        if connection.body_stream.is_none()
+            && self.router.has_streaming_routes()
+            && !connection.redirect_to_https
+            && self.maintenance_page.is_none()
+        {
+            if let Ok(Some(head)) = parse_head_unbounded(&connection.request_buffer) {
+                let mut request = head.request;
+                request.remote_addr = Some(connection.remote_addr);
+                // ハンドラにボディを渡し始める前に、通常のリクエストと同じ前処理と拒否の判定をする。
+                // 拒否するリクエストはストリーミングせず、下で通常のリクエストとして拒否のレスポンスを返す
+                for hook in &self.request_hooks {
+                    hook(&mut request);
+                }
+                let streamable = request.target.len() <= self.config.max_target_length
+                    && !is_blocked_user_agent(&self.blocked_user_agents, &request)
+                    && self.config.is_access_allowed(request.path(), request.remote_addr.map(|addr| addr.ip()));
+                if let Some(handler) = streamable.then(|| self.router.open_stream(&request)).flatten() {
+                    connection.consume_buffered(head.len);
+                    connection.body_stream = Some(BodyStream::new(request, head.content_length, handler));
+                }
+            }
+        }
+        let mut streamed_handler = None;
+        let parsed = match connection.body_stream.as_mut() {
+            Some(stream) => {
+                let len = stream.feed(&connection.request_buffer);
+                let complete = stream.is_complete();
+                connection.consume_buffered(len);
+                if !complete {
+                    // ボディの続きを待つ
+                    return Ok(false);
+                }
+                let (request, handler) = connection.body_stream.take().unwrap().into_parts();
+                streamed_handler = Some(handler);
+                Ok(Some((request, 0)))
+            }
+            None => parse_request(&connection.request_buffer).and_then(|parsed| match parsed {
+                // リクエストターゲットの長さはリクエストライン全体の制限とは別に制限する
+                Some((request, _)) if request.target.len() > self.config.max_target_length => {
+                    Err(ParseError::TooLong(Section::Target))
+                }
+                parsed => Ok(parsed),
+            }),
+        };
+        let response = match parsed {
+            Ok(Some((mut request, len))) => {
+                let started = self.clock.now();
+                let received_at = self.clock.system_now();
+                request.remote_addr = Some(connection.remote_addr);
+                // 1回の読み込みでボディまで揃ったアップロードは受信中に数えていないので、ここで上限を確かめる
+                let upload_rejected = streamed_handler.is_none()
+                    && connection.upload_bytes.is_none()
+                    && !request.body.is_empty()
+                    && UPLOAD_METHODS.contains(&request.method.as_str())
+                    && !upload_fits(&self.config, active_uploads, request.body.len());
+                connection.upload_bytes = None;
+                if connection.state != ConnectionState::Processing {
+                    connection.set_state(ConnectionState::Processing);
+                }
+                connection.consume_buffered(len);
+                let tls_info = connection.tls.as_ref().map(|tls| format!(" (ALPN {})", alpn_protocol(tls)));
+                debug!(
+                    "{} {} HTTP/1.{} {:?}{}",
+                    request.method,
+                    request.target,
+                    request.version,
+                    request.headers,
+                    tls_info.unwrap_or_default()
+                );
+                // リクエストごとにread_dirしないように、読めている間はROOT_AVAILABILITY_CHECK_INTERVALごとに確かめる
+                let now = self.clock.now();
+                let check_due = !self.root_available
+                    || self.root_checked_at.is_none_or(|at| now.duration_since(at) >= ROOT_AVAILABILITY_CHECK_INTERVAL);
+                if check_due {
+                    self.root_checked_at = Some(now);
+                    let available = root.is_available();
+                    if available != self.root_available {
+                        if available {
+                            info!("Document root is available again");
+                        } else {
+                            warn!("Document root is unavailable; responding 503 until it recovers");
+                        }
+                        self.root_available = available;
+                    }
+                }
+                let root_available = self.root_available;
+                // ストリーミングしたリクエストにはボディを受け取り始める前にフックを適用済み
+                if streamed_handler.is_none() {
+                    for hook in &self.request_hooks {
+                        hook(&mut request);
+                    }
+                }
+                // アクセス制御で拒否するリクエストは、WebSocketやイベントストリーム、ルートを含めてどの処理にも渡さない
+                let access_allowed =
+                    self.config.is_access_allowed(request.path(), request.remote_addr.map(|addr| addr.ip()));
+                let websocket = self.config.websocket_echo_path.as_deref() == Some(request.path())
+                    && is_upgrade_request(&request)
+                    && access_allowed;
+                let event_stream = self.config.sse_path.as_deref() == Some(request.path())
+                    && request.method == "GET"
+                    && access_allowed;
+                // 長時間維持する接続で通常のリクエストに使う接続を使い切らないように、上限を超える分は断る
+                let long_lived_full = (websocket || event_stream) && long_lived >= self.config.max_long_lived_connections;
+                if long_lived_full {
+                    warn!(
+                        "Rejected {} {} on conn_id {}: {} WebSocket/event stream connections open",
+                        request.method, request.target, conn_id, long_lived
+                    );
+                }
+                let websocket = websocket && !long_lived_full;
+                let event_stream = event_stream && !long_lived_full;
+                let blocked_user_agent = is_blocked_user_agent(&self.blocked_user_agents, &request);
+                // 拒否したリクエストはWebSocketやイベントストリームに切り替えない
+                let websocket = websocket && !blocked_user_agent;
+                let event_stream = event_stream && !blocked_user_agent;
+                let maintenance = self.maintenance_page.as_ref().filter(|_| {
+                    self.config.health_check_path.as_deref() != Some(request.path())
+                });
+                let response = if connection.redirect_to_https {
+                    let https_port = self.config.redirect_listener.as_ref().and_then(|redirect| redirect.https_port);
+                    https_redirect(&request, https_port)?
+                } else if blocked_user_agent {
+                    info!("Blocked user agent on conn_id {}: {:?}", conn_id, request.header("User-Agent"));
+                    create_msg_from_code(self.config.blocked_user_agent_status, None)?
+                } else if let Some(page) = maintenance {
+                    let mut response = service_unavailable(&self.config, MAINTENANCE_RETRY_AFTER)?;
+                    response.body = page.clone();
+                    response.add_header("Content-Type", "text/html; charset=utf-8");
+                    response
+                } else if !access_allowed {
+                    create_msg_from_code(403, None)?
+                } else if upload_rejected {
+                    warn!(
+                        "Rejected upload on conn_id {}: {} uploads ({} bytes) in progress",
+                        conn_id, active_uploads.0, active_uploads.1
+                    );
+                    service_unavailable(&self.config, UPLOAD_RETRY_AFTER)?
+                } else if let Some(list) = connection_list.filter(|_| request.path() == DEBUG_CONNECTIONS_PATH) {
+                    create_content_response(200, "application/json", list)?
+                } else if self.config.status_dashboard && request.path() == STATUS_DASHBOARD_PATH {
+                    let html = self.request_stats.render_html(active_connections, self.clock.now());
+                    let mut response = create_content_response(200, "text/html; charset=utf-8", html)?;
+                    response.add_header("Cache-Control", "no-store");
+                    response
+                } else if long_lived_full {
+                    service_unavailable(&self.config, LONG_LIVED_RETRY_AFTER)?
+                } else if websocket {
+                    handshake_response(&request)?
+                } else if event_stream {
+                    event_stream_response()?
+                } else {
+                    // リクエスト処理中のパニックやエラーで接続を失わないように500を返す
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        let routed = match streamed_handler {
+                            Some(handler) => Some(handler.and_then(|handler| handler.finish(&request))),
+                            None => self.router.dispatch(&request, self.config.head_as_get),
+                        };
+                        match routed {
+                            Some(response) => response.map(Handled::Ready),
+                            // ファイルを参照しない/versionなどは、ドキュメントルートが読めなくても応答する
+                            None if !root_available => special_response(&request, &self.config)
+                                .and_then(|response| match response {
+                                    Some(response) => Ok(response),
+                                    None => service_unavailable(&self.config, ROOT_UNAVAILABLE_RETRY_AFTER),
+                                })
+                                .map(Handled::Ready),
+                            None => handle_request(&request, &root, &self.config),
+                        }
+                    }));
+                    match result {
+                        Ok(Ok(Handled::Ready(response))) => response,
+                        Ok(Ok(handled)) => {
+                            // CGIスクリプトの終了や、一時的なエラーで読めなかったファイルの読み直しを待つ間も
+                            // 他の接続を処理できるように別スレッドで実行し、終了したらdispatch_job_resultsで残りの処理をする
+                            let job_id = self.next_job;
+                            self.next_job += 1;
+                            let context = ResponseContext { started, received_at, closed, websocket, event_stream };
+                            let reread = PreloadedFiles::new();
+                            connection.pending_job = Some(PendingJob { job_id, request, context, reread });
+                            spawn_job(
+                                handled,
+                                JobTicket { conn_id, job_id },
+                                self.job_sender.clone(),
+                                Arc::clone(&self.event_sender.waker),
+                                Arc::clone(&self.clock),
+                            );
+                            return Ok(true);
+                        }
+                        Ok(Err(e)) => {
+                            error!("Error while handling {} {}: {}", request.method, request.target, e);
+                            create_msg_from_code(500, None)?
+                        }
+                        Err(_) => {
+                            error!("Panic while handling {} {}", request.method, request.target);
+                            create_msg_from_code(500, None)?
+                        }
+                    }
+                };
+                let context = ResponseContext { started, received_at, closed, websocket, event_stream };
+                return self.complete_response(conn_id, request, response, context).map(|()| true);
+            }
+            // リクエストの続きを待つ
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                if connection.state != ConnectionState::Processing {
+                    connection.set_state(ConnectionState::Processing);
+                }
+                if e == ParseError::Http2Preface {
+                    // HTTP/2に対応していないため、クライアントがHTTP/1.1でやり直せるように505を返す
+                    info!("HTTP/2 connection preface on conn_id {}; responding 505", conn_id);
+                } else {
+                    warn!("Bad request on conn_id {}: {}", conn_id, e);
+                }
+                // 不正なリクエストの後は接続を閉じる
+                connection.keep_alive = false;
+                let mut response = create_msg_from_code(e.status_code(), None)?;
+                if self.config.verbose_errors {
+                    // 固定の説明文だけを返し、受信したリクエストの内容は含めない
+                    response.body = format!("{} {}: {}\n", response.status_code, response.reason, e).into_bytes();
+                    response.add_header("Content-Type", "text/plain; charset=utf-8");
+                }
+                apply_status_page(&mut response, &self.status_pages);
+                response.add_header("Connection", "close");
+                response.to_bytes(&self.config, None)
+            }
+        };
+        connection.queue_response(response);
+        Ok(true)
+    }
+
+    /**
+    * 作成したレスポンスにAllowやConnection、ステータスページ、レスポンスフックなどを適用し、送信待ちの末尾に加える
+    */
+    fn complete_response(
+        &mut self,
+        conn_id: usize,
+        request: Request,
+        mut response: Response,
+        context: ResponseContext,
+    ) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        // Allowにはこのパスに登録したルートのメソッドも含める
+        for method in self.router.methods_for(&request, self.config.head_as_get) {
+            response.add_allowed_method(method);
+        }
+        // 大きなレスポンスで送信待ちの合計が上限を超える場合は、メモリを使い切らないように503に置き換える
+        let body_len = response.body.len();
+        let buffered = self.buffered_response_bytes.load(Ordering::Relaxed);
+        if body_len >= LARGE_RESPONSE_BYTES
+            && buffered + body_len > self.config.max_response_buffer_bytes
+        {
+            warn!(
+                "Response buffer limit reached on conn_id {} ({} bytes buffered); responding 503 to {} {}",
+                conn_id, buffered, request.method, request.target
+            );
+            response = service_unavailable(&self.config, RESPONSE_BUFFER_RETRY_AFTER)?;
+        }
+        // 101やイベントストリームを送信した後はHTTPのリクエストとして扱わない
+        connection.after_response = if context.websocket && response.status_code == 101 {
+            Some(ConnectionState::WebSocket)
+        } else if context.event_stream {
+            Some(ConnectionState::EventStream)
+        } else {
+            None
+        };
+        connection.keep_alive = self.config.keep_alive_timeout > 0
+            && !context.closed
+            && !self.shutting_down
+            && connection.after_response.is_none()
+            && request.wants_keep_alive();
+        if connection.keep_alive {
+            response.add_header("Connection", "keep-alive");
+            response.add_header(
+                "Keep-Alive",
+                &format!("timeout={}", self.config.keep_alive_timeout),
+            );
+        } else if connection.after_response.is_none() {
+            response.add_header("Connection", "close");
+        }
+        apply_status_page(&mut response, &self.status_pages);
+        for hook in &self.response_hooks {
+            hook(&request, &mut response);
+        }
+        // ルートやフックが付与したヘッダが大きすぎる場合は、そのまま送らずに500に置き換える
+        let head_len = response.head(&self.config, Some(request.path())).len();
+        if head_len > self.config.max_response_header_size {
+            let largest = response.headers.iter().max_by_key(|(name, value)| name.len() + value.len());
+            error!(
+                "Response headers for {} {} are {} bytes ({} fields, largest {:?}); responding 500",
+                request.method,
+                request.target,
+                head_len,
+                response.headers.len(),
+                largest.map(|(name, value)| (name, value.len()))
+            );
+            connection.keep_alive = false;
+            response = create_msg_from_code(500, None)?;
+            apply_status_page(&mut response, &self.status_pages);
+            response.add_header("Connection", "close");
+        }
+        let elapsed = self.clock.now().saturating_duration_since(context.started);
+        if self.config.slow_request_ms.is_some_and(|threshold| elapsed >= Duration::from_millis(threshold)) {
+            warn!(
+                "Slow request on conn_id {}: {} {} {} {}ms",
+                conn_id,
+                request.method,
+                request.target,
+                response.status_code,
+                elapsed.as_millis()
+            );
+        }
+        // 1xxを受け取れるHTTP/1.1のクライアントには、重要なリソースのLinkを先に103で送る
+        let early_hints = self.config.early_hints_for(request.path());
+        if !early_hints.is_empty() && request.version >= 1 && matches!(request.method.as_str(), "GET" | "HEAD") {
+            let mut hints = create_msg_from_code(103, None)?;
+            for link in early_hints {
+                hints.add_header("Link", link);
+                if response.status_code < 300 {
+                    response.add_header("Link", link);
+                }
+            }
+            connection.queue_response(hints.to_bytes(&self.config, Some(request.path())));
+        }
+        // プロキシがサーバープッシュに使えるように、成功したレスポンスにpreloadのLinkを付ける
+        if (200..300).contains(&response.status_code) && matches!(request.method.as_str(), "GET" | "HEAD") {
+            for link in self.config.preload_links_for(request.path()) {
+                // 103と同じLinkを既に付与している場合は重複させない
+                if !response.headers.iter().any(|(name, value)| name.eq_ignore_ascii_case("Link") && value == link) {
+                    response.add_header("Link", link);
+                }
+            }
+        }
+        // HEADにはGETと同じヘッダを返し、ボディは送信しない
+        response.omit_body = request.method == "HEAD";
+        self.request_stats.record(response.status_code, self.clock.now());
+        self.access_log.log_request(&RequestLog {
+            time: context.received_at,
+            remote_addr: request.remote_addr,
+            method: request.method.clone(),
+            target: request.target.clone(),
+            version: request.version,
+            status: response.status_code,
+            body_bytes: if response.omit_body { 0 } else { response.body.len() },
+            duration: elapsed,
+        });
+        let bytes = response.to_bytes(&self.config, Some(request.path()));
+        connection.queue_response(bytes);
+        Ok(())
+    }
+
+    /**
+    * 接続済みソケットで発生したイベントのハンドラ。
+    * エラーが発生した、またはClosingに遷移した接続はここで閉じる
+    */
+    fn http_handler(
+        &mut self,
+        conn_id: usize,
+        event: &Event,
+        poll: &Poll,
+    ) -> Result<(), Error> {
+        let state = self
+            .connections
+            .get(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?
+            .state;
+        let result = match state {
+            ConnectionState::ReadingRequest | ConnectionState::KeepAliveIdle(_)
+                if event.is_readable() =>
+            {
+                self.on_readable(conn_id, poll)
+            }
+            ConnectionState::WritingResponse if event.is_writable() => {
+                self.on_writable(conn_id, poll)
+            }
+            ConnectionState::ReadingRequest if event.is_writable() => self.on_tls_writable(conn_id, poll),
+            ConnectionState::WebSocket => self.on_websocket(conn_id, event, poll),
+            ConnectionState::EventStream => self.on_event_stream(conn_id, event, poll),
+            _ => {
+                debug!("Ignored event in state {:?} conn_id: {}", state, conn_id);
+                Ok(())
+            }
+        };
+
+        if let Some(connection) = self.connections.get_mut(&conn_id) {
+            if result.is_err() {
+                connection.set_state(ConnectionState::Closing);
+            }
+            if connection.state == ConnectionState::Closing {
+                self.connections.remove(&conn_id);
+            }
+        }
+        result
+    }
+
+    /**
+    * ソケットから読み込み可能
+    */
+    fn on_readable(&mut self, conn_id: usize, poll: &Poll) -> Result<(), Error> {
+        debug!("readable conn_id: {}", conn_id);
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if let ConnectionState::KeepAliveIdle(_) = connection.state {
+            connection.set_state(ConnectionState::ReadingRequest);
+        }
+        if connection.receives_plaintext_on_tls()? {
+            info!("Plaintext HTTP on TLS listener from {}; responding 400", connection.remote_addr);
+            // 以降は平文で送受信する。受信済みのリクエストは読み捨て、閉じる時にリセットしないようにする
+            connection.tls = None;
+            connection.read_available(&mut self.read_buffer)?;
+            connection.request_buffer.clear();
+            let mut response = create_msg_from_code(400, Some(self.config.tls_plaintext_message.clone().into_bytes()))?;
+            response.add_header("Content-Type", "text/plain; charset=utf-8");
+            respond_and_close(conn_id, connection, poll, response, &self.config, &self.status_pages)?;
+            return Ok(());
+        }
+        while let Some(connection) = self.connections.get_mut(&conn_id) {
+            connection.read_paused = false;
+            let handshaking = connection.tls.as_ref().is_some_and(|tls| tls.is_handshaking());
+            let closed = connection.read_available(&mut self.read_buffer)?;
+            if let Some(tls) = connection.tls.as_ref().filter(|tls| handshaking && !tls.is_handshaking()) {
+                debug!(
+                    "TLS handshake completed on conn_id {}: {:?}, ALPN {}",
+                    conn_id,
+                    tls.protocol_version(),
+                    alpn_protocol(tls)
+                );
+            }
+
+            if !self.process_requests(conn_id, poll, closed)? {
+                if closed {
+                    // リクエストが揃う前に通信終了
+                    if let Some(connection) = self.connections.get_mut(&conn_id) {
+                        connection.set_state(ConnectionState::Closing);
+                    }
+                } else {
+                    self.admit_upload(conn_id, poll)?;
+                }
+            }
+            // 読み込みを途中で止めた場合は、受信済みの分を処理してから続きを読む。
+            // レスポンスの送信を始めた場合は、次に読み込みを監視した時に続きを読む
+            if !self.connections.get(&conn_id).is_some_and(|connection| {
+                connection.read_paused && connection.state == ConnectionState::ReadingRequest
+            }) {
+                break;
+            }
+        }
+        if let Some(connection) = self.connections.get_mut(&conn_id) {
+            if connection.state == ConnectionState::ReadingRequest && connection.wants_write() {
+                // 送信しきれなかったハンドシェイクの応答は書き込み可能になった時に送る
+                poll.registry().reregister(
+                    &mut connection.stream,
+                    Token(conn_id),
+                    Interest::READABLE | Interest::WRITABLE,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    * リクエストの受信中に、送信しきれなかったTLSのデータを送信する
+    */
+    fn on_tls_writable(&mut self, conn_id: usize, poll: &Poll) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if let Some(tls) = &mut connection.tls {
+            if flush_tls(tls, &mut connection.stream)? {
+                poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::READABLE)?;
+            }
+        }
+        Ok(())
+    }
+
+    /**
+    * ボディを受信中のアップロードを数え、同時に受信できる数や合計サイズを超える場合は503を返して閉じる
+    */
+    fn admit_upload(&mut self, conn_id: usize, poll: &Poll) -> Result<(), Error> {
+        let (active_uploads, active_bytes) = self.active_uploads(conn_id);
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if connection.upload_bytes.is_some()
+            || connection.body_stream.is_some()
+            || connection.state != ConnectionState::ReadingRequest
+        {
+            return Ok(());
+        }
+        // ヘッダが揃っていない、またはボディがなければまだ数えない
+        let Ok(Some(head)) = parse_request_head(&connection.request_buffer) else {
+            return Ok(());
+        };
+        if head.content_length == 0 || !UPLOAD_METHODS.contains(&head.request.method.as_str()) {
+            return Ok(());
+        }
+        if upload_fits(&self.config, (active_uploads, active_bytes), head.content_length) {
+            connection.upload_bytes = Some(head.content_length);
+            return Ok(());
+        }
+        warn!(
+            "Rejected upload on conn_id {}: {} uploads ({} bytes) in progress",
+            conn_id, active_uploads, active_bytes
+        );
+        let response = service_unavailable(&self.config, UPLOAD_RETRY_AFTER)?;
+        respond_and_close(conn_id, connection, poll, response, &self.config, &self.status_pages)?;
+        Ok(())
+    }
+
+    /**
+    * conn_id以外の接続で受信中のアップロードの数とボディの合計バイト数
+    */
+    fn active_uploads(&self, conn_id: usize) -> (usize, usize) {
+        self.connections
+            .iter()
+            .filter(|(id, _)| **id != conn_id)
+            .filter_map(|(_, connection)| connection.upload_bytes)
+            .fold((0, 0), |(count, total), bytes| (count + 1, total + bytes))
+    }
+
+    /**
+    * ソケットに書き込み可能
+    */
+    fn on_writable(&mut self, conn_id: usize, poll: &Poll) -> Result<(), Error> {
+        debug!("writable conn_id: {}", conn_id);
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if !connection.write_responses()? {
+            // 残りは次に書き込み可能になった時に送る
+            return Ok(());
+        }
+        if connection.pending_job.is_some() {
+            // 先に処理したリクエストのレスポンスを送り終えたので、CGIが終了するまで待つ
+            connection.set_state(ConnectionState::Processing);
+            return Ok(());
+        }
+        match connection.after_response.take() {
+            Some(ConnectionState::WebSocket) => {
+                connection.set_state(ConnectionState::WebSocket);
+                // WebSocketではCloseを送信するまで接続を維持する
+                connection.keep_alive = true;
+                poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::READABLE)?;
+                // ハンドシェイクの直後に送られたフレームを受信済みであれば処理する
+                return self.process_frames(conn_id, poll);
+            }
+            Some(ConnectionState::EventStream) => {
+                connection.set_state(ConnectionState::EventStream);
+                // 切断を検知するために読み込みを監視する
+                poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::READABLE)?;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if !connection.keep_alive {
+            connection.set_state(ConnectionState::Closing);
+            return Ok(());
+        }
+        // パイプライン化された次のリクエストを受信済みであれば続けて処理する。
+        // request_timeoutの期限はここから数える
+        connection.request_started = connection.now();
+        if !self.process_requests(conn_id, poll, false)? {
+            // 次のリクエストを待つ
+            let connection = self.connections.get_mut(&conn_id).unwrap();
+            connection.set_state(ConnectionState::KeepAliveIdle(connection.now()));
+            poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::READABLE)?;
+        }
+        Ok(())
+    }
+
+    /**
+    * イベントストリームを送信している接続のイベント。
+    * クライアントから送られたデータは読み捨て、切断されたら閉じる
+    */
+    fn on_event_stream(&mut self, conn_id: usize, event: &Event, poll: &Poll) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if event.is_readable() {
+            let closed = connection.read_available(&mut self.read_buffer)?;
+            connection.request_buffer.clear();
+            if closed {
+                debug!("Event stream closed by client conn_id: {}", conn_id);
+                connection.set_state(ConnectionState::Closing);
+                return Ok(());
+            }
+        }
+        flush_event_stream(conn_id, connection, poll)
+    }
+
+    /**
+    * EventSenderから送られたイベントを全ての購読者の送信待ちに加えて送信する
+    */
+    fn dispatch_events(&mut self, poll: &Poll) {
+        let events: Vec<Vec<u8>> = self.event_receiver.try_iter().map(|data| format_event(&data)).collect();
+        if events.is_empty() {
+            return;
+        }
+        for (conn_id, connection) in self.connections.iter_mut() {
+            if connection.state != ConnectionState::EventStream {
+                continue;
+            }
+            for event in &events {
+                connection.queue_response(event.clone());
+            }
+            if let Err(e) = flush_event_stream(*conn_id, connection, poll) {
+                debug!("Failed to send events to conn_id {}: {}", conn_id, e);
+                connection.set_state(ConnectionState::Closing);
+            }
+        }
+        self.connections.retain(|_, connection| connection.state != ConnectionState::Closing);
+    }
+
+    /**
+    * 別スレッドで終了したCGIのレスポンスを、実行を待っていた接続の送信待ちに加えて送信する。
+    * ファイルを読み直した場合は、読み直した内容を使ってリクエストを処理し直す
+    */
+    fn dispatch_job_results(&mut self, poll: &Poll) {
+        while let Ok(JobResult { ticket, output }) = self.job_receiver.try_recv() {
+            let conn_id = ticket.conn_id;
+            // 実行中に閉じた接続や、同じIDで受け付けた別の接続には返さない
+            let Some(connection) = self.connections.get_mut(&conn_id) else {
+                continue;
+            };
+            if connection.pending_job.as_ref().is_none_or(|pending| pending.job_id != ticket.job_id) {
+                continue;
+            }
+            let Some(PendingJob { request, context, mut reread, .. }) = connection.pending_job.take() else {
+                continue;
+            };
+            let response = match output {
+                JobOutput::Response(response) => response.or_else(|e| {
+                    error!("CGI failed for {} {}: {}", request.method, request.target, e);
+                    create_msg_from_code(500, None)
+                }),
+                JobOutput::File(relative, Ok(file)) => {
+                    reread.insert(relative, file);
+                    let root = self.document_root(conn_id).with_files(reread.clone());
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| handle_request(&request, &root, &self.config)));
+                    match result {
+                        Ok(Ok(Handled::Ready(response))) => Ok(response),
+                        Ok(Ok(handled)) => {
+                            // 別のファイルも読めなかった場合は、それも読み直してから処理し直す
+                            let job_id = self.next_job;
+                            self.next_job += 1;
+                            if let Some(connection) = self.connections.get_mut(&conn_id) {
+                                connection.pending_job = Some(PendingJob { job_id, request, context, reread });
+                            }
+                            spawn_job(
+                                handled,
+                                JobTicket { conn_id, job_id },
+                                self.job_sender.clone(),
+                                Arc::clone(&self.event_sender.waker),
+                                Arc::clone(&self.clock),
+                            );
+                            continue;
+                        }
+                        Ok(Err(e)) => {
+                            error!("Error while handling {} {}: {}", request.method, request.target, e);
+                            create_msg_from_code(500, None)
+                        }
+                        Err(_) => {
+                            error!("Panic while handling {} {}", request.method, request.target);
+                            create_msg_from_code(500, None)
+                        }
+                    }
+                }
+                JobOutput::File(relative, Err(e)) => {
+                    error!("Failed to read {} for {} {}: {}", relative, request.method, request.target, e);
+                    create_msg_from_code(500, None)
+                }
+            };
+            let result = response
+                .and_then(|response| self.complete_response(conn_id, request, response, context))
+                .and_then(|()| {
+                    let connection = self
+                        .connections
+                        .get_mut(&conn_id)
+                        .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+                    // 先に処理したリクエストのレスポンスを送信中であれば、送り終えた時に続けて送る
+                    if connection.state != ConnectionState::WritingResponse {
+                        connection.set_state(ConnectionState::WritingResponse);
+                        poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::WRITABLE)?;
+                    }
+                    Ok(())
+                });
+            if let Err(e) = result {
+                error!("{}", e);
+                self.connections.remove(&conn_id);
+            }
+        }
+    }
+
+    /**
+    * WebSocketに切り替えた接続のイベント
+    */
+    fn on_websocket(&mut self, conn_id: usize, event: &Event, poll: &Poll) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        if event.is_readable() && connection.read_available(&mut self.read_buffer)? {
+            connection.set_state(ConnectionState::Closing);
+            return Ok(());
+        }
+        self.process_frames(conn_id, poll)
+    }
+
+    /**
+    * 受信済みのフレームを処理して応答を送信する。
+    * テキストとバイナリはそのまま返し、PingにはPong、CloseにはCloseを返して接続を閉じる
+    */
+    fn process_frames(&mut self, conn_id: usize, poll: &Poll) -> Result<(), Error> {
+        let connection = self
+            .connections
+            .get_mut(&conn_id)
+            .ok_or_else(|| Error::Internal(format!("Invalid connection ID {}", conn_id)))?;
+        // Closeを送った後は受信したフレームを無視する
+        while connection.keep_alive {
+            let (frame, len) = match parse_frame(&connection.request_buffer) {
+                Ok(Some(t)) => t,
+                Ok(None) => break,
+                Err(()) => {
+                    // 1002: プロトコルエラー
+                    warn!("Invalid WebSocket frame on conn_id {}", conn_id);
+                    connection.queue_response(encode_frame(OPCODE_CLOSE, &1002u16.to_be_bytes()));
+                    connection.keep_alive = false;
+                    break;
+                }
+            };
+            connection.consume_buffered(len);
+            match frame.opcode {
+                OPCODE_TEXT | OPCODE_BINARY => {
+                    connection.queue_response(encode_frame(frame.opcode, &frame.payload));
+                }
+                OPCODE_PING => connection.queue_response(encode_frame(OPCODE_PONG, &frame.payload)),
+                OPCODE_CLOSE => {
+                    connection.queue_response(encode_frame(OPCODE_CLOSE, &frame.payload));
+                    connection.keep_alive = false;
+                }
+                _ => {}
+            }
+        }
+        if connection.write_responses()? {
+            if !connection.keep_alive {
+                connection.set_state(ConnectionState::Closing);
+                return Ok(());
+            }
+            poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::READABLE)?;
+        } else {
+            // 送信しきれなかったフレームは書き込み可能になった時に送る
+            poll.registry().reregister(
+                &mut connection.stream,
+                Token(conn_id),
+                Interest::READABLE | Interest::WRITABLE,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/**
+* SNIのホスト名に対応しない接続のドキュメントルート。archiveがあればwebrootsより優先する
+*/
+fn base_root(webroots: &[PathBuf], archive: &Option<Rc<Archive>>) -> DocumentRoot {
+    match archive {
+        Some(archive) => DocumentRoot::Archive(archive.clone()),
+        None => DocumentRoot::Directories(webroots.to_vec()),
+    }
+}
+
+/**
+* User-Agentが小文字にしたblocked_user_agentsのいずれかを含むか
+*/
+fn is_blocked_user_agent(patterns: &[String], request: &Request) -> bool {
+    !patterns.is_empty()
+        && request.header("User-Agent").is_some_and(|user_agent| {
+            let user_agent = user_agent.to_ascii_lowercase();
+            patterns.iter().any(|pattern| user_agent.contains(pattern.as_str()))
+        })
+}
+
+/**
+* 大文字小文字を区別せずに照合するために小文字にしたパターン
+*/
+fn lowercase_patterns(patterns: &[String]) -> Vec<String> {
+    patterns.iter().map(|pattern| pattern.to_ascii_lowercase()).collect()
+}
+
+/**
+* 閉じていないリスニングソケット
+*/
+fn listener_socket(listener: &Listener) -> io::Result<&mio::net::TcpListener> {
+    listener
+        .socket
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "listener is closed"))
+}
+
+/**
+* debug_connectionsで返す接続の一覧のJSON。IDの順に並べる
+*/
+fn connections_json(connections: &HashMap<usize, Connection>) -> String {
+    let mut ids: Vec<&usize> = connections.keys().collect();
+    ids.sort();
+    let entries: Vec<String> = ids
+        .into_iter()
+        .map(|id| {
+            let connection = &connections[id];
+            format!(
+                "{{\"id\":{},\"state\":\"{}\",\"remote_addr\":\"{}\",\"tls\":{},\"bytes_read\":{},\"bytes_written\":{},\"age_ms\":{}}}",
+                id,
+                connection.state.name(),
+                connection.remote_addr,
+                connection.tls.is_some(),
+                connection.bytes_read,
+                connection.bytes_written,
+                connection.now().saturating_duration_since(connection.accepted_at).as_millis()
+            )
+        })
+        .collect();
+    format!("{{\"connections\":[{}]}}\n", entries.join(","))
+}
+
+/**
+* status_pagesのファイルを読み込む。読めないファイルがあれば設定のエラーとする
+*/
+fn load_status_pages(config: &Config) -> Result<StatusPages, Error> {
+    let mut pages = HashMap::new();
+    for page in &config.status_pages {
+        let body = fs::read(&page.file)
+            .map_err(|e| Error::Config(format!("Failed to read status page {}: {}", page.file, e)))?;
+        let content_type = match page.content_type.as_deref().or_else(|| content_type_for(&page.file)) {
+            Some(content_type) => content_type.to_string(),
+            None => {
+                return Err(Error::Config(format!(
+                    "Unknown content type of status page {}; set content_type",
+                    page.file
+                )))
+            }
+        };
+        pages.insert(page.status, (content_type, body));
+    }
+    Ok(pages)
+}
+
+/**
+* ボディのないレスポンスに、ステータスコードに対応するstatus_pagesのボディを付ける
+*/
+fn apply_status_page(response: &mut Response, status_pages: &StatusPages) {
+    if !response.body.is_empty() || response.stream {
+        return;
+    }
+    if let Some((content_type, body)) = status_pages.get(&response.status_code) {
+        response.headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+        response.add_header("Content-Type", content_type);
+        response.body = body.clone();
+    }
+}
+
+/**
+* maintenance_pageのファイルを読み込む。ファイルがなければNone(メンテナンス中でない)を返す
+*/
+fn load_maintenance_page(config: &Config) -> Option<Vec<u8>> {
+    let path = config.maintenance_page.as_ref()?;
+    match fs::read(path) {
+        Ok(page) => Some(page),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => {
+            error!("Failed to read maintenance page {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/**
+* 503のレスポンス。retry_afterを設定していない場合はRetry-Afterをdefault_retry_after秒とする
+*/
+fn service_unavailable(config: &Config, default_retry_after: u64) -> Result<Response, Error> {
+    let mut response = create_msg_from_code(503, None)?;
+    if config.retry_after.is_none() {
+        response.add_header("Retry-After", &default_retry_after.to_string());
+    }
+    Ok(response)
+}
+
+/**
+* 設定のドキュメントルートを絶対パスにする。snapshot_root有効時はシンボリックリンクも解決する。
+* リクエストごとにカレントディレクトリを参照しないように絶対パスにしておく
+*/
+fn resolve_webroots(config: &Config) -> Result<Vec<PathBuf>, Error> {
+    if config.snapshot_root {
+        resolve_snapshot_root(&config.webroots)
+    } else {
+        Ok(config.webroots.iter().map(path::absolute).collect::<io::Result<_>>()?)
+    }
+}
+
+/**
+* archive設定時に配信元とするアーカイブを開く
+*/
+fn open_archive(config: &Config) -> Result<Option<Rc<Archive>>, Error> {
+    match &config.archive {
+        Some(path) => Ok(Some(Rc::new(Archive::open(path)?))),
+        None => Ok(None),
+    }
+}
+
+/**
+* health_check_path設定時にヘルスチェックに返すレスポンス
+*/
+fn health_check_responses(config: &Config) -> Result<Option<HealthCheckResponses>, Error> {
+    match config.health_check_path {
+        Some(_) => Ok(Some((health_check_response(config, true)?, health_check_response(config, false)?))),
+        None => Ok(None),
+    }
+}
+
+/**
+* ヘルスチェックに返す200のレスポンス。レスポンスフックは適用しない
+*/
+fn health_check_response(config: &Config, keep_alive: bool) -> Result<Vec<u8>, Error> {
+    let mut response = create_msg_from_code(200, None)?;
+    if keep_alive {
+        response.add_header("Connection", "keep-alive");
+        response.add_header("Keep-Alive", &format!("timeout={}", config.keep_alive_timeout));
+    } else {
+        response.add_header("Connection", "close");
+    }
+    Ok(response.to_bytes(config, config.health_check_path.as_deref()))
+}
+
+/**
+* 受信途中のリクエストに応答を返して接続を閉じる。タイムアウトや受け付けの制限に使う
+*/
+fn respond_and_close(
+    conn_id: usize,
+    connection: &mut Connection,
+    poll: &Poll,
+    mut response: Response,
+    config: &Config,
+    status_pages: &StatusPages,
+) -> io::Result<()> {
+    connection.set_state(ConnectionState::Processing);
+    connection.keep_alive = false;
+    connection.upload_bytes = None;
+    // request_timeoutを過ぎていてもこの応答を送信できるように期限を改める
+    connection.request_started = connection.now();
+    apply_status_page(&mut response, status_pages);
+    response.add_header("Connection", "close");
+    connection.queue_response(response.to_bytes(config, None));
+    connection.set_state(ConnectionState::WritingResponse);
+    poll.registry().reregister(&mut connection.stream, Token(conn_id), Interest::WRITABLE)
+}
+
+/**
+* 受信中のアップロード(数と合計バイト数)に長さlenのアップロードを加えても上限に収まるか
+*/
+fn upload_fits(config: &Config, (active_uploads, active_bytes): (usize, usize), len: usize) -> bool {
+    active_uploads < config.max_concurrent_uploads && active_bytes + len <= config.max_upload_bytes
+}
+
+/**
+* イベントストリームの送信待ちのイベントを送信する。送信しきれなければ書き込みも監視する
+*/
+fn flush_event_stream(
+    conn_id: usize,
+    connection: &mut Connection,
+    poll: &Poll,
+) -> Result<(), Error> {
+    let interest = if connection.write_responses()? {
+        Interest::READABLE
+    } else {
+        Interest::READABLE | Interest::WRITABLE
+    };
+    poll.registry().reregister(&mut connection.stream, Token(conn_id), interest)?;
+    Ok(())
+}
+
+/**
+* max_connectionsに対してファイルディスクリプタの上限(RLIMIT_NOFILE)が足りるか確認する。
+* raise_fd_limitが有効な場合はソフトリミットをハードリミットまで引き上げてから確認する
+*/
+fn check_fd_limit(config: &Config) {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: limitは有効なrlimitへのポインタ
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        warn!("Failed to get the file descriptor limit: {}", io::Error::last_os_error());
+        return;
+    }
+    let required = (config.max_connections as libc::rlim_t).saturating_add(RESERVED_FDS);
+    if config.raise_fd_limit && limit.rlim_cur < required && limit.rlim_cur < limit.rlim_max {
+        let raised = libc::rlimit { rlim_cur: limit.rlim_max, rlim_max: limit.rlim_max };
+        // SAFETY: raisedは有効なrlimitへのポインタ
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            info!("Raised the file descriptor limit from {} to {}", limit.rlim_cur, raised.rlim_cur);
+            limit = raised;
+        } else {
+            warn!("Failed to raise the file descriptor limit: {}", io::Error::last_os_error());
+        }
+    }
+    if limit.rlim_cur < required {
+        warn!(
+            "File descriptor limit {} is lower than max_connections {} plus {} reserved; \
+             accept and open may fail under load",
+            limit.rlim_cur, config.max_connections, RESERVED_FDS
+        );
+    }
+}
+
+/**
+* acceptがプロセスまたはシステムのファイルディスクリプタ不足で失敗したか
+*/
+fn is_resource_exhausted(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE))
+}
+
+/**
+* 受け付けた接続にTCPキープアライブを設定する
+*/
+fn set_tcp_keepalive(stream: &mio::net::TcpStream, config: &crate::config::TcpKeepalive) -> io::Result<()> {
+    let mut keepalive = TcpKeepalive::new().with_time(Duration::from_secs(config.idle));
+    if let Some(interval) = config.interval {
+        keepalive = keepalive.with_interval(Duration::from_secs(interval));
+    }
+    if let Some(count) = config.count {
+        keepalive = keepalive.with_retries(count);
+    }
+    // SAFETY: streamが所有するファイルディスクリプタは、この関数の間は閉じられない
+    let fd = unsafe { BorrowedFd::borrow_raw(stream.as_raw_fd()) };
+    SockRef::from(&fd).set_tcp_keepalive(&keepalive)
+}
+
+/**
+* CGIスクリプトの実行またはファイルの読み直しを別スレッドで行い、終了したら結果を送ってイベントループを起こす
+*/
+fn spawn_job(handled: Handled, ticket: JobTicket, sender: Sender<JobResult>, waker: Arc<Mutex<Waker>>, clock: Arc<dyn Clock>) {
+    thread::spawn(move || {
+        let output = match handled {
+            Handled::Ready(response) => JobOutput::Response(Ok(response)),
+            // パニックしても接続がProcessingのまま残らないように500を返す
+            Handled::Cgi(job) => JobOutput::Response(
+                panic::catch_unwind(AssertUnwindSafe(|| job.run(clock.as_ref())))
+                    .unwrap_or_else(|_| Err(Error::Internal("Panic while running a CGI script".to_string()))),
+            ),
+            Handled::Read(job) => JobOutput::File(job.relative().to_string(), job.run()),
+        };
+        // サーバが停止していれば結果は捨てる
+        if sender.send(JobResult { ticket, output }).is_ok() {
+            if let Err(e) = waker.lock().unwrap_or_else(|e| e.into_inner()).wake() {
+                error!("Failed to wake the event loop: {}", e);
+            }
+        }
+    });
+}
+
+/**
+* backlogを指定してリスニングソケットを作成する
+*/
+fn bind_listener(
+    address: SocketAddr,
+    backlog: i32,
+) -> Result<mio::net::TcpListener, Error> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    // mioのTcpListener::bindと同様にSO_REUSEADDRを設定する
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(backlog)?;
+    Ok(mio::net::TcpListener::from_std(socket.into()))
+}
+
+/**
+* systemdなどのソケットアクティベーション(LISTEN_PIDとLISTEN_FDS)で渡されたリスニングソケット。
+* 自分宛てに渡されていなければNoneを返す。複数渡された場合は最初のものだけを使う。
+* 環境変数は他のスレッドと競合しないように変更しない。CGIのスクリプトには環境変数を引き継がない
+*/
+fn activated_listener() -> Result<Option<mio::net::TcpListener>, Error> {
+    let for_this_process = env::var("LISTEN_PID").is_ok_and(|pid| pid.parse() == Ok(process::id()));
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<i32>().ok()).unwrap_or(0);
+    if !for_this_process || count <= 0 {
+        return Ok(None);
+    }
+    if count > 1 {
+        warn!("{} sockets were passed by socket activation; only the first one is used", count);
+    }
+    // SAFETY: LISTEN_FDSの数だけLISTEN_FDS_STARTから連続したファイルディスクリプタが渡され、他では所有していない
+    let socket = unsafe { Socket::from_raw_fd(LISTEN_FDS_START) };
+    // 渡されたファイルディスクリプタにはFD_CLOEXECが設定されていないので、CGIのスクリプトに引き継がないようにする
+    // SAFETY: socketは有効なファイルディスクリプタを所有している
+    if unsafe { libc::fcntl(LISTEN_FDS_START, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if socket.r#type()? != Type::STREAM || socket.local_addr()?.as_socket().is_none() {
+        return Err(Error::Config("the socket passed by socket activation is not a TCP socket".to_string()));
+    }
+    socket.set_nonblocking(true)?;
+    Ok(Some(mio::net::TcpListener::from_std(socket.into())))
+}
+
+/**
+* ドキュメントルートのシンボリックリンクを解決した絶対パスを返す
+*/
+fn resolve_snapshot_root(webroots: &[String]) -> Result<Vec<PathBuf>, Error> {
+    webroots
+        .iter()
+        .map(|root| Ok(fs::canonicalize(root)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+
+    #[test]
+    fn webroots_are_made_absolute_once() {
+        let absolute = env::temp_dir().join("web-server-absolute-root");
+        let config = Config {
+            webroots: vec!["webroot".to_string(), absolute.to_string_lossy().into_owned()],
+            ..Config::default()
+        };
+        let roots = resolve_webroots(&config).unwrap();
+        assert_eq!(roots, [env::current_dir().unwrap().join("webroot"), absolute]);
+    }
+
+    #[test]
+    fn repeated_poll_failures_restart_then_stop() {
+        let start = Instant::now();
+        let mut watchdog = PollWatchdog::default();
+        for _ in 0..MAX_POLL_RESTARTS {
+            for failures in 1..POLL_FAILURES_BEFORE_RESTART {
+                let recovery = watchdog.on_failure(start);
+                assert!(matches!(recovery, PollRecovery::Retry(backoff) if backoff <= MAX_POLL_FAILURE_BACKOFF));
+                assert_eq!(watchdog.failures, failures);
+            }
+            assert_eq!(watchdog.on_failure(start), PollRecovery::Restart);
+        }
+        for _ in 1..POLL_FAILURES_BEFORE_RESTART {
+            watchdog.on_failure(start);
+        }
+        assert_eq!(watchdog.on_failure(start), PollRecovery::Stop);
+
+        // 作り直した後に正常に動作し続ければ、作り直せる回数が戻る
+        let mut watchdog = PollWatchdog::default();
+        for _ in 0..POLL_FAILURES_BEFORE_RESTART {
+            watchdog.on_failure(start);
+        }
+        assert_eq!(watchdog.restarts, 1);
+        assert!(!watchdog.on_success(start + POLL_RESTART_RESET / 2));
+        assert_eq!(watchdog.failures, 0);
+        assert!(watchdog.on_success(start + POLL_RESTART_RESET));
+        assert_eq!(watchdog.restarts, 0);
+    }
+
+    #[test]
+    fn restarted_poll_receives_events_for_existing_sockets() {
+        let mut server = WebServer::new("127.0.0.1:0", Config::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let old_poll = server.poll.take().unwrap();
+        let mut signals = Signals::new([SIGHUP]).unwrap();
+        server.register_sources(&old_poll, &mut signals).unwrap();
+        // 作り直す前に届いていた接続は、作り直した時に受け付ける
+        let mut client = std::net::TcpStream::connect(addr).unwrap();
+        let mut poll = server.restart_poll(&old_poll, &mut signals).unwrap();
+        assert_eq!(server.connections.len(), 1);
+        let conn_id = *server.connections.keys().next().unwrap();
+
+        let mut events = Events::with_capacity(16);
+        client.write_all(b"GET / HTTP/1.0\r\n\r\n").unwrap();
+        server.event_sender().waker.lock().unwrap().wake().unwrap();
+        let mut tokens = Vec::new();
+        while !(tokens.contains(&Token(conn_id)) && tokens.contains(&WAKER)) {
+            poll.poll(&mut events, Some(Duration::from_secs(5))).unwrap();
+            assert!(!events.is_empty(), "timed out with {:?}", tokens);
+            tokens.extend(events.iter().map(|event| event.token()));
+        }
+    }
+
+    #[test]
+    fn connection_ids_skip_live_connections_and_wrap_before_reserved_tokens() {
+        let mut server = WebServer::new("127.0.0.1:0", Config::default()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let poll = server.poll.take().unwrap();
+        let mut clients = Vec::new();
+        let mut accept = |server: &mut WebServer| {
+            let client = std::net::TcpStream::connect(addr).unwrap();
+            let before: Vec<usize> = server.connections.keys().copied().collect();
+            server.accept_connections(&poll, 0);
+            let conn_id = *server.connections.keys().find(|id| !before.contains(id)).unwrap();
+            assert_eq!(server.connections[&conn_id].remote_addr, client.local_addr().unwrap());
+            clients.push(client);
+            conn_id
+        };
+        assert_eq!(accept(&mut server), 1);
+        assert_eq!(accept(&mut server), 2);
+        // IDが一周して使用中のIDに戻っても、既存の接続を上書きせずに空いているIDを使う
+        server.next_connection_id = 1;
+        assert_eq!(accept(&mut server), 3);
+
+        let reserved = LISTENER_BASE + 1 - server.listeners.len();
+        server.next_connection_id = reserved - 1;
+        assert_eq!(accept(&mut server), reserved - 1);
+        assert_eq!(accept(&mut server), 4);
+        assert_eq!(server.connections.len(), 5);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn tcp_keepalive_is_applied_to_accepted_sockets() {
+        let keepalive = crate::config::TcpKeepalive { idle: 30, interval: Some(5), count: Some(4) };
+        let enabled = Config { tcp_keepalive: Some(keepalive), ..Config::default() };
+        for (config, expected) in [(Config::default(), false), (enabled, true)] {
+            let mut server = WebServer::new("127.0.0.1:0", config).unwrap();
+            let poll = server.poll.take().unwrap();
+            let _client = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+            server.accept_connections(&poll, 0);
+            let connection = server.connections.values().next().unwrap();
+            // SAFETY: connectionが所有するファイルディスクリプタは、このブロックの間は閉じられない
+            let fd = unsafe { BorrowedFd::borrow_raw(connection.stream.as_raw_fd()) };
+            let socket = SockRef::from(&fd);
+            assert_eq!(socket.keepalive().unwrap(), expected);
+            if expected {
+                assert_eq!(socket.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+                assert_eq!(socket.tcp_keepalive_interval().unwrap(), Duration::from_secs(5));
+                assert_eq!(socket.tcp_keepalive_retries().unwrap(), 4);
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use mio::Waker;
+use crate::error::{Error, Result};
+use crate::response::{create_msg_from_code, Response};
+
+/**
+* Server-Sent Eventsの購読者にイベントを送るためのハンドル。
+* 別スレッドから送信でき、送信するとイベントループを起こして配信させる
+*/
+#[derive(Clone)]
+pub struct EventSender {
+    pub(crate) sender: Sender<String>,
+    // イベントループがPollを作り直した場合は、新しいPollに登録したWakerに置き換わる
+    pub(crate) waker: Arc<Mutex<Waker>>,
+}
+
+impl EventSender {
+    /**
+    * 全ての購読者にdataをイベントとして送る
+    */
+    pub fn send(&self, data: &str) -> Result<(), Error> {
+        self.sender
+            .send(data.to_string())
+            .map_err(|_| Error::Internal("Server has stopped".to_string()))?;
+        self.waker.lock().map_err(|_| Error::Internal("Server has stopped".to_string()))?.wake()?;
+        Ok(())
+    }
+}
+
+/**
+* イベントストリームを開始するレスポンス。ボディは接続を閉じるまで送り続ける
+*/
+pub(crate) fn event_stream_response() -> Result<Response, Error> {
+    let mut response = create_msg_from_code(200, None)?;
+    response.add_header("Content-Type", "text/event-stream");
+    response.add_header("Cache-Control", "no-cache");
+    response.stream = true;
+    Ok(response)
+}
+
+/**
+* dataをイベントの形式にする。複数行のデータは行ごとにdataフィールドにする
+*/
+pub(crate) fn format_event(data: &str) -> Vec<u8> {
+    let mut event = String::new();
+    for line in data.lines() {
+        event.push_str("data: ");
+        event.push_str(line);
+        event.push('\n');
+    }
+    if data.is_empty() {
+        event.push_str("data: \n");
+    }
+    event.push('\n');
+    event.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_line_becomes_a_data_field() {
+        assert_eq!(format_event("hello"), b"data: hello\n\n");
+        assert_eq!(format_event("a\nb"), b"data: a\ndata: b\n\n");
+        assert_eq!(format_event(""), b"data: \n\n");
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// 1秒あたりのリクエスト数を平均する期間
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+
+/**
+* 処理したリクエストの件数。合計、ステータスコードごとの件数、直近RATE_WINDOWの1秒ごとの件数を数える
+*/
+pub(crate) struct RequestStats {
+    started_at: Instant,
+    total: u64,
+    status_counts: BTreeMap<u16, u64>,
+    // 起動からの秒数とその1秒間の件数。RATE_WINDOWより古いものは捨てる
+    recent: VecDeque<(u64, u64)>,
+}
+
+impl RequestStats {
+    pub(crate) fn new(now: Instant) -> Self {
+        RequestStats { started_at: now, total: 0, status_counts: BTreeMap::new(), recent: VecDeque::new() }
+    }
+
+    /**
+    * レスポンスを返したリクエストを1件数える
+    */
+    pub(crate) fn record(&mut self, status_code: u16, now: Instant) {
+        self.total += 1;
+        *self.status_counts.entry(status_code).or_insert(0) += 1;
+        let second = now.saturating_duration_since(self.started_at).as_secs();
+        match self.recent.back_mut() {
+            Some((last, count)) if *last == second => *count += 1,
+            _ => self.recent.push_back((second, 1)),
+        }
+        self.discard_old(second);
+    }
+
+    /**
+    * 直近RATE_WINDOWの1秒あたりのリクエスト数。起動からRATE_WINDOWが経っていなければ経過時間で割る
+    */
+    pub(crate) fn requests_per_second(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.started_at);
+        self.discard_old(elapsed.as_secs());
+        let count: u64 = self.recent.iter().map(|(_, count)| count).sum();
+        let window = elapsed.min(RATE_WINDOW).as_secs_f64().max(1.0);
+        count as f64 / window
+    }
+
+    /**
+    * /statusで返すHTML。接続数、リクエストの合計と1秒あたりの件数、ステータスコードごとの件数を表示する
+    */
+    pub(crate) fn render_html(&mut self, active_connections: usize, now: Instant) -> String {
+        let rate = self.requests_per_second(now);
+        let rows: String = self
+            .status_counts
+            .iter()
+            .map(|(status, count)| format!("<tr><td>{}</td><td>{}</td></tr>\n", status, count))
+            .collect();
+        format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Server status</title></head>\n<body>\n\
+             <h1>Server status</h1>\n<ul>\n<li>Uptime: {}s</li>\n<li>Active connections: {}</li>\n\
+             <li>Total requests: {}</li>\n<li>Requests per second (last {}s): {:.2}</li>\n</ul>\n\
+             <table>\n<tr><th>Status</th><th>Requests</th></tr>\n{}</table>\n</body>\n</html>\n",
+            now.saturating_duration_since(self.started_at).as_secs(),
+            active_connections,
+            self.total,
+            RATE_WINDOW.as_secs(),
+            rate,
+            rows
+        )
+    }
+
+    fn discard_old(&mut self, second: u64) {
+        while self.recent.front().is_some_and(|(oldest, _)| oldest + RATE_WINDOW.as_secs() <= second) {
+            self.recent.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_is_averaged_over_the_recent_window() {
+        let start = Instant::now();
+        let mut stats = RequestStats::new(start);
+        for _ in 0..5 {
+            stats.record(200, start);
+        }
+        stats.record(404, start + Duration::from_millis(500));
+        // 起動から1秒未満は1秒として割る
+        assert_eq!(stats.requests_per_second(start + Duration::from_millis(900)), 6.0);
+        assert_eq!(stats.requests_per_second(start + Duration::from_secs(3)), 2.0);
+        stats.record(200, start + Duration::from_secs(9));
+        assert_eq!(stats.requests_per_second(start + Duration::from_secs(10)), 0.1);
+        // 窓を過ぎた件数は1秒あたりの件数から外れるが、合計とステータスコードごとの件数には残る
+        assert_eq!(stats.requests_per_second(start + Duration::from_secs(30)), 0.0);
+        assert_eq!(stats.total, 7);
+        assert_eq!(stats.status_counts[&200], 6);
+        assert_eq!(stats.status_counts[&404], 1);
+    }
+
+    #[test]
+    fn html_lists_connections_totals_and_status_counts() {
+        let start = Instant::now();
+        let mut stats = RequestStats::new(start);
+        stats.record(200, start);
+        stats.record(503, start);
+        let html = stats.render_html(3, start + Duration::from_secs(2));
+        assert!(html.contains("<li>Uptime: 2s</li>"), "{}", html);
+        assert!(html.contains("<li>Active connections: 3</li>"), "{}", html);
+        assert!(html.contains("<li>Total requests: 2</li>"), "{}", html);
+        assert!(html.contains("<li>Requests per second (last 10s): 1.00</li>"), "{}", html);
+        assert!(html.contains("<tr><td>200</td><td>1</td></tr>\n<tr><td>503</td><td>1</td></tr>"), "{}", html);
+    }
+}
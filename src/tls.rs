@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::sync::Arc;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection};
+use crate::config::TlsConfig;
+use crate::error::{Error, Result};
+
+// ALPNで提示するプロトコル。HTTP/1.1のみに対応する
+const ALPN_PROTOCOLS: [&[u8]; 1] = [b"http/1.1"];
+
+/**
+* 証明書と秘密鍵のPEMファイルを読み込んでTLSのサーバ設定を作る。
+* sniを設定した場合はClientHelloのSNIのホスト名で証明書を選ぶ
+*/
+pub(crate) fn load_server_config(tls: &TlsConfig) -> Result<Arc<ServerConfig>, Error> {
+    let provider = rustls::crypto::ring::default_provider();
+    let default = Arc::new(load_certified_key(&tls.cert, &tls.key, &provider)?);
+    let mut by_name = HashMap::new();
+    for sni in &tls.sni {
+        let key = load_certified_key(&sni.cert, &sni.key, &provider)?;
+        by_name.insert(sni.name.to_ascii_lowercase(), Arc::new(key));
+    }
+    let mut config = ServerConfig::builder_with_provider(Arc::new(provider))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(SniResolver { default, by_name }));
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(config))
+}
+
+/**
+* ALPNで合意したプロトコル。クライアントがALPNを使わなかった場合やハンドシェイク中は"none"を返す
+*/
+pub(crate) fn alpn_protocol(tls: &ServerConnection) -> String {
+    tls.alpn_protocol().map_or_else(|| "none".to_string(), |protocol| String::from_utf8_lossy(protocol).into_owned())
+}
+
+/**
+* 証明書(チェーン)と秘密鍵のPEMファイルを読み込み、対応しているかを確認する
+*/
+fn load_certified_key(cert: &str, key: &str, provider: &CryptoProvider) -> Result<CertifiedKey, Error> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::Config(format!("Failed to read certificate {}: {}", cert, e)))?;
+    if certs.is_empty() {
+        return Err(Error::Config(format!("No certificate found in {}", cert)));
+    }
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| Error::Config(format!("Failed to read private key {}: {}", key, e)))?;
+    CertifiedKey::from_der(certs, key, provider)
+        .map_err(|e| Error::Config(format!("Invalid certificate or private key: {}", e)))
+}
+
+/**
+* SNIのホスト名(小文字)に一致する証明書を選び、一致しなければdefaultを使う
+*/
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+        Some(key.clone())
+    }
+}
+
+/**
+* WouldBlockになるまでソケットから読み込んで復号し、平文をrequest_bufferに溜める。
+* 相手が接続を閉じた場合はtrueを返す。ハンドシェイクの応答などの送信すべきデータは可能な限り送信する
+*/
+pub(crate) fn read_tls(
+    tls: &mut ServerConnection,
+    stream: &mut mio::net::TcpStream,
+    request_buffer: &mut Vec<u8>,
+    buffer: &mut [u8],
+) -> io::Result<bool> {
+    loop {
+        let (closed, would_block) = match tls.read_tls(stream) {
+            Ok(0) => (true, false),
+            Ok(_) => (false, false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (false, true),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Err(e) = tls.process_new_packets() {
+            // クライアントにアラートを送ってから閉じる
+            let _ = flush_tls(tls, stream);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+        // 平文のバッファが埋まると次のread_tlsが失敗するため、読み込むたびに取り出す
+        loop {
+            match tls.reader().read(buffer) {
+                // close_notifyを受信した
+                Ok(0) => return Ok(true),
+                Ok(nbytes) => request_buffer.extend_from_slice(&buffer[..nbytes]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // close_notifyを送らずに切断された
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(true),
+                Err(e) => return Err(e),
+            }
+        }
+        if closed {
+            return Ok(true);
+        }
+        if would_block {
+            break;
+        }
+    }
+    flush_tls(tls, stream)?;
+    Ok(false)
+}
+
+/**
+* 送信待ちの暗号化済みデータをWouldBlockになるまで送信する。全て送信できればtrueを返す
+*/
+pub(crate) fn flush_tls(tls: &mut ServerConnection, stream: &mut mio::net::TcpStream) -> io::Result<bool> {
+    while tls.wants_write() {
+        match tls.write_tls(stream) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,164 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::http_date::parse_http_date;
+use crate::path::to_relative_path;
+use crate::request::Request;
+use crate::response::{create_msg_from_code, Response};
+
+// ボディを受け取ってファイルに書き込むメソッド
+pub(crate) const UPLOAD_METHODS: [&str; 3] = ["PUT", "POST", "PATCH"];
+// PATCHで更新した後のファイルの最大長
+const MAX_PATCHED_FILE_LEN: u64 = 64 * 1024 * 1024;
+
+/**
+* アップロード先のパス。パスが不正な場合はNoneを返す
+*/
+fn upload_path(upload_root: &str, target: &str, config: &Config) -> Option<PathBuf> {
+    let relative = to_relative_path(target)?;
+    if relative.is_empty() || relative.ends_with('/') || config.is_blocked_extension(&relative) {
+        return None;
+    }
+    let path = Path::new(upload_root).join(relative);
+    is_inside_upload_root(Path::new(upload_root), &path).then_some(path)
+}
+
+/**
+* pathがシンボリックリンクを辿ってもupload_rootの中にあるか。
+* まだ存在しない部分は書き込み時にディレクトリとして作るので、存在する最も深い祖先(path自身を含む)を正規化して比べる。
+* リンク先が存在しないシンボリックリンクは書き込むとリンク先に作成されるので外として扱う
+*/
+fn is_inside_upload_root(upload_root: &Path, path: &Path) -> bool {
+    let Ok(root) = fs::canonicalize(upload_root) else {
+        return false;
+    };
+    let Some(existing) = path.ancestors().find(|ancestor| fs::symlink_metadata(ancestor).is_ok()) else {
+        return false;
+    };
+    fs::canonicalize(existing).is_ok_and(|existing| existing.starts_with(root))
+}
+
+/**
+* PUTのボディをアップロード先に書き込む。新しく作成した場合は201、上書きした場合は204を返す
+*/
+pub(crate) fn put_file(
+    request: &Request,
+    upload_root: &str,
+    config: &Config,
+) -> Result<Response, Error> {
+    let Some(path) = upload_path(upload_root, request.path(), config) else {
+        return create_msg_from_code(403, None);
+    };
+    if path.is_dir() {
+        return create_msg_from_code(403, None);
+    }
+    if !is_unmodified_since(request, &path) {
+        return create_msg_from_code(412, None);
+    }
+    let existed = path.is_file();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&path, &request.body)?;
+    if existed {
+        create_msg_from_code(204, None)
+    } else {
+        let mut response = create_msg_from_code(201, None)?;
+        response.add_header("Location", request.path());
+        Ok(response)
+    }
+}
+
+/**
+* アップロード先のファイルを削除する。存在しない場合は404を返す
+*/
+pub(crate) fn delete_file(
+    request: &Request,
+    upload_root: &str,
+    config: &Config,
+) -> Result<Response, Error> {
+    let Some(path) = upload_path(upload_root, request.path(), config) else {
+        return create_msg_from_code(403, None);
+    };
+    if !is_unmodified_since(request, &path) {
+        return create_msg_from_code(412, None);
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => create_msg_from_code(204, None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => create_msg_from_code(404, None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/**
+* PATCHのボディでアップロード先の既存のファイルを部分的に更新する。
+* "Content-Range: bytes 開始-終了/全体の長さ"(全体の長さは"*"でもよい)があれば開始位置に書き込み、なければ末尾に追記する。
+* 開始位置はファイルの長さ以下とし(穴は作らない)、成功した場合は204を返す
+*/
+pub(crate) fn patch_file(
+    request: &Request,
+    upload_root: &str,
+    config: &Config,
+) -> Result<Response, Error> {
+    let Some(path) = upload_path(upload_root, request.path(), config) else {
+        return create_msg_from_code(403, None);
+    };
+    if !path.is_file() {
+        return create_msg_from_code(404, None);
+    }
+    if !is_unmodified_since(request, &path) {
+        return create_msg_from_code(412, None);
+    }
+    let len = fs::metadata(&path)?.len();
+    let offset = match request.header("Content-Range") {
+        Some(value) => match parse_content_range(value, request.body.len()) {
+            Some(offset) if offset <= len => offset,
+            Some(_) => {
+                let mut response = create_msg_from_code(416, None)?;
+                response.add_header("Content-Range", &format!("bytes */{}", len));
+                return Ok(response);
+            }
+            None => return create_msg_from_code(400, None),
+        },
+        None => len,
+    };
+    if offset + request.body.len() as u64 > MAX_PATCHED_FILE_LEN {
+        return create_msg_from_code(413, None);
+    }
+    let mut file = OpenOptions::new().write(true).open(&path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    file.write_all(&request.body)?;
+    create_msg_from_code(204, None)
+}
+
+/**
+* "bytes 100-199/1000"の形式のContent-Rangeから開始位置を取り出す。全体の長さは検証しない。
+* 範囲の長さがボディの長さと一致しない場合はNoneを返す
+*/
+fn parse_content_range(value: &str, body_len: usize) -> Option<u64> {
+    let (range, _complete_len) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let (start, end) = range.split_once('-')?;
+    let start: u64 = start.parse().ok()?;
+    let end: u64 = end.parse().ok()?;
+    (end >= start && end - start + 1 == body_len as u64).then_some(start)
+}
+
+/**
+* If-Unmodified-Sinceの日時以降にファイルが更新されていないか。
+* ヘッダがない、日時が不正、またはファイルが存在しない場合は条件を無視してtrueを返す
+*/
+fn is_unmodified_since(request: &Request, path: &Path) -> bool {
+    let Some(since) = request.header("If-Unmodified-Since").and_then(parse_http_date) else {
+        return true;
+    };
+    let Ok(modified) = fs::metadata(path).and_then(|metadata| metadata.modified()) else {
+        return true;
+    };
+    // HTTP-dateは秒単位なので、更新日時の秒未満は切り捨てて比べる
+    let modified = modified.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    let since = since.duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs());
+    modified <= since
+}
@@ -0,0 +1,188 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use crate::error::{Error, Result};
+use crate::request::Request;
+use crate::response::{create_msg_from_code, Response};
+
+// Sec-WebSocket-Acceptの計算に使うGUID(RFC 6455 1.3)
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+// サポートしているWebSocketのバージョン
+const WEBSOCKET_VERSION: &str = "13";
+// 受け付けるフレームのペイロードの最大長
+const MAX_PAYLOAD_LEN: u64 = 1024 * 1024;
+
+pub(crate) const OPCODE_TEXT: u8 = 0x1;
+pub(crate) const OPCODE_BINARY: u8 = 0x2;
+pub(crate) const OPCODE_CLOSE: u8 = 0x8;
+pub(crate) const OPCODE_PING: u8 = 0x9;
+pub(crate) const OPCODE_PONG: u8 = 0xa;
+
+/**
+* WebSocketへのアップグレードを要求しているか
+*/
+pub(crate) fn is_upgrade_request(request: &Request) -> bool {
+    request
+        .header("Upgrade")
+        .is_some_and(|value| value.split(',').any(|v| v.trim().eq_ignore_ascii_case("websocket")))
+}
+
+/**
+* オープニングハンドシェイクに応答する。要求が不正な場合は400を返す
+*/
+pub(crate) fn handshake_response(request: &Request) -> Result<Response, Error> {
+    let key = request.header("Sec-WebSocket-Key").filter(|key| {
+        STANDARD.decode(key).is_ok_and(|decoded| decoded.len() == 16)
+    });
+    let valid = request.method == "GET"
+        && request.version >= 1
+        && request.has_connection_option("upgrade")
+        && request.header("Sec-WebSocket-Version") == Some(WEBSOCKET_VERSION);
+    let (true, Some(key)) = (valid, key) else {
+        let mut response = create_msg_from_code(400, None)?;
+        response.add_header("Sec-WebSocket-Version", WEBSOCKET_VERSION);
+        return Ok(response);
+    };
+    let mut response = create_msg_from_code(101, None)?;
+    response.add_header("Upgrade", "websocket");
+    response.add_header("Connection", "Upgrade");
+    response.add_header("Sec-WebSocket-Accept", &accept_key(key));
+    Ok(response)
+}
+
+/**
+* Sec-WebSocket-Keyに対するSec-WebSocket-Acceptの値
+*/
+fn accept_key(key: &str) -> String {
+    let mut sha1 = sha1_smol::Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(WEBSOCKET_GUID.as_bytes());
+    STANDARD.encode(sha1.digest().bytes())
+}
+
+/**
+* クライアントから受信したフレーム
+*/
+pub(crate) struct Frame {
+    pub(crate) opcode: u8,
+    pub(crate) payload: Vec<u8>,
+}
+
+/**
+* 受信済みのデータからフレームを1つ取り出す。フレームが揃っていなければNoneを返す。
+* マスクされていないフレーム、分割されたフレーム、大きすぎるフレームはErrを返す
+*/
+pub(crate) fn parse_frame(buffer: &[u8]) -> Result<Option<(Frame, usize)>, ()> {
+    if buffer.len() < 2 {
+        return Ok(None);
+    }
+    let fin = buffer[0] & 0x80 != 0;
+    let opcode = buffer[0] & 0x0f;
+    let masked = buffer[1] & 0x80 != 0;
+    // クライアントからのフレームは必ずマスクされている(RFC 6455 5.1)
+    if !fin || !masked {
+        return Err(());
+    }
+    let (payload_len, mut offset) = match buffer[1] & 0x7f {
+        126 if buffer.len() >= 4 => (u16::from_be_bytes([buffer[2], buffer[3]]) as u64, 4),
+        127 if buffer.len() >= 10 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&buffer[2..10]);
+            (u64::from_be_bytes(len), 10)
+        }
+        126 | 127 => return Ok(None),
+        len => (len as u64, 2),
+    };
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(());
+    }
+    let payload_len = payload_len as usize;
+    if buffer.len() < offset + 4 + payload_len {
+        return Ok(None);
+    }
+    let mask = [buffer[offset], buffer[offset + 1], buffer[offset + 2], buffer[offset + 3]];
+    offset += 4;
+    let payload = buffer[offset..offset + payload_len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((Frame { opcode, payload }, offset + payload_len)))
+}
+
+/**
+* サーバから送信するフレーム(マスクなし)を作成する
+*/
+pub(crate) fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc_example() {
+        // RFC 6455 1.3の例
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn masked_frame_is_unmasked() {
+        let mut buffer = masked_frame(OPCODE_TEXT, b"hello");
+        buffer.extend_from_slice(b"next");
+        let (frame, len) = parse_frame(&buffer).unwrap().unwrap();
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        assert_eq!(frame.payload, b"hello");
+        assert_eq!(len, buffer.len() - 4);
+    }
+
+    #[test]
+    fn incomplete_frame_waits_for_more_bytes() {
+        let frame = masked_frame(OPCODE_TEXT, b"hello");
+        for end in 0..frame.len() {
+            assert!(parse_frame(&frame[..end]).unwrap().is_none(), "{}", end);
+        }
+    }
+
+    #[test]
+    fn unmasked_and_fragmented_frames_are_rejected() {
+        assert!(parse_frame(&encode_frame(OPCODE_TEXT, b"hello")).is_err());
+        let mut fragment = masked_frame(OPCODE_TEXT, b"hel");
+        fragment[0] &= 0x7f;
+        assert!(parse_frame(&fragment).is_err());
+        let mut too_long = vec![0x80 | OPCODE_BINARY, 0x80 | 127];
+        too_long.extend_from_slice(&(MAX_PAYLOAD_LEN + 1).to_be_bytes());
+        assert!(parse_frame(&too_long).is_err());
+    }
+
+    #[test]
+    fn encoded_frame_uses_the_shortest_length() {
+        assert_eq!(encode_frame(OPCODE_TEXT, b"hi"), b"\x81\x02hi");
+        let medium = encode_frame(OPCODE_BINARY, &[0; 300]);
+        assert_eq!(&medium[..4], &[0x82, 126, 0x01, 0x2c]);
+        let large = encode_frame(OPCODE_BINARY, &[0; 70_000]);
+        assert_eq!(&large[..2], &[0x82, 127]);
+        assert_eq!(large.len(), 10 + 70_000);
+    }
+}
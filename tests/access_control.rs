@@ -0,0 +1,96 @@
+mod common;
+
+use std::io::Write;
+use common::{body, config_with_files, connect, exchange, read_response, start, status};
+use web_server::{create_content_response, AccessControlRule, Cidr, Config};
+
+const WEBSOCKET_UPGRADE: &[u8] = b"GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+/**
+* /ws、/events、/route、/index.htmlへのアクセスをallowのアドレス範囲だけに許可する設定
+*/
+fn config(name: &str, allow: &str) -> Config {
+    let mut config = config_with_files(name, &[("index.html", b"hello")]);
+    config.websocket_echo_path = Some("/ws".to_string());
+    config.sse_path = Some("/events".to_string());
+    config.access_control = vec![AccessControlRule {
+        path_prefix: "/".to_string(),
+        allow: vec![Cidr::try_from(allow.to_string()).unwrap()],
+    }];
+    config
+}
+
+fn start_with_route(config: Config) -> std::net::SocketAddr {
+    start(config, |server| {
+        server.add_route("GET", "/route", |_, _| create_content_response(200, "text/plain", b"routed".to_vec()));
+    })
+}
+
+#[test]
+fn allowed_address_is_served() {
+    let addr = start_with_route(config("acl-allowed", "127.0.0.0/8"));
+    let response = exchange(addr, b"GET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "hello");
+    let response = exchange(addr, b"GET /route HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(body(&response), "routed");
+    let mut stream = connect(addr);
+    stream.write_all(WEBSOCKET_UPGRADE).unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert_eq!(status(&head), 101);
+}
+
+#[test]
+fn denied_address_gets_403_for_every_kind_of_request() {
+    let addr = start_with_route(config("acl-denied", "10.0.0.0/8"));
+    for raw in [
+        &b"GET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n"[..],
+        b"GET /route HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+        b"GET /events HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+        WEBSOCKET_UPGRADE,
+    ] {
+        let mut stream = connect(addr);
+        stream.write_all(raw).unwrap();
+        let (head, _) = read_response(&mut stream);
+        assert_eq!(status(&head), 403, "{}", String::from_utf8_lossy(raw));
+    }
+}
+
+#[test]
+fn prefix_rules_see_the_path_with_collapsed_slashes() {
+    let mut config = config_with_files("collapsed-slashes", &[("admin/secret.txt", b"secret"), ("a/b.txt", b"b")]);
+    config.access_control = vec![AccessControlRule {
+        path_prefix: "/admin/".to_string(),
+        allow: vec![Cidr::try_from("192.0.2.0/24".to_string()).unwrap()],
+    }];
+    let addr = start(config, |_| {});
+    let get = |target: &str| format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", target).into_bytes();
+    assert_eq!(body(&exchange(addr, &get("//a///b.txt"))), "b");
+    for target in ["/admin/secret.txt", "//admin//secret.txt", "///admin/secret.txt"] {
+        assert_eq!(status(&exchange(addr, &get(target))), 403, "{}", target);
+    }
+}
+
+fn get_as(addr: std::net::SocketAddr, path: &str, user_agent: &str) -> u16 {
+    let raw = format!("GET {} HTTP/1.1\r\nHost: a\r\nUser-Agent: {}\r\nConnection: close\r\n\r\n", path, user_agent);
+    status(&exchange(addr, raw.as_bytes()))
+}
+
+#[test]
+fn blocked_user_agents_are_rejected_before_serving() {
+    let blocking = |name: &str, status: u16| Config {
+        blocked_user_agents: vec!["BadBot".to_string()],
+        blocked_user_agent_status: status,
+        ..config(name, "0.0.0.0/0")
+    };
+    let addr = start_with_route(blocking("blocked-ua", 403));
+    assert_eq!(get_as(addr, "/index.html", "Mozilla/5.0 (compatible; badbot/2.1)"), 403);
+    assert_eq!(get_as(addr, "/route", "BadBot"), 403);
+    assert_eq!(get_as(addr, "/index.html", "Mozilla/5.0"), 200);
+    let response = exchange(addr, b"GET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+
+    let addr = start_with_route(blocking("blocked-ua-404", 404));
+    assert_eq!(get_as(addr, "/index.html", "BadBot"), 404);
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::fs;
+use std::sync::{Arc, Mutex};
+use common::{config_with_files, exchange, start, status, temp_dir};
+use web_server::{AccessLogConfig, AccessLogSink, Config, RequestLog};
+
+// 記録されたリクエストのメソッド、ターゲット、ステータスコード、ボディのバイト数
+type Records = Arc<Mutex<Vec<(String, String, u16, usize)>>>;
+
+struct CapturingSink(Records);
+
+impl AccessLogSink for CapturingSink {
+    fn log_request(&mut self, record: &RequestLog) {
+        assert!(record.remote_addr.is_some_and(|addr| addr.ip().is_loopback()));
+        self.0.lock().unwrap().push((record.method.clone(), record.target.clone(), record.status, record.body_bytes));
+    }
+}
+
+#[test]
+fn custom_sink_receives_every_request() {
+    let records = Records::default();
+    let sink = CapturingSink(records.clone());
+    let addr = start(config_with_files("access-log-sink", &[("a.txt", b"hello")]), move |server| {
+        server.set_access_log(sink);
+    });
+    assert_eq!(status(&exchange(addr, b"GET /a.txt?x=1 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")), 200);
+    assert_eq!(status(&exchange(addr, b"HEAD /a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")), 200);
+    assert_eq!(status(&exchange(addr, b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")), 404);
+    assert_eq!(
+        *records.lock().unwrap(),
+        [
+            ("GET".to_string(), "/a.txt?x=1".to_string(), 200, 5),
+            // HEADはボディを送信しない
+            ("HEAD".to_string(), "/a.txt".to_string(), 200, 0),
+            ("GET".to_string(), "/missing".to_string(), 404, 0),
+        ]
+    );
+}
+
+#[test]
+fn json_sink_appends_one_line_per_request() {
+    let path = temp_dir("access-log-json").join("access.log");
+    let config = Config {
+        access_log: AccessLogConfig::Json(path.to_string_lossy().into_owned()),
+        ..config_with_files("access-log-json-root", &[("a.txt", b"hello")])
+    };
+    let addr = start(config, |_| {});
+    exchange(addr, b"GET /a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    exchange(addr, b"GET /\"quoted\" HTTP/1.0\r\n\r\n");
+    let log = fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(lines[0].contains("\"remote_addr\":\"127.0.0.1\",\"method\":\"GET\",\"target\":\"/a.txt\""), "{}", log);
+    assert!(lines[0].contains("\"version\":\"HTTP/1.1\",\"status\":200,\"body_bytes\":5,"), "{}", log);
+    assert!(lines[1].contains("\"target\":\"/\\\"quoted\\\"\""), "{}", log);
+}
@@ -0,0 +1,77 @@
+mod common;
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use common::{body, exchange, header, start, status, temp_dir};
+use web_server::Config;
+use zip::write::SimpleFileOptions;
+
+const FILES: [(&str, &[u8]); 4] = [
+    ("index.html", b"<h1>zip</h1>"),
+    ("css/site.css", b"body {}"),
+    (".env", b"SECRET=1"),
+    ("../outside.txt", b"outside"),
+];
+
+fn get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path)
+}
+
+fn write_zip(path: &Path) {
+    let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+    for (name, content) in FILES {
+        writer.start_file(name, SimpleFileOptions::default()).unwrap();
+        writer.write_all(content).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+fn write_tar(path: &Path) {
+    let mut builder = tar::Builder::new(File::create(path).unwrap());
+    // tarのBuilderは".."を含むパスを書き込めないので除く
+    for (name, content) in &FILES[..3] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, *content).unwrap();
+    }
+    builder.finish().unwrap();
+}
+
+fn serve(archive: &Path) -> std::net::SocketAddr {
+    start(Config { archive: Some(archive.to_string_lossy().into_owned()), ..Config::default() }, |_| {})
+}
+
+#[test]
+fn files_are_served_out_of_a_zip_archive() {
+    let path = temp_dir("archive-zip").join("site.zip");
+    write_zip(&path);
+    let addr = serve(&path);
+    let response = exchange(addr, get("/index.html").as_bytes());
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(body(&response), "<h1>zip</h1>");
+    assert_eq!(body(&exchange(addr, get("/css/site.css").as_bytes())), "body {}");
+    assert_eq!(status(&exchange(addr, get("/missing.html").as_bytes())), 404);
+}
+
+#[test]
+fn unsafe_zip_entries_are_not_served() {
+    let path = temp_dir("archive-zip-unsafe").join("site.zip");
+    write_zip(&path);
+    let addr = serve(&path);
+    // ドットファイルはドキュメントルートと同じく403、"../"で始まるエントリは一覧に入らず404になる
+    assert_eq!(status(&exchange(addr, get("/.env").as_bytes())), 403);
+    assert_eq!(status(&exchange(addr, get("/outside.txt").as_bytes())), 404);
+}
+
+#[test]
+fn files_are_served_out_of_a_tar_archive() {
+    let path = temp_dir("archive-tar").join("site.tar");
+    write_tar(&path);
+    let addr = serve(&path);
+    assert_eq!(body(&exchange(addr, get("/css/site.css").as_bytes())), "body {}");
+    assert_eq!(status(&exchange(addr, get("/.env").as_bytes())), 403);
+}
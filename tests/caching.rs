@@ -0,0 +1,264 @@
+mod common;
+
+use std::fs::{self, File};
+use common::{config_with_files, exchange, header, start, status};
+use web_server::{CacheControlRule, Config, EtagStrength};
+
+fn get(path: &str) -> String {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path)
+}
+
+#[test]
+fn cache_control_is_chosen_by_extension() {
+    let rule = |extension: &str, value: &str| CacheControlRule {
+        extension: Some(extension.to_string()),
+        path_prefix: None,
+        value: value.to_string(),
+    };
+    let config = Config {
+        cache_control: vec![rule("js", "max-age=31536000, immutable"), rule("html", "no-cache")],
+        ..config_with_files("cache-control", &[("app.js", b"js"), ("index.html", b"html"), ("a.txt", b"a")])
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, get("/app.js").as_bytes());
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Cache-Control"), Some("max-age=31536000, immutable"));
+    assert_eq!(header(&exchange(addr, get("/index.html").as_bytes()), "Cache-Control"), Some("no-cache"));
+    assert_eq!(header(&exchange(addr, get("/a.txt").as_bytes()), "Cache-Control"), None);
+}
+
+#[test]
+fn negotiated_encoding_adds_vary() {
+    let html = "<p>hello</p>\n".repeat(200);
+    let config = Config {
+        compression: true,
+        ..config_with_files("vary", &[("index.html", html.as_bytes())])
+    };
+    let addr = start(config, |_| {});
+    let gzip = exchange(
+        addr,
+        b"GET /index.html HTTP/1.1\r\nHost: a\r\nAccept-Encoding: gzip\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(header(&gzip, "Content-Encoding"), Some("gzip"));
+    assert_eq!(header(&gzip, "Vary"), Some("Accept-Encoding"));
+    // 圧縮しなかったレスポンスもAccept-Encodingで変わり得るのでVaryを付ける
+    let identity = exchange(addr, get("/index.html").as_bytes());
+    assert_eq!(header(&identity, "Content-Encoding"), None);
+    assert_eq!(header(&identity, "Vary"), Some("Accept-Encoding"));
+}
+
+#[test]
+fn changed_file_gets_a_new_etag_without_a_restart() {
+    let config = config_with_files("etag-change", &[("a.txt", b"old")]);
+    let path = std::path::Path::new(&config.webroots[0]).join("a.txt");
+    let addr = start(config, |_| {});
+
+    let response = exchange(addr, get("/a.txt").as_bytes());
+    let old_etag = header(&response, "ETag").unwrap().to_string();
+
+    std::fs::write(&path, b"new content").unwrap();
+    let response = exchange(addr, get("/a.txt").as_bytes());
+    assert_ne!(header(&response, "ETag"), Some(old_etag.as_str()));
+    assert!(response.ends_with("new content"), "{}", response);
+
+    // 古いETagでの条件付きリクエストは304にならず、新しい内容を返す
+    let conditional = format!("GET /a.txt HTTP/1.1\r\nHost: a\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n", old_etag);
+    let response = exchange(addr, conditional.as_bytes());
+    assert_eq!(status(&response), 200);
+    assert!(response.ends_with("new content"), "{}", response);
+}
+
+#[test]
+fn head_matches_get_and_follows_file_changes() {
+    let config = Config {
+        last_modified: true,
+        cache_control: vec![CacheControlRule {
+            extension: Some("txt".to_string()),
+            path_prefix: None,
+            value: "no-cache".to_string(),
+        }],
+        ..config_with_files("head-metadata", &[("a.txt", b"hello")])
+    };
+    let path = std::path::Path::new(&config.webroots[0]).join("a.txt");
+    let addr = start(config, |_| {});
+    let head = |path: &str| exchange(addr, format!("HEAD {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).as_bytes());
+
+    let get_response = exchange(addr, get("/a.txt").as_bytes());
+    let response = head("/a.txt");
+    assert_eq!(status(&response), 200);
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    for name in ["Content-Length", "Content-Type", "ETag", "Last-Modified", "Cache-Control"] {
+        assert!(header(&response, name).is_some(), "{}", name);
+        assert_eq!(header(&response, name), header(&get_response, name), "{}", name);
+    }
+    assert_eq!(header(&response, "Content-Length"), Some("5"));
+
+    std::fs::write(&path, b"hello, world").unwrap();
+    let response = head("/a.txt");
+    assert_eq!(header(&response, "Content-Length"), Some("12"));
+    assert_ne!(header(&response, "ETag"), header(&get_response, "ETag"));
+    assert_eq!(status(&head("/missing.txt")), 404);
+}
+
+fn conditional_range(etag: &str) -> Vec<u8> {
+    format!("GET /a.txt HTTP/1.1\r\nHost: a\r\nRange: bytes=0-3\r\nIf-Range: {}\r\nConnection: close\r\n\r\n", etag)
+        .into_bytes()
+}
+
+#[test]
+fn weak_etags_are_default_and_not_used_for_if_range() {
+    let addr = start(config_with_files("etag-weak", &[("a.txt", b"0123456789")]), |_| {});
+    let response = exchange(addr, get("/a.txt").as_bytes());
+    let etag = header(&response, "ETag").unwrap().to_string();
+    assert!(etag.starts_with("W/\""), "{}", etag);
+
+    // 弱いETagは範囲の結合に使えないので、全体を200で返す
+    let response = exchange(addr, &conditional_range(&etag));
+    assert_eq!(status(&response), 200);
+    assert!(response.ends_with("\r\n\r\n0123456789"), "{}", response);
+    let response = exchange(addr, &conditional_range(etag.trim_start_matches("W/")));
+    assert_eq!(status(&response), 200);
+
+    // If-None-Matchは弱い比較なので一致する
+    let conditional = format!("GET /a.txt HTTP/1.1\r\nHost: a\r\nIf-None-Match: {}\r\nConnection: close\r\n\r\n", etag);
+    assert_eq!(status(&exchange(addr, conditional.as_bytes())), 304);
+}
+
+#[test]
+fn strong_etags_satisfy_if_range() {
+    let config = Config { etag: EtagStrength::Strong, ..config_with_files("etag-strong", &[("a.txt", b"0123456789")]) };
+    let addr = start(config, |_| {});
+    let etag = header(&exchange(addr, get("/a.txt").as_bytes()), "ETag").unwrap().to_string();
+    assert!(etag.starts_with('"'), "{}", etag);
+
+    let response = exchange(addr, &conditional_range(&etag));
+    assert_eq!(status(&response), 206);
+    assert_eq!(header(&response, "Content-Range"), Some("bytes 0-3/10"));
+    assert!(response.ends_with("\r\n\r\n0123"), "{}", response);
+    assert_eq!(status(&exchange(addr, &conditional_range("\"other\""))), 200);
+
+    let config = Config { etag: EtagStrength::Off, ..config_with_files("etag-off", &[("a.txt", b"a")]) };
+    let addr = start(config, |_| {});
+    assert_eq!(header(&exchange(addr, get("/a.txt").as_bytes()), "ETag"), None);
+}
+
+fn if_modified_since(date: &str, extra: &str) -> Vec<u8> {
+    format!("GET /a.txt HTTP/1.1\r\nHost: a\r\nIf-Modified-Since: {}\r\n{}Connection: close\r\n\r\n", date, extra)
+        .into_bytes()
+}
+
+#[test]
+fn dates_alone_validate_when_etags_are_off() {
+    let config = Config {
+        etag: EtagStrength::Off,
+        last_modified: true,
+        cache_control: vec![CacheControlRule {
+            extension: Some("txt".to_string()),
+            path_prefix: None,
+            value: "no-cache".to_string(),
+        }],
+        ..config_with_files("last-modified", &[("a.txt", b"a")])
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, get("/a.txt").as_bytes());
+    assert_eq!(header(&response, "ETag"), None);
+    let last_modified = header(&response, "Last-Modified").unwrap().to_string();
+    assert!(last_modified.ends_with(" GMT"), "{}", last_modified);
+
+    let response = exchange(addr, &if_modified_since(&last_modified, ""));
+    assert_eq!(status(&response), 304);
+    assert_eq!(header(&response, "ETag"), None);
+    assert_eq!(header(&response, "Cache-Control"), Some("no-cache"));
+    assert_eq!(header(&response, "Content-Length"), None);
+
+    // 更新日時より前の日時、不正な日時、If-None-Matchとの組み合わせでは全体を返す
+    assert_eq!(status(&exchange(addr, &if_modified_since("Thu, 01 Jan 1970 00:00:00 GMT", ""))), 200);
+    assert_eq!(status(&exchange(addr, &if_modified_since("yesterday", ""))), 200);
+    assert_eq!(status(&exchange(addr, &if_modified_since(&last_modified, "If-None-Match: \"other\"\r\n"))), 200);
+
+    // 既定では付与しない
+    let addr = start(config_with_files("last-modified-off", &[("a.txt", b"a")]), |_| {});
+    assert_eq!(header(&exchange(addr, get("/a.txt").as_bytes()), "Last-Modified"), None);
+}
+
+fn range(value: &str) -> Vec<u8> {
+    format!("GET /a.txt HTTP/1.1\r\nHost: a\r\nRange: {}\r\nConnection: close\r\n\r\n", value).into_bytes()
+}
+
+#[test]
+fn media_player_range_forms_get_exact_content_ranges() {
+    let addr = start(config_with_files("ranges", &[("a.txt", b"0123456789")]), |_| {});
+    assert_eq!(header(&exchange(addr, get("/a.txt").as_bytes()), "Accept-Ranges"), Some("bytes"));
+    for (value, content_range, content) in [
+        ("bytes=0-", "bytes 0-9/10", "0123456789"),
+        ("bytes=7-", "bytes 7-9/10", "789"),
+        ("bytes=-3", "bytes 7-9/10", "789"),
+        ("bytes=-20", "bytes 0-9/10", "0123456789"),
+        ("bytes=2-4", "bytes 2-4/10", "234"),
+        ("bytes=8-100", "bytes 8-9/10", "89"),
+    ] {
+        let response = exchange(addr, &range(value));
+        assert_eq!(status(&response), 206, "{}", value);
+        assert_eq!(header(&response, "Content-Range"), Some(content_range), "{}", value);
+        assert_eq!(header(&response, "Content-Length"), Some(content.len().to_string().as_str()), "{}", value);
+        assert!(response.ends_with(&format!("\r\n\r\n{}", content)), "{}", response);
+    }
+    // ファイルの外の範囲や長さ0の末尾は満たせない
+    for value in ["bytes=10-", "bytes=-0"] {
+        let response = exchange(addr, &range(value));
+        assert_eq!(status(&response), 416, "{}", value);
+        assert_eq!(header(&response, "Content-Range"), Some("bytes */10"));
+    }
+    // 複数の範囲や不正な形式は無視して全体を返す
+    for value in ["bytes=0-1,4-5", "bytes=5-2", "items=0-1", "bytes=a-"] {
+        assert_eq!(status(&exchange(addr, &range(value))), 200, "{}", value);
+    }
+
+    let config = Config { byte_ranges: false, ..config_with_files("ranges-off", &[("a.txt", b"0123456789")]) };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, &range("bytes=0-"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Accept-Ranges"), None);
+}
+
+#[test]
+fn head_uses_cached_metadata_until_the_size_or_time_changes() {
+    let config = config_with_files("head-metadata-cache", &[("a.txt", b"\xFF\xFEh\0i\0")]);
+    let path = std::path::Path::new(&config.webroots[0]).join("a.txt");
+    let addr = start(config, |_| {});
+    let head = || exchange(addr, b"HEAD /a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    let with_bom = exchange(addr, get("/a.txt").as_bytes());
+
+    // サイズと更新日時が同じままなら、ファイルを読まずにBOMから決めたcharsetを返し続ける
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    fs::write(&path, b"plain!").unwrap();
+    File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    let response = head();
+    assert_eq!(header(&response, "Content-Type"), header(&with_bom, "Content-Type"));
+    assert_eq!(header(&response, "Content-Length"), Some("6"));
+    assert_eq!(header(&response, "ETag"), header(&with_bom, "ETag"));
+
+    fs::write(&path, b"plain text").unwrap();
+    let response = head();
+    let fresh = exchange(addr, get("/a.txt").as_bytes());
+    assert_eq!(header(&response, "Content-Length"), Some("10"));
+    assert_eq!(header(&response, "Content-Type"), header(&fresh, "Content-Type"));
+    assert_ne!(header(&response, "Content-Type"), header(&with_bom, "Content-Type"));
+    assert_ne!(header(&response, "ETag"), header(&with_bom, "ETag"));
+}
+
+#[test]
+fn head_reads_the_file_when_the_metadata_cache_is_disabled() {
+    let config = Config {
+        metadata_cache_entries: 0,
+        ..config_with_files("head-metadata-cache-off", &[("a.txt", b"\xFF\xFEh\0i\0")])
+    };
+    let path = std::path::Path::new(&config.webroots[0]).join("a.txt");
+    let addr = start(config, |_| {});
+    let with_bom = exchange(addr, get("/a.txt").as_bytes());
+    let modified = fs::metadata(&path).unwrap().modified().unwrap();
+    fs::write(&path, b"plain!").unwrap();
+    File::options().write(true).open(&path).unwrap().set_modified(modified).unwrap();
+    let response = exchange(addr, b"HEAD /a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_ne!(header(&response, "Content-Type"), header(&with_bom, "Content-Type"));
+}
@@ -0,0 +1,123 @@
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use common::{body, config_with_files, connect, exchange, read_to_close, start, start_with_clock, status, temp_dir};
+use web_server::{CgiConfig, Config, MockClock};
+
+/**
+* scriptsを置いたCGIのディレクトリを設定する
+*/
+fn cgi_config(name: &str, scripts: &[(&str, &str)], max_output_bytes: usize) -> Config {
+    let dir = temp_dir(name);
+    for (name, script) in scripts {
+        let path = dir.join(name);
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    let mut config = config_with_files(name, &[("index.html", b"static")]);
+    config.cgi = Some(CgiConfig {
+        path_prefix: "/cgi-bin".to_string(),
+        dir: dir.to_string_lossy().into_owned(),
+        timeout: 10,
+        max_output_bytes,
+    });
+    config
+}
+
+#[test]
+fn request_is_passed_in_environment_without_proxy() {
+    let config = cgi_config("cgi-env", &[("env.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nenv\n")], 1024 * 1024);
+    let addr = start(config, |_| {});
+    let response = exchange(
+        addr,
+        b"GET /cgi-bin/env.sh/extra?a=1 HTTP/1.1\r\nHost: example.com\r\nX-Test: yes\r\n\
+          Proxy: http://attacker.example\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(status(&response), 200);
+    let env = body(&response);
+    for expected in [
+        "REQUEST_METHOD=GET",
+        "SCRIPT_NAME=/cgi-bin/env.sh",
+        "PATH_INFO=/extra",
+        "QUERY_STRING=a=1",
+        "SERVER_NAME=example.com",
+        "REMOTE_ADDR=127.0.0.1",
+        "HTTP_X_TEST=yes",
+    ] {
+        assert!(env.lines().any(|line| line == expected), "{} not in {}", expected, env);
+    }
+    assert!(!env.contains("HTTP_PROXY"), "{}", env);
+}
+
+#[test]
+fn slow_script_does_not_block_other_connections() {
+    let config = cgi_config("cgi-slow", &[("slow.sh", "#!/bin/sh\nsleep 2\nprintf 'Content-Type: text/plain\\n\\ndone'\n")], 1024);
+    let addr = start(config, |_| {});
+    let mut slow = connect(addr);
+    slow.write_all(b"GET /cgi-bin/slow.sh HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    // スクリプトの実行が始まるまで待つ
+    thread::sleep(Duration::from_millis(200));
+    let started = Instant::now();
+    let response = exchange(addr, b"GET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(body(&response), "static");
+    assert!(started.elapsed() < Duration::from_secs(1), "static file waited for the script: {:?}", started.elapsed());
+    let response = read_to_close(&mut slow);
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "done");
+}
+
+#[test]
+fn pipelined_request_waits_for_the_script() {
+    let config = cgi_config("cgi-pipeline", &[("hello.sh", "#!/bin/sh\nsleep 1\nprintf 'Content-Type: text/plain\\n\\nhello'\n")], 1024);
+    let addr = start(config, |_| {});
+    let response = exchange(
+        addr,
+        b"GET /cgi-bin/hello.sh HTTP/1.1\r\nHost: a\r\n\r\nGET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n",
+    );
+    // レスポンスはリクエストの順に返す
+    let hello = response.find("hello").expect(&response);
+    let static_body = response.find("static").expect(&response);
+    assert!(hello < static_body, "{}", response);
+}
+
+#[test]
+fn output_over_the_limit_is_502() {
+    let config = cgi_config(
+        "cgi-large",
+        &[("large.sh", "#!/bin/sh\nprintf 'Content-Type: text/plain\\n\\n'\nhead -c 100000 /dev/zero\n")],
+        1024,
+    );
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /cgi-bin/large.sh HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 502);
+}
+
+#[test]
+fn timeout_follows_the_server_clock() {
+    let config = cgi_config("cgi-timeout", &[("hang.sh", "#!/bin/sh\nexec sleep 30\n")], 1024);
+    let clock = Arc::new(MockClock::new());
+    let addr = start_with_clock(config, clock.clone(), |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /cgi-bin/hang.sh HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    thread::sleep(Duration::from_millis(200));
+    // 実時間では待たずに、時計を進めるだけでタイムアウトさせる
+    clock.advance(Duration::from_secs(11));
+    let started = Instant::now();
+    let response = read_to_close(&mut stream);
+    assert_eq!(status(&response), 504);
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn script_status_is_passed_through() {
+    let config = cgi_config("cgi-status", &[("auth.sh", "#!/bin/sh\nprintf 'Status: 401 Unauthorized\\nContent-Type: text/plain\\n\\nlogin'\n")], 1024);
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /cgi-bin/auth.sh HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 401);
+    assert_eq!(body(&response), "login");
+}
@@ -0,0 +1,126 @@
+mod common;
+
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::SocketAddr;
+use std::os::fd::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use common::{exchange, temp_dir};
+
+#[test]
+fn print_addr_reports_the_port_chosen_for_port_0() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_web-server"))
+        .args(["127.0.0.1:0", "--print-addr"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    let addr: SocketAddr = line.trim().parse().unwrap_or_else(|_| panic!("not an address: {:?}", line));
+    let port = addr.port();
+    // 出力したアドレスで実際に接続できる
+    let response = exchange(addr, b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_ne!(port, 0);
+    assert!(response.starts_with("HTTP/1.0 "), "{}", response);
+}
+
+/**
+* ファイルディスクリプタのソフトリミットをsoft_limitに下げてサーバを起動し、起動を終えるまでのログを返す
+*/
+fn startup_log(soft_limit: libc::rlim_t, config: &str) -> String {
+    let config_path = temp_dir("fd-limit").join("server.toml");
+    fs::write(&config_path, config).unwrap();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_web-server"));
+    command.args(["127.0.0.1:0", config_path.to_str().unwrap(), "--print-addr"]);
+    command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    // SAFETY: fork後のexecの前にはsetrlimitとgetrlimitだけを呼ぶ
+    unsafe {
+        command.pre_exec(move || {
+            let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+            if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            limit.rlim_cur = soft_limit.min(limit.rlim_max);
+            if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    child.kill().unwrap();
+    child.wait().unwrap();
+    let mut log = String::new();
+    child.stderr.take().unwrap().read_to_string(&mut log).unwrap();
+    log
+}
+
+#[test]
+fn low_fd_limit_is_warned_at_startup() {
+    let log = startup_log(128, "max_connections = 1024\n");
+    assert!(log.contains("File descriptor limit 128 is lower than max_connections 1024"), "{}", log);
+    let log = startup_log(128, "max_connections = 64\n");
+    assert!(!log.contains("File descriptor limit"), "{}", log);
+}
+
+#[test]
+fn fd_limit_is_raised_toward_the_hard_limit() {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    // SAFETY: limitは有効なrlimitへのポインタ
+    assert_eq!(unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) }, 0);
+    if limit.rlim_max < 1024 + 32 {
+        // ハードリミットが低い環境では引き上げても足りない
+        return;
+    }
+    let log = startup_log(128, "max_connections = 1024\nraise_fd_limit = true\n");
+    assert!(log.contains("Raised the file descriptor limit from 128"), "{}", log);
+    assert!(!log.contains("is lower than max_connections"), "{}", log);
+}
+
+#[test]
+fn socket_passed_by_activation_is_used_instead_of_binding() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let fd = listener.as_raw_fd();
+    let config_path = temp_dir("socket-activation").join("server.toml");
+    fs::write(&config_path, "socket_activation = true\n").unwrap();
+    // LISTEN_PIDはexecした後のサーバのPIDにする必要があるので、シェルの$$を引き継いでexecする
+    let mut command = Command::new("sh");
+    command
+        .args(["-c", "LISTEN_PID=$$ exec \"$0\" \"$@\"", env!("CARGO_BIN_EXE_web-server")])
+        .args(["127.0.0.1:0", config_path.to_str().unwrap(), "--print-addr"])
+        .env("LISTEN_FDS", "1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    // SAFETY: fork後のexecの前にはdup2とfcntlだけを呼ぶ
+    unsafe {
+        command.pre_exec(move || {
+            // 渡すソケットはLISTEN_FDS_START(3)に置き、execで閉じられないようにする
+            let result = if fd == 3 {
+                libc::fcntl(3, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    drop(listener);
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap()).read_line(&mut line).unwrap();
+    // 指定したアドレスにはバインドせず、渡したソケットで受け付ける
+    let response = exchange(addr, b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    child.kill().unwrap();
+    child.wait().unwrap();
+    assert_eq!(line.trim(), addr.to_string());
+    assert!(response.starts_with("HTTP/1.0 "), "{}", response);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use common::{body, connect, exchange, header, read_to_close, start_with_clock, status};
+use web_server::{create_content_response, Config, MockClock};
+
+#[test]
+fn read_timeout_responds_408_and_closes() {
+    let clock = Arc::new(MockClock::new());
+    let config = Config { read_timeout: 30, ..Config::default() };
+    let addr = start_with_clock(config, clock.clone(), |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\n").unwrap();
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(31));
+    // 別の接続でイベントループを起こし、タイムアウトを確認させる
+    let _wake = connect(addr);
+    let response = read_to_close(&mut stream);
+    assert_eq!(status(&response), 408);
+    assert_eq!(header(&response, "Connection"), Some("close"));
+}
+
+#[test]
+fn age_of_cached_response_follows_the_clock() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_with_clock(Config::default(), clock.clone(), |server| {
+        server.add_cached_route("GET", "/time", Duration::from_secs(60), |_, _| {
+            create_content_response(200, "text/plain", b"stored".to_vec())
+        });
+    });
+    let request = b"GET /time HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+    exchange(addr, request);
+    for (advance, age) in [(0, "0"), (7, "7"), (45, "52")] {
+        clock.advance(Duration::from_secs(advance));
+        let response = exchange(addr, request);
+        assert_eq!(body(&response), "stored");
+        assert_eq!(header(&response, "Age"), Some(age));
+    }
+}
@@ -0,0 +1,151 @@
+// 結合テストで共有する、サーバの起動と生のリクエストの送受信
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use web_server::{Clock, Config, SystemClock, WebServer};
+
+// 応答が来ない場合にテストを失敗させるまでの時間
+pub const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/**
+* 別のスレッドでサーバを起動し、バインドしたアドレスを返す。setupでルートなどを登録できる
+*/
+pub fn start(config: Config, setup: impl FnOnce(&mut WebServer) + Send + 'static) -> SocketAddr {
+    start_with_clock(config, Arc::new(SystemClock), setup)
+}
+
+/**
+* startと同じだが、時刻をclockから取得する
+*/
+pub fn start_with_clock(
+    config: Config,
+    clock: Arc<dyn Clock>,
+    setup: impl FnOnce(&mut WebServer) + Send + 'static,
+) -> SocketAddr {
+    spawn_server(config, clock, setup)[0]
+}
+
+/**
+* startと同じだが、全てのリスニングソケットのアドレスをlocal_addrsと同じ順で返す
+*/
+pub fn start_listeners(config: Config, setup: impl FnOnce(&mut WebServer) + Send + 'static) -> Vec<SocketAddr> {
+    spawn_server(config, Arc::new(SystemClock), setup)
+}
+
+fn spawn_server(
+    config: Config,
+    clock: Arc<dyn Clock>,
+    setup: impl FnOnce(&mut WebServer) + Send + 'static,
+) -> Vec<SocketAddr> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut server = WebServer::with_clock("127.0.0.1:0", config, clock).unwrap();
+        setup(&mut server);
+        sender.send(server.local_addrs().unwrap()).unwrap();
+        server.run().unwrap();
+    });
+    receiver.recv().unwrap()
+}
+
+/**
+* テストごとの空のディレクトリ
+*/
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "web-server-test-{}-{}-{}",
+        std::process::id(),
+        name,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/**
+* filesを置いたドキュメントルートを配信する設定
+*/
+pub fn config_with_files(name: &str, files: &[(&str, &[u8])]) -> Config {
+    let dir = temp_dir(name);
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    Config { webroots: vec![dir.to_string_lossy().into_owned()], ..Config::default() }
+}
+
+pub fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+    stream
+}
+
+/**
+* rawを送信し、サーバが接続を閉じるまでに受信した全てのバイト列を文字列で返す
+*/
+pub fn exchange(addr: SocketAddr, raw: &[u8]) -> String {
+    let mut stream = connect(addr);
+    stream.write_all(raw).unwrap();
+    read_to_close(&mut stream)
+}
+
+pub fn read_to_close(stream: &mut TcpStream) -> String {
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+/**
+* Content-Lengthまでのレスポンスを1つ受信する。ヘッダとボディを返す
+*/
+pub fn read_response(stream: &mut TcpStream) -> (String, Vec<u8>) {
+    let mut buf = Vec::new();
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        let n = stream.read(&mut byte).unwrap();
+        assert!(n > 0, "connection closed before the response head: {:?}", String::from_utf8_lossy(&buf));
+        buf.push(byte[0]);
+    }
+    let head = String::from_utf8(buf).unwrap();
+    let len = header(&head, "Content-Length").map_or(0, |len| len.parse().unwrap());
+    let mut body = vec![0u8; len];
+    if !head.starts_with("HTTP/1.0 304") && !head.starts_with("HTTP/1.1 1") {
+        stream.read_exact(&mut body).unwrap();
+    }
+    (head, body)
+}
+
+/**
+* レスポンスのステータスコード
+*/
+pub fn status(response: &str) -> u16 {
+    response.split(' ').nth(1).and_then(|code| code.parse().ok()).unwrap_or_else(|| panic!("no status: {:?}", response))
+}
+
+/**
+* レスポンスのヘッダの値。名前の大文字小文字は区別しない
+*/
+pub fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    let head = response.split("\r\n\r\n").next().unwrap_or_default();
+    head.split("\r\n").skip(1).find_map(|line| {
+        let (n, v) = line.split_once(':')?;
+        n.eq_ignore_ascii_case(name).then(|| v.trim())
+    })
+}
+
+/**
+* レスポンスのボディ
+*/
+pub fn body(response: &str) -> &str {
+    response.split_once("\r\n\r\n").map_or("", |(_, body)| body)
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use std::io::{Read, Write};
+use flate2::read::GzDecoder;
+use common::{config_with_files, connect, header, read_response, start};
+use web_server::Config;
+
+fn get_gzip(addr: std::net::SocketAddr, path: &str) -> (String, Vec<u8>) {
+    let mut stream = connect(addr);
+    write!(stream, "GET {} HTTP/1.1\r\nHost: a\r\nAccept-Encoding: gzip\r\n\r\n", path).unwrap();
+    read_response(&mut stream)
+}
+
+#[test]
+fn only_files_above_the_threshold_are_compressed() {
+    let large = "compressible text\n".repeat(100);
+    // 圧縮してもほとんど小さくならない内容(xorshiftの疑似乱数)
+    let mut state = 0x2545f491u32;
+    let noise: Vec<u8> = (0..2000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let config = Config {
+        compression: true,
+        compression_min_size: 1024,
+        ..config_with_files(
+            "compression",
+            &[("small.txt", b"short text"), ("large.txt", large.as_bytes()), ("noise.txt", &noise)],
+        )
+    };
+    let addr = start(config, |_| {});
+
+    let (head, body) = get_gzip(addr, "/small.txt");
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert_eq!(body, b"short text");
+
+    let (head, body) = get_gzip(addr, "/large.txt");
+    assert_eq!(header(&head, "Content-Encoding"), Some("gzip"));
+    assert!(body.len() < large.len());
+    let mut decoded = String::new();
+    GzDecoder::new(&body[..]).read_to_string(&mut decoded).unwrap();
+    assert_eq!(decoded, large);
+
+    let (head, body) = get_gzip(addr, "/noise.txt");
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert_eq!(body, noise);
+}
+
+#[test]
+fn only_allowlisted_types_are_compressed() {
+    let text = "compressible text\n".repeat(100);
+    let config = Config {
+        compression: true,
+        compressible_types: vec!["application/json".to_string(), "image/x-custom".to_string()],
+        ..config_with_files("compression-types", &[("data.json", text.as_bytes()), ("notes.txt", text.as_bytes())])
+    };
+    let addr = start(config, |_| {});
+    let (head, _) = get_gzip(addr, "/data.json");
+    assert_eq!(header(&head, "Content-Encoding"), Some("gzip"));
+    // text/plainは既定では圧縮するが、一覧から外すと圧縮しない
+    let (head, body) = get_gzip(addr, "/notes.txt");
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert_eq!(body, text.as_bytes());
+}
+
+#[test]
+fn gzip_refused_with_q_0_is_not_used() {
+    let text = "compressible text\n".repeat(100);
+    let config = Config { compression: true, ..config_with_files("compression-q0", &[("a.txt", text.as_bytes())]) };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\nAccept-Encoding: *, gzip;q=0\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(header(&head, "Content-Encoding"), None);
+    assert_eq!(body, text.as_bytes());
+}
@@ -0,0 +1,406 @@
+mod common;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use common::{body, config_with_files, connect, exchange, header, read_to_close, start, start_with_clock, status, temp_dir};
+use web_server::{Config, EarlyHintRule, Error, MockClock, PreloadLinkRule, RootResponse, TemplateConfig, WebServer};
+
+fn get(path: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).into_bytes()
+}
+
+#[test]
+fn snapshot_root_keeps_serving_the_resolved_directory() {
+    let dir = temp_dir("snapshot");
+    for release in ["v1", "v2"] {
+        fs::create_dir(dir.join(release)).unwrap();
+        fs::write(dir.join(release).join("index.html"), release).unwrap();
+    }
+    let current = dir.join("current");
+    symlink(dir.join("v1"), &current).unwrap();
+    let config = Config {
+        webroots: vec![current.to_string_lossy().into_owned()],
+        snapshot_root: true,
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/index.html"))), "v1");
+    // リンクを付け替えても、SIGHUPを受け取るまでは起動時に解決したディレクトリから配信する
+    fs::remove_file(&current).unwrap();
+    symlink(dir.join("v2"), &current).unwrap();
+    let response = exchange(addr, &get("/index.html"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "v1");
+}
+
+#[test]
+fn template_files_are_rendered_with_configured_vars() {
+    let mut config = config_with_files(
+        "templates",
+        &[("status.shtml", b"status: {{status}}"), ("plain.html", b"status: {{status}}")],
+    );
+    config.templates = Some(TemplateConfig {
+        extension: "shtml".to_string(),
+        vars: HashMap::from([("status".to_string(), "ok".to_string())]),
+    });
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/status.shtml"))), "status: ok");
+    // 拡張子が違うファイルは置換しない
+    assert_eq!(body(&exchange(addr, &get("/plain.html"))), "status: {{status}}");
+}
+
+#[test]
+fn extensionless_files_are_sniffed() {
+    let files: [(&str, &[u8]); 2] = [("README", b"# Title\n\ntext\n"), ("blob", b"\x00\x01\x02binary")];
+    let addr = start(config_with_files("sniff", &files), |_| {});
+    assert_eq!(header(&exchange(addr, &get("/README")), "Content-Type"), Some("text/plain; charset=utf-8"));
+    assert_eq!(header(&exchange(addr, &get("/blob")), "Content-Type"), Some("application/octet-stream"));
+
+    let config = Config { content_sniffing: false, ..config_with_files("sniff-off", &files) };
+    let addr = start(config, |_| {});
+    assert_eq!(header(&exchange(addr, &get("/README")), "Content-Type"), None);
+}
+
+#[test]
+fn earlier_roots_shadow_later_ones() {
+    let user = temp_dir("overlay-user");
+    let theme = temp_dir("overlay-theme");
+    fs::write(user.join("style.css"), "user").unwrap();
+    fs::write(theme.join("style.css"), "theme").unwrap();
+    fs::write(theme.join("logo.txt"), "theme logo").unwrap();
+    // 先のルートの中から".."で後のルートのファイルを指すことはできない
+    fs::write(theme.join("secret.txt"), "secret").unwrap();
+    let webroots = [&user, &theme].iter().map(|dir| dir.to_string_lossy().into_owned()).collect();
+    let addr = start(Config { webroots, ..Config::default() }, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/style.css"))), "user");
+    assert_eq!(body(&exchange(addr, &get("/logo.txt"))), "theme logo");
+    let escape = format!("/../{}/secret.txt", theme.file_name().unwrap().to_string_lossy());
+    assert_ne!(status(&exchange(addr, &get(&escape))), 200);
+    assert_eq!(status(&exchange(addr, &get("/missing.txt"))), 404);
+}
+
+#[test]
+fn unavailable_root_gets_503_until_it_comes_back() {
+    let config = config_with_files("unavailable-root", &[("a.txt", b"a")]);
+    let root = std::path::PathBuf::from(&config.webroots[0]);
+    let moved = root.with_extension("unmounted");
+    let clock = Arc::new(MockClock::new());
+    let addr = start_with_clock(config, clock.clone(), |_| {});
+    assert_eq!(status(&exchange(addr, &get("/a.txt"))), 200);
+
+    // ボリュームのアンマウントと同じく、ドキュメントルートのディレクトリがなくなる
+    fs::rename(&root, &moved).unwrap();
+    // 確かめ直す間隔が過ぎるまでは読めるものとして扱い、ファイルがないので404になる
+    assert_eq!(status(&exchange(addr, &get("/a.txt"))), 404);
+    clock.advance(Duration::from_secs(1));
+    let response = exchange(addr, &get("/a.txt"));
+    assert_eq!(status(&response), 503);
+    assert_eq!(header(&response, "Retry-After"), Some("30"));
+
+    fs::rename(&moved, &root).unwrap();
+    assert_eq!(body(&exchange(addr, &get("/a.txt"))), "a");
+}
+
+#[test]
+fn transient_read_errors_are_retried_without_blocking_other_requests() {
+    // /proc/self/memは先頭のアドレスが割り当てられていないので、読み込むと毎回EIOになる
+    let config = Config {
+        webroots: vec!["/proc/self".to_string()],
+        read_retries: 2,
+        read_retry_delay_ms: 200,
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let started = Instant::now();
+    let mut retried = connect(addr);
+    retried.write_all(&get("/mem")).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    // 再試行を待つ間も他のリクエストに応答する
+    assert_eq!(status(&exchange(addr, &get("/comm"))), 200);
+    assert!(started.elapsed() < Duration::from_millis(200));
+    assert_eq!(status(&read_to_close(&mut retried)), 500);
+    assert!(started.elapsed() >= Duration::from_millis(600));
+}
+
+#[test]
+fn root_response_replaces_only_the_root_path() {
+    let config = Config {
+        root_response: Some(RootResponse { body: "status: ok\n".to_string(), content_type: "text/plain".to_string() }),
+        ..config_with_files("root-response", &[("index.html", b"<h1>index</h1>")])
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, &get("/"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/plain"));
+    assert_eq!(body(&response), "status: ok\n");
+    assert_eq!(body(&exchange(addr, &get("/index.html"))), "<h1>index</h1>");
+}
+
+fn root_with_symlinks(name: &str) -> Config {
+    let config = config_with_files(name, &[("real/a.txt", b"inside")]);
+    let root = std::path::PathBuf::from(&config.webroots[0]);
+    let outside = temp_dir(&format!("{}-outside", name));
+    fs::write(outside.join("secret.txt"), "outside").unwrap();
+    symlink(root.join("real/a.txt"), root.join("link.txt")).unwrap();
+    symlink(root.join("real"), root.join("linked-dir")).unwrap();
+    symlink(outside.join("secret.txt"), root.join("escape.txt")).unwrap();
+    symlink(&outside, root.join("escape-dir")).unwrap();
+    config
+}
+
+#[test]
+fn symlinks_inside_the_root_are_followed_when_enabled() {
+    let config = root_with_symlinks("symlinks-on");
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/link.txt"))), "inside");
+    assert_eq!(body(&exchange(addr, &get("/linked-dir/a.txt"))), "inside");
+    assert_eq!(status(&exchange(addr, &get("/escape.txt"))), 403);
+    assert_eq!(status(&exchange(addr, &get("/escape-dir/secret.txt"))), 403);
+}
+
+#[test]
+fn symlinks_are_rejected_when_disabled() {
+    let config = root_with_symlinks("symlinks-off");
+    let addr = start(Config { follow_symlinks: false, ..config }, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/real/a.txt"))), "inside");
+    for path in ["/link.txt", "/linked-dir/a.txt", "/escape.txt", "/escape-dir/secret.txt"] {
+        assert_eq!(status(&exchange(addr, &get(path))), 403, "{}", path);
+    }
+}
+
+#[test]
+fn blocked_extensions_are_forbidden_whether_or_not_they_exist() {
+    let config = Config {
+        blocked_extensions: vec!["bak".to_string(), "php".to_string()],
+        ..config_with_files("blocked", &[("config.php.bak", b"password"), ("index.php", b"<?php"), ("a.txt", b"a")])
+    };
+    let addr = start(config, |_| {});
+    for path in ["/config.php.bak", "/index.php", "/missing.bak", "/INDEX.PHP"] {
+        assert_eq!(status(&exchange(addr, &get(path))), 403, "{}", path);
+    }
+    assert_eq!(body(&exchange(addr, &get("/a.txt"))), "a");
+}
+
+#[test]
+fn relative_webroot_is_resolved_from_the_config_file() {
+    let dir = temp_dir("relative-root");
+    fs::create_dir(dir.join("public")).unwrap();
+    fs::write(dir.join("public/a.txt"), "a").unwrap();
+    let config_path = dir.join("server.toml");
+    fs::write(&config_path, "webroots = [\"public\"]\n").unwrap();
+    let config = Config::load(config_path.to_str().unwrap()).unwrap();
+    assert_eq!(config.webroots, [dir.join("public").to_string_lossy()]);
+    // サーバのカレントディレクトリに関わらず設定ファイルの隣のディレクトリから配信する
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/a.txt"))), "a");
+}
+
+#[test]
+fn downloads_get_content_disposition() {
+    let config = Config {
+        download_extensions: vec!["zip".to_string()],
+        download_query: true,
+        ..config_with_files("downloads", &[("files/site-backup.zip", b"zip"), ("report.txt", b"text")])
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, &get("/files/site-backup.zip"));
+    assert_eq!(header(&response, "Content-Disposition"), Some("attachment; filename=\"site-backup.zip\""));
+    assert_eq!(header(&exchange(addr, &get("/report.txt")), "Content-Disposition"), None);
+    let response = exchange(addr, &get("/report.txt?download"));
+    assert_eq!(header(&response, "Content-Disposition"), Some("attachment; filename=\"report.txt\""));
+}
+
+#[test]
+fn server_header_is_absent_when_disabled() {
+    let config = Config { server_header: false, ..config_with_files("no-server-header", &[("a.txt", b"a")]) };
+    let addr = start(config, |_| {});
+    for (path, expected) in [("/a.txt", 200), ("/missing", 404)] {
+        let response = exchange(addr, &get(path));
+        assert_eq!(status(&response), expected);
+        assert_eq!(header(&response, "Server"), None, "{}", response);
+    }
+}
+
+#[test]
+fn exact_case_rejects_a_mismatched_case_request() {
+    let config = Config { exact_case: true, ..config_with_files("exact-case", &[("Docs/Readme.txt", b"readme")]) };
+    // 大文字小文字を区別しないファイルシステムでのみ、大文字小文字の異なる名前でファイルが見つかる
+    let case_insensitive = std::path::Path::new(&config.webroots[0]).join("docs/readme.txt").is_file();
+    let webroots = config.webroots.clone();
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/Docs/Readme.txt"))), "readme");
+    assert_eq!(status(&exchange(addr, &get("/docs/readme.txt"))), 404);
+
+    if case_insensitive {
+        let addr = start(Config { webroots, ..Config::default() }, |_| {});
+        assert_eq!(status(&exchange(addr, &get("/docs/readme.txt"))), 200);
+    }
+}
+
+#[test]
+fn empty_root_gets_the_placeholder_page_only_when_enabled() {
+    let dir = temp_dir("placeholder").to_string_lossy().into_owned();
+    let addr = start(Config { webroots: vec![dir.clone()], placeholder_page: true, ..Config::default() }, |_| {});
+    let response = exchange(addr, &get("/"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+    assert!(body(&response).contains("<h1>It works</h1>"), "{}", response);
+    // 他のパスは通常どおり404
+    assert_eq!(status(&exchange(addr, &get("/missing.html"))), 404);
+
+    // index.htmlがあればそちらを返す
+    fs::write(std::path::Path::new(&dir).join("index.html"), "home").unwrap();
+    assert_eq!(body(&exchange(addr, &get("/"))), "home");
+
+    let empty = temp_dir("placeholder-off").to_string_lossy().into_owned();
+    let addr = start(Config { webroots: vec![empty], ..Config::default() }, |_| {});
+    assert_eq!(status(&exchange(addr, &get("/"))), 404);
+}
+
+#[test]
+fn download_types_are_matched_against_the_content_type() {
+    let config = Config {
+        download_types: vec!["Text/CSV".to_string(), "application/pdf".to_string()],
+        ..config_with_files("download-types", &[("data.csv", b"a,b"), ("doc.pdf", b"%PDF"), ("page.html", b"<p>")])
+    };
+    let addr = start(config, |_| {});
+    // Content-Typeのcharsetや大文字小文字に関わらず一致する
+    let response = exchange(addr, &get("/data.csv"));
+    assert_eq!(header(&response, "Content-Type"), Some("text/csv; charset=utf-8"));
+    assert_eq!(header(&response, "Content-Disposition"), Some("attachment; filename=\"data.csv\""));
+    let response = exchange(addr, &get("/doc.pdf"));
+    assert_eq!(header(&response, "Content-Disposition"), Some("attachment; filename=\"doc.pdf\""));
+    // 設定していないContent-Typeはブラウザで表示させる
+    assert_eq!(header(&exchange(addr, &get("/page.html")), "Content-Disposition"), None);
+}
+
+#[test]
+fn query_value_selects_a_variant_file() {
+    let config = Config {
+        query_variants: vec!["lang".to_string()],
+        ..config_with_files(
+            "query-variants",
+            &[("docs/index.html", b"english"), ("docs/index.fr.html", b"french"), ("docs/.fr", b"hidden")],
+        )
+    };
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/docs/?lang=fr"))), "french");
+    assert_eq!(body(&exchange(addr, &get("/docs/index.html?x=1&lang=fr"))), "french");
+    // 変種がなければ元のファイルを返す
+    assert_eq!(body(&exchange(addr, &get("/docs/?lang=de"))), "english");
+    assert_eq!(body(&exchange(addr, &get("/docs/"))), "english");
+    // 別のディレクトリやドットファイルを指す値は無視する
+    for value in ["..%2F..%2Fsecret", "fr%2F..", ".fr", "fr.html"] {
+        assert_eq!(body(&exchange(addr, &get(&format!("/docs/?lang={}", value)))), "english", "{}", value);
+    }
+}
+
+#[test]
+fn query_variants_with_a_blocked_extension_are_not_served() {
+    let config = Config {
+        query_variants: vec!["lang".to_string()],
+        blocked_extensions: vec!["bak".to_string()],
+        ..config_with_files("query-variants-blocked", &[("README", b"readme"), ("README.bak", b"backup")])
+    };
+    let addr = start(config, |_| {});
+    assert_eq!(body(&exchange(addr, &get("/README?lang=bak"))), "readme");
+    assert_eq!(status(&exchange(addr, &get("/README.bak"))), 403);
+}
+
+#[test]
+fn charset_follows_a_bom_or_meta_declaration() {
+    let files: &[(&str, &[u8])] = &[
+        ("bom.txt", b"\xEF\xBB\xBFhello"),
+        ("utf16.txt", b"\xFF\xFEh\0i\0"),
+        ("legacy.html", b"<html><head><meta charset=\"iso-8859-1\"></head><body>caf\xe9</body></html>"),
+        ("plain.html", b"<p>plain</p>"),
+    ];
+    let addr = start(config_with_files("charset", files), |_| {});
+    let content_type = |path: &str| header(&exchange(addr, &get(path)), "Content-Type").map(str::to_string);
+    assert_eq!(content_type("/bom.txt").as_deref(), Some("text/plain; charset=utf-8"));
+    assert_eq!(content_type("/utf16.txt").as_deref(), Some("text/plain; charset=utf-16le"));
+    assert_eq!(content_type("/legacy.html").as_deref(), Some("text/html; charset=iso-8859-1"));
+    assert_eq!(content_type("/plain.html").as_deref(), Some("text/html; charset=utf-8"));
+
+    let config = Config { charset_detection: false, ..config_with_files("charset-off", files) };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, &get("/legacy.html"));
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+}
+
+#[test]
+fn preloaded_files_are_served_from_memory() {
+    let config = Config {
+        preload: vec!["/app.js".to_string()],
+        ..config_with_files("preload", &[("app.js", b"preloaded"), ("other.js", b"other")])
+    };
+    let dir = config.webroots[0].clone();
+    let addr = start(config, |_| {});
+    // 起動後にディスク上のファイルを消しても、読み込んだ内容を返す
+    fs::remove_file(format!("{}/app.js", dir)).unwrap();
+    let response = exchange(addr, &get("/app.js"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/javascript; charset=utf-8"));
+    assert_eq!(body(&response), "preloaded");
+    assert_eq!(body(&exchange(addr, &get("/other.js"))), "other");
+
+    // 配信できないファイルを指定した場合は起動しない
+    for path in ["/missing.js", "/.env", "/"] {
+        let config =
+            Config { preload: vec![path.to_string()], ..config_with_files("preload-invalid", &[(".env", b"x")]) };
+        assert!(matches!(WebServer::new("127.0.0.1:0", config), Err(Error::Config(_))), "{}", path);
+    }
+}
+
+#[test]
+fn concat_path_joins_files_in_the_requested_order() {
+    let config = Config {
+        concat_path: Some("/bundle".to_string()),
+        ..config_with_files(
+            "concat",
+            &[("css/reset.css", b"* { margin: 0 }\n"), ("css/site.css", b"body { color: red }\n"), ("app.js", b"js")],
+        )
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, &get("/bundle?files=/css/site.css,/css/reset.css"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/css; charset=utf-8"));
+    assert_eq!(header(&response, "Content-Length"), Some("36"));
+    assert_eq!(body(&response), "body { color: red }\n* { margin: 0 }\n");
+    let response = exchange(addr, &get("/bundle?files=/css/site.css,/app.js"));
+    assert_eq!(header(&response, "Content-Type"), Some("application/octet-stream"));
+
+    // 各パスをドキュメントルートの外やドットファイルに向けることはできない
+    assert_eq!(status(&exchange(addr, &get("/bundle?files=/css/site.css,/css/../../etc/passwd"))), 403);
+    assert_eq!(status(&exchange(addr, &get("/bundle?files=/css/site.css,/missing.css"))), 404);
+    assert_eq!(status(&exchange(addr, &get("/bundle"))), 400);
+}
+
+#[test]
+fn preload_links_are_added_to_successful_responses() {
+    let link = "</style.css>; rel=preload; as=style";
+    let rule = |path: &str| PreloadLinkRule { path: path.to_string(), links: vec![link.to_string()] };
+    let config = Config {
+        preload_links: vec![rule("/"), rule("/missing.html")],
+        early_hints: vec![EarlyHintRule { path: "/".to_string(), links: vec![link.to_string()] }],
+        ..config_with_files("preload-links", &[("index.html", b"index"), ("other.html", b"other")])
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET / HTTP/1.0\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Link"), Some(link));
+    // 103で送ったものと同じLinkは最終的なレスポンスに重ねて付けない
+    let response = exchange(addr, &get("/"));
+    let (_, final_response) = response.split_once("\r\n\r\nHTTP/1.0 ").unwrap();
+    assert_eq!(final_response.matches("Link: ").count(), 1, "{}", response);
+
+    assert_eq!(header(&exchange(addr, &get("/other.html")), "Link"), None);
+    let response = exchange(addr, &get("/missing.html"));
+    assert_eq!(status(&response), 404);
+    assert_eq!(header(&response, "Link"), None);
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use common::{body, connect, exchange, header, start, status, temp_dir};
+use web_server::{create_msg_from_code, AccessControlRule, Cidr, Config};
+
+#[test]
+fn debug_echo_returns_the_parsed_request() {
+    let addr = start(Config { debug_echo: true, ..Config::default() }, |_| {});
+    let response = exchange(addr, b"GET /debug/echo?x=1 HTTP/1.1\r\nHost: a\r\nX-Trace: abc\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/plain; charset=utf-8"));
+    let echo = body(&response);
+    assert!(echo.starts_with("method: GET\ntarget: /debug/echo?x=1\nversion: HTTP/1.1\n\n"), "{}", echo);
+    assert!(echo.contains("X-Trace: abc\n"), "{}", echo);
+}
+
+#[test]
+fn debug_echo_is_off_by_default() {
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(addr, b"GET /debug/echo HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
+
+#[test]
+fn trace_is_rejected_by_default() {
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(addr, b"TRACE / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 405);
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+}
+
+#[test]
+fn trace_echo_returns_the_request_as_message_http() {
+    let addr = start(Config { trace_echo: true, ..Config::default() }, |_| {});
+    let response = exchange(addr, b"TRACE /x HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("message/http"));
+    let echo = body(&response);
+    assert!(echo.starts_with("TRACE /x HTTP/1.1\r\n"), "{}", echo);
+    assert!(echo.contains("Host: a\r\n"), "{}", echo);
+
+    let response = exchange(addr, b"OPTIONS * HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS, TRACE"));
+}
+
+#[test]
+fn version_endpoint_reports_the_crate_version() {
+    // ファイルシステムを参照しないので、ドキュメントルートがなくても応答する
+    let config = Config {
+        version_endpoint: true,
+        webroots: vec!["/nonexistent/web-server-test-root".to_string()],
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /version HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+    let json = body(&response);
+    assert!(json.contains(&format!("\"version\":\"{}\"", env!("CARGO_PKG_VERSION"))), "{}", json);
+    assert!(json.contains("\"commit\":\""), "{}", json);
+    assert!(json.contains("\"build_timestamp\":\""), "{}", json);
+
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(addr, b"GET /version HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
+
+#[test]
+fn health_check_head_is_answered_for_the_configured_path_only() {
+    let config = Config { health_check_path: Some("/healthz".to_string()), ..Config::default() };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"HEAD /healthz HTTP/1.1\r\nHost: lb\r\n\r\nHEAD /healthz HTTP/1.1\r\nHost: lb\r\n\r\n").unwrap();
+    for _ in 0..2 {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte).unwrap();
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head).unwrap();
+        assert_eq!(status(&head), 200);
+        assert_eq!(header(&head, "Content-Length"), Some("0"));
+        assert_eq!(header(&head, "Connection"), Some("keep-alive"));
+    }
+    let response = exchange(addr, b"HEAD /other HTTP/1.1\r\nHost: lb\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
+
+fn allow_header(addr: SocketAddr, target: &str) -> String {
+    let response = exchange(addr, format!("OPTIONS {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", target).as_bytes());
+    assert_eq!(status(&response), 204);
+    header(&response, "Allow").unwrap().to_string()
+}
+
+#[test]
+fn allow_follows_upload_support_and_routes() {
+    let addr = start(Config::default(), |_| {});
+    assert_eq!(allow_header(addr, "/file.txt"), "GET, HEAD, OPTIONS");
+
+    let upload_root = temp_dir("allow-upload").to_string_lossy().into_owned();
+    let addr = start(Config { upload_root: Some(upload_root), ..Config::default() }, |server| {
+        server.add_route("POST", "/forms/:name", |_, _| create_msg_from_code(204, None));
+    });
+    assert_eq!(allow_header(addr, "/file.txt"), "GET, HEAD, OPTIONS, PUT, DELETE, PATCH");
+    // ルートのメソッドはパスが一致するリソースにだけ加える
+    assert_eq!(allow_header(addr, "/forms/contact"), "GET, HEAD, OPTIONS, PUT, DELETE, PATCH, POST");
+}
+
+#[test]
+fn debug_connections_lists_open_connections() {
+    let addr = start(Config { debug_connections: true, ..Config::default() }, |_| {});
+    let partial = b"GET /slow HTTP/1.1\r\nHost: a\r\n";
+    let mut stream = connect(addr);
+    stream.write_all(partial).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    let response = exchange(addr, b"GET /debug/connections HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+    let json = body(&response);
+    let local = stream.local_addr().unwrap();
+    let entry = format!(
+        "\"state\":\"ReadingRequest\",\"remote_addr\":\"{}\",\"tls\":false,\"bytes_read\":{},\"bytes_written\":0,",
+        local,
+        partial.len()
+    );
+    assert!(json.contains(&entry), "{}", json);
+    // 一覧を要求している接続自身も含む
+    assert_eq!(json.matches("\"remote_addr\"").count(), 2, "{}", json);
+}
+
+#[test]
+fn debug_connections_follows_access_control() {
+    let config = Config {
+        debug_connections: true,
+        access_control: vec![AccessControlRule {
+            path_prefix: "/debug/".to_string(),
+            allow: vec![Cidr::try_from("10.0.0.0/8".to_string()).unwrap()],
+        }],
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /debug/connections HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 403);
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(addr, b"GET /debug/connections HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
+
+#[test]
+fn status_dashboard_counts_requests() {
+    let addr = start(Config { status_dashboard: true, ..Config::default() }, |_| {});
+    let request = |path: &str| {
+        exchange(addr, format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).as_bytes())
+    };
+    assert_eq!(status(&request("/missing")), 404);
+    assert_eq!(status(&request("/missing")), 404);
+    let response = request("/status");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(header(&response, "Cache-Control"), Some("no-store"));
+    let html = body(&response);
+    assert!(html.contains("<li>Total requests: 2</li>"), "{}", html);
+    assert!(html.contains("<tr><td>404</td><td>2</td></tr>"), "{}", html);
+    // 表示したリクエストも次に表示する件数に含める
+    assert!(body(&request("/status")).contains("<li>Total requests: 3</li>"));
+
+    let config = Config {
+        status_dashboard: true,
+        access_control: vec![AccessControlRule {
+            path_prefix: "/status".to_string(),
+            allow: vec![Cidr::try_from("10.0.0.0/8".to_string()).unwrap()],
+        }],
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /status HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 403);
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(addr, b"GET /status HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use std::io::Write;
+use common::{config_with_files, connect, exchange, header, read_response, read_to_close, start, status};
+use web_server::{Config, EarlyHintRule};
+
+#[test]
+fn chunked_body_is_not_parsed_as_the_next_request() {
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(
+        addr,
+        b"POST / HTTP/1.1\r\nHost: a\r\nTransfer-Encoding: chunked\r\n\r\n\
+          1c\r\nGET /smuggled HTTP/1.1\r\n\r\n\r\n0\r\n\r\n",
+    );
+    // 501を返して接続を閉じ、チャンクの中身をリクエストとして処理しない
+    assert_eq!(status(&response), 501);
+    assert_eq!(response.matches("HTTP/1.").count(), 1, "{}", response);
+}
+
+#[test]
+fn content_length_with_transfer_encoding_closes_the_connection() {
+    let addr = start(Config::default(), |_| {});
+    let response = exchange(
+        addr,
+        b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n\
+          0\r\n\r\nGET / HTTP/1.1\r\nHost: a\r\n\r\n",
+    );
+    assert_eq!(status(&response), 400);
+    assert_eq!(response.matches("HTTP/1.").count(), 1, "{}", response);
+}
+
+#[test]
+fn conflicting_content_lengths_are_rejected() {
+    let addr = start(Config::default(), |_| {});
+    for raw in [
+        &b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5, 6\r\n\r\nhello!"[..],
+        b"POST / HTTP/1.1\r\nHost: a\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+    ] {
+        let response = exchange(addr, raw);
+        assert_eq!(status(&response), 400);
+        assert_eq!(response.matches("HTTP/1.").count(), 1, "{}", response);
+    }
+}
+
+#[test]
+fn options_response_is_framed_for_the_next_request() {
+    let addr = start(config_with_files("options-framing", &[("a.txt", b"a")]), |_| {});
+    let mut stream = connect(addr);
+    stream
+        .write_all(b"OPTIONS * HTTP/1.1\r\nHost: a\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 204);
+    // 204はボディを持たないので、Content-Lengthを付けずに次のレスポンスと区切れる
+    assert_eq!(header(&head, "Content-Length"), None);
+    assert!(body.is_empty());
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(body, b"a");
+}
+
+#[test]
+fn unsupported_expectation_is_417() {
+    let addr = start(config_with_files("expect", &[("a.txt", b"a")]), |_| {});
+    let response = exchange(addr, b"GET /a.txt HTTP/1.1\r\nHost: a\r\nExpect: foo\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 417);
+    let response = exchange(
+        addr,
+        b"GET /a.txt HTTP/1.1\r\nHost: a\r\nExpect: 100-Continue\r\nConnection: close\r\n\r\n",
+    );
+    assert_eq!(status(&response), 200);
+}
+
+#[test]
+fn http2_preface_is_505_and_closes_the_connection() {
+    let addr = start(Config::default(), |_| {});
+    // プリフェイスの後にSETTINGSフレームが続いても、HTTP/1.xのリクエストとして解釈しない
+    let response = exchange(addr, b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n\x00\x00\x00\x04\x00\x00\x00\x00\x00");
+    assert!(response.starts_with("HTTP/1.0 505 HTTP Version Not Supported\r\n"), "{}", response);
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    assert_eq!(response.matches("HTTP/1.0 ").count(), 1);
+}
+
+#[test]
+fn non_utf8_header_value_is_served_and_keeps_the_connection() {
+    let addr = start(config_with_files("obs-text", &[("a.txt", b"a")]), |_| {});
+    let mut stream = connect(addr);
+    stream
+        .write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\nX-Name: caf\xe9\r\n\r\nGET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n")
+        .unwrap();
+    for _ in 0..2 {
+        let (head, body) = read_response(&mut stream);
+        assert_eq!(status(&head), 200);
+        assert_eq!(body, b"a");
+    }
+    // UTF-8でないリクエストラインは接続を切らずに400を返す
+    let response = exchange(addr, b"GET /a\xff.txt HTTP/1.1\r\nHost: a\r\n\r\n");
+    assert_eq!(status(&response), 400);
+}
+
+#[test]
+fn early_hints_are_sent_before_the_final_response() {
+    let links = ["</style.css>; rel=preload; as=style", "</app.js>; rel=preload; as=script"];
+    let rule = EarlyHintRule { path: "/".to_string(), links: links.iter().map(|link| link.to_string()).collect() };
+    let config = Config {
+        early_hints: vec![rule],
+        ..config_with_files("early-hints", &[("index.html", b"index"), ("other.html", b"other")])
+    };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 103 Early Hints\r\n"), "{}", head);
+    assert_eq!(header(&head, "Content-Length"), None);
+    assert!(body.is_empty());
+    for link in links {
+        assert!(head.contains(&format!("Link: {}\r\n", link)), "{}", head);
+    }
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(body, b"index");
+    assert_eq!(head.matches("Link: ").count(), 2, "{}", head);
+
+    // HTTP/1.0のクライアントや設定していないパスには送らない
+    let response = exchange(addr, b"GET / HTTP/1.0\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(response.matches("HTTP/1.").count(), 1, "{}", response);
+    let response = exchange(addr, b"GET /other.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Link"), None);
+}
+
+#[test]
+fn header_less_http09_request_is_rejected_without_waiting() {
+    let addr = start(config_with_files("http09", &[("a.txt", b"a")]), |_| {});
+    let mut stream = connect(addr);
+    // 空行を送らずに、リクエストラインだけで応答を待つ
+    stream.write_all(b"GET /a.txt\r\n").unwrap();
+    let response = read_to_close(&mut stream);
+    assert_eq!(status(&response), 400);
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    // ヘッダのないHTTP/1.0のリクエストは配信する
+    let response = exchange(addr, b"GET /a.txt HTTP/1.0\r\n\r\n");
+    assert_eq!(status(&response), 200);
+}
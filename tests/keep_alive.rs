@@ -0,0 +1,68 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use common::{config_with_files, connect, header, read_response, start, start_with_clock, status};
+use web_server::{Config, MockClock};
+
+fn files(name: &str) -> Config {
+    config_with_files(name, &[("a.txt", b"a"), ("b.txt", b"bb")])
+}
+
+#[test]
+fn connection_is_reused_until_the_idle_timeout() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_with_clock(files("keep-alive-idle"), clock.clone(), |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(header(&head, "Connection"), Some("keep-alive"));
+    assert_eq!(header(&head, "Keep-Alive"), Some("timeout=5"));
+    assert_eq!(body, b"a");
+    stream.write_all(b"GET /b.txt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (_, body) = read_response(&mut stream);
+    assert_eq!(body, b"bb");
+
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(6));
+    // 別の接続でイベントループを起こし、タイムアウトを確認させる
+    drop(connect(addr));
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn zero_timeout_disables_keep_alive() {
+    let config = Config { keep_alive_timeout: 0, ..files("keep-alive-off") };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(header(&head, "Connection"), Some("close"));
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn connection_header_follows_the_version_default() {
+    let addr = start(files("keep-alive-versions"), |_| {});
+    for (version, connection, keep_alive) in [
+        ("1.0", "", false),
+        ("1.0", "Connection: keep-alive\r\n", true),
+        ("1.1", "", true),
+        ("1.1", "Connection: close\r\n", false),
+    ] {
+        let mut stream = connect(addr);
+        write!(stream, "GET /a.txt HTTP/{}\r\nHost: a\r\n{}\r\n", version, connection).unwrap();
+        let (head, _) = read_response(&mut stream);
+        let expected = if keep_alive { "keep-alive" } else { "close" };
+        assert_eq!(header(&head, "Connection"), Some(expected), "{} {:?}", version, connection);
+        if !keep_alive {
+            let mut rest = Vec::new();
+            assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+// イベントループを使わずにライブラリの関数だけでレスポンスを作る(ベンチマークと同じ使い方)
+use std::fs;
+use std::io;
+use web_server::{
+    create_msg_from_code, make_response, parse_request, Config, DocumentRoot, Error, ListenerConfig, ParseError, TlsConfig,
+    WebServer,
+};
+
+fn root(name: &str, files: &[(&str, &[u8])]) -> DocumentRoot {
+    let dir = std::env::temp_dir().join(format!("web-server-library-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for (path, content) in files {
+        fs::write(dir.join(path), content).unwrap();
+    }
+    DocumentRoot::Directories(vec![dir])
+}
+
+#[test]
+fn make_response_serves_files_without_a_server() {
+    let root = root("serve", &[("index.html", b"<p>hi</p>")]);
+    let config = Config::default();
+    let (request, _) = parse_request(b"GET /index.html HTTP/1.0\r\n\r\n").unwrap().unwrap();
+    let response = make_response(&request, &root, &config).unwrap();
+    assert_eq!(response.status_code, 200);
+    assert_eq!(response.body, b"<p>hi</p>");
+    assert_eq!(response.header("Content-Type"), Some("text/html; charset=utf-8"));
+    let bytes = response.to_bytes(&config, Some(request.path()));
+    assert!(bytes.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    assert!(bytes.ends_with(b"\r\n\r\n<p>hi</p>"));
+
+    let (request, _) = parse_request(b"GET /missing.html HTTP/1.0\r\n\r\n").unwrap().unwrap();
+    assert_eq!(make_response(&request, &root, &config).unwrap().status_code, 404);
+}
+
+#[test]
+fn errors_are_categorized() {
+    let dir = std::env::temp_dir().join(format!("web-server-library-{}-errors", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+
+    let missing = dir.join("missing.toml");
+    let err = Config::load(missing.to_str().unwrap()).unwrap_err();
+    assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::NotFound), "{:?}", err);
+
+    let invalid = dir.join("invalid.toml");
+    fs::write(&invalid, "max_connections = \"many\"").unwrap();
+    assert!(matches!(Config::load(invalid.to_str().unwrap()), Err(Error::Config(_))));
+    let zero = dir.join("zero.toml");
+    fs::write(&zero, "max_connections = 0").unwrap();
+    assert!(matches!(Config::load(zero.to_str().unwrap()), Err(Error::Config(_))));
+
+    assert!(matches!(WebServer::new("not an address", Config::default()), Err(Error::Config(_))));
+    let tls = TlsConfig {
+        cert: dir.join("missing.pem").to_string_lossy().into_owned(),
+        key: dir.join("missing.key").to_string_lossy().into_owned(),
+        sni: Vec::new(),
+    };
+    let config = Config {
+        listeners: vec![ListenerConfig { addr: "127.0.0.1:0".to_string(), tls: Some(tls) }],
+        ..Config::default()
+    };
+    assert!(matches!(WebServer::new("127.0.0.1:0", config), Err(Error::Config(_))));
+
+    assert!(matches!(create_msg_from_code(299, None), Err(Error::Protocol(_))));
+    let err = Error::from(parse_request(b"GET / HTTP/2.0\r\n\r\n").err().unwrap());
+    assert!(matches!(err, Error::Parse(ParseError::UnsupportedVersion)), "{:?}", err);
+}
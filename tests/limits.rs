@@ -0,0 +1,75 @@
+mod common;
+
+use std::io::{Read, Write};
+use common::{body, config_with_files, connect, exchange, header, start, status};
+use web_server::Config;
+
+fn get(target: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", target).into_bytes()
+}
+
+#[test]
+fn long_query_string_is_414_below_the_request_line_limit() {
+    let addr = start(Config { max_target_length: 64, ..Config::default() }, |_| {});
+    let within = format!("/search?q={}", "a".repeat(64 - "/search?q=".len()));
+    assert_eq!(status(&exchange(addr, &get(&within))), 404);
+    let over = format!("{}a", within);
+    assert_eq!(status(&exchange(addr, &get(&over))), 414);
+}
+
+#[test]
+fn large_responses_beyond_the_buffer_cap_get_503() {
+    // ソケットのバッファに収まりきらず、読まないクライアントの分が送信待ちに残る大きさにする
+    let large: Vec<u8> = (0..32 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let config = Config {
+        max_response_buffer_bytes: 40 * 1024 * 1024,
+        ..config_with_files("response-buffer-cap", &[("large.bin", &large), ("a.txt", b"a")])
+    };
+    let addr = start(config, |_| {});
+
+    // 先頭だけ読んで止めると、残りは送信待ちのまま
+    let mut stalled = connect(addr);
+    stalled.write_all(&get("/large.bin")).unwrap();
+    let mut head = [0u8; 15];
+    stalled.read_exact(&mut head).unwrap();
+    assert_eq!(&head, b"HTTP/1.0 200 OK");
+
+    let response = exchange(addr, &get("/large.bin"));
+    assert_eq!(status(&response), 503);
+    assert!(header(&response, "Retry-After").is_some());
+    // 小さなレスポンスは上限に関わらず返す
+    assert_eq!(status(&exchange(addr, &get("/a.txt"))), 200);
+
+    // 送信し終えると合計から除かれ、再び大きなレスポンスを返せる
+    let mut rest = Vec::new();
+    stalled.read_to_end(&mut rest).unwrap();
+    assert!(rest.ends_with(&large[large.len() - 1024..]));
+    let mut stream = connect(addr);
+    stream.write_all(&get("/large.bin")).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(received.starts_with(b"HTTP/1.0 200 OK\r\n"));
+    assert!(received.ends_with(&large[large.len() - 1024..]));
+}
+
+#[test]
+fn verbose_errors_explain_the_problem_without_echoing_the_request() {
+    let config = Config { max_target_length: 64, verbose_errors: true, ..Config::default() };
+    let addr = start(config, |_| {});
+    let target = format!("/<script>{}", "a".repeat(100));
+    let response = exchange(addr, &get(&target));
+    assert_eq!(status(&response), 414);
+    assert_eq!(body(&response), "414 URI Too Long: request target too long\n");
+    assert_eq!(header(&response, "Content-Type"), Some("text/plain; charset=utf-8"));
+    assert!(!response.contains("<script>"), "{}", response);
+
+    let response = exchange(addr, b"GET /a HTTP/1.1\r\nHost a\r\n\r\n");
+    assert_eq!(status(&response), 400);
+    assert_eq!(body(&response), "400 Bad Request: malformed header field\n");
+
+    // 無効の場合はボディを返さない
+    let addr = start(Config { max_target_length: 64, ..Config::default() }, |_| {});
+    let response = exchange(addr, &get(&target));
+    assert_eq!(status(&response), 414);
+    assert_eq!(body(&response), "");
+}
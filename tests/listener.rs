@@ -0,0 +1,129 @@
+mod common;
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use common::{connect, exchange, header, read_response, read_to_close, start, start_listeners, start_with_clock, status};
+use web_server::{AcceptRate, Cidr, Config, MockClock, RedirectListener};
+
+#[test]
+fn small_backlog_still_accepts_every_connection() {
+    let addr = start(Config { listen_backlog: 1, ..Config::default() }, |_| {});
+    let clients: Vec<_> = (0..8)
+        .map(|_| std::thread::spawn(move || exchange(addr, b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")))
+        .collect();
+    for client in clients {
+        assert_eq!(status(&client.join().unwrap()), 404);
+    }
+}
+
+#[test]
+fn burst_of_connections_is_accepted_from_one_event() {
+    let addr = start(Config::default(), |_| {});
+    // 先に全て接続しておき、1回の通知で複数の接続が待っている状態にする
+    let mut streams: Vec<_> = (0..64).map(|_| connect(addr)).collect();
+    for stream in &mut streams {
+        stream.write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    }
+    for stream in &mut streams {
+        assert_eq!(status(&read_to_close(stream)), 404);
+    }
+}
+
+#[test]
+fn redirect_listener_sends_every_request_to_https() {
+    let config = Config {
+        redirect_listener: Some(RedirectListener { addr: "127.0.0.1:0".to_string(), https_port: Some(8443) }),
+        ..Config::default()
+    };
+    let addrs = start_listeners(config, |_| {});
+    let response = exchange(addrs[1], b"GET /a/b?x=1 HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 301);
+    assert_eq!(header(&response, "Location"), Some("https://example.com:8443/a/b?x=1"));
+    // Hostがなければリダイレクト先を決められない
+    let response = exchange(addrs[1], b"GET / HTTP/1.0\r\n\r\n");
+    assert_eq!(status(&response), 400);
+    // 起動時のアドレスは通常どおり配信する
+    assert_eq!(status(&exchange(addrs[0], b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")), 404);
+}
+
+#[test]
+fn connections_beyond_the_accept_rate_wait_for_tokens() {
+    let clock = Arc::new(MockClock::new());
+    let config = Config { accept_rate: Some(AcceptRate { per_second: 1.0, burst: 2 }), ..Config::default() };
+    let addr = start_with_clock(config, clock.clone(), |_| {});
+    let request = b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+    let mut streams: Vec<_> = (0..3).map(|_| connect(addr)).collect();
+    for stream in &mut streams {
+        stream.write_all(request).unwrap();
+    }
+    assert_eq!(status(&read_to_close(&mut streams[0])), 404);
+    assert_eq!(status(&read_to_close(&mut streams[1])), 404);
+
+    // 3つ目はトークンが溜まるまで受け付けられず、応答が来ない
+    let mut third = streams.pop().unwrap();
+    third.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let mut byte = [0u8; 1];
+    let err = third.read(&mut byte).unwrap_err();
+    assert!(matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{}", err);
+
+    clock.advance(Duration::from_secs(1));
+    third.set_read_timeout(Some(common::READ_TIMEOUT)).unwrap();
+    assert_eq!(status(&read_to_close(&mut third)), 404);
+}
+
+fn cidrs(ranges: &[&str]) -> Vec<Cidr> {
+    ranges.iter().map(|range| Cidr::try_from(range.to_string()).unwrap()).collect()
+}
+
+#[test]
+fn denied_clients_are_closed_at_accept() {
+    let config = Config {
+        allow_clients: cidrs(&["127.0.0.0/8"]),
+        deny_clients: cidrs(&["127.0.0.1/32"]),
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    // 拒否した接続はリクエストを読まずに閉じるので、何も返さない
+    let mut stream = connect(addr);
+    let _ = stream.write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\n");
+    let mut buf = Vec::new();
+    match stream.read_to_end(&mut buf) {
+        Ok(_) => assert!(buf.is_empty(), "{}", String::from_utf8_lossy(&buf)),
+        Err(err) => assert_eq!(err.kind(), io::ErrorKind::ConnectionReset, "{}", err),
+    }
+}
+
+#[test]
+fn allowed_clients_proceed() {
+    let config = Config { allow_clients: cidrs(&["10.0.0.0/8", "127.0.0.1/32"]), ..Config::default() };
+    let addr = start(config, |_| {});
+    let response = exchange(addr, b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+}
+
+#[test]
+fn existing_connections_progress_during_a_connection_flood() {
+    let addr = start(Config { max_accepts_per_poll: 1, ..Config::default() }, |_| {});
+    let mut existing = connect(addr);
+    let flood: Vec<_> = (0..8)
+        .map(|_| {
+            std::thread::spawn(move || {
+                let streams: Vec<_> = (0..16).map(|_| connect(addr)).collect();
+                for mut stream in streams {
+                    stream.write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+                    assert_eq!(status(&read_to_close(&mut stream)), 404);
+                }
+            })
+        })
+        .collect();
+    // 接続を受け付けている間も、既存の接続のリクエストに応答し続ける
+    for _ in 0..20 {
+        existing.write_all(b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+        assert_eq!(status(&read_response(&mut existing).0), 404);
+    }
+    // 1回に1つずつ受け付けても、接続待ちに残した接続は全て受け付ける
+    for client in flood {
+        client.join().unwrap();
+    }
+}
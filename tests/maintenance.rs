@@ -0,0 +1,40 @@
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+use common::{body, config_with_files, connect, exchange, read_response, start, status, temp_dir};
+use web_server::create_content_response;
+
+fn get(path: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).into_bytes()
+}
+
+#[test]
+fn maintenance_page_is_toggled_by_sighup_and_spares_health_checks() {
+    let mut config = config_with_files("maintenance", &[("index.html", b"<p>site</p>")]);
+    let page = temp_dir("maintenance-page").join("maintenance.html");
+    fs::write(&page, "<p>maintenance</p>").unwrap();
+    config.maintenance_page = Some(page.to_string_lossy().into_owned());
+    config.health_check_path = Some("/healthz".to_string());
+    let addr = start(config, |server| {
+        server.add_route("GET", "/healthz", |_, _| create_content_response(200, "text/plain", b"ok".to_vec()));
+    });
+
+    let response = exchange(addr, &get("/index.html"));
+    assert_eq!(status(&response), 503);
+    assert_eq!(body(&response), "<p>maintenance</p>");
+    assert_eq!(body(&exchange(addr, &get("/healthz"))), "ok");
+    let mut stream = connect(addr);
+    stream.write_all(b"HEAD /healthz HTTP/1.1\r\nHost: lb\r\n\r\n").unwrap();
+    assert_eq!(status(&read_response(&mut stream).0), 200);
+
+    // ページを消してSIGHUPを送るとメンテナンスを終える
+    fs::remove_file(&page).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    // SAFETY: 自プロセスにシグナルを送るだけで、サーバがハンドラを登録済み
+    assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGHUP) }, 0);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(body(&exchange(addr, &get("/index.html"))), "<p>site</p>");
+}
@@ -0,0 +1,140 @@
+mod common;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use common::{config_with_files, connect, header, read_response, start, status};
+use web_server::{create_content_response, Config};
+
+fn files(name: &str) -> Config {
+    config_with_files(name, &[("a.txt", b"a"), ("b.txt", b"bb"), ("c.txt", b"ccc")])
+}
+
+#[test]
+fn pipelined_requests_are_answered_in_order() {
+    let addr = start(files("pipeline-three"), |_| {});
+    let mut stream = connect(addr);
+    stream
+        .write_all(
+            b"GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /b.txt HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /c.txt HTTP/1.1\r\nHost: a\r\n\r\n",
+        )
+        .unwrap();
+    for expected in [&b"a"[..], b"bb", b"ccc"] {
+        let (head, body) = read_response(&mut stream);
+        assert_eq!(status(&head), 200);
+        assert_eq!(body, expected);
+    }
+}
+
+#[test]
+fn request_split_across_reads_is_kept_for_the_next_parse() {
+    let addr = start(files("pipeline-split"), |_| {});
+    let mut stream = connect(addr);
+    // 2つ目のリクエストの途中までを1つ目と同じ書き込みで送る
+    stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\nGET /b.t").unwrap();
+    let (_, body) = read_response(&mut stream);
+    assert_eq!(body, b"a");
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(b"xt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (_, body) = read_response(&mut stream);
+    assert_eq!(body, b"bb");
+}
+
+#[test]
+fn fast_responses_do_not_overtake_a_large_one() {
+    // 送信待ちの上限を超える大きなファイルの後に、すぐに作れる304と小さなファイルを続ける
+    let large: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let config = config_with_files("pipeline-order", &[("large.bin", &large), ("a.txt", b"a")]);
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    let etag = header(&head, "ETag").unwrap().to_string();
+
+    let pipelined = format!(
+        "GET /large.bin HTTP/1.1\r\nHost: a\r\n\r\n\
+         GET /a.txt HTTP/1.1\r\nHost: a\r\nIf-None-Match: {}\r\n\r\n\
+         GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n",
+        etag
+    );
+    stream.write_all(pipelined.as_bytes()).unwrap();
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert!(body == large, "the large body was interleaved with another response");
+    let (head, _) = read_response(&mut stream);
+    assert_eq!(status(&head), 304);
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(body, b"a");
+}
+
+#[test]
+fn queued_responses_stay_bounded_for_a_slow_reader() {
+    let large: Vec<u8> = (0..512 * 1024u32).map(|i| (i % 251) as u8).collect();
+    // 全てのレスポンスを一度に送信待ちにすると上限を超えて503になる
+    let config = Config {
+        max_response_buffer_bytes: 3 * 1024 * 1024,
+        ..config_with_files("pipeline-backpressure", &[("large.bin", &large)])
+    };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(&b"GET /large.bin HTTP/1.1\r\nHost: a\r\n\r\n".repeat(12)).unwrap();
+    // 読まずに待つ間も、後続のリクエストは送信し終えるまで処理されない
+    thread::sleep(Duration::from_millis(300));
+    for _ in 0..12 {
+        let (head, body) = read_response(&mut stream);
+        assert_eq!(status(&head), 200, "{}", head);
+        assert!(body == large);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn requests_beyond_the_pipeline_limit_are_answered_after_the_queue_drains() {
+    let config = Config { max_pipelined_requests: 1, ..files("pipeline-limit") };
+    let addr = start(config, |_| {});
+    let mut stream = connect(addr);
+    stream
+        .write_all(
+            b"GET /c.txt HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /b.txt HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n",
+        )
+        .unwrap();
+    for expected in [&b"ccc"[..], b"bb", b"a"] {
+        let (_, body) = read_response(&mut stream);
+        assert_eq!(body, expected);
+    }
+}
+
+#[test]
+fn one_connection_cannot_monopolize_the_loop_with_pipelined_requests() {
+    // ハンドラが処理したリクエストのパスを処理した順に記録する
+    let handled = Arc::new(Mutex::new(Vec::new()));
+    let log = Arc::clone(&handled);
+    let addr = start(Config { max_pipelined_requests: 2, ..Config::default() }, move |server| {
+        server.add_route("GET", "/:name", move |request, _| {
+            log.lock().unwrap().push(request.path().to_string());
+            if request.path() == "/slow" {
+                thread::sleep(Duration::from_millis(20));
+            }
+            create_content_response(200, "text/plain", b"done".to_vec())
+        });
+    });
+    let mut busy = connect(addr);
+    busy.write_all(&b"GET /slow HTTP/1.1\r\nHost: a\r\n\r\n".repeat(20)).unwrap();
+    thread::sleep(Duration::from_millis(10));
+    let mut other = connect(addr);
+    other.write_all(b"GET /fast HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    assert_eq!(read_response(&mut other).1, b"done");
+    for _ in 0..20 {
+        read_response(&mut busy);
+    }
+    // 上限の数だけ処理したら他の接続に順番を譲るので、20件すべてを待たずに処理される
+    let handled = handled.lock().unwrap();
+    let position = handled.iter().position(|path| path == "/fast").unwrap();
+    assert!(position < 10, "{:?}", handled);
+}
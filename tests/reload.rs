@@ -0,0 +1,96 @@
+// SIGHUPで設定ファイルを読み直す。シグナルはプロセス全体に届くので別のテストバイナリにする
+mod common;
+
+use std::fs;
+use std::net::SocketAddr;
+use std::os::unix::fs::symlink;
+use std::thread;
+use std::time::Duration;
+use common::{body, exchange, header, start, status, temp_dir};
+use web_server::Config;
+
+fn get_header(addr: SocketAddr) -> Option<String> {
+    let response = exchange(addr, b"GET /a.txt HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    header(&response, "X-Reloaded").map(str::to_string)
+}
+
+fn send_sighup() {
+    // SAFETY: 自プロセスにシグナルを送るだけで、サーバがハンドラを登録済み
+    assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGHUP) }, 0);
+    thread::sleep(Duration::from_millis(200));
+}
+
+#[test]
+fn sighup_applies_a_valid_config_and_keeps_the_old_one_otherwise() {
+    let dir = temp_dir("reload");
+    fs::write(dir.join("a.txt"), "a").unwrap();
+    let path = dir.join("server.toml");
+    fs::write(&path, "webroots = [\".\"]\n").unwrap();
+    let config = Config::load(path.to_str().unwrap()).unwrap();
+    let path_str = path.to_string_lossy().into_owned();
+    let addr = start(config, move |server| server.set_config_path(&path_str));
+    assert_eq!(get_header(addr), None);
+
+    fs::write(&path, "webroots = [\".\"]\n\n[[headers]]\nname = \"X-Reloaded\"\nvalue = \"yes\"\n").unwrap();
+    send_sighup();
+    assert_eq!(get_header(addr).as_deref(), Some("yes"));
+
+    // 検証に失敗する設定は使わず、直前の設定のまま続ける
+    fs::write(&path, "webroots = [\".\"]\nmax_connections = 0\n").unwrap();
+    send_sighup();
+    assert_eq!(get_header(addr).as_deref(), Some("yes"));
+}
+
+#[test]
+fn sighup_rereads_preloaded_files() {
+    let dir = temp_dir("reload-preload");
+    fs::write(dir.join("app.js"), "v1").unwrap();
+    let config = Config {
+        webroots: vec![dir.to_string_lossy().into_owned()],
+        preload: vec!["/app.js".to_string()],
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let get_body = || {
+        let response = exchange(addr, b"GET /app.js HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        body(&response).to_string()
+    };
+    assert_eq!(get_body(), "v1");
+
+    fs::write(dir.join("app.js"), "v2").unwrap();
+    send_sighup();
+    assert_eq!(get_body(), "v2");
+    // 読み込み直せない場合は以前の内容を返し続ける
+    fs::remove_file(dir.join("app.js")).unwrap();
+    send_sighup();
+    assert_eq!(get_body(), "v2");
+}
+
+#[test]
+fn sighup_resolves_the_snapshot_root_again() {
+    let dir = temp_dir("reload-snapshot");
+    for release in ["v1", "v2"] {
+        fs::create_dir(dir.join(release)).unwrap();
+        fs::write(dir.join(release).join("index.html"), release).unwrap();
+    }
+    let current = dir.join("current");
+    symlink(dir.join("v1"), &current).unwrap();
+    let config = Config {
+        webroots: vec![current.to_string_lossy().into_owned()],
+        snapshot_root: true,
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    let get_body = || {
+        let response = exchange(addr, b"GET /index.html HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+        body(&response).to_string()
+    };
+    assert_eq!(get_body(), "v1");
+
+    // リンクを付け替えてSIGHUPを送ると、新しいリンク先から配信する
+    fs::remove_file(&current).unwrap();
+    symlink(dir.join("v2"), &current).unwrap();
+    send_sighup();
+    assert_eq!(get_body(), "v2");
+}
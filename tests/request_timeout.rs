@@ -0,0 +1,47 @@
+mod common;
+
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use common::{connect, read_to_close, start_with_clock, status};
+use web_server::{Config, MockClock};
+
+fn start_server(clock: &Arc<MockClock>) -> SocketAddr {
+    let config = Config { request_timeout: 10, read_timeout: 0, ..Config::default() };
+    start_with_clock(config, clock.clone(), |_| {})
+}
+
+/**
+* 時計を進めてから別の接続でイベントループを起こし、タイムアウトを確認させる
+*/
+fn advance_and_wake(clock: &MockClock, addr: SocketAddr, secs: u64) {
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(secs));
+    drop(connect(addr));
+    thread::sleep(Duration::from_millis(100));
+}
+
+#[test]
+fn budget_starts_at_the_first_byte() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_server(&clock);
+    let mut stream = connect(addr);
+    // 接続してから何も送らずにいた時間は数えない
+    advance_and_wake(&clock, addr, 8);
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    advance_and_wake(&clock, addr, 5);
+    stream.write_all(b"Host: a\r\nConnection: close\r\n\r\n").unwrap();
+    assert_ne!(status(&read_to_close(&mut stream)), 408);
+}
+
+#[test]
+fn exceeding_the_budget_while_reading_is_408() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_server(&clock);
+    let mut stream = connect(addr);
+    stream.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+    advance_and_wake(&clock, addr, 11);
+    assert_eq!(status(&read_to_close(&mut stream)), 408);
+}
@@ -0,0 +1,42 @@
+mod common;
+
+use common::{config_with_files, exchange, header, start, status};
+use web_server::{create_msg_from_code, Config, RetryAfter};
+
+/**
+* maintenance.htmlを置いてメンテナンス中にした設定
+*/
+fn maintenance_config(name: &str) -> Config {
+    let mut config = config_with_files(name, &[("maintenance.html", b"<p>maintenance</p>")]);
+    config.maintenance_page = Some(format!("{}/maintenance.html", config.webroots[0]));
+    config
+}
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn maintenance_503_uses_configured_retry_after() {
+    let config = Config { retry_after: Some(RetryAfter::Seconds(120)), ..maintenance_config("retry-configured") };
+    let response = exchange(start(config, |_| {}), REQUEST);
+    assert_eq!(status(&response), 503);
+    assert_eq!(header(&response, "Retry-After"), Some("120"));
+}
+
+#[test]
+fn maintenance_503_falls_back_to_server_default() {
+    let response = exchange(start(maintenance_config("retry-default"), |_| {}), REQUEST);
+    assert_eq!(status(&response), 503);
+    assert_eq!(header(&response, "Retry-After"), Some("300"));
+}
+
+#[test]
+fn route_429_gets_retry_after() {
+    let config = Config { retry_after: Some(RetryAfter::Seconds(30)), ..Config::default() };
+    let addr = start(config, |server| {
+        server.add_route("GET", "/limited", |_, _| create_msg_from_code(429, None));
+    });
+    let response = exchange(addr, b"GET /limited HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 429);
+    assert!(response.starts_with("HTTP/1.0 429 Too Many Requests\r\n"), "{}", response);
+    assert_eq!(header(&response, "Retry-After"), Some("30"));
+}
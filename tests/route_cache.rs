@@ -0,0 +1,38 @@
+mod common;
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use common::{body, exchange, header, start_with_clock, status};
+use web_server::{create_content_response, Config, MockClock};
+
+const REQUEST: &[u8] = b"GET /report HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+
+#[test]
+fn cached_response_is_reused_within_ttl_and_regenerated_after() {
+    let clock = Arc::new(MockClock::new());
+    let calls = Arc::new(AtomicUsize::new(0));
+    let handler_calls = Arc::clone(&calls);
+    let addr = start_with_clock(Config::default(), clock.clone(), move |server| {
+        server.add_cached_route("GET", "/report", Duration::from_secs(30), move |_, _| {
+            let call = handler_calls.fetch_add(1, Ordering::SeqCst) + 1;
+            create_content_response(200, "text/plain", format!("report {}", call).into_bytes())
+        });
+    });
+
+    let first = exchange(addr, REQUEST);
+    assert_eq!(status(&first), 200);
+    assert_eq!(body(&first), "report 1");
+    assert_eq!(header(&first, "Age"), None);
+
+    clock.advance(Duration::from_secs(10));
+    let hit = exchange(addr, REQUEST);
+    assert_eq!(body(&hit), "report 1");
+    assert_eq!(header(&hit, "Age"), Some("10"));
+
+    clock.advance(Duration::from_secs(25));
+    let regenerated = exchange(addr, REQUEST);
+    assert_eq!(body(&regenerated), "report 2");
+    assert_eq!(header(&regenerated, "Age"), None);
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+}
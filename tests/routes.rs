@@ -0,0 +1,283 @@
+mod common;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use common::{body, config_with_files, connect, exchange, header, read_to_close, start, status};
+use web_server::{create_content_response, BodyHandler, Config, Error, Request, Response, WebServer};
+
+fn get(path: &str) -> Vec<u8> {
+    format!("GET {} HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n", path).into_bytes()
+}
+
+#[test]
+fn panicking_handler_gets_500_and_the_server_keeps_running() {
+    let addr = start(Config::default(), |server| {
+        server.add_route("GET", "/panic", |_, _| panic!("handler bug"));
+        server.add_route("GET", "/ok", |_, _| create_content_response(200, "text/plain", b"fine".to_vec()));
+    });
+    assert_eq!(status(&exchange(addr, &get("/panic"))), 500);
+    let response = exchange(addr, &get("/ok"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "fine");
+}
+
+#[test]
+fn large_request_is_assembled_with_a_small_read_buffer() {
+    let config = Config { read_buffer_size: 64, ..Config::default() };
+    let addr = start(config, |server| {
+        server.add_route("POST", "/sum", |request, _| {
+            let sum: u64 = request.body.iter().map(|&b| u64::from(b)).sum();
+            create_content_response(200, "text/plain", format!("{} {}", request.body.len(), sum).into_bytes())
+        });
+    });
+    let payload: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+    let mut raw = format!(
+        "POST /sum HTTP/1.1\r\nHost: a\r\nX-Padding: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        "p".repeat(500),
+        payload.len()
+    )
+    .into_bytes();
+    raw.extend_from_slice(&payload);
+    let response = exchange(addr, &raw);
+    assert_eq!(status(&response), 200);
+    let sum: u64 = payload.iter().map(|&b| u64::from(b)).sum();
+    assert_eq!(body(&response), format!("{} {}", payload.len(), sum));
+}
+
+#[test]
+fn response_hook_sees_every_response() {
+    let addr = start(Config::default(), |server| {
+        server.add_route("GET", "/ok", |_, _| create_content_response(200, "text/plain", b"fine".to_vec()));
+        server.add_response_hook(|request, response| {
+            response.add_header("X-Hooked", &format!("{} {}", request.method, request.path()));
+        });
+    });
+    assert_eq!(header(&exchange(addr, &get("/ok")), "X-Hooked"), Some("GET /ok"));
+    let response = exchange(addr, &get("/missing"));
+    assert_eq!(status(&response), 404);
+    assert_eq!(header(&response, "X-Hooked"), Some("GET /missing"));
+}
+
+#[test]
+fn path_parameters_are_passed_to_the_handler() {
+    let config = config_with_files("router", &[("about.html", b"static")]);
+    let addr = start(config, |server| {
+        server.add_route("GET", "/users/:id", |_, params| {
+            create_content_response(200, "text/plain", format!("user {}", params["id"]).into_bytes())
+        });
+        // 後から登録しても、固定のパスはパラメータより優先する
+        server.add_route("GET", "/users/me", |_, _| create_content_response(200, "text/plain", b"me".to_vec()));
+    });
+    assert_eq!(body(&exchange(addr, &get("/users/42"))), "user 42");
+    assert_eq!(body(&exchange(addr, &get("/users/42?x=1"))), "user 42");
+    assert_eq!(body(&exchange(addr, &get("/users/me"))), "me");
+    // どのルートにも一致しなければ静的ファイルを配信する
+    assert_eq!(body(&exchange(addr, &get("/about.html"))), "static");
+    assert_eq!(status(&exchange(addr, &get("/users"))), 404);
+}
+
+// 受け取ったボディのバイト数と合計を数える
+#[derive(Default)]
+struct Summer {
+    len: usize,
+    sum: u64,
+    chunks: usize,
+}
+
+impl BodyHandler for Summer {
+    fn write(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.len += chunk.len();
+        self.sum += chunk.iter().map(|&b| u64::from(b)).sum::<u64>();
+        self.chunks += 1;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, _request: &Request) -> Result<Response, Error> {
+        let body = format!("{} {} {}", self.len, self.sum, self.chunks > 1);
+        create_content_response(200, "text/plain", body.into_bytes())
+    }
+}
+
+#[test]
+fn streaming_route_receives_a_large_body_in_chunks() {
+    let addr = start(Config::default(), |server| {
+        server.add_streaming_route("POST", "/sum", |_, _| Ok(Box::new(Summer::default()) as Box<dyn BodyHandler>));
+    });
+    // バッファするリクエストのボディの上限を超える大きさ
+    let payload: Vec<u8> = (0..5 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    let mut stream = connect(addr);
+    let head = format!("POST /sum HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", payload.len());
+    stream.write_all(head.as_bytes()).unwrap();
+    for chunk in payload.chunks(1024 * 1024) {
+        stream.write_all(chunk).unwrap();
+        thread::sleep(Duration::from_millis(20));
+    }
+    let response = read_to_close(&mut stream);
+    assert_eq!(status(&response), 200, "{}", response);
+    let sum: u64 = payload.iter().map(|&b| u64::from(b)).sum();
+    assert_eq!(body(&response), format!("{} {} true", payload.len(), sum));
+}
+
+#[test]
+fn extension_methods_can_be_routed() {
+    let addr = start(Config::default(), |server| {
+        server.add_route("PROPFIND", "/dav/:name", |request, params| {
+            let body = format!("{} {} depth={}", request.method, params["name"], request.header("Depth").unwrap_or("-"));
+            create_content_response(200, "text/plain", body.into_bytes())
+        });
+    });
+    let response = exchange(addr, b"PROPFIND /dav/notes HTTP/1.1\r\nHost: a\r\nDepth: 1\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "PROPFIND notes depth=1");
+    // 登録していない拡張メソッドは501のまま
+    let response = exchange(addr, b"MKCOL /dav/notes HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 501);
+}
+
+#[test]
+#[should_panic(expected = "invalid method token")]
+fn invalid_method_tokens_are_rejected_at_registration() {
+    let mut server = WebServer::new("127.0.0.1:0", Config::default()).unwrap();
+    server.add_route("GET /", "/", |_, _| create_content_response(200, "text/plain", Vec::new()));
+}
+
+#[test]
+fn oversized_response_headers_become_500() {
+    let config = Config { max_response_header_size: 1024, ..Config::default() };
+    let addr = start(config, |server| {
+        server.add_route("GET", "/many-headers", |_, _| {
+            let mut response = create_content_response(200, "text/plain", b"hidden".to_vec())?;
+            for i in 0..100 {
+                response.add_header(&format!("X-Extra-{}", i), "value");
+            }
+            Ok(response)
+        });
+        server.add_route("GET", "/few-headers", |_, _| {
+            let mut response = create_content_response(200, "text/plain", b"shown".to_vec())?;
+            response.add_header("X-Extra", "value");
+            Ok(response)
+        });
+    });
+    let response = exchange(addr, &get("/many-headers"));
+    assert_eq!(status(&response), 500);
+    assert_eq!(header(&response, "X-Extra-0"), None);
+    assert_eq!(header(&response, "Connection"), Some("close"));
+    assert!(!response.contains("hidden"), "{}", response);
+    assert_eq!(body(&exchange(addr, &get("/few-headers"))), "shown");
+}
+
+#[test]
+fn generated_json_gets_its_length_and_type() {
+    let addr = start(Config::default(), |server| {
+        server.add_route("GET", "/api/greeting", |_, _| {
+            create_content_response(200, "application/json", "{\"message\":\"こんにちは\"}")
+        });
+    });
+    let response = exchange(addr, &get("/api/greeting"));
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+    // 文字数ではなくUTF-8のバイト数
+    assert_eq!(header(&response, "Content-Length"), Some("29"));
+    assert_eq!(body(&response), "{\"message\":\"こんにちは\"}");
+    assert!(header(&response, "Server").is_some());
+}
+
+// 認証フックが確かめたユーザ
+struct User(String);
+
+#[test]
+fn request_hook_passes_the_user_to_the_handler() {
+    let addr = start(Config::default(), |server| {
+        server.add_request_hook(|request| {
+            if let Some(name) = request.header("Authorization").and_then(|value| value.strip_prefix("User ")) {
+                let name = name.to_string();
+                request.extensions.insert(User(name));
+            }
+        });
+        server.add_route("GET", "/whoami", |request, _| {
+            let name = request.extensions.get::<User>().map_or("anonymous", |user| &user.0);
+            create_content_response(200, "text/plain", name.as_bytes().to_vec())
+        });
+        server.add_response_hook(|request, response| {
+            if let Some(user) = request.extensions.get::<User>() {
+                response.add_header("X-User", &user.0);
+            }
+        });
+    });
+    let raw = b"GET /whoami HTTP/1.1\r\nHost: a\r\nAuthorization: User alice\r\nConnection: close\r\n\r\n";
+    let response = exchange(addr, raw);
+    assert_eq!(body(&response), "alice");
+    assert_eq!(header(&response, "X-User"), Some("alice"));
+
+    let response = exchange(addr, &get("/whoami"));
+    assert_eq!(body(&response), "anonymous");
+    assert_eq!(header(&response, "X-User"), None);
+}
+
+#[test]
+fn head_is_answered_by_the_get_route_with_the_same_validators() {
+    let setup = |server: &mut WebServer| {
+        server.add_route("GET", "/report", |_, _| {
+            let mut response = create_content_response(200, "text/plain", b"report body".to_vec())?;
+            response.add_header("ETag", "\"v1\"");
+            response.add_header("Last-Modified", "Sun, 06 Nov 1994 08:49:37 GMT");
+            response.add_header("Cache-Control", "no-cache");
+            Ok(response)
+        });
+    };
+    let head = b"HEAD /report HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n";
+    let addr = start(Config::default(), setup);
+    let get_response = exchange(addr, &get("/report"));
+    let response = exchange(addr, head);
+    assert_eq!(status(&response), 200);
+    assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    for name in ["ETag", "Last-Modified", "Cache-Control", "Content-Type", "Content-Length"] {
+        assert!(header(&response, name).is_some(), "{}", name);
+        assert_eq!(header(&response, name), header(&get_response, name), "{}", name);
+    }
+    let response = exchange(addr, b"OPTIONS /report HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(header(&response, "Allow"), Some("GET, HEAD, OPTIONS"));
+
+    let addr = start(Config { head_as_get: false, ..Config::default() }, setup);
+    // 無効にするとHEADに一致するルートはなく、静的ファイルとして探す
+    assert_eq!(status(&exchange(addr, head)), 404);
+}
+
+#[test]
+fn streaming_routes_see_hooks_and_never_receive_blocked_bodies() {
+    let opened = Arc::new(AtomicUsize::new(0));
+    let config = Config { blocked_user_agents: vec!["BadBot".to_string()], ..Config::default() };
+    let addr = start(config, {
+        let opened = Arc::clone(&opened);
+        move |server| {
+            server.add_request_hook(|request| {
+                if let Some(name) = request.header("Authorization").and_then(|value| value.strip_prefix("User ")) {
+                    let name = name.to_string();
+                    request.extensions.insert(User(name));
+                }
+            });
+            server.add_streaming_route("POST", "/sum", move |request, _| {
+                opened.fetch_add(1, Ordering::SeqCst);
+                // フックはハンドラを作る前に適用されている
+                assert!(request.extensions.get::<User>().is_some());
+                Ok(Box::new(Summer::default()) as Box<dyn BodyHandler>)
+            });
+        }
+    });
+    let post = |user_agent: &str| {
+        format!(
+            "POST /sum HTTP/1.1\r\nHost: a\r\nUser-Agent: {}\r\nAuthorization: User alice\r\nContent-Length: 3\r\n\
+             Connection: close\r\n\r\nabc",
+            user_agent
+        )
+        .into_bytes()
+    };
+    assert_eq!(status(&exchange(addr, &post("BadBot/1.0"))), 403);
+    assert_eq!(opened.load(Ordering::SeqCst), 0);
+    let response = exchange(addr, &post("curl/8.0"));
+    assert_eq!(body(&response), "3 294 false");
+    assert_eq!(opened.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,33 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::thread;
+use std::time::Duration;
+use common::{config_with_files, connect, header, read_response, read_to_close, start, status};
+
+#[test]
+fn sigterm_closes_idle_connections_and_finishes_in_flight_requests() {
+    let addr = start(config_with_files("shutdown", &[("index.html", b"hello")]), |_| {});
+    let mut fresh = connect(addr);
+    let mut idle = connect(addr);
+    idle.write_all(b"GET /index.html HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut idle);
+    assert_eq!(header(&head, "Connection"), Some("keep-alive"));
+    let mut in_flight = connect(addr);
+    in_flight.write_all(b"GET /index.html HTTP/1.1\r\n").unwrap();
+    // シグナルハンドラの登録と接続の受け付けを待つ
+    thread::sleep(Duration::from_millis(200));
+
+    // SAFETY: 自プロセスにシグナルを送るだけで、サーバがハンドラを登録済み
+    assert_eq!(unsafe { libc::kill(libc::getpid(), libc::SIGTERM) }, 0);
+
+    // リクエストをまだ送っていない接続とキープアライブ中の接続は、何も送られずに閉じられる
+    let mut buf = Vec::new();
+    assert_eq!(fresh.read_to_end(&mut buf).unwrap(), 0);
+    assert_eq!(idle.read_to_end(&mut buf).unwrap(), 0);
+    // 受信途中のリクエストには応答してから閉じる
+    in_flight.write_all(b"Host: a\r\n\r\n").unwrap();
+    let response = read_to_close(&mut in_flight);
+    assert_eq!(status(&response), 200);
+    assert_eq!(header(&response, "Connection"), Some("close"));
+}
@@ -0,0 +1,56 @@
+// slow_request_msを超えたリクエストのwarnログ。ロガーはプロセスで1つなので別のテストバイナリにする
+mod common;
+
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+use common::{exchange, start, status};
+use log::{Level, Log, Metadata, Record};
+use web_server::{create_content_response, Config};
+
+// warn以上のログのメッセージを溜める
+struct CapturedLog(Mutex<Vec<String>>);
+
+impl Log for CapturedLog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Warn
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG: CapturedLog = CapturedLog(Mutex::new(Vec::new()));
+
+fn slow_warnings() -> Vec<String> {
+    LOG.0.lock().unwrap().iter().filter(|message| message.starts_with("Slow request")).cloned().collect()
+}
+
+#[test]
+fn requests_slower_than_the_threshold_are_warned() {
+    log::set_logger(&LOG).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+    let addr = start(Config { slow_request_ms: Some(50), ..Config::default() }, |server| {
+        server.add_route("GET", "/slow", |_, _| {
+            thread::sleep(Duration::from_millis(100));
+            create_content_response(200, "text/plain", b"slow".to_vec())
+        });
+        server.add_route("GET", "/fast", |_, _| create_content_response(200, "text/plain", b"fast".to_vec()));
+    });
+
+    let response = exchange(addr, b"GET /fast HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    assert!(slow_warnings().is_empty(), "{:?}", slow_warnings());
+
+    let response = exchange(addr, b"GET /slow?x=1 HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 200);
+    let warnings = slow_warnings();
+    assert_eq!(warnings.len(), 1, "{:?}", warnings);
+    assert!(warnings[0].contains("GET /slow?x=1 200 "), "{}", warnings[0]);
+    assert!(warnings[0].ends_with("ms"), "{}", warnings[0]);
+}
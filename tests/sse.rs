@@ -0,0 +1,40 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use common::{connect, header, read_response, start, status};
+use web_server::Config;
+
+#[test]
+fn subscriber_receives_pushed_events() {
+    let (sender, receiver) = mpsc::channel();
+    let config = Config { sse_path: Some("/events".to_string()), ..Config::default() };
+    let addr = start(config, move |server| sender.send(server.event_sender()).unwrap());
+    let events = receiver.recv().unwrap();
+
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /events HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(header(&head, "Content-Type"), Some("text/event-stream"));
+    assert_eq!(header(&head, "Content-Length"), None);
+
+    events.send("first\nsecond").unwrap();
+    let expected = b"data: first\ndata: second\n\n";
+    let mut received = vec![0u8; expected.len()];
+    stream.read_exact(&mut received).unwrap();
+    assert_eq!(received, expected);
+
+    // 切断した購読者がいても他の購読者への配信は続く
+    drop(stream);
+    thread::sleep(Duration::from_millis(50));
+    let mut other = connect(addr);
+    other.write_all(b"GET /events HTTP/1.1\r\nHost: a\r\n\r\n").unwrap();
+    read_response(&mut other);
+    events.send("third").unwrap();
+    let mut received = vec![0u8; b"data: third\n\n".len()];
+    other.read_exact(&mut received).unwrap();
+    assert_eq!(received, b"data: third\n\n");
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use std::io::Write;
+use common::{body, config_with_files, connect, exchange, header, read_response, start, status};
+use web_server::{create_msg_from_code, Error, StatusPage};
+
+#[test]
+fn configured_page_is_used_and_other_codes_have_no_body() {
+    let mut config = config_with_files(
+        "status-pages",
+        &[("index.html", b"home"), ("errors/404.html", b"<p>not here</p>"), ("errors/401.json", b"{\"error\":\"login\"}")],
+    );
+    let dir = config.webroots[0].clone();
+    config.status_pages = vec![
+        StatusPage { status: 404, file: format!("{}/errors/404.html", dir), content_type: None },
+        StatusPage {
+            status: 401,
+            file: format!("{}/errors/401.json", dir),
+            content_type: Some("application/json".to_string()),
+        },
+    ];
+    let addr = start(config, |server| {
+        server.add_route("GET", "/private", |_, _| create_msg_from_code(401, None));
+        server.add_route("GET", "/gone", |_, _| create_msg_from_code(410, None));
+    });
+
+    let response = exchange(addr, b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 404);
+    assert_eq!(header(&response, "Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!(body(&response), "<p>not here</p>");
+
+    let response = exchange(addr, b"GET /private HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 401);
+    assert_eq!(header(&response, "Content-Type"), Some("application/json"));
+    assert_eq!(body(&response), "{\"error\":\"login\"}");
+
+    // 設定していないステータスコードはボディなしのまま返す
+    let response = exchange(addr, b"GET /gone HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 410);
+    assert_eq!(header(&response, "Content-Length"), Some("0"));
+    assert_eq!(body(&response), "");
+}
+
+#[test]
+fn handler_errors_get_the_configured_500_page() {
+    let mut config = config_with_files("status-pages-500", &[("errors/500.html", b"<p>try again later</p>")]);
+    let dir = config.webroots[0].clone();
+    config.status_pages = vec![StatusPage { status: 500, file: format!("{}/errors/500.html", dir), content_type: None }];
+    let addr = start(config, |server| {
+        server.add_route("GET", "/fail", |_, _| Err(Error::Internal("database is down".to_string())));
+    });
+
+    let response = exchange(addr, b"GET /fail HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n");
+    assert_eq!(status(&response), 500);
+    assert_eq!(body(&response), "<p>try again later</p>");
+    assert_eq!(header(&response, "Content-Length"), Some("22"));
+    // 内部のエラーの内容はクライアントに返さない
+    assert!(!response.contains("database"), "{}", response);
+}
+
+#[test]
+fn custom_error_page_is_framed_for_the_next_request() {
+    let mut config =
+        config_with_files("status-pages-framing", &[("a.txt", b"a"), ("errors/404.html", b"<p>not here</p>")]);
+    let dir = config.webroots[0].clone();
+    config.status_pages = vec![StatusPage { status: 404, file: format!("{}/errors/404.html", dir), content_type: None }];
+    let addr = start(config, |server| {
+        // ハンドラが付与した誤ったContent-Lengthは使わない
+        server.add_route("GET", "/wrong-length", |_, _| {
+            let mut response = create_msg_from_code(404, None)?;
+            response.add_header("Content-Length", "1000");
+            Ok(response)
+        });
+    });
+    let mut stream = connect(addr);
+    stream
+        .write_all(
+            b"GET /missing HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /wrong-length HTTP/1.1\r\nHost: a\r\n\r\n\
+              GET /a.txt HTTP/1.1\r\nHost: a\r\n\r\n",
+        )
+        .unwrap();
+    for _ in 0..2 {
+        let (head, body) = read_response(&mut stream);
+        assert_eq!(status(&head), 404);
+        assert_eq!(header(&head, "Content-Length"), Some("15"));
+        assert_eq!(head.matches("Content-Length").count(), 1, "{}", head);
+        assert_eq!(body, b"<p>not here</p>");
+    }
+    let (head, body) = read_response(&mut stream);
+    assert_eq!(status(&head), 200);
+    assert_eq!(body, b"a");
+}
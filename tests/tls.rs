@@ -0,0 +1,174 @@
+// 平文とTLSのリスナーを並べた時の応答
+mod common;
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use common::{body, config_with_files, connect, exchange, start_listeners, status, temp_dir};
+use log::{Log, Metadata, Record};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use web_server::{Config, ListenerConfig, SniConfig, TlsConfig};
+
+fn fixture(name: &str) -> String {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls").join(name).to_string_lossy().into_owned()
+}
+
+/**
+* fixturesのテスト用CAの証明書を信頼し、alpnをALPNで提示するクライアントの設定
+*/
+fn client_config(alpn: &[&[u8]]) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(fixture("ca.pem")).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Arc::new(config)
+}
+
+/**
+* server_nameをSNIで送ってTLSで接続し、rawを送信して接続が閉じるまでの応答を返す
+*/
+fn tls_exchange(addr: SocketAddr, server_name: &str, raw: &[u8]) -> String {
+    tls_session(addr, server_name, &[], raw).0
+}
+
+/**
+* tls_exchangeと同じだが、alpnをALPNで提示し、ハンドシェイクの結果を確かめられるようにクライアントの接続も返す
+*/
+fn tls_session(addr: SocketAddr, server_name: &str, alpn: &[&[u8]], raw: &[u8]) -> (String, ClientConnection) {
+    let name = ServerName::try_from(server_name.to_string()).unwrap();
+    let conn = ClientConnection::new(client_config(alpn), name).unwrap();
+    let mut stream = StreamOwned::new(conn, connect(addr));
+    stream.write_all(raw).unwrap();
+    let mut buf = Vec::new();
+    if let Err(e) = stream.read_to_end(&mut buf) {
+        // close_notifyを送らずに閉じられた場合も、それまでに受信した分で判定する
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{}", e);
+    }
+    (String::from_utf8_lossy(&buf).into_owned(), stream.conn)
+}
+
+fn fixture_cert(name: &str) -> CertificateDer<'static> {
+    CertificateDer::from_pem_file(fixture(name)).unwrap()
+}
+
+fn tls_listener(cert: &str, key: &str) -> ListenerConfig {
+    ListenerConfig {
+        addr: "127.0.0.1:0".to_string(),
+        tls: Some(TlsConfig { cert: fixture(cert), key: fixture(key), sni: Vec::new() }),
+    }
+}
+
+#[test]
+fn plaintext_and_tls_listeners_serve_the_same_site() {
+    let config = Config {
+        listeners: vec![tls_listener("localhost.pem", "localhost.key")],
+        ..config_with_files("tls-listeners", &[("index.html", b"hello")])
+    };
+    let addrs = start_listeners(config, |_| {});
+    let tls_addr = *addrs.last().unwrap();
+    let request = b"GET /index.html HTTP/1.0\r\n\r\n";
+
+    let response = exchange(addrs[0], request);
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "hello");
+
+    let response = tls_exchange(tls_addr, "localhost", request);
+    assert_eq!(status(&response), 200);
+    assert_eq!(body(&response), "hello");
+}
+
+#[test]
+fn sni_selects_the_certificate_and_document_root() {
+    let site = |name: &str| {
+        let dir = temp_dir(name);
+        fs::write(dir.join("index.html"), name).unwrap();
+        dir.to_string_lossy().into_owned()
+    };
+    let sni = |name: &str, webroot: Option<String>| SniConfig {
+        name: name.to_string(),
+        cert: fixture(&format!("{}.pem", name)),
+        key: fixture(&format!("{}.key", name)),
+        webroot,
+    };
+    let mut listener = tls_listener("localhost.pem", "localhost.key");
+    listener.tls.as_mut().unwrap().sni = vec![sni("a.test", Some(site("a.test"))), sni("b.test", Some(site("b.test")))];
+    let config = Config { webroots: vec![site("default")], listeners: vec![listener], ..Config::default() };
+    let addrs = start_listeners(config, |_| {});
+    let tls_addr = *addrs.last().unwrap();
+    let request = b"GET /index.html HTTP/1.0\r\n\r\n";
+
+    for (server_name, cert, content) in
+        [("a.test", "a.test.pem", "a.test"), ("b.test", "b.test.pem", "b.test"), ("localhost", "localhost.pem", "default")]
+    {
+        let (response, conn) = tls_session(tls_addr, server_name, &[], request);
+        assert_eq!(conn.peer_certificates().unwrap()[0], fixture_cert(cert), "{}", server_name);
+        assert_eq!(status(&response), 200);
+        assert_eq!(body(&response), content);
+    }
+}
+
+// サーバのログのメッセージを溜める。ロガーはプロセスで1つなので、このファイルではこのテストだけが設定する
+struct CapturedLog(Mutex<Vec<String>>);
+
+impl Log for CapturedLog {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+static LOG: CapturedLog = CapturedLog(Mutex::new(Vec::new()));
+
+#[test]
+fn negotiated_alpn_is_logged() {
+    log::set_logger(&LOG).unwrap();
+    log::set_max_level(log::LevelFilter::Debug);
+    let config = Config {
+        listeners: vec![tls_listener("localhost.pem", "localhost.key")],
+        ..config_with_files("tls-alpn", &[("alpn.html", b"alpn")])
+    };
+    let tls_addr = *start_listeners(config, |_| {}).last().unwrap();
+
+    let request = b"GET /alpn.html HTTP/1.0\r\n\r\n";
+    let (response, conn) = tls_session(tls_addr, "localhost", &[b"h2", b"http/1.1"], request);
+    assert_eq!(body(&response), "alpn");
+    // HTTP/2には対応していないので、http/1.1で合意する
+    assert_eq!(conn.alpn_protocol(), Some(&b"http/1.1"[..]));
+    let logs = LOG.0.lock().unwrap().clone();
+    let logged = |prefix: &str, suffix: &str| {
+        logs.iter().any(|message| message.starts_with(prefix) && message.ends_with(suffix))
+    };
+    assert!(logged("TLS handshake completed", "ALPN http/1.1"), "{:?}", logs);
+    assert!(logged("GET /alpn.html HTTP/1.0", "(ALPN http/1.1)"), "{:?}", logs);
+}
+
+#[test]
+fn plaintext_request_on_the_tls_port_is_told_to_use_https() {
+    let config = Config {
+        listeners: vec![tls_listener("localhost.pem", "localhost.key")],
+        tls_plaintext_message: "Use https:// for this port.\n".to_string(),
+        ..Config::default()
+    };
+    let tls_addr = *start_listeners(config, |_| {}).last().unwrap();
+    let response = exchange(tls_addr, b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert_eq!(status(&response), 400);
+    assert_eq!(body(&response), "Use https:// for this port.\n");
+    // TLSの接続は引き続き受け付ける
+    assert_eq!(status(&tls_exchange(tls_addr, "localhost", b"GET /missing HTTP/1.0\r\n\r\n")), 404);
+}
@@ -0,0 +1,165 @@
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::symlink;
+use std::thread;
+use std::time::Duration;
+use common::{connect, exchange, header, read_to_close, start, status, temp_dir};
+use web_server::Config;
+
+fn put(path: &str, body: &str) -> Vec<u8> {
+    format!("PUT {} HTTP/1.1\r\nHost: a\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", path, body.len(), body)
+        .into_bytes()
+}
+
+#[test]
+fn uploads_beyond_the_cap_are_rejected_until_one_finishes() {
+    let root = temp_dir("upload-cap");
+    let config = Config {
+        upload_root: Some(root.to_string_lossy().into_owned()),
+        max_concurrent_uploads: 1,
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    // ボディを送り終えていないアップロードが1つある間は、次のアップロードを受け付けない
+    let mut slow = connect(addr);
+    slow.write_all(b"PUT /slow.txt HTTP/1.1\r\nHost: a\r\nContent-Length: 10\r\nConnection: close\r\n\r\n12345")
+        .unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let rejected = exchange(addr, &put("/fast.txt", "fast"));
+    assert_eq!(status(&rejected), 503);
+    assert!(header(&rejected, "Retry-After").is_some());
+    assert!(!root.join("fast.txt").exists());
+
+    slow.write_all(b"67890").unwrap();
+    assert_eq!(status(&read_to_close(&mut slow)), 201);
+    assert_eq!(fs::read_to_string(root.join("slow.txt")).unwrap(), "1234567890");
+    assert_eq!(status(&exchange(addr, &put("/fast.txt", "fast"))), 201);
+}
+
+#[test]
+fn upload_larger_than_the_byte_budget_is_rejected() {
+    let root = temp_dir("upload-bytes");
+    let config = Config {
+        upload_root: Some(root.to_string_lossy().into_owned()),
+        max_upload_bytes: 8,
+        ..Config::default()
+    };
+    let addr = start(config, |_| {});
+    assert_eq!(status(&exchange(addr, &put("/small.txt", "12345678"))), 201);
+    assert_eq!(status(&exchange(addr, &put("/large.txt", "123456789"))), 503);
+}
+
+fn patch(path: &str, content_range: Option<&str>, body: &str) -> Vec<u8> {
+    let range = content_range.map_or(String::new(), |range| format!("Content-Range: {}\r\n", range));
+    format!(
+        "PATCH {} HTTP/1.1\r\nHost: a\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        range,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[test]
+fn patch_appends_or_writes_at_the_content_range() {
+    let root = temp_dir("patch");
+    fs::write(root.join("a.txt"), "hello").unwrap();
+    let config = Config { upload_root: Some(root.to_string_lossy().into_owned()), ..Config::default() };
+    let addr = start(config, |_| {});
+
+    // Content-Rangeがなければ末尾に追記する
+    assert_eq!(status(&exchange(addr, &patch("/a.txt", None, " world"))), 204);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "hello world");
+
+    assert_eq!(status(&exchange(addr, &patch("/a.txt", Some("bytes 0-4/*"), "HELLO"))), 204);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "HELLO world");
+
+    // 開始位置がファイルの長さを超える場合は穴を作らずに416を返す
+    let response = exchange(addr, &patch("/a.txt", Some("bytes 20-21/*"), "!!"));
+    assert_eq!(status(&response), 416);
+    assert_eq!(header(&response, "Content-Range"), Some("bytes */11"));
+    // 範囲の長さとボディの長さが一致しない
+    assert_eq!(status(&exchange(addr, &patch("/a.txt", Some("bytes 0-9/*"), "x"))), 400);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "HELLO world");
+
+    assert_eq!(status(&exchange(addr, &patch("/missing.txt", None, "x"))), 404);
+    assert!(!root.join("missing.txt").exists());
+}
+
+// アップロード先のファイルより前と後の日時
+const BEFORE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+const AFTER: &str = "Fri, 01 Jan 2100 00:00:00 GMT";
+
+fn conditional(method: &str, path: &str, since: &str, body: &str) -> Vec<u8> {
+    format!(
+        "{} {} HTTP/1.1\r\nHost: a\r\nIf-Unmodified-Since: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        method,
+        path,
+        since,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[test]
+fn writes_to_a_modified_target_are_412() {
+    let root = temp_dir("unmodified-since");
+    fs::write(root.join("a.txt"), "original").unwrap();
+    let config = Config { upload_root: Some(root.to_string_lossy().into_owned()), ..Config::default() };
+    let addr = start(config, |_| {});
+
+    for method in ["PUT", "PATCH", "DELETE"] {
+        assert_eq!(status(&exchange(addr, &conditional(method, "/a.txt", BEFORE, "x"))), 412, "{}", method);
+    }
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "original");
+}
+
+#[test]
+fn writes_to_an_unmodified_target_proceed() {
+    let root = temp_dir("unmodified-since-ok");
+    fs::write(root.join("a.txt"), "original").unwrap();
+    let config = Config { upload_root: Some(root.to_string_lossy().into_owned()), ..Config::default() };
+    let addr = start(config, |_| {});
+
+    assert_eq!(status(&exchange(addr, &conditional("PUT", "/a.txt", AFTER, "put"))), 204);
+    assert_eq!(status(&exchange(addr, &conditional("PATCH", "/a.txt", AFTER, "+"))), 204);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "put+");
+    // 日時として解釈できない値は無視する
+    assert_eq!(status(&exchange(addr, &conditional("PATCH", "/a.txt", "yesterday", "!"))), 204);
+    assert_eq!(fs::read_to_string(root.join("a.txt")).unwrap(), "put+!");
+    assert_eq!(status(&exchange(addr, &conditional("DELETE", "/a.txt", AFTER, ""))), 204);
+    assert!(!root.join("a.txt").exists());
+    // 存在しないファイルは更新されていないものとして作成する
+    assert_eq!(status(&exchange(addr, &conditional("PUT", "/b.txt", BEFORE, "new"))), 201);
+}
+
+#[test]
+fn writes_through_symlinks_out_of_the_upload_root_are_forbidden() {
+    let root = temp_dir("upload-symlink");
+    let outside = temp_dir("upload-symlink-outside");
+    fs::write(outside.join("secret.txt"), "secret").unwrap();
+    symlink(&outside, root.join("escape-dir")).unwrap();
+    symlink(outside.join("secret.txt"), root.join("escape.txt")).unwrap();
+    symlink(outside.join("missing.txt"), root.join("dangling.txt")).unwrap();
+    fs::create_dir(root.join("inside")).unwrap();
+    symlink(root.join("inside"), root.join("inside-link")).unwrap();
+    let config = Config { upload_root: Some(root.to_string_lossy().into_owned()), ..Config::default() };
+    let addr = start(config, |_| {});
+
+    for path in ["/escape-dir/new.txt", "/escape-dir/sub/new.txt", "/escape.txt", "/dangling.txt"] {
+        assert_eq!(status(&exchange(addr, &put(path, "x"))), 403, "{}", path);
+    }
+    assert_eq!(status(&exchange(addr, &patch("/escape-dir/secret.txt", None, "x"))), 403);
+    assert_eq!(status(&exchange(addr, &conditional("DELETE", "/escape-dir/secret.txt", AFTER, ""))), 403);
+    assert_eq!(status(&exchange(addr, &conditional("DELETE", "/escape.txt", AFTER, ""))), 403);
+    assert_eq!(fs::read_to_string(outside.join("secret.txt")).unwrap(), "secret");
+    assert!(!outside.join("new.txt").exists() && !outside.join("sub").exists() && !outside.join("missing.txt").exists());
+
+    // upload_rootの中を指すリンクは辿ってよい
+    assert_eq!(status(&exchange(addr, &put("/inside-link/a.txt", "a"))), 201);
+    assert_eq!(fs::read_to_string(root.join("inside/a.txt")).unwrap(), "a");
+}
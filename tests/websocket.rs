@@ -0,0 +1,97 @@
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use common::{connect, exchange, header, read_response, start, status};
+use web_server::Config;
+
+const HANDSHAKE: &[u8] = b"GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+
+fn config() -> Config {
+    Config { websocket_echo_path: Some("/ws".to_string()), ..Config::default() }
+}
+
+fn send_frame(stream: &mut TcpStream, opcode: u8, payload: &[u8]) {
+    let mask = [1, 2, 3, 4];
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+    stream.write_all(&frame).unwrap();
+}
+
+/**
+* サーバからのマスクされていない短いフレームを1つ受信し、opcodeとペイロードを返す
+*/
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0u8; 2];
+    stream.read_exact(&mut head).unwrap();
+    assert_eq!(head[1] & 0x80, 0, "server frames must not be masked");
+    let mut payload = vec![0u8; (head[1] & 0x7f) as usize];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0] & 0x0f, payload)
+}
+
+#[test]
+fn handshake_then_text_frames_are_echoed() {
+    let addr = start(config(), |_| {});
+    let mut stream = connect(addr);
+    stream.write_all(HANDSHAKE).unwrap();
+    let (head, _) = read_response(&mut stream);
+    assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+    assert_eq!(header(&head, "Upgrade"), Some("websocket"));
+    assert_eq!(header(&head, "Sec-WebSocket-Accept"), Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+    send_frame(&mut stream, 0x1, b"hello");
+    assert_eq!(read_frame(&mut stream), (0x1, b"hello".to_vec()));
+    send_frame(&mut stream, 0x9, b"ping");
+    assert_eq!(read_frame(&mut stream), (0xa, b"ping".to_vec()));
+    send_frame(&mut stream, 0x8, &1000u16.to_be_bytes());
+    assert_eq!(read_frame(&mut stream), (0x8, 1000u16.to_be_bytes().to_vec()));
+    let mut rest = Vec::new();
+    assert_eq!(stream.read_to_end(&mut rest).unwrap(), 0);
+}
+
+#[test]
+fn handshake_without_a_key_is_rejected() {
+    let addr = start(config(), |_| {});
+    let response = exchange(
+        addr,
+        b"GET /ws HTTP/1.1\r\nHost: a\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\r\n",
+    );
+    assert_eq!(status(&response), 400);
+    assert_eq!(header(&response, "Sec-WebSocket-Version"), Some("13"));
+}
+
+#[test]
+fn upgrades_beyond_the_long_lived_cap_get_503() {
+    let config = Config { max_long_lived_connections: 1, sse_path: Some("/events".to_string()), ..config() };
+    let addr = start(config, |_| {});
+    let mut first = connect(addr);
+    first.write_all(HANDSHAKE).unwrap();
+    let (head, _) = read_response(&mut first);
+    assert_eq!(status(&head), 101);
+
+    // 断った接続はキープアライブで残るので、閉じるのを待たずにレスポンスだけを読む
+    let rejected = |raw: &[u8]| {
+        let mut stream = connect(addr);
+        stream.write_all(raw).unwrap();
+        read_response(&mut stream).0
+    };
+    let head = rejected(HANDSHAKE);
+    assert_eq!(status(&head), 503);
+    assert!(header(&head, "Retry-After").is_some());
+    assert_eq!(status(&rejected(b"GET /events HTTP/1.1\r\nHost: a\r\n\r\n")), 503);
+    // 通常のリクエストは上限に関係なく処理する
+    assert_eq!(status(&exchange(addr, b"GET /missing HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n")), 404);
+
+    // 閉じた接続の分は新しい接続に使える
+    send_frame(&mut first, 0x8, &1000u16.to_be_bytes());
+    read_frame(&mut first);
+    let mut rest = Vec::new();
+    first.read_to_end(&mut rest).unwrap();
+    let mut second = connect(addr);
+    second.write_all(HANDSHAKE).unwrap();
+    let (head, _) = read_response(&mut second);
+    assert_eq!(status(&head), 101);
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use std::io::{ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use common::{config_with_files, connect, start_with_clock};
+use web_server::{Config, MockClock};
+
+// ソケットのバッファに収まらず、読まないクライアントには送り切れない大きさ
+const LARGE_LEN: usize = 32 * 1024 * 1024;
+
+fn start_server(clock: &Arc<MockClock>) -> SocketAddr {
+    let large = vec![b'x'; LARGE_LEN];
+    let config = Config { write_timeout: 10, ..config_with_files("write-timeout", &[("large.bin", &large)]) };
+    start_with_clock(config, clock.clone(), |_| {})
+}
+
+/**
+* 時計を進めてから別の接続でイベントループを起こし、タイムアウトを確認させる
+*/
+fn advance_and_wake(clock: &MockClock, addr: SocketAddr, secs: u64) {
+    thread::sleep(Duration::from_millis(100));
+    clock.advance(Duration::from_secs(secs));
+    drop(connect(addr));
+    thread::sleep(Duration::from_millis(100));
+}
+
+/**
+* 接続が閉じるまでに受信したバイト数。リセットされた場合もそれまでの分を返す
+*/
+fn received_len(stream: &mut std::net::TcpStream) -> usize {
+    let mut total = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        match stream.read(&mut buf) {
+            Ok(0) => return total,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => return total,
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn client_that_stops_reading_is_closed() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_server(&clock);
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /large.bin HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    advance_and_wake(&clock, addr, 11);
+    // 送信待ちのレスポンスを捨てて閉じるので、全体は届かない
+    assert!(received_len(&mut stream) < LARGE_LEN);
+}
+
+#[test]
+fn pause_shorter_than_the_timeout_keeps_the_connection() {
+    let clock = Arc::new(MockClock::new());
+    let addr = start_server(&clock);
+    let mut stream = connect(addr);
+    stream.write_all(b"GET /large.bin HTTP/1.1\r\nHost: a\r\nConnection: close\r\n\r\n").unwrap();
+    advance_and_wake(&clock, addr, 9);
+    assert!(received_len(&mut stream) > LARGE_LEN);
+}